@@ -328,6 +328,17 @@ impl StarknetClient for StarknetClientHttp {
         Ok(events)
     }
 
+    async fn class_hash_at(
+        &self,
+        contract_address: FieldElement,
+        block: BlockId,
+    ) -> Result<FieldElement, StarknetClientError> {
+        self.provider
+            .get_class_hash_at(block, contract_address)
+            .await
+            .map_err(StarknetClientError::Provider)
+    }
+
     async fn call_contract(
         &self,
         contract_address: FieldElement,
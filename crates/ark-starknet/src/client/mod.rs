@@ -1,4 +1,5 @@
 pub mod http;
+pub mod tiered;
 use crate::EventResult;
 use async_trait::async_trait;
 pub use http::StarknetClientHttp;
@@ -8,6 +9,7 @@ use starknet::core::{types::FieldElement, types::*};
 use starknet::providers::ProviderError;
 use std::collections::HashMap;
 use std::marker::Sized;
+pub use tiered::TieredClient;
 
 /// Generic errors for starknet client.
 #[derive(Debug, thiserror::Error)]
@@ -92,6 +94,13 @@ pub trait StarknetClient {
         keys: Option<Vec<Vec<FieldElement>>>,
     ) -> Result<HashMap<u64, Vec<EmittedEvent>>, StarknetClientError>;
 
+    /// Returns the class hash of the contract deployed at the given address.
+    async fn class_hash_at(
+        &self,
+        contract_address: FieldElement,
+        block: BlockId,
+    ) -> Result<FieldElement, StarknetClientError>;
+
     /// Call a contract trying all the given selectors.
     /// All selector must accept the same arguments.
     async fn call_contract(
@@ -0,0 +1,335 @@
+//! Starknet Client routing the calls between an archive node
+//! and a head node, depending on the block requested.
+use super::{StarknetClient, StarknetClientError};
+use crate::EventResult;
+use async_trait::async_trait;
+use starknet::core::types::*;
+use std::collections::HashMap;
+
+/// A client holding an archive client, complete but slow, and a head client,
+/// fast but pruned.
+///
+/// The calls related to a block number lower than `split_height` are sent
+/// to the archive client, all the others (recent blocks, latest and pending
+/// blocks, calls without block) are sent to the head client.
+/// As a block hash can't be located without a call, the calls related to
+/// a block hash are sent to the archive client, which has all the blocks.
+#[derive(Debug)]
+pub struct TieredClient<A: StarknetClient, B: StarknetClient> {
+    pub archive: A,
+    pub head: B,
+    split_height: u64,
+}
+
+impl<A: StarknetClient, B: StarknetClient> TieredClient<A, B> {
+    /// Initializes a new instance, the blocks lower than `split_height`
+    /// being fetched from the archive client.
+    pub fn new(archive: A, head: B, split_height: u64) -> Self {
+        Self {
+            archive,
+            head,
+            split_height,
+        }
+    }
+
+    /// Returns the first block fetched from the head client.
+    pub fn split_height(&self) -> u64 {
+        self.split_height
+    }
+
+    /// Returns true if the given block must be fetched from the archive client.
+    fn is_archived(&self, block: &BlockId) -> bool {
+        match block {
+            BlockId::Number(n) => *n < self.split_height,
+            BlockId::Hash(_) => true,
+            BlockId::Tag(_) => false,
+        }
+    }
+
+    /// Returns true if the given range of blocks must be fetched from the
+    /// archive client. A range overlapping the split height is fetched from
+    /// the archive client, which is the only one having all of its blocks.
+    fn is_range_archived(&self, from_block: Option<&BlockId>) -> bool {
+        match from_block {
+            Some(block) => self.is_archived(block),
+            // No lower bound, the range starts at the genesis block.
+            None => self.split_height > 0,
+        }
+    }
+}
+
+#[async_trait]
+impl<A, B> StarknetClient for TieredClient<A, B>
+where
+    A: StarknetClient + Send + Sync,
+    B: StarknetClient + Send + Sync,
+{
+    /// Both clients are using the same node, all the calls are sent to the head client.
+    fn new(rpc_url: &str) -> Result<Self, StarknetClientError> {
+        Ok(Self::new(A::new(rpc_url)?, B::new(rpc_url)?, 0))
+    }
+
+    async fn events_from_tx_receipt(
+        &self,
+        transaction_hash: FieldElement,
+        keys: Option<Vec<Vec<FieldElement>>>,
+    ) -> Result<Vec<EmittedEvent>, StarknetClientError> {
+        self.head
+            .events_from_tx_receipt(transaction_hash, keys)
+            .await
+    }
+
+    async fn block_txs_hashes(
+        &self,
+        block: BlockId,
+    ) -> Result<(u64, Vec<FieldElement>), StarknetClientError> {
+        if self.is_archived(&block) {
+            self.archive.block_txs_hashes(block).await
+        } else {
+            self.head.block_txs_hashes(block).await
+        }
+    }
+
+    async fn block_id_to_u64(&self, id: &BlockId) -> Result<u64, StarknetClientError> {
+        if self.is_archived(id) {
+            self.archive.block_id_to_u64(id).await
+        } else {
+            self.head.block_id_to_u64(id).await
+        }
+    }
+
+    fn parse_block_range(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<(BlockId, BlockId), StarknetClientError> {
+        self.head.parse_block_range(from, to)
+    }
+
+    fn parse_block_id(&self, id: &str) -> Result<BlockId, StarknetClientError> {
+        self.head.parse_block_id(id)
+    }
+
+    async fn block_time(&self, block: BlockId) -> Result<u64, StarknetClientError> {
+        if self.is_archived(&block) {
+            self.archive.block_time(block).await
+        } else {
+            self.head.block_time(block).await
+        }
+    }
+
+    async fn block_number(&self) -> Result<u64, StarknetClientError> {
+        self.head.block_number().await
+    }
+
+    async fn fetch_events(
+        &self,
+        from_block: Option<BlockId>,
+        to_block: Option<BlockId>,
+        keys: Option<Vec<Vec<FieldElement>>>,
+        contract_address: Option<FieldElement>,
+        continuation_token: Option<String>,
+    ) -> Result<EventResult, StarknetClientError> {
+        if self.is_range_archived(from_block.as_ref()) {
+            self.archive
+                .fetch_events(
+                    from_block,
+                    to_block,
+                    keys,
+                    contract_address,
+                    continuation_token,
+                )
+                .await
+        } else {
+            self.head
+                .fetch_events(
+                    from_block,
+                    to_block,
+                    keys,
+                    contract_address,
+                    continuation_token,
+                )
+                .await
+        }
+    }
+
+    async fn fetch_all_block_events(
+        &self,
+        block_id: BlockId,
+        keys: Option<Vec<Vec<FieldElement>>>,
+    ) -> Result<HashMap<u64, Vec<EmittedEvent>>, StarknetClientError> {
+        if self.is_archived(&block_id) {
+            self.archive.fetch_all_block_events(block_id, keys).await
+        } else {
+            self.head.fetch_all_block_events(block_id, keys).await
+        }
+    }
+
+    async fn fetch_all_block_events_for_pending_block(
+        &self,
+        timestamp: u64,
+        keys: Option<Vec<Vec<FieldElement>>>,
+    ) -> Result<HashMap<u64, Vec<EmittedEvent>>, StarknetClientError> {
+        self.head
+            .fetch_all_block_events_for_pending_block(timestamp, keys)
+            .await
+    }
+
+    async fn class_hash_at(
+        &self,
+        contract_address: FieldElement,
+        block: BlockId,
+    ) -> Result<FieldElement, StarknetClientError> {
+        if self.is_archived(&block) {
+            self.archive.class_hash_at(contract_address, block).await
+        } else {
+            self.head.class_hash_at(contract_address, block).await
+        }
+    }
+
+    async fn call_contract(
+        &self,
+        contract_address: FieldElement,
+        selector: FieldElement,
+        calldata: Vec<FieldElement>,
+        block: BlockId,
+    ) -> Result<Vec<FieldElement>, StarknetClientError> {
+        if self.is_archived(&block) {
+            self.archive
+                .call_contract(contract_address, selector, calldata, block)
+                .await
+        } else {
+            self.head
+                .call_contract(contract_address, selector, calldata, block)
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MockStarknetClient;
+
+    fn client(
+        archive: MockStarknetClient,
+        head: MockStarknetClient,
+    ) -> TieredClient<MockStarknetClient, MockStarknetClient> {
+        TieredClient::new(archive, head, 100)
+    }
+
+    #[tokio::test]
+    async fn test_block_routed_at_boundary() {
+        let mut archive = MockStarknetClient::default();
+        archive
+            .expect_block_time()
+            .withf(|block| *block == BlockId::Number(99))
+            .times(1)
+            .returning(|_| Ok(1));
+
+        let mut head = MockStarknetClient::default();
+        head.expect_block_time()
+            .withf(|block| *block == BlockId::Number(100))
+            .times(1)
+            .returning(|_| Ok(2));
+        head.expect_block_time()
+            .withf(|block| *block == BlockId::Tag(BlockTag::Pending))
+            .times(1)
+            .returning(|_| Ok(3));
+
+        let client = client(archive, head);
+
+        assert_eq!(client.block_time(BlockId::Number(99)).await.unwrap(), 1);
+        assert_eq!(client.block_time(BlockId::Number(100)).await.unwrap(), 2);
+        assert_eq!(
+            client
+                .block_time(BlockId::Tag(BlockTag::Pending))
+                .await
+                .unwrap(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_events_routed_at_boundary() {
+        let mut archive = MockStarknetClient::default();
+        archive
+            .expect_fetch_all_block_events()
+            .withf(|block, _| *block == BlockId::Number(99))
+            .times(1)
+            .returning(|_, _| Ok(HashMap::from([(99, vec![])])));
+
+        let mut head = MockStarknetClient::default();
+        head.expect_fetch_all_block_events()
+            .withf(|block, _| *block == BlockId::Number(100))
+            .times(1)
+            .returning(|_, _| Ok(HashMap::from([(100, vec![])])));
+        head.expect_block_number().times(1).returning(|| Ok(120));
+
+        let client = client(archive, head);
+
+        let events = client
+            .fetch_all_block_events(BlockId::Number(99), None)
+            .await
+            .unwrap();
+        assert!(events.contains_key(&99));
+
+        let events = client
+            .fetch_all_block_events(BlockId::Number(100), None)
+            .await
+            .unwrap();
+        assert!(events.contains_key(&100));
+
+        assert_eq!(client.block_number().await.unwrap(), 120);
+    }
+
+    #[tokio::test]
+    async fn test_range_overlapping_boundary_routed_to_archive() {
+        let mut archive = MockStarknetClient::default();
+        archive
+            .expect_fetch_events()
+            .times(1)
+            .returning(|_, _, _, _, _| {
+                Ok(EventResult {
+                    events: HashMap::new(),
+                    continuation_token: Some("archive".to_string()),
+                })
+            });
+
+        let mut head = MockStarknetClient::default();
+        head.expect_fetch_events()
+            .times(1)
+            .returning(|_, _, _, _, _| {
+                Ok(EventResult {
+                    events: HashMap::new(),
+                    continuation_token: Some("head".to_string()),
+                })
+            });
+
+        let client = client(archive, head);
+
+        let result = client
+            .fetch_events(
+                Some(BlockId::Number(90)),
+                Some(BlockId::Number(110)),
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.continuation_token.as_deref(), Some("archive"));
+
+        let result = client
+            .fetch_events(
+                Some(BlockId::Number(100)),
+                Some(BlockId::Number(110)),
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.continuation_token.as_deref(), Some("head"));
+    }
+}
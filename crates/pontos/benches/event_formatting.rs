@@ -0,0 +1,124 @@
+//! Benchmarks of the formatting of the transfer events, over a synthetic
+//! block of 10k events already registered, as met when backfilling.
+//!
+//! The events used to be rendered into strings before being registered
+//! (`format_transfer_event` + `upsert_event`). They are now decoded into typed
+//! values, and only rendered once known to be new (`decode_transfer_event` +
+//! `upsert_decoded_event`). The number of allocations of each path is
+//! printed before the timings.
+//!
+//! Run with `cargo bench -p pontos --features testing --bench event_formatting`.
+use criterion::{criterion_group, criterion_main, Criterion};
+use pontos::managers::{BlockContext, EventManager};
+use pontos::storage::types::ContractType;
+use pontos::storage::Storage;
+use pontos::testing::{
+    synthetic_block, synthetic_block_timestamp, synthetic_contracts, InMemoryStorage,
+};
+use starknet::core::types::EmittedEvent;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const EVENTS: usize = 10_000;
+const BLOCK: u64 = 1;
+
+/// Global allocator counting the allocations, to quantify the formatting.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+type BenchEventManager = EventManager<InMemoryStorage>;
+
+/// Returns the events of the block, and a storage where they are all registered.
+fn registered_block(rt: &tokio::runtime::Runtime) -> (Vec<EmittedEvent>, Arc<InMemoryStorage>) {
+    let events = synthetic_block(BLOCK, EVENTS, &synthetic_contracts(8, 0));
+    let storage = Arc::new(InMemoryStorage::new());
+    let event_manager = BenchEventManager::new(Arc::clone(&storage));
+
+    rt.block_on(async {
+        for event in &events {
+            event_manager
+                .format_and_register_event(event, ContractType::ERC721, &block())
+                .await
+                .unwrap();
+        }
+    });
+
+    (events, storage)
+}
+
+fn block() -> BlockContext {
+    BlockContext::new(BLOCK, synthetic_block_timestamp(BLOCK))
+}
+
+/// Renders each event, then registers it.
+async fn eager(events: &[EmittedEvent], storage: &InMemoryStorage) {
+    for event in events {
+        let (_, token_event) =
+            BenchEventManager::format_transfer_event(event, ContractType::ERC721, &block())
+                .unwrap();
+        storage.upsert_event(&token_event).await.unwrap();
+    }
+}
+
+/// Decodes each event, and lets the storage render the new ones.
+async fn deferred(events: &[EmittedEvent], storage: &InMemoryStorage) {
+    for event in events {
+        let transfer =
+            BenchEventManager::decode_transfer_event(event, ContractType::ERC721, &block())
+                .unwrap();
+        storage.upsert_decoded_event(&transfer).await.unwrap();
+    }
+}
+
+/// Returns the number of allocations done by the future.
+fn count_allocations<F: Future<Output = ()>>(rt: &tokio::runtime::Runtime, f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    rt.block_on(f);
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn bench_event_formatting(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (events, storage) = registered_block(&rt);
+
+    let eager_allocations = count_allocations(&rt, eager(&events, &storage));
+    let deferred_allocations = count_allocations(&rt, deferred(&events, &storage));
+    println!(
+        "Allocations for a block of {} registered events: eager={}, deferred={}",
+        EVENTS, eager_allocations, deferred_allocations
+    );
+
+    let mut group = c.benchmark_group("event_formatting");
+    group.bench_function("eager", |b| {
+        b.to_async(&rt).iter(|| eager(&events, &storage))
+    });
+    group.bench_function("deferred", |b| {
+        b.to_async(&rt).iter(|| deferred(&events, &storage))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_event_formatting);
+criterion_main!(benches);
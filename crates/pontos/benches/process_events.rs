@@ -0,0 +1,118 @@
+//! Benchmarks of the events processing, without network nor database.
+//!
+//! Run with `cargo bench -p pontos --features testing`.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use pontos::testing::{
+    mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+    SyntheticContract,
+};
+use pontos::{Pontos, PontosConfig};
+use starknet::core::types::BlockId;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type BenchPontos =
+    Pontos<InMemoryStorage, ark_starknet::client::MockStarknetClient, NoopEventHandler>;
+
+const EVENTS_PER_BLOCK: [usize; 3] = [10, 100, 1000];
+const BLOCKS: u64 = 4;
+const CHAIN_ID: &str = "SN_MAIN";
+
+/// Contract mixes as (name, ERC721 count, ERC1155 count).
+const MIXES: [(&str, usize, usize); 3] = [("erc721", 8, 0), ("erc1155", 0, 8), ("mixed", 4, 4)];
+
+fn pontos(n_events: usize, contracts: &[SyntheticContract]) -> Arc<BenchPontos> {
+    let blocks: HashMap<u64, _> = (1..=BLOCKS)
+        .map(|b| (b, synthetic_block(b, n_events, contracts)))
+        .collect();
+
+    Arc::new(Pontos::new(
+        Arc::new(mock_client(blocks, contracts)),
+        Arc::new(InMemoryStorage::new()),
+        Arc::new(NoopEventHandler),
+        PontosConfig {
+            indexer_version: "v0.0.1".to_string(),
+            indexer_identifier: "bench".to_string(),
+            ..Default::default()
+        },
+    ))
+}
+
+/// Indexes all the blocks, one task per block when `concurrent` is set.
+async fn index(pontos: &Arc<BenchPontos>, concurrent: bool) {
+    if !concurrent {
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(BLOCKS), true, CHAIN_ID)
+            .await
+            .unwrap();
+        return;
+    }
+
+    let tasks: Vec<_> = (1..=BLOCKS)
+        .map(|b| {
+            let pontos = Arc::clone(pontos);
+            tokio::spawn(async move {
+                pontos
+                    .index_block_range(BlockId::Number(b), BlockId::Number(b), true, CHAIN_ID)
+                    .await
+                    .unwrap();
+            })
+        })
+        .collect();
+
+    for t in tasks {
+        t.await.unwrap();
+    }
+}
+
+fn bench_process_events(c: &mut Criterion, concurrent: bool) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mode = if concurrent {
+        "concurrent"
+    } else {
+        "sequential"
+    };
+    let mut group = c.benchmark_group(format!("process_events/{}", mode));
+
+    for n_events in EVENTS_PER_BLOCK {
+        for (mix, erc721, erc1155) in MIXES {
+            let contracts = synthetic_contracts(erc721, erc1155);
+
+            // New contracts: a fresh instance is used for each iteration,
+            // so every contract has to be identified.
+            group.bench_with_input(
+                BenchmarkId::new(format!("new_contracts/{}", mix), n_events),
+                &n_events,
+                |b, &n| {
+                    b.to_async(&rt).iter_batched(
+                        || pontos(n, &contracts),
+                        |p| async move { index(&p, concurrent).await },
+                        criterion::BatchSize::SmallInput,
+                    )
+                },
+            );
+
+            // Cached contracts: the same instance is re-indexing the blocks.
+            let p = pontos(n_events, &contracts);
+            rt.block_on(index(&p, false));
+            group.bench_with_input(
+                BenchmarkId::new(format!("cached_contracts/{}", mix), n_events),
+                &n_events,
+                |b, _| b.to_async(&rt).iter(|| index(&p, concurrent)),
+            );
+        }
+    }
+
+    group.finish();
+}
+
+fn sequential(c: &mut Criterion) {
+    bench_process_events(c, false);
+}
+
+fn concurrent(c: &mut Criterion) {
+    bench_process_events(c, true);
+}
+
+criterion_group!(benches, sequential, concurrent);
+criterion_main!(benches);
@@ -0,0 +1,158 @@
+//! Attribution of the token events emitted on behalf of a collection by
+//! another contract, like an account contract executing a multicall through
+//! a library call: the event is emitted by the account instead of the collection.
+//!
+//! The rules are tried in order on the events which emitter is not an NFT
+//! contract. An event attributed to a NFT contract is processed as if it was
+//! emitted by the collection. An event matched by a rule but which can't be
+//! attributed is quarantined in the storage for a manual review.
+use starknet::core::types::{EmittedEvent, FieldElement};
+use starknet::macros::selector;
+use std::fmt;
+use std::sync::Arc;
+
+/// Outcome of an attribution rule for an event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attribution {
+    /// The event doesn't match the pattern of the rule.
+    NotApplicable,
+    /// The event is attributed to the collection emitting the given event,
+    /// laid out as if emitted by the collection itself.
+    Resolved(EmittedEvent),
+    /// The event matches the pattern of the rule, but the collection
+    /// can't be resolved confidently, for the given reason.
+    Unresolved(String),
+}
+
+/// A rule attributing the events of a known wrapper pattern to their collection.
+///
+/// Rules must not call the node: the collection resolved is identified
+/// by Pontos before the event is attributed to it.
+pub trait AttributionRule: fmt::Debug + Send + Sync {
+    /// Name of the rule, recorded with the quarantined events.
+    fn name(&self) -> &str;
+
+    /// Attributes the event, given all the events of its transaction,
+    /// in the order they were emitted (including the event itself).
+    fn attribute(&self, event: &EmittedEvent, transaction_events: &[EmittedEvent]) -> Attribution;
+}
+
+/// Wrapper pattern where the account emits the `Transfer` of the collection
+/// with the collection address inserted as first key:
+/// `[Transfer, collection, from, to, token_id.low, token_id.high]`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EmbeddedCollectionKey;
+
+impl AttributionRule for EmbeddedCollectionKey {
+    fn name(&self) -> &str {
+        "embedded_collection_key"
+    }
+
+    fn attribute(&self, event: &EmittedEvent, _transaction_events: &[EmittedEvent]) -> Attribution {
+        let [selector, collection, from, to, low, high] = event.keys[..] else {
+            return Attribution::NotApplicable;
+        };
+
+        if selector != selector!("Transfer") || !event.data.is_empty() {
+            return Attribution::NotApplicable;
+        }
+
+        if collection == FieldElement::ZERO || collection == event.from_address {
+            return Attribution::Unresolved(format!(
+                "Invalid embedded collection address 0x{:064x}",
+                collection
+            ));
+        }
+
+        Attribution::Resolved(EmittedEvent {
+            from_address: collection,
+            keys: vec![selector, from, to, low, high],
+            ..event.clone()
+        })
+    }
+}
+
+/// Returns the rules used when `PontosConfig::attribution_rules` is not set.
+pub fn default_attribution_rules() -> Vec<Arc<dyn AttributionRule>> {
+    vec![Arc::new(EmbeddedCollectionKey)]
+}
+
+/// Returns the events of the transaction of the event at `index`,
+/// the events of a transaction being contiguous.
+pub(crate) fn transaction_events(events: &[EmittedEvent], index: usize) -> &[EmittedEvent] {
+    let tx_hash = events[index].transaction_hash;
+    let start = events[..index]
+        .iter()
+        .rposition(|e| e.transaction_hash != tx_hash)
+        .map_or(0, |i| i + 1);
+    let end = events[index..]
+        .iter()
+        .position(|e| e.transaction_hash != tx_hash)
+        .map_or(events.len(), |i| index + i);
+
+    &events[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::wrapped_transfer;
+
+    #[test]
+    fn test_embedded_collection_key() {
+        let account = FieldElement::from(0xacc_u64);
+        let collection = FieldElement::from(0x721_u64);
+        let event = wrapped_transfer(1, account, collection, FieldElement::from(7_u64));
+
+        let Attribution::Resolved(attributed) = EmbeddedCollectionKey.attribute(&event, &[]) else {
+            panic!("Wrapped transfer not attributed");
+        };
+        assert_eq!(attributed.from_address, collection);
+        assert_eq!(
+            attributed.keys,
+            vec![
+                selector!("Transfer"),
+                FieldElement::ZERO,
+                account,
+                FieldElement::from(7_u64),
+                FieldElement::ZERO,
+            ]
+        );
+        assert_eq!(attributed.transaction_hash, event.transaction_hash);
+
+        // A regular ERC721 transfer doesn't match the pattern.
+        let regular = EmittedEvent {
+            keys: attributed.keys.clone(),
+            ..event.clone()
+        };
+        assert_eq!(
+            EmbeddedCollectionKey.attribute(&regular, &[]),
+            Attribution::NotApplicable
+        );
+
+        let self_wrapped = wrapped_transfer(1, account, account, FieldElement::ONE);
+        assert!(matches!(
+            EmbeddedCollectionKey.attribute(&self_wrapped, &[]),
+            Attribution::Unresolved(_)
+        ));
+    }
+
+    #[test]
+    fn test_transaction_events() {
+        let events: Vec<EmittedEvent> = [1_u64, 1, 2, 2, 2, 3]
+            .into_iter()
+            .map(|tx| EmittedEvent {
+                from_address: FieldElement::ONE,
+                keys: vec![],
+                data: vec![],
+                block_hash: None,
+                block_number: None,
+                transaction_hash: FieldElement::from(tx),
+            })
+            .collect();
+
+        assert_eq!(transaction_events(&events, 0), &events[0..2]);
+        assert_eq!(transaction_events(&events, 3), &events[2..5]);
+        assert_eq!(transaction_events(&events, 5), &events[5..6]);
+    }
+}
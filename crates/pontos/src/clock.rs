@@ -0,0 +1,39 @@
+//! Source of time of the time-dependent logic of Pontos: the waits of the
+//! pending loop, the retries and the backoffs, the deadlines and the uptime,
+//! and the wall clock time of the pending lag and of the metadata patches.
+use async_trait::async_trait;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[async_trait]
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current instant.
+    fn now(&self) -> Instant;
+
+    /// Returns the wall clock time, as the time elapsed since the epoch.
+    fn unix_time(&self) -> Duration;
+
+    /// Waits until `duration` has elapsed on this clock.
+    async fn sleep(&self, duration: Duration);
+}
+
+/// Clock of the runtime, used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_time(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+}
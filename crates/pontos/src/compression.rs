@@ -0,0 +1,145 @@
+//! Compression of the exported streams and of the raw event payloads.
+//!
+//! Storage implementations storing raw event payloads compressed (for instance
+//! the payloads of the dead-letter queue) are expected to store the bytes
+//! returned by `encode_payload`, and to read them back with `decode_payload`.
+//! A compressed payload starts with the magic bytes of its format, which can't
+//! start a JSON payload: the payloads stored before enabling the compression
+//! remain readable, and the compression can be changed at any time.
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// First bytes of a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// First bytes of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression applied to an exported stream or to a payload.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// The bytes are written as is.
+    #[default]
+    None,
+    /// Gzip, with the default level.
+    Gzip,
+    /// Zstd, with a level from 1 (fastest) to 22 (smallest).
+    Zstd(i32),
+}
+
+/// Compresses a raw payload, to be stored.
+pub async fn encode_payload(payload: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    let mut writer = CompressedWriter::new(Vec::new(), compression);
+    writer.write_all(payload).await?;
+    writer.shutdown().await?;
+    Ok(writer.into_inner())
+}
+
+/// Decompresses a payload encoded by `encode_payload`, with any compression.
+/// Payloads stored uncompressed are returned as is.
+pub async fn decode_payload(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+
+    if bytes.starts_with(&GZIP_MAGIC) {
+        GzipDecoder::new(bytes).read_to_end(&mut payload).await?;
+    } else if bytes.starts_with(&ZSTD_MAGIC) {
+        ZstdDecoder::new(bytes).read_to_end(&mut payload).await?;
+    } else {
+        payload.extend_from_slice(bytes);
+    }
+
+    Ok(payload)
+}
+
+/// A writer compressing the bytes written into the inner writer.
+///
+/// `shutdown` must be called once all the bytes are written, to write
+/// the end of the compressed stream. It also shuts the inner writer down.
+#[derive(Debug)]
+pub enum CompressedWriter<W: AsyncWrite + Unpin> {
+    Plain(W),
+    Gzip(GzipEncoder<W>),
+    Zstd(ZstdEncoder<W>),
+}
+
+impl<W: AsyncWrite + Unpin> CompressedWriter<W> {
+    pub fn new(writer: W, compression: Compression) -> Self {
+        match compression {
+            Compression::None => CompressedWriter::Plain(writer),
+            Compression::Gzip => CompressedWriter::Gzip(GzipEncoder::new(writer)),
+            Compression::Zstd(level) => CompressedWriter::Zstd(ZstdEncoder::with_quality(
+                writer,
+                Level::Precise(level.clamp(1, 22)),
+            )),
+        }
+    }
+
+    /// Returns the inner writer.
+    pub fn into_inner(self) -> W {
+        match self {
+            CompressedWriter::Plain(w) => w,
+            CompressedWriter::Gzip(e) => e.into_inner(),
+            CompressedWriter::Zstd(e) => e.into_inner(),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CompressedWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            CompressedWriter::Plain(w) => Pin::new(w).poll_write(cx, buf),
+            CompressedWriter::Gzip(e) => Pin::new(e).poll_write(cx, buf),
+            CompressedWriter::Zstd(e) => Pin::new(e).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            CompressedWriter::Plain(w) => Pin::new(w).poll_flush(cx),
+            CompressedWriter::Gzip(e) => Pin::new(e).poll_flush(cx),
+            CompressedWriter::Zstd(e) => Pin::new(e).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            CompressedWriter::Plain(w) => Pin::new(w).poll_shutdown(cx),
+            CompressedWriter::Gzip(e) => Pin::new(e).poll_shutdown(cx),
+            CompressedWriter::Zstd(e) => Pin::new(e).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPRESSIONS: [Compression; 4] = [
+        Compression::None,
+        Compression::Gzip,
+        Compression::Zstd(1),
+        Compression::Zstd(19),
+    ];
+
+    #[tokio::test]
+    async fn test_payload_round_trip() {
+        let payload = br#"{"from_address":"0x1","keys":[],"data":[]}"#;
+
+        for compression in COMPRESSIONS {
+            let encoded = encode_payload(payload, compression).await.unwrap();
+            if compression != Compression::None {
+                assert_ne!(encoded, payload);
+            }
+            assert_eq!(decode_payload(&encoded).await.unwrap(), payload);
+        }
+    }
+}
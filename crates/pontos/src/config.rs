@@ -0,0 +1,764 @@
+//! Configuration of a Pontos instance.
+use crate::attribution::AttributionRule;
+use crate::clock::Clock;
+use starknet::core::types::FieldElement;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default number of blocks per chunk of `index_block_range`.
+pub const DEFAULT_RANGE_CHUNK_BLOCKS: u64 = 10_000;
+
+/// Default chain id of the events indexed by `index_pending`.
+pub const DEFAULT_CHAIN_ID: &str = "SN_MAIN";
+
+/// Configuration of a Pontos instance.
+///
+/// New fields may be added over time, consider initializing
+/// the configuration with `..Default::default()`.
+#[derive(Debug, Default, Clone)]
+pub struct PontosConfig {
+    pub indexer_version: String,
+    pub indexer_identifier: String,
+    /// Operator metadata (environment, region, chain...) distinguishing the
+    /// instances, added to the span wrapping the indexing loops.
+    pub indexer_tags: HashMap<String, String>,
+    /// Chain id of the events indexed by `index_pending`,
+    /// `DEFAULT_CHAIN_ID` if `None`.
+    pub chain_id: Option<String>,
+    /// Polling strategy used by `index_pending`.
+    pub pending_polling: PendingPolling,
+    /// Strategy used to identify the type of the contracts.
+    pub identification_strategy: CollectionIdentificationStrategy,
+    /// Initial detail of the per-transaction and per-event logs,
+    /// which can be changed at runtime with `Pontos::set_log_detail`.
+    pub log_detail: LogDetail,
+    /// If true, `index_block_range` called with `Latest` as `to_block`
+    /// doesn't return once the latest block is indexed, but keeps polling
+    /// for new latest blocks at the base interval of `pending_polling`.
+    pub continuous_mode: bool,
+    /// Pauses the contracts failing repeatedly to be indexed.
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// If set, only the given number of blocks before the last indexed block
+    /// are kept: the older blocks are pruned at the end of each `index_block_range`,
+    /// or each time the latest block is reached with `continuous_mode`.
+    pub retention_blocks: Option<u64>,
+    /// If set, the chain head is fetched from the node at most once per interval
+    /// to compute the lag reported by `Pontos::status` and `EventHandler::on_lag_update`.
+    /// If `None`, no call is dedicated to it: the chain head is only updated
+    /// when the indexer fetches the latest block for its own needs.
+    pub chain_head_refresh_interval: Option<Duration>,
+    /// Blocks per chunk of `index_block_range`, `DEFAULT_RANGE_CHUNK_BLOCKS` if `None`.
+    /// The completion of each chunk is saved, for an interrupted range to be
+    /// resumed from its last chunk. Ranges of a single chunk are not affected.
+    pub range_chunk_blocks: Option<u64>,
+    /// If true, `index_block_range` and `index_pending` run `Pontos::preflight_check`
+    /// before indexing the first block of the instance, and return
+    /// `IndexerError::PreflightFailed` if a critical inconsistency is found.
+    pub preflight_check: bool,
+    /// If true, the contracts identified as NFT contracts after being classified
+    /// as `Other` are backfilled as soon as reclassified, over the blocks already
+    /// terminated. Otherwise, they are only marked to be backfilled with
+    /// `Pontos::backfill_contracts`.
+    pub backfill_reclassified_contracts: bool,
+    /// `(attempts, interval)`: a block found in processing by `index_block_range`,
+    /// possibly by a peer which crashed, is checked again up to `attempts` times
+    /// at the given interval, in case the peer recovers. The block is then
+    /// skipped or taken over according to the force policy.
+    /// If `None`, the decision is taken immediately.
+    pub processing_backoff: Option<(u32, Duration)>,
+    /// Behaviour when an event of a block fails to be processed.
+    pub processing_strictness: ProcessingStrictness,
+    /// With `ProcessingStrictness::Strict`, if true, `index_block_range` returns
+    /// `IndexerError::BlockFailed` on the first failed block. Otherwise, it
+    /// continues with the next block.
+    pub abort_on_failed_block: bool,
+    /// Maximum number of events of a block processed by `index_block_range`.
+    /// A block returned with more events, likely by a faulty node, is marked
+    /// as failed without being processed, as with `ProcessingStrictness::Strict`.
+    /// Unbounded if `None`.
+    pub max_events_per_block: Option<usize>,
+    /// Maximum number of calls to the Starknet node running concurrently,
+    /// across all the indexing paths of the instance. Unbounded if 0.
+    pub rpc_max_concurrent_calls: usize,
+    /// If true, a terminated block is indexed again when it was terminated with
+    /// other event selectors than the current ones, including the blocks
+    /// terminated before the selectors were recorded. Adding event types
+    /// only revisits the blocks indexed without them.
+    pub reindex_on_selector_change: bool,
+    /// Clock of the waits, retries, backoffs and deadlines.
+    /// If `None`, the `SystemClock` is used.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Rules attributing to their collection the token events emitted by
+    /// another contract, like an account executing a multicall. Tried in order.
+    /// If `None`, the `default_attribution_rules` are used.
+    pub attribution_rules: Option<Vec<Arc<dyn AttributionRule>>>,
+    /// Events processed between two heartbeats of the block being indexed by
+    /// `index_block_range` (see `BlockManager::heartbeat`). No heartbeat if 0.
+    pub heartbeat_interval: usize,
+    /// Maximum number of contracts kept in the cache of the contracts types,
+    /// the least recently accessed being evicted. If `None`, the cache is unbounded.
+    pub collection_cache_size: Option<usize>,
+    /// Event selectors removed from the selectors of `EventManager::keys_selector`,
+    /// for the node not to return the events of those types.
+    /// Ignored if it would remove all the selectors.
+    pub event_key_denylist: HashSet<FieldElement>,
+    /// If set, `index_pending` checks that its loop keeps making
+    /// progress, see `PendingWatchdog`.
+    pub pending_watchdog: Option<PendingWatchdog>,
+}
+
+/// Error of `PontosConfig::from_env`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A required variable is not set.
+    Missing(&'static str),
+    /// A variable can't be parsed.
+    Invalid {
+        var: &'static str,
+        value: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing(var) => {
+                write!(f, "Environment variable {} is required but not set", var)
+            }
+            ConfigError::Invalid { var, value, reason } => write!(
+                f,
+                "Environment variable {} has an invalid value {:?}: {}",
+                var, value, reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl PontosConfig {
+    /// Builds the configuration from the environment variables:
+    ///
+    /// - `PONTOS_INDEXER_VERSION` and `PONTOS_INDEXER_IDENTIFIER` (required).
+    /// - `PONTOS_INDEXER_TAGS`: comma separated `key=value` pairs.
+    /// - `PONTOS_CHAIN_ID`.
+    /// - `PONTOS_PENDING_POLL_INTERVAL_SECS`, and `PONTOS_PENDING_POLL_MAX_INTERVAL_SECS`
+    ///   for an adaptive polling from the interval up to the max interval.
+    /// - `PONTOS_IDENTIFICATION_STRATEGY`: `entrypoint_probing`, `interface_probing`,
+    ///   `event_pattern_matching` or `class_hash`, with the comma separated
+    ///   `PONTOS_ERC721_CLASS_HASHES` and `PONTOS_ERC1155_CLASS_HASHES`.
+    /// - `PONTOS_EVENT_KEY_DENYLIST`: comma separated event selectors.
+    /// - `PONTOS_WATCHDOG_INTERVAL_SECS` with `PONTOS_WATCHDOG_TIMEOUT_SECS`,
+    ///   and the flag `PONTOS_WATCHDOG_RESTART`.
+    /// - `PONTOS_LOG_DETAIL`: `quiet`, `normal` or `verbose`.
+    /// - `PONTOS_PROCESSING_STRICTNESS`: `lenient` or `strict`.
+    /// - `PONTOS_PROCESSING_BACKOFF_ATTEMPTS` with `PONTOS_PROCESSING_BACKOFF_INTERVAL_SECS`.
+    /// - `PONTOS_CHAIN_HEAD_REFRESH_INTERVAL_SECS`, `PONTOS_RETENTION_BLOCKS`,
+    ///   `PONTOS_RANGE_CHUNK_BLOCKS`, `PONTOS_CIRCUIT_BREAKER_FAILURE_THRESHOLD`,
+    ///   `PONTOS_RPC_MAX_CONCURRENT_CALLS`, `PONTOS_HEARTBEAT_INTERVAL`,
+    ///   `PONTOS_COLLECTION_CACHE_SIZE` and `PONTOS_MAX_EVENTS_PER_BLOCK`.
+    /// - The flags `PONTOS_CONTINUOUS_MODE`, `PONTOS_PREFLIGHT_CHECK`,
+    ///   `PONTOS_BACKFILL_RECLASSIFIED_CONTRACTS`, `PONTOS_ABORT_ON_FAILED_BLOCK`
+    ///   and `PONTOS_REINDEX_ON_SELECTOR_CHANGE` (`true`/`false`, `1`/`0`, `yes`/`no`).
+    ///
+    /// The variables not set keep their default value. The clock and the
+    /// attribution rules can't be configured from the environment.
+    pub fn from_env() -> Result<PontosConfig, ConfigError> {
+        Self::from_vars(|var| std::env::var(var).ok())
+    }
+
+    /// Builds the configuration from the variables returned by `lookup`.
+    fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<PontosConfig, ConfigError> {
+        let env = EnvVars { lookup };
+        let defaults = PontosConfig::default();
+
+        let pending_polling = match (
+            env.parse::<u64>("PONTOS_PENDING_POLL_INTERVAL_SECS")?,
+            env.parse::<u64>("PONTOS_PENDING_POLL_MAX_INTERVAL_SECS")?,
+        ) {
+            (None, None) => defaults.pending_polling,
+            (Some(interval), None) => PendingPolling::FixedInterval(Duration::from_secs(interval)),
+            (min, Some(max)) => {
+                let min = min.map_or(
+                    defaults.pending_polling.base_interval(),
+                    Duration::from_secs,
+                );
+                PendingPolling::Adaptive {
+                    min,
+                    max: Duration::from_secs(max).max(min),
+                }
+            }
+        };
+
+        let processing_backoff = match (
+            env.parse::<u32>("PONTOS_PROCESSING_BACKOFF_ATTEMPTS")?,
+            env.parse::<u64>("PONTOS_PROCESSING_BACKOFF_INTERVAL_SECS")?,
+        ) {
+            (Some(attempts), Some(interval)) => Some((attempts, Duration::from_secs(interval))),
+            (None, None) => None,
+            (Some(_), None) => {
+                return Err(ConfigError::Missing(
+                    "PONTOS_PROCESSING_BACKOFF_INTERVAL_SECS",
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::Missing("PONTOS_PROCESSING_BACKOFF_ATTEMPTS"))
+            }
+        };
+
+        let pending_watchdog = match (
+            env.parse::<u64>("PONTOS_WATCHDOG_INTERVAL_SECS")?,
+            env.parse::<u64>("PONTOS_WATCHDOG_TIMEOUT_SECS")?,
+        ) {
+            (Some(interval), Some(timeout)) => Some(PendingWatchdog {
+                interval: Duration::from_secs(interval),
+                timeout: Duration::from_secs(timeout),
+                restart: env.flag("PONTOS_WATCHDOG_RESTART")?,
+            }),
+            (None, None) => None,
+            (Some(_), None) => return Err(ConfigError::Missing("PONTOS_WATCHDOG_TIMEOUT_SECS")),
+            (None, Some(_)) => return Err(ConfigError::Missing("PONTOS_WATCHDOG_INTERVAL_SECS")),
+        };
+
+        Ok(PontosConfig {
+            indexer_version: env.required("PONTOS_INDEXER_VERSION")?,
+            indexer_identifier: env.required("PONTOS_INDEXER_IDENTIFIER")?,
+            indexer_tags: env.tags("PONTOS_INDEXER_TAGS")?,
+            chain_id: env.get("PONTOS_CHAIN_ID"),
+            pending_polling,
+            identification_strategy: env.identification_strategy()?,
+            log_detail: env
+                .choice(
+                    "PONTOS_LOG_DETAIL",
+                    &[
+                        ("quiet", LogDetail::Quiet),
+                        ("normal", LogDetail::Normal),
+                        ("verbose", LogDetail::Verbose),
+                    ],
+                )?
+                .unwrap_or(defaults.log_detail),
+            continuous_mode: env.flag("PONTOS_CONTINUOUS_MODE")?,
+            circuit_breaker: CircuitBreakerConfig {
+                failure_threshold: env
+                    .parse("PONTOS_CIRCUIT_BREAKER_FAILURE_THRESHOLD")?
+                    .unwrap_or(defaults.circuit_breaker.failure_threshold),
+            },
+            retention_blocks: env.parse("PONTOS_RETENTION_BLOCKS")?,
+            chain_head_refresh_interval: env
+                .parse("PONTOS_CHAIN_HEAD_REFRESH_INTERVAL_SECS")?
+                .map(Duration::from_secs),
+            range_chunk_blocks: env.parse("PONTOS_RANGE_CHUNK_BLOCKS")?,
+            preflight_check: env.flag("PONTOS_PREFLIGHT_CHECK")?,
+            backfill_reclassified_contracts: env.flag("PONTOS_BACKFILL_RECLASSIFIED_CONTRACTS")?,
+            processing_backoff,
+            processing_strictness: env
+                .choice(
+                    "PONTOS_PROCESSING_STRICTNESS",
+                    &[
+                        ("lenient", ProcessingStrictness::Lenient),
+                        ("strict", ProcessingStrictness::Strict),
+                    ],
+                )?
+                .unwrap_or_default(),
+            abort_on_failed_block: env.flag("PONTOS_ABORT_ON_FAILED_BLOCK")?,
+            max_events_per_block: env.parse("PONTOS_MAX_EVENTS_PER_BLOCK")?,
+            rpc_max_concurrent_calls: env
+                .parse("PONTOS_RPC_MAX_CONCURRENT_CALLS")?
+                .unwrap_or_default(),
+            reindex_on_selector_change: env.flag("PONTOS_REINDEX_ON_SELECTOR_CHANGE")?,
+            heartbeat_interval: env.parse("PONTOS_HEARTBEAT_INTERVAL")?.unwrap_or_default(),
+            collection_cache_size: env.parse("PONTOS_COLLECTION_CACHE_SIZE")?,
+            event_key_denylist: env.felts("PONTOS_EVENT_KEY_DENYLIST")?,
+            pending_watchdog,
+            ..defaults
+        })
+    }
+}
+
+/// Reads and parses the variables of `PontosConfig::from_env`.
+struct EnvVars<F: Fn(&str) -> Option<String>> {
+    lookup: F,
+}
+
+impl<F: Fn(&str) -> Option<String>> EnvVars<F> {
+    /// Returns the value of the variable, `None` if not set or blank.
+    fn get(&self, var: &str) -> Option<String> {
+        (self.lookup)(var)
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    }
+
+    fn required(&self, var: &'static str) -> Result<String, ConfigError> {
+        self.get(var).ok_or(ConfigError::Missing(var))
+    }
+
+    fn parse<T: FromStr>(&self, var: &'static str) -> Result<Option<T>, ConfigError>
+    where
+        T::Err: fmt::Display,
+    {
+        self.get(var)
+            .map(|value| {
+                value.parse().map_err(|e: T::Err| ConfigError::Invalid {
+                    var,
+                    reason: e.to_string(),
+                    value,
+                })
+            })
+            .transpose()
+    }
+
+    fn flag(&self, var: &'static str) -> Result<bool, ConfigError> {
+        Ok(self
+            .choice(
+                var,
+                &[
+                    ("true", true),
+                    ("1", true),
+                    ("yes", true),
+                    ("false", false),
+                    ("0", false),
+                    ("no", false),
+                ],
+            )?
+            .unwrap_or_default())
+    }
+
+    /// Parses a case insensitive value among the given choices.
+    fn choice<T: Clone>(
+        &self,
+        var: &'static str,
+        choices: &[(&str, T)],
+    ) -> Result<Option<T>, ConfigError> {
+        let Some(value) = self.get(var) else {
+            return Ok(None);
+        };
+
+        choices
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&value))
+            .map(|(_, choice)| Some(choice.clone()))
+            .ok_or_else(|| ConfigError::Invalid {
+                var,
+                value,
+                reason: format!(
+                    "expected one of {}",
+                    choices
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            })
+    }
+
+    fn tags(&self, var: &'static str) -> Result<HashMap<String, String>, ConfigError> {
+        let Some(value) = self.get(var) else {
+            return Ok(HashMap::new());
+        };
+
+        value
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((k, v)) if !k.trim().is_empty() => {
+                    Ok((k.trim().to_string(), v.trim().to_string()))
+                }
+                _ => Err(ConfigError::Invalid {
+                    var,
+                    value: value.clone(),
+                    reason: format!("expected key=value pairs, got {:?}", pair),
+                }),
+            })
+            .collect()
+    }
+
+    fn felts(&self, var: &'static str) -> Result<HashSet<FieldElement>, ConfigError> {
+        let Some(value) = self.get(var) else {
+            return Ok(HashSet::new());
+        };
+
+        value
+            .split(',')
+            .filter(|h| !h.trim().is_empty())
+            .map(|h| {
+                FieldElement::from_hex_be(h.trim()).map_err(|e| ConfigError::Invalid {
+                    var,
+                    value: value.clone(),
+                    reason: format!("invalid felt {:?}: {}", h, e),
+                })
+            })
+            .collect()
+    }
+
+    fn identification_strategy(&self) -> Result<CollectionIdentificationStrategy, ConfigError> {
+        const VAR: &str = "PONTOS_IDENTIFICATION_STRATEGY";
+
+        let Some(strategy) = self.choice(
+            VAR,
+            &[
+                ("entrypoint_probing", "entrypoint_probing"),
+                ("interface_probing", "interface_probing"),
+                ("event_pattern_matching", "event_pattern_matching"),
+                ("class_hash", "class_hash"),
+            ],
+        )?
+        else {
+            return Ok(CollectionIdentificationStrategy::default());
+        };
+
+        Ok(match strategy {
+            "interface_probing" => CollectionIdentificationStrategy::InterfaceProbing,
+            "event_pattern_matching" => CollectionIdentificationStrategy::EventPatternMatching,
+            "class_hash" => CollectionIdentificationStrategy::ClassHash {
+                erc721: self.felts("PONTOS_ERC721_CLASS_HASHES")?,
+                erc1155: self.felts("PONTOS_ERC1155_CLASS_HASHES")?,
+            },
+            _ => CollectionIdentificationStrategy::EntrypointProbing,
+        })
+    }
+}
+
+/// Defines how the blocks are processed when some of their events fail.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProcessingStrictness {
+    /// The events failing to be processed are added to the dead-letter queue,
+    /// and the block is terminated with the other events.
+    #[default]
+    Lenient,
+    /// The processing of the block is aborted on the first event failing,
+    /// which is still added to the dead-letter queue. The block is marked as
+    /// `BlockIndexingStatus::Failed`, and indexed again by the next run,
+    /// whatever its version. The pending block is always processed leniently.
+    Strict,
+}
+
+/// Defines which blocks already indexed are indexed again by `index_block_range`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ForcePolicy {
+    /// No block is forced: only the blocks indexed by a version older than
+    /// the current one are indexed again.
+    #[default]
+    Never,
+    /// All the blocks are indexed again.
+    Always,
+    /// Only the blocks indexed by a version older than the given one are
+    /// indexed again. Legacy versions without any number are considered older.
+    OlderThan(String),
+}
+
+impl From<bool> for ForcePolicy {
+    fn from(do_force: bool) -> Self {
+        if do_force {
+            ForcePolicy::Always
+        } else {
+            ForcePolicy::Never
+        }
+    }
+}
+
+/// Watchdog of `index_pending`, run alongside the loop. If the pending loop
+/// runs without a successful tick for `timeout`, like when the node fails every
+/// call, `EventHandler::on_watchdog_trigger` is called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingWatchdog {
+    /// Interval between two checks of the pending loop.
+    pub interval: Duration,
+    /// Time without a successful tick after which the loop is stalled.
+    /// Must be longer than the maximum polling interval.
+    pub timeout: Duration,
+    /// If true, the stalled loop is restarted at its next tick: its retries
+    /// and its polling interval are reset. The pending block is kept.
+    pub restart: bool,
+}
+
+/// Thresholds of the per-contract circuit breaker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures of a contract's events after which the contract
+    /// is paused. 0 disables the circuit breaker.
+    pub failure_threshold: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 10,
+        }
+    }
+}
+
+/// Detail of the logs emitted for each transaction and each event,
+/// which are the most frequent ones.
+///
+/// Those logs are also emitted under dedicated targets
+/// (`pontos::pending::tx` and `pontos::events`) to be filtered
+/// independently of the other logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogDetail {
+    /// Per-transaction and per-event logs are suppressed.
+    Quiet = 0,
+    /// Per-transaction and per-event logs are emitted with a summary.
+    #[default]
+    Normal = 1,
+    /// Per-event logs also contain the full event.
+    Verbose = 2,
+}
+
+impl From<u8> for LogDetail {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => LogDetail::Quiet,
+            1 => LogDetail::Normal,
+            _ => LogDetail::Verbose,
+        }
+    }
+}
+
+/// Defines how the type of a contract (ERC721, ERC1155 or other) is detected.
+/// Each strategy has a different cost in terms of RPC calls.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum CollectionIdentificationStrategy {
+    /// Calls the `ownerOf` / `balanceOf` entrypoints and checks
+    /// how the contract responds. Up to 4 calls per contract.
+    #[default]
+    EntrypointProbing,
+    /// Matches the class hash of the contract against known class hashes.
+    /// One call per contract, any unknown class hash is identified as other.
+    ClassHash {
+        erc721: HashSet<FieldElement>,
+        erc1155: HashSet<FieldElement>,
+    },
+    /// Calls `supports_interface` (SRC5 and legacy ERC165 interface ids).
+    InterfaceProbing,
+    /// Infers the type from the layout of the event emitted by the contract,
+    /// without any call. Layouts which are ambiguous (like Cairo 0 transfers, which
+    /// are identical for ERC20 and ERC721) fall back to the entrypoint probing.
+    EventPatternMatching,
+}
+
+/// Defines how often the pending block is polled by `index_pending`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PendingPolling {
+    /// Always waits the same interval between two ticks.
+    FixedInterval(Duration),
+    /// Starts with `min` interval, and doubles the interval (up to `max`)
+    /// each time the pending block is unchanged since the previous tick.
+    /// The interval is reset to `min` as soon as the pending block changes.
+    Adaptive { min: Duration, max: Duration },
+}
+
+impl Default for PendingPolling {
+    fn default() -> Self {
+        PendingPolling::FixedInterval(Duration::from_secs(2))
+    }
+}
+
+impl PendingPolling {
+    /// Returns the interval to use when the loop is (re)started.
+    pub fn base_interval(&self) -> Duration {
+        match self {
+            PendingPolling::FixedInterval(d) => *d,
+            PendingPolling::Adaptive { min, .. } => *min,
+        }
+    }
+
+    /// Computes the next interval from the current one.
+    /// `has_changed` must be true if the pending block (timestamp or
+    /// transactions count) changed since the previous tick.
+    pub fn next_interval(&self, current: Duration, has_changed: bool) -> Duration {
+        match self {
+            PendingPolling::FixedInterval(d) => *d,
+            PendingPolling::Adaptive { min, max } => {
+                if has_changed {
+                    *min
+                } else {
+                    std::cmp::min(current.saturating_mul(2), *max).max(*min)
+                }
+            }
+        }
+    }
+
+    /// Adds up to 10% of jitter to the given interval for adaptive polling,
+    /// to avoid several instances polling the node at the same time.
+    pub fn with_jitter(&self, interval: Duration) -> Duration {
+        match self {
+            PendingPolling::FixedInterval(_) => interval,
+            PendingPolling::Adaptive { .. } => {
+                let max_jitter_ms = interval.as_millis() as u64 / 10;
+                if max_jitter_ms == 0 {
+                    return interval;
+                }
+
+                let nanos = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.subsec_nanos() as u64)
+                    .unwrap_or_default();
+
+                interval + Duration::from_millis(nanos % max_jitter_ms)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_interval() {
+        let polling = PendingPolling::FixedInterval(Duration::from_secs(2));
+        let d = polling.next_interval(Duration::from_secs(2), false);
+        assert_eq!(d, Duration::from_secs(2));
+        assert_eq!(polling.with_jitter(d), d);
+    }
+
+    #[test]
+    fn test_adaptive_interval() {
+        let polling = PendingPolling::Adaptive {
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(5),
+        };
+
+        let mut d = polling.base_interval();
+        assert_eq!(d, Duration::from_secs(1));
+
+        d = polling.next_interval(d, false);
+        assert_eq!(d, Duration::from_secs(2));
+        d = polling.next_interval(d, false);
+        assert_eq!(d, Duration::from_secs(4));
+        d = polling.next_interval(d, false);
+        assert_eq!(d, Duration::from_secs(5));
+        d = polling.next_interval(d, false);
+        assert_eq!(d, Duration::from_secs(5));
+
+        // Any change resets the interval.
+        d = polling.next_interval(d, true);
+        assert_eq!(d, Duration::from_secs(1));
+
+        let jittered = polling.with_jitter(Duration::from_secs(5));
+        assert!(jittered >= Duration::from_secs(5));
+        assert!(jittered < Duration::from_millis(5500));
+    }
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<PontosConfig, ConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        PontosConfig::from_vars(|var| vars.get(var).cloned())
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        let config = from_vars(&[
+            ("PONTOS_INDEXER_VERSION", "v1.2.3"),
+            ("PONTOS_INDEXER_IDENTIFIER", "TASK#1"),
+            ("PONTOS_INDEXER_TAGS", "env=prod, region=eu"),
+            ("PONTOS_CHAIN_ID", "SN_SEPOLIA"),
+            ("PONTOS_PENDING_POLL_INTERVAL_SECS", "1"),
+            ("PONTOS_PENDING_POLL_MAX_INTERVAL_SECS", "8"),
+            ("PONTOS_IDENTIFICATION_STRATEGY", "class_hash"),
+            ("PONTOS_ERC721_CLASS_HASHES", "0x721,0x722"),
+            ("PONTOS_LOG_DETAIL", "Verbose"),
+            ("PONTOS_CONTINUOUS_MODE", "yes"),
+            ("PONTOS_PROCESSING_STRICTNESS", "strict"),
+            ("PONTOS_PROCESSING_BACKOFF_ATTEMPTS", "3"),
+            ("PONTOS_PROCESSING_BACKOFF_INTERVAL_SECS", "10"),
+            ("PONTOS_RPC_MAX_CONCURRENT_CALLS", "16"),
+            ("PONTOS_RETENTION_BLOCKS", " "),
+            ("PONTOS_COLLECTION_CACHE_SIZE", "10000"),
+            ("PONTOS_MAX_EVENTS_PER_BLOCK", "50000"),
+            ("PONTOS_EVENT_KEY_DENYLIST", "0xa,0xb"),
+            ("PONTOS_WATCHDOG_INTERVAL_SECS", "10"),
+            ("PONTOS_WATCHDOG_TIMEOUT_SECS", "60"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.indexer_version, "v1.2.3");
+        assert_eq!(config.indexer_identifier, "TASK#1");
+        assert_eq!(config.indexer_tags.len(), 2);
+        assert_eq!(config.indexer_tags["region"], "eu");
+        assert_eq!(config.chain_id.as_deref(), Some("SN_SEPOLIA"));
+        assert_eq!(
+            config.pending_polling,
+            PendingPolling::Adaptive {
+                min: Duration::from_secs(1),
+                max: Duration::from_secs(8),
+            }
+        );
+        assert_eq!(
+            config.identification_strategy,
+            CollectionIdentificationStrategy::ClassHash {
+                erc721: HashSet::from([
+                    FieldElement::from(0x721_u64),
+                    FieldElement::from(0x722_u64)
+                ]),
+                erc1155: HashSet::new(),
+            }
+        );
+        assert_eq!(config.log_detail, LogDetail::Verbose);
+        assert!(config.continuous_mode);
+        assert!(!config.preflight_check);
+        assert_eq!(config.processing_strictness, ProcessingStrictness::Strict);
+        assert_eq!(
+            config.processing_backoff,
+            Some((3, Duration::from_secs(10)))
+        );
+        assert_eq!(config.rpc_max_concurrent_calls, 16);
+        assert_eq!(config.retention_blocks, None);
+        assert_eq!(config.collection_cache_size, Some(10000));
+        assert_eq!(config.max_events_per_block, Some(50000));
+        assert_eq!(
+            config.event_key_denylist,
+            HashSet::from([FieldElement::from(0xa_u64), FieldElement::from(0xb_u64)])
+        );
+        assert_eq!(config.circuit_breaker, CircuitBreakerConfig::default());
+        assert_eq!(
+            config.pending_watchdog,
+            Some(PendingWatchdog {
+                interval: Duration::from_secs(10),
+                timeout: Duration::from_secs(60),
+                restart: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_config_from_env_errors() {
+        assert_eq!(
+            from_vars(&[("PONTOS_INDEXER_VERSION", "v1")]).unwrap_err(),
+            ConfigError::Missing("PONTOS_INDEXER_IDENTIFIER")
+        );
+
+        let required = [
+            ("PONTOS_INDEXER_VERSION", "v1"),
+            ("PONTOS_INDEXER_IDENTIFIER", "TASK#1"),
+        ];
+        let with = |var, value| from_vars(&[required[0], required[1], (var, value)]);
+
+        let err = with("PONTOS_RPC_MAX_CONCURRENT_CALLS", "many").unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                var: "PONTOS_RPC_MAX_CONCURRENT_CALLS",
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("\"many\""));
+
+        let err = with("PONTOS_LOG_DETAIL", "loud").unwrap_err();
+        assert!(err.to_string().contains("quiet, normal, verbose"));
+
+        assert_eq!(
+            with("PONTOS_PROCESSING_BACKOFF_ATTEMPTS", "3").unwrap_err(),
+            ConfigError::Missing("PONTOS_PROCESSING_BACKOFF_INTERVAL_SECS")
+        );
+        assert!(with("PONTOS_INDEXER_TAGS", "env").is_err());
+        assert!(with("PONTOS_CONTINUOUS_MODE", "maybe").is_err());
+    }
+}
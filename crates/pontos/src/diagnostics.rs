@@ -0,0 +1,1050 @@
+//! Health, status and diagnostics of the indexer and of the indexed blocks.
+use crate::event_handler::EventHandler;
+use crate::managers::{BlockContext, EventManager};
+use crate::storage::types::{ContractType, IndexerInfo, TokenEvent};
+use crate::storage::Storage;
+use crate::{
+    is_marketplace_contract, BlockDiagnosis, DecodedTokenEvent, HealthStatus, IndexerError,
+    IndexerLag, IndexerResult, Pontos, PontosStatistics, PontosStatus, PreflightFinding,
+    PreflightReport, ValidationError, ELEMENT_MARKETPLACE_EVENT_HEX, VENTORY_MARKETPLACE_EVENT_HEX,
+    VENTORY_MARKETPLACE_OFFER_ACCEPTED_EVENT_HEX,
+};
+use anyhow::Result;
+use ark_starknet::client::StarknetClient;
+use ark_starknet::format::to_hex_str;
+use starknet::core::types::*;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::warn;
+use version_compare::{compare, Cmp};
+
+/// Maximum duration of each check done by `Pontos::healthz`.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Window over which `Pontos::statistics` computes the indexing rate.
+const STATISTICS_WINDOW_SECS: u64 = 60;
+
+/// Seconds after which a block still in processing is reported as stuck
+/// by `Pontos::statistics`.
+const STUCK_BLOCK_SECS: u64 = 600;
+
+/// Time after its last heartbeat during which an indexer
+/// is listed by `Pontos::list_active_indexers`.
+const ACTIVE_INDEXER_WINDOW: Duration = Duration::from_secs(300);
+
+impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C, E> {
+    /// Checks the storage and the RPC, each with a timeout,
+    /// and returns the health of this instance.
+    pub async fn healthz(&self) -> HealthStatus {
+        let (storage, rpc) = tokio::join!(
+            tokio::time::timeout(HEALTH_CHECK_TIMEOUT, self.storage.health_check()),
+            tokio::time::timeout(
+                HEALTH_CHECK_TIMEOUT,
+                self.rpc_permits.call(self.client.block_number())
+            ),
+        );
+
+        let last_indexed_block = match self.last_indexed_block.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(n - 1),
+        };
+
+        HealthStatus {
+            storage_ok: matches!(storage, Ok(Ok(_))),
+            rpc_ok: matches!(rpc, Ok(Ok(_))),
+            last_indexed_block,
+            pending_loop_running: self.pending_loop_running.load(Ordering::Relaxed),
+            uptime_secs: self.uptime().as_secs(),
+        }
+    }
+
+    /// Checks the consistency of the storage with the chain, before indexing:
+    /// * the highest terminated block must exist on the chain, with the same
+    ///   timestamp (the block hash is not stored).
+    /// * no block must be left in processing by this indexer identifier.
+    /// * the versions of the indexers which indexed the blocks must be readable.
+    ///
+    /// The findings are logged and returned, nothing is modified.
+    pub async fn preflight_check(&self) -> IndexerResult<PreflightReport> {
+        let chain_head = self.rpc_permits.call(self.client.block_number()).await?;
+        self.observe_chain_head(chain_head);
+
+        let last_terminated = self.storage.get_last_terminated_block().await?;
+        let indexer_versions = self.storage.get_indexer_versions().await?;
+        let mut findings = vec![];
+
+        if let Some((block, stored)) = last_terminated {
+            if block > chain_head {
+                findings.push(PreflightFinding::TerminatedAboveChainHead { block, chain_head });
+            } else {
+                let chain = self
+                    .rpc_permits
+                    .call(self.client.block_time(BlockId::Number(block)))
+                    .await?;
+                if chain != stored {
+                    findings.push(PreflightFinding::BlockTimestampMismatch {
+                        block,
+                        stored,
+                        chain,
+                    });
+                }
+            }
+        }
+
+        let interrupted = self
+            .storage
+            .get_processing_blocks(&self.config.indexer_identifier)
+            .await?;
+        if !interrupted.is_empty() {
+            findings.push(PreflightFinding::InterruptedBlocks {
+                blocks: interrupted,
+            });
+        }
+
+        for version in &indexer_versions {
+            if let Ok(Cmp::Lt) = compare(&self.config.indexer_version, version) {
+                findings.push(PreflightFinding::NewerIndexerVersion {
+                    version: version.clone(),
+                });
+            }
+        }
+
+        for finding in &findings {
+            warn!("Preflight check: {:?}", finding);
+        }
+
+        Ok(PreflightReport {
+            chain_head,
+            last_terminated_block: last_terminated.map(|(n, _)| n),
+            indexer_versions,
+            findings,
+        })
+    }
+
+    /// Runs the preflight check once per instance if required by the configuration.
+    pub(crate) async fn ensure_preflight(&self) -> IndexerResult<()> {
+        if !self.config.preflight_check || self.preflight_passed.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        let report = self.preflight_check().await?;
+        if report.is_critical() {
+            return Err(IndexerError::PreflightFailed(
+                report
+                    .findings
+                    .into_iter()
+                    .filter(PreflightFinding::is_critical)
+                    .collect(),
+            ));
+        }
+
+        self.preflight_passed.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the current runtime status of this instance.
+    pub fn status(&self) -> PontosStatus {
+        PontosStatus {
+            pending_poll_interval: Duration::from_millis(
+                self.pending_poll_interval_ms.load(Ordering::Relaxed),
+            ),
+            discarded_events: self.discarded_events.load(Ordering::Relaxed),
+            log_detail: self.log_detail(),
+            suppressed_logs: self.suppressed_logs.load(Ordering::Relaxed),
+            lag: self.lag(),
+        }
+    }
+
+    /// Computes the lag from the last chain head known.
+    fn lag(&self) -> IndexerLag {
+        let chain_head = match self.chain_head.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(n - 1),
+        };
+        let last_indexed_block = match self.last_indexed_block.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(n - 1),
+        };
+
+        let pending_timestamp = self.pending_timestamp.load(Ordering::Relaxed);
+        let pending_age_secs =
+            if self.pending_loop_running.load(Ordering::Relaxed) && pending_timestamp > 0 {
+                let now = self.clock.unix_time().as_secs();
+                Some(now.saturating_sub(pending_timestamp))
+            } else {
+                None
+            };
+
+        IndexerLag {
+            chain_head,
+            blocks_behind: chain_head
+                .zip(last_indexed_block)
+                .map(|(head, last)| head.saturating_sub(last)),
+            pending_unprocessed_txs: self.pending_unprocessed_txs.load(Ordering::Relaxed),
+            pending_age_secs,
+        }
+    }
+
+    /// Fetches the chain head if `chain_head_refresh_interval` elapsed since
+    /// the last fetch, and reports the lag to the event handler.
+    pub(crate) async fn report_lag(&self) {
+        if let Some(interval) = self.config.chain_head_refresh_interval {
+            let now_ms = self.uptime().as_millis() as u64 + 1;
+            let last_ms = self.chain_head_refreshed_ms.load(Ordering::Relaxed);
+
+            // Only one of the concurrent loops does the call.
+            if (last_ms == 0 || now_ms - last_ms >= interval.as_millis() as u64)
+                && self
+                    .chain_head_refreshed_ms
+                    .compare_exchange(last_ms, now_ms, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            {
+                match self.rpc_permits.call(self.client.block_number()).await {
+                    Ok(n) => self.observe_chain_head(n),
+                    Err(e) => warn!("Couldn't refresh the chain head: {:?}", e),
+                }
+            }
+        }
+
+        self.event_handler.on_lag_update(self.lag()).await;
+    }
+
+    /// Records in the storage that this instance is alive, with its last block
+    /// indexed, for `list_active_indexers`. A failure is only logged.
+    pub(crate) async fn register_indexer(&self) {
+        if let Err(e) = self.storage.register_indexer(&self.indexer_info()).await {
+            warn!("Couldn't register the indexer heartbeat: {:?}", e);
+        }
+    }
+
+    pub(crate) fn indexer_info(&self) -> IndexerInfo {
+        IndexerInfo {
+            identifier: self.config.indexer_identifier.clone(),
+            version: self.config.indexer_version.clone(),
+            last_heartbeat: self.clock.unix_time().as_millis() as u64,
+            last_block: match self.last_indexed_block.load(Ordering::Relaxed) {
+                0 => None,
+                n => Some(n - 1),
+            },
+            inactive: false,
+        }
+    }
+
+    /// Returns the indexers sharing the storage of this instance, including
+    /// itself, which reported to be alive during the last `ACTIVE_INDEXER_WINDOW`.
+    /// The instances report after each block indexed, each tick of
+    /// `index_pending` and each poll of the latest block in `continuous_mode`.
+    /// The instances stopped by `shutdown` are not listed.
+    pub async fn list_active_indexers(&self) -> IndexerResult<Vec<IndexerInfo>> {
+        let active_since = (self.clock.unix_time().as_millis() as u64)
+            .saturating_sub(ACTIVE_INDEXER_WINDOW.as_millis() as u64);
+
+        Ok(self
+            .storage
+            .get_indexers()
+            .await?
+            .into_iter()
+            .filter(|i| !i.inactive && i.last_heartbeat >= active_since)
+            .collect())
+    }
+
+    /// Returns the indexing throughput over the last `STATISTICS_WINDOW_SECS` seconds,
+    /// and the blocks currently in processing.
+    pub async fn statistics(&self) -> IndexerResult<PontosStatistics> {
+        Ok(PontosStatistics {
+            indexing_rate: self
+                .block_manager
+                .compute_indexing_rate(STATISTICS_WINDOW_SECS),
+            window: Duration::from_secs(STATISTICS_WINDOW_SECS),
+            processing_blocks: self.block_manager.pending_block_count().await?,
+            stuck_blocks: self.block_manager.stuck_blocks(STUCK_BLOCK_SECS).await?,
+        })
+    }
+
+    /// Fetches and decodes the token events of the given block,
+    /// without writing anything into the storage.
+    ///
+    /// The contracts identification uses the cache and the storage,
+    /// but the contracts discovered during this call are not persisted.
+    /// The pending block is supported, using the transactions receipts.
+    pub async fn peek_block(
+        &self,
+        block: BlockId,
+        chain_id: &str,
+    ) -> IndexerResult<Vec<DecodedTokenEvent>> {
+        let keys = self.event_manager.keys_selector();
+
+        let (block_timestamp, events) = if block == BlockId::Tag(BlockTag::Pending) {
+            let (ts, txs) = self
+                .rpc_permits
+                .call(self.client.block_txs_hashes(block))
+                .await?;
+            let mut events = vec![];
+            for tx_hash in txs {
+                events.extend(
+                    self.rpc_permits
+                        .call(self.client.events_from_tx_receipt(tx_hash, keys.clone()))
+                        .await?,
+                );
+            }
+            (ts, events)
+        } else {
+            let ts = self.rpc_permits.call(self.client.block_time(block)).await?;
+            let events = self
+                .rpc_permits
+                .call(self.client.fetch_all_block_events(block, keys))
+                .await?
+                .into_values()
+                .flatten()
+                .collect::<Vec<EmittedEvent>>();
+            (ts, events)
+        };
+
+        let mut decoded = vec![];
+        for event in events {
+            let context = BlockContext::from_event(&event, block_timestamp);
+            match self.decode_event(&event, &context, chain_id).await {
+                Ok(Some(d)) => decoded.push(d),
+                Ok(None) => (),
+                Err(e) => warn!(
+                    "Can't decode event of tx 0x{:064x}: {:?}",
+                    event.transaction_hash, e
+                ),
+            }
+        }
+
+        Ok(decoded)
+    }
+
+    /// Decodes a single event, without registering it.
+    /// Returns `None` if the event is not related to a NFT contract.
+    async fn decode_event(
+        &self,
+        event: &EmittedEvent,
+        block: &BlockContext,
+        chain_id: &str,
+    ) -> Result<Option<DecodedTokenEvent>> {
+        if is_marketplace_contract(&event.from_address) {
+            let event_name = match event.keys.first() {
+                Some(name) => *name,
+                None => return Ok(None),
+            };
+
+            let mut sale =
+                if event_name == FieldElement::from_hex_be(ELEMENT_MARKETPLACE_EVENT_HEX)? {
+                    self.event_manager
+                        .format_element_sale_event(event, block)
+                        .await?
+                } else if event_name == FieldElement::from_hex_be(VENTORY_MARKETPLACE_EVENT_HEX)?
+                    || event_name
+                        == FieldElement::from_hex_be(VENTORY_MARKETPLACE_OFFER_ACCEPTED_EVENT_HEX)?
+                {
+                    self.event_manager
+                        .format_ventory_sale_or_accepted_offer_event(event, block)
+                        .await?
+                } else {
+                    return Ok(None);
+                };
+
+            let contract_type = self
+                .contract_manager
+                .peek_contract_type(
+                    FieldElement::from_hex_be(&sale.nft_contract_address)?,
+                    chain_id,
+                )
+                .await?;
+
+            if contract_type == ContractType::Other {
+                return Ok(None);
+            }
+
+            sale.nft_type = Some(contract_type.to_string());
+
+            return Ok(Some(DecodedTokenEvent {
+                contract_type,
+                event: TokenEvent::Sale(sale),
+            }));
+        }
+
+        let contract_type = self
+            .contract_manager
+            .peek_contract_type(event.from_address, chain_id)
+            .await?;
+
+        if contract_type == ContractType::Other {
+            return Ok(None);
+        }
+
+        let (_, transfer) =
+            EventManager::<S>::format_transfer_event(event, contract_type.clone(), block)?;
+
+        Ok(Some(DecodedTokenEvent {
+            contract_type,
+            event: TokenEvent::Transfer(transfer),
+        }))
+    }
+
+    /// Compares the transfer events stored for the given block to the ones
+    /// returned by the node, for incident response.
+    ///
+    /// Nothing is written into the storage, and the contracts identified
+    /// during this call are not cached.
+    pub async fn diagnose_block(&self, block_number: u64) -> IndexerResult<BlockDiagnosis> {
+        let status = self
+            .block_manager
+            .get_block_info(block_number)
+            .await?
+            .map(|details| details.info.status);
+        let stored_events = self.storage.count_block_events(block_number).await?;
+        let mut missing_on_node = self.storage.get_block_event_ids(block_number).await?;
+
+        let block = BlockId::Number(block_number);
+        let block_timestamp = self.rpc_permits.call(self.client.block_time(block)).await?;
+        let events = self
+            .rpc_permits
+            .call(
+                self.client
+                    .fetch_all_block_events(block, self.event_manager.keys_selector()),
+            )
+            .await?
+            .into_values()
+            .flatten()
+            .collect::<Vec<EmittedEvent>>();
+
+        let mut contracts = BTreeMap::new();
+        let mut node_events = 0;
+        let mut missing_in_storage = vec![];
+        for event in events {
+            if is_marketplace_contract(&event.from_address) {
+                continue;
+            }
+
+            let address = to_hex_str(&event.from_address);
+            if !contracts.contains_key(&address) {
+                let identified = match self
+                    .contract_manager
+                    .cached_contract_type(event.from_address)
+                {
+                    Some(contract_type) => Ok(contract_type),
+                    None => self
+                        .contract_manager
+                        .get_contract_type(event.from_address)
+                        .await
+                        .map_err(|e| e.to_string()),
+                };
+                contracts.insert(address.clone(), identified);
+            }
+
+            let contract_type = match &contracts[&address] {
+                Ok(ContractType::Other) | Err(_) => continue,
+                Ok(contract_type) => contract_type.clone(),
+            };
+
+            node_events += 1;
+            let context = BlockContext::from_event(&event, block_timestamp);
+            let stored = EventManager::<S>::format_transfer_event(&event, contract_type, &context)
+                .ok()
+                .and_then(|(_, transfer)| {
+                    missing_on_node
+                        .iter()
+                        .position(|id| *id == transfer.event_id)
+                });
+            match stored {
+                Some(index) => {
+                    missing_on_node.remove(index);
+                }
+                None => missing_in_storage.push(event),
+            }
+        }
+
+        Ok(BlockDiagnosis {
+            block_number,
+            status,
+            stored_events,
+            node_events,
+            missing_on_node,
+            missing_in_storage,
+            contracts,
+        })
+    }
+
+    /// Validates the blocks of the given range against the node data.
+    ///
+    /// For each block, the number of transfer events stored is compared
+    /// to the number of transfer events emitted by NFT contracts returned by the node.
+    /// This is an expensive operation, as all the events of each block are fetched again.
+    pub async fn index_block_range_validate(
+        &self,
+        from: u64,
+        to: u64,
+        chain_id: &str,
+    ) -> IndexerResult<Vec<ValidationError>> {
+        let mut errors = vec![];
+
+        for block in from..=to {
+            let stored = self.block_manager.count_block_events(block).await?;
+
+            let blocks_events = self
+                .rpc_permits
+                .call(self.client.fetch_all_block_events(
+                    BlockId::Number(block),
+                    self.event_manager.keys_selector(),
+                ))
+                .await?;
+
+            let mut live = 0;
+            for event in blocks_events.into_values().flatten() {
+                if is_marketplace_contract(&event.from_address) {
+                    continue;
+                }
+
+                let contract_type = self
+                    .contract_manager
+                    .peek_contract_type(event.from_address, chain_id)
+                    .await?;
+
+                if contract_type != ContractType::Other {
+                    live += 1;
+                }
+            }
+
+            if stored != live {
+                warn!(
+                    "Block {} validation failed: stored={}, live={}",
+                    block, stored, live
+                );
+                errors.push(ValidationError::EventCountMismatch {
+                    block,
+                    stored,
+                    live,
+                });
+            }
+        }
+
+        Ok(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::types::{BlockIndexingStatus, StorageError};
+    use crate::storage::MockStorage;
+    use crate::tests::{config, indexing_storage, transfer_event, TestEventHandler};
+    use crate::PontosConfig;
+    use ark_starknet::client::MockStarknetClient;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_peek_block_does_not_write() {
+        // No write expectation is set on the storage, any write would panic.
+        let mut storage = MockStorage::default();
+        storage
+            .expect_get_contract_type_overrides()
+            .returning(|| Box::pin(futures::future::ready(Ok(vec![]))));
+        storage
+            .expect_get_contract_type()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(None))));
+        storage
+            .expect_get_class_hash_type()
+            .returning(|_| Box::pin(futures::future::ready(Ok(None))));
+
+        let contract_address = FieldElement::from_hex_be("0x1234").unwrap();
+
+        let mut client = MockStarknetClient::default();
+        client.expect_block_time().returning(|_| Ok(1000));
+        client
+            .expect_fetch_all_block_events()
+            .returning(move |_, _| {
+                Ok(HashMap::from([(
+                    1,
+                    vec![transfer_event(contract_address, Some(1))],
+                )]))
+            });
+        client
+            .expect_class_hash_at()
+            .returning(|address, _| Ok(address));
+        // Answers `ownerOf`, the contract is identified as ERC721.
+        client
+            .expect_call_contract()
+            .returning(|_, _, _, _| Ok(vec![FieldElement::ONE]));
+
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::new(storage),
+            Arc::new(TestEventHandler),
+            config(),
+        );
+
+        let decoded = pontos
+            .peek_block(BlockId::Number(1), "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].contract_type, ContractType::ERC721);
+        match &decoded[0].event {
+            TokenEvent::Transfer(e) => {
+                assert_eq!(e.block_number, Some(1));
+                assert_eq!(e.timestamp, 1000);
+            }
+            _ => panic!("Expected a transfer event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_validate() {
+        let mut storage = MockStorage::default();
+        storage
+            .expect_get_contract_type_overrides()
+            .returning(|| Box::pin(futures::future::ready(Ok(vec![]))));
+        storage
+            .expect_get_contract_type()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(Some(ContractType::ERC721)))));
+        // Block 1 is consistent, block 2 is missing one event.
+        storage.expect_count_block_events().returning(|block| {
+            Box::pin(futures::future::ready(Ok(if block == 1 { 2 } else { 0 })))
+        });
+
+        let contract_address = FieldElement::from_hex_be("0x1234").unwrap();
+
+        let mut client = MockStarknetClient::default();
+        client
+            .expect_fetch_all_block_events()
+            .returning(move |block, _| {
+                let n = match block {
+                    BlockId::Number(n) => n,
+                    _ => 0,
+                };
+                let events = if n == 1 {
+                    vec![
+                        transfer_event(contract_address, Some(n)),
+                        transfer_event(contract_address, Some(n)),
+                    ]
+                } else {
+                    vec![transfer_event(contract_address, Some(n))]
+                };
+                Ok(HashMap::from([(n, events)]))
+            });
+
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::new(storage),
+            Arc::new(TestEventHandler),
+            config(),
+        );
+
+        let errors = pontos
+            .index_block_range_validate(1, 2, "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            errors,
+            vec![ValidationError::EventCountMismatch {
+                block: 2,
+                stored: 0,
+                live: 1
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let mut storage = indexing_storage();
+        storage.expect_health_check().returning(|| {
+            Box::pin(futures::future::ready(Err(StorageError::DatabaseError(
+                "down".to_string(),
+            ))))
+        });
+
+        let mut client = MockStarknetClient::default();
+        client.expect_block_id_to_u64().returning(|id| match id {
+            BlockId::Number(n) => Ok(*n),
+            _ => Ok(0),
+        });
+        client.expect_block_number().returning(|| Ok(10));
+        client.expect_block_time().returning(|_| Ok(1234));
+        client
+            .expect_fetch_all_block_events()
+            .returning(|_, _| Ok(HashMap::new()));
+
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::new(storage),
+            Arc::new(TestEventHandler),
+            config(),
+        );
+
+        let health = pontos.healthz().await;
+        assert!(!health.storage_ok);
+        assert!(health.rpc_ok);
+        assert_eq!(health.last_indexed_block, None);
+        assert!(!health.pending_loop_running);
+
+        pontos
+            .index_block_range(BlockId::Number(3), BlockId::Number(4), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert_eq!(pontos.healthz().await.last_indexed_block, Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_lag_reported_per_block() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct LagRecorder {
+            lags: Mutex<Vec<IndexerLag>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for LagRecorder {
+            async fn on_lag_update(&self, lag: IndexerLag) {
+                self.lags.lock().unwrap().push(lag);
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = HashMap::from([
+            (1, synthetic_block(1, 1, &contracts)),
+            (2, synthetic_block(2, 1, &contracts)),
+            (5, synthetic_block(5, 1, &contracts)),
+        ]);
+
+        let handler = Arc::new(LagRecorder::default());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks.clone(), &contracts)),
+            Arc::new(InMemoryStorage::new()),
+            Arc::clone(&handler),
+            PontosConfig {
+                chain_head_refresh_interval: Some(Duration::ZERO),
+                ..config()
+            },
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(2), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let lags = handler.lags.lock().unwrap().clone();
+        assert_eq!(
+            lags.iter().map(|l| l.blocks_behind).collect::<Vec<_>>(),
+            vec![Some(4), Some(3)]
+        );
+        assert_eq!(pontos.status().lag, lags[1]);
+        assert_eq!(lags[1].chain_head, Some(5));
+        assert_eq!(lags[1].pending_age_secs, None);
+
+        // Without refresh interval, the node is not called for the chain head.
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::new(InMemoryStorage::new()),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(2), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert_eq!(pontos.status().lag.chain_head, None);
+        assert_eq!(pontos.status().lag.blocks_behind, None);
+    }
+
+    #[tokio::test]
+    async fn test_preflight_check() {
+        use crate::storage::types::BlockInfo;
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_block_timestamp, synthetic_contracts,
+            InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks: HashMap<u64, Vec<EmittedEvent>> = (1..=5)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        let block_info = |n: u64, status: BlockIndexingStatus, version: &str, id: &str| BlockInfo {
+            indexer_version: version.to_string(),
+            indexer_identifier: id.to_string(),
+            status,
+            block_number: n,
+            selector_hash: None,
+            last_heartbeat_at: 0,
+        };
+
+        // Builds the storage with the given blocks, and checks it.
+        let check = |stored: Vec<(u64, u64, BlockInfo)>| {
+            let blocks = blocks.clone();
+            let contracts = contracts.clone();
+            async move {
+                let storage = Arc::new(InMemoryStorage::new());
+                for (n, ts, info) in stored {
+                    storage.set_block_info(n, ts, info).await.unwrap();
+                }
+                let pontos = Pontos::new(
+                    Arc::new(mock_client(blocks, &contracts)),
+                    storage,
+                    Arc::new(NoopEventHandler),
+                    config(),
+                );
+                pontos.preflight_check().await.unwrap()
+            }
+        };
+        let terminated = |n: u64, ts: u64| {
+            (
+                n,
+                ts,
+                block_info(n, BlockIndexingStatus::Terminated, "v0.0.1", "TASK#123"),
+            )
+        };
+
+        // Consistent storage.
+        let report = check(vec![
+            terminated(2, synthetic_block_timestamp(2)),
+            terminated(3, synthetic_block_timestamp(3)),
+        ])
+        .await;
+        assert_eq!(report.chain_head, 5);
+        assert_eq!(report.last_terminated_block, Some(3));
+        assert_eq!(report.indexer_versions, vec!["v0.0.1".to_string()]);
+        assert!(report.findings.is_empty());
+        assert!(!report.is_critical());
+
+        // Terminated block above the chain head.
+        let report = check(vec![terminated(8, synthetic_block_timestamp(8))]).await;
+        assert_eq!(
+            report.findings,
+            vec![PreflightFinding::TerminatedAboveChainHead {
+                block: 8,
+                chain_head: 5
+            }]
+        );
+        assert!(report.is_critical());
+
+        // Terminated block with another timestamp than on the chain.
+        let report = check(vec![terminated(3, 42)]).await;
+        assert_eq!(
+            report.findings,
+            vec![PreflightFinding::BlockTimestampMismatch {
+                block: 3,
+                stored: 42,
+                chain: synthetic_block_timestamp(3)
+            }]
+        );
+
+        // Blocks left in processing, only those of this identifier are reported.
+        let processing = |n: u64, id: &str| {
+            (
+                n,
+                synthetic_block_timestamp(n),
+                block_info(n, BlockIndexingStatus::Processing, "v0.0.1", id),
+            )
+        };
+        let report = check(vec![
+            processing(4, "TASK#123"),
+            processing(2, "TASK#123"),
+            processing(3, "OTHER"),
+        ])
+        .await;
+        assert_eq!(
+            report.findings,
+            vec![PreflightFinding::InterruptedBlocks { blocks: vec![2, 4] }]
+        );
+
+        // Blocks indexed by a more recent version.
+        let report = check(vec![(
+            1,
+            synthetic_block_timestamp(1),
+            block_info(1, BlockIndexingStatus::Terminated, "v0.1.0", "OTHER"),
+        )])
+        .await;
+        assert_eq!(
+            report.findings,
+            vec![PreflightFinding::NewerIndexerVersion {
+                version: "v0.1.0".to_string()
+            }]
+        );
+        assert!(!report.is_critical());
+    }
+
+    #[tokio::test]
+    async fn test_preflight_check_refuses_indexing() {
+        use crate::storage::types::BlockInfo;
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_block_timestamp, synthetic_contracts,
+            InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = (1..=3)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        storage
+            .set_block_info(
+                2,
+                synthetic_block_timestamp(2),
+                BlockInfo {
+                    indexer_version: "v0.0.1".to_string(),
+                    indexer_identifier: "TASK#123".to_string(),
+                    status: BlockIndexingStatus::Processing,
+                    block_number: 2,
+                    selector_hash: None,
+                    last_heartbeat_at: 0,
+                },
+            )
+            .await
+            .unwrap();
+
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                preflight_check: true,
+                ..config()
+            },
+        );
+
+        let result = pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(3), false, "SN_MAIN")
+            .await;
+        assert!(matches!(
+            result,
+            Err(IndexerError::PreflightFailed(findings))
+                if findings == vec![PreflightFinding::InterruptedBlocks { blocks: vec![2] }]
+        ));
+        assert!(storage.dump().transfer_events.is_empty());
+
+        // Once the interrupted block is cleaned, the indexing starts.
+        storage
+            .clean_block(synthetic_block_timestamp(2), Some(2))
+            .await
+            .unwrap();
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(3), false, "SN_MAIN")
+            .await
+            .unwrap();
+        assert_eq!(storage.dump().blocks.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_block() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let events = synthetic_block(1, 3, &contracts);
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = |events: &[EmittedEvent]| {
+            Pontos::new(
+                Arc::new(mock_client(
+                    HashMap::from([(1, events.to_vec())]),
+                    &contracts,
+                )),
+                Arc::clone(&storage),
+                Arc::new(NoopEventHandler),
+                config(),
+            )
+        };
+
+        let diagnosis = pontos(&events).diagnose_block(1).await.unwrap();
+        assert_eq!(diagnosis.status, None);
+        assert_eq!(diagnosis.stored_events, 0);
+        assert_eq!(diagnosis.node_events, 3);
+        assert_eq!(diagnosis.missing_in_storage, events);
+        assert_eq!(
+            diagnosis.contracts,
+            BTreeMap::from([(to_hex_str(&contracts[0].address), Ok(ContractType::ERC721))])
+        );
+
+        // The first event was stored, but is no longer returned by the node,
+        // which returns a new one instead.
+        pontos(&events[..2])
+            .index_block_range(BlockId::Number(1), BlockId::Number(1), false, "SN_MAIN")
+            .await
+            .unwrap();
+        let diagnosis = pontos(&events[1..]).diagnose_block(1).await.unwrap();
+        assert_eq!(diagnosis.status, Some(BlockIndexingStatus::Terminated));
+        assert_eq!(diagnosis.stored_events, 2);
+        assert_eq!(diagnosis.node_events, 2);
+        assert_eq!(diagnosis.missing_in_storage, events[2..].to_vec());
+        assert_eq!(diagnosis.missing_on_node.len(), 1);
+        assert_eq!(
+            storage.dump().transfer_events[&diagnosis.missing_on_node[0]].token_id_hex,
+            to_hex_str(&events[0].data[2])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_active_indexers() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks: HashMap<u64, Vec<EmittedEvent>> = (1..=4)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        // An instance which stopped long ago.
+        storage
+            .register_indexer(&IndexerInfo {
+                identifier: "TASK#0".to_string(),
+                version: "v0.0.0".to_string(),
+                last_heartbeat: 1,
+                last_block: Some(10),
+                inactive: false,
+            })
+            .await
+            .unwrap();
+
+        let first = Pontos::new(
+            Arc::new(mock_client(blocks.clone(), &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+        let second = first.with_config(PontosConfig {
+            indexer_identifier: "TASK#456".to_string(),
+            ..config()
+        });
+
+        assert!(first.list_active_indexers().await.unwrap().is_empty());
+
+        first
+            .index_block_range(BlockId::Number(1), BlockId::Number(2), false, "SN_MAIN")
+            .await
+            .unwrap();
+        second
+            .index_block_range(BlockId::Number(3), BlockId::Number(4), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let indexers = second.list_active_indexers().await.unwrap();
+        assert_eq!(
+            indexers
+                .iter()
+                .map(|i| (i.identifier.as_str(), i.version.as_str(), i.last_block))
+                .collect::<Vec<_>>(),
+            vec![
+                ("TASK#123", "v0.0.1", Some(2)),
+                ("TASK#456", "v0.0.1", Some(4))
+            ]
+        );
+        assert!(indexers.iter().all(|i| i.last_heartbeat > 0));
+    }
+}
@@ -1,6 +1,13 @@
 //! Trait related to any events that Pontos can emit to be handled.
-use crate::storage::types::{TokenEvent, TokenInfo};
+use crate::storage::types::{ContractType, FailedEvent, StorageError, TokenEvent, TokenInfo};
+use crate::{CollectionActivity, IndexerError, IndexerLag, RangeChunkReport, SkipReason};
 use async_trait::async_trait;
+use starknet::core::types::FieldElement;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub mod routing;
+pub use routing::{Route, RoutingEventHandler};
 
 /// A trait to be implemented in order to handle
 /// events emitted by Pontos, in an external code.
@@ -9,6 +16,13 @@ use async_trait::async_trait;
 /// will directly impact Pontos performances.
 /// Please consider spawning tasks if some work may
 /// be too heavy and impact negatively Pontos performances.
+///
+/// Pontos requires the handler to be `Send + Sync`: the handler is shared
+/// through an `Arc` between all the tasks indexing with the same instance
+/// (`Sync`), and the futures returned by the methods hold a reference
+/// to the handler while they may be moved across threads by the runtime (`Send`).
+/// To share a handler with other parts of the code, wrap it in an `Arc`,
+/// which implements this trait by delegating to the inner handler.
 #[async_trait]
 #[allow(unused)]
 pub trait EventHandler {
@@ -27,6 +41,263 @@ pub trait EventHandler {
     /// A new event has be registered.
     async fn on_event_registered(&self, event: TokenEvent) {}
 
+    /// A token event and its token have been successfully registered,
+    /// during the indexation of a block range or of the pending block.
+    ///
+    /// Fired exactly once for each event successfully registered.
+    /// When a block is re-indexed with `do_force`, the events of the block
+    /// are cleaned first, and this callback fires again for each of them, even if
+    /// the token was already registered.
+    async fn on_token_event(&self, event: &TokenEvent, token: &TokenInfo) {}
+
     // A new latest block has been detected.
     async fn on_new_latest_block(&self, block_number: u64) {}
+
+    /// The pending block indexed by `Pontos::index_pending` with the timestamp
+    /// `pending_ts` was promoted to the latest block `latest_block_number`:
+    /// its events got the block number and the block was marked as terminated,
+    /// so they can be treated as final. Fired before `on_new_latest_block`.
+    async fn on_pending_transition(&self, pending_ts: u64, latest_block_number: u64) {}
+
+    /// An event failed to be processed, and was added to the dead-letter queue.
+    /// It can be reprocessed with `Pontos::reprocess_failed_events`.
+    async fn on_event_error(&self, event: &FailedEvent) {}
+
+    /// A call to the node failed with a transient error, and is about to be retried.
+    /// `attempt` starts at 1 for the first failure of the call.
+    ///
+    /// For the pending block, `block` is the number the pending block will have
+    /// once promoted (the last latest block seen plus one), or 0 if no latest
+    /// block was seen yet.
+    async fn on_rpc_retry(&self, block: u64, attempt: u32, error: &IndexerError) {}
+
+    /// `Pontos::index_pending` had no successful tick for `stalled_for`,
+    /// longer than the timeout of `PontosConfig::pending_watchdog`.
+    /// Called again if the loop is still stalled after an other timeout.
+    async fn on_watchdog_trigger(&self, stalled_for: Duration) {}
+
+    /// The contract failed `failure_count` consecutive times to be indexed,
+    /// and was paused. Its next events are added to the dead-letter queue
+    /// until the contract is resumed with `Pontos::resume_contract`.
+    async fn on_contract_circuit_open(&self, contract_address: FieldElement, failure_count: u32) {}
+
+    /// A write into the storage failed while processing the events of the block.
+    /// Called before each retry, `retry_count` being the number of retries
+    /// already done, and once more when the retries are exhausted, with
+    /// `retry_count` equal to `STORAGE_WRITE_RETRIES`.
+    ///
+    /// For the pending block, `block` is 0.
+    async fn on_storage_write_failure(&self, block: u64, error: &StorageError, retry_count: u32) {}
+
+    /// The events of the block were processed by `Pontos::index_block_range`, and the
+    /// block is about to be marked as terminated. `collections` summarizes the
+    /// transfers of each collection of the block, and is empty if no
+    /// transfer was registered.
+    async fn on_block_collections_summary(
+        &self,
+        block_number: u64,
+        collections: Vec<CollectionActivity>,
+    ) {
+    }
+
+    /// The lag of the indexer was recomputed, at the end of each tick of
+    /// `Pontos::index_pending` and after each block of `Pontos::index_block_range`.
+    /// Meant to update gauges, the same values being returned by `Pontos::status`.
+    async fn on_lag_update(&self, lag: IndexerLag) {}
+
+    /// A chunk of the range indexed by `Pontos::index_block_range` is completed,
+    /// and its completion saved. Only fired for the ranges larger than
+    /// `PontosConfig::range_chunk_blocks`.
+    async fn on_range_chunk_completed(&self, report: &RangeChunkReport) {}
+
+    /// All the token events of the transaction were registered, in the order they
+    /// were emitted. Fired once per transaction having at least one token event
+    /// registered, after the per-event callbacks, to process the events
+    /// of a transaction (a sweep purchase for instance) atomically.
+    ///
+    /// `block_number` is `None` for the transactions of the pending block.
+    async fn on_transaction_events(
+        &self,
+        tx_hash: FieldElement,
+        block_number: Option<u64>,
+        events: &[TokenEvent],
+    ) {
+    }
+
+    /// An event of the block failed to be processed with
+    /// `ProcessingStrictness::Strict`: the block was marked as failed
+    /// instead of terminated, and will be indexed again by the next run.
+    async fn on_block_failed(&self, block_number: u64, error: &IndexerError) {}
+
+    /// A block already indexed was skipped by `Pontos::index_block_range`.
+    /// `SkipReason::OtherIdentifier` reveals an other deployment sharing the storage.
+    async fn on_block_skipped(&self, block_number: u64, reason: SkipReason) {}
+
+    /// The type of the contract was changed by `Pontos::reclassify_contract`
+    /// or `Pontos::reprobe_contract`. A contract identified as an NFT contract
+    /// after being classified as `Other` has its past events discarded: it is
+    /// marked to be backfilled (see `Pontos::backfill_contracts`).
+    async fn on_contract_reclassified(
+        &self,
+        contract_address: FieldElement,
+        old: &ContractType,
+        new: &ContractType,
+    ) {
+    }
+
+    /// A page of the events walked through by `Pontos::apply_migration` was
+    /// migrated: `processed` events out of the `total` stored when the
+    /// migration started.
+    async fn on_migration_progress(&self, processed: u64, total: u64) {}
+}
+
+#[async_trait]
+impl<E: EventHandler + Send + Sync + ?Sized> EventHandler for Arc<E> {
+    async fn on_block_processed(&self, block_number: u64, indexation_progress: f64) {
+        (**self)
+            .on_block_processed(block_number, indexation_progress)
+            .await
+    }
+
+    async fn on_block_processing(&self, block_timestamp: u64, block_number: Option<u64>) {
+        (**self)
+            .on_block_processing(block_timestamp, block_number)
+            .await
+    }
+
+    async fn on_indexation_range_completed(&self) {
+        (**self).on_indexation_range_completed().await
+    }
+
+    async fn on_token_registered(&self, token: TokenInfo) {
+        (**self).on_token_registered(token).await
+    }
+
+    async fn on_event_registered(&self, event: TokenEvent) {
+        (**self).on_event_registered(event).await
+    }
+
+    async fn on_token_event(&self, event: &TokenEvent, token: &TokenInfo) {
+        (**self).on_token_event(event, token).await
+    }
+
+    async fn on_new_latest_block(&self, block_number: u64) {
+        (**self).on_new_latest_block(block_number).await
+    }
+
+    async fn on_pending_transition(&self, pending_ts: u64, latest_block_number: u64) {
+        (**self)
+            .on_pending_transition(pending_ts, latest_block_number)
+            .await
+    }
+
+    async fn on_event_error(&self, event: &FailedEvent) {
+        (**self).on_event_error(event).await
+    }
+
+    async fn on_rpc_retry(&self, block: u64, attempt: u32, error: &IndexerError) {
+        (**self).on_rpc_retry(block, attempt, error).await
+    }
+
+    async fn on_watchdog_trigger(&self, stalled_for: Duration) {
+        (**self).on_watchdog_trigger(stalled_for).await
+    }
+
+    async fn on_contract_circuit_open(&self, contract_address: FieldElement, failure_count: u32) {
+        (**self)
+            .on_contract_circuit_open(contract_address, failure_count)
+            .await
+    }
+
+    async fn on_storage_write_failure(&self, block: u64, error: &StorageError, retry_count: u32) {
+        (**self)
+            .on_storage_write_failure(block, error, retry_count)
+            .await
+    }
+
+    async fn on_block_collections_summary(
+        &self,
+        block_number: u64,
+        collections: Vec<CollectionActivity>,
+    ) {
+        (**self)
+            .on_block_collections_summary(block_number, collections)
+            .await
+    }
+
+    async fn on_lag_update(&self, lag: IndexerLag) {
+        (**self).on_lag_update(lag).await
+    }
+
+    async fn on_range_chunk_completed(&self, report: &RangeChunkReport) {
+        (**self).on_range_chunk_completed(report).await
+    }
+
+    async fn on_transaction_events(
+        &self,
+        tx_hash: FieldElement,
+        block_number: Option<u64>,
+        events: &[TokenEvent],
+    ) {
+        (**self)
+            .on_transaction_events(tx_hash, block_number, events)
+            .await
+    }
+
+    async fn on_block_failed(&self, block_number: u64, error: &IndexerError) {
+        (**self).on_block_failed(block_number, error).await
+    }
+
+    async fn on_block_skipped(&self, block_number: u64, reason: SkipReason) {
+        (**self).on_block_skipped(block_number, reason).await
+    }
+
+    async fn on_contract_reclassified(
+        &self,
+        contract_address: FieldElement,
+        old: &ContractType,
+        new: &ContractType,
+    ) {
+        (**self)
+            .on_contract_reclassified(contract_address, old, new)
+            .await
+    }
+
+    async fn on_migration_progress(&self, processed: u64, total: u64) {
+        (**self).on_migration_progress(processed, total).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[derive(Default)]
+    struct BlockCounter {
+        blocks: AtomicU64,
+    }
+
+    #[async_trait]
+    impl EventHandler for BlockCounter {
+        async fn on_block_processed(&self, _block_number: u64, _indexation_progress: f64) {
+            self.blocks.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn assert_handler<E: EventHandler + Send + Sync>(_handler: &E) {}
+
+    #[tokio::test]
+    async fn test_arc_handler_delegates() {
+        let counter = Arc::new(BlockCounter::default());
+        let shared: Arc<dyn EventHandler + Send + Sync> = counter.clone();
+
+        assert_handler(&counter);
+        assert_handler(&shared);
+
+        counter.on_block_processed(1, 100.0).await;
+        shared.on_block_processed(2, 100.0).await;
+
+        assert_eq!(counter.blocks.load(Ordering::SeqCst), 2);
+    }
 }
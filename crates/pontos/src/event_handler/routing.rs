@@ -0,0 +1,359 @@
+//! Event handler routing events to different handlers
+//! based on the contract address of the event.
+use super::EventHandler;
+use crate::storage::types::{ContractType, FailedEvent, StorageError, TokenEvent, TokenInfo};
+use crate::{CollectionActivity, IndexerError, IndexerLag, RangeChunkReport, SkipReason};
+use ark_starknet::format::to_hex_str;
+use async_trait::async_trait;
+use starknet::core::types::FieldElement;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// A handler that can be registered into the [`RoutingEventHandler`].
+pub type SharedEventHandler = Arc<dyn EventHandler + Send + Sync>;
+
+/// Defines which contract addresses are handled by a route.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    /// Matches any address in the set.
+    Addresses(HashSet<FieldElement>),
+    /// Matches any address which padded hex representation
+    /// (`0x` followed by 64 hex digits, as stored by Pontos) starts
+    /// with the given prefix. The comparison is case insensitive.
+    Prefix(String),
+}
+
+impl Route {
+    /// Returns true if the given contract address (hex string) matches the route.
+    pub fn matches(&self, contract_address: &str) -> bool {
+        match self {
+            Route::Addresses(addresses) => FieldElement::from_hex_be(contract_address)
+                .map(|a| addresses.contains(&a))
+                .unwrap_or(false),
+            Route::Prefix(prefix) => contract_address
+                .to_lowercase()
+                .starts_with(&prefix.to_lowercase()),
+        }
+    }
+}
+
+/// An event handler dispatching the events related to a contract
+/// to the first route matching the contract address, or to the fallback
+/// handler if no route matches.
+///
+/// Callbacks which are not related to a single contract (`on_block_processing`,
+/// `on_block_processed`, `on_indexation_range_completed`, `on_new_latest_block`,
+/// `on_pending_transition`, `on_rpc_retry`, `on_watchdog_trigger`, `on_storage_write_failure`,
+/// `on_block_collections_summary`,
+/// `on_block_failed`, `on_block_skipped`, `on_transaction_events`) are
+/// broadcasted to all the registered handlers, including the fallback.
+/// A handler registered for several routes only receives those callbacks once.
+///
+/// The initial routes are registered with `with_route` when building the
+/// handler. Routes can then be added and removed at runtime, while Pontos
+/// is indexing.
+pub struct RoutingEventHandler {
+    routes: RwLock<Vec<(Route, SharedEventHandler)>>,
+    fallback: SharedEventHandler,
+}
+
+impl RoutingEventHandler {
+    /// Initializes a new instance without any route.
+    pub fn new(fallback: SharedEventHandler) -> Self {
+        Self {
+            routes: RwLock::new(vec![]),
+            fallback,
+        }
+    }
+
+    /// Registers a new route, evaluated after the routes already registered.
+    pub fn with_route(mut self, route: Route, handler: SharedEventHandler) -> Self {
+        self.routes
+            .get_mut()
+            .expect("Routing table lock poisoned")
+            .push((route, handler));
+        self
+    }
+
+    /// Registers a new route. Routes are evaluated in the order
+    /// they were added.
+    pub fn add_route(&self, route: Route, handler: SharedEventHandler) {
+        self.routes
+            .write()
+            .expect("Routing table lock poisoned")
+            .push((route, handler));
+    }
+
+    /// Removes all the routes equal to the given one.
+    /// Returns true if at least one route was removed.
+    pub fn remove_route(&self, route: &Route) -> bool {
+        let mut routes = self.routes.write().expect("Routing table lock poisoned");
+        let len = routes.len();
+        routes.retain(|(r, _)| r != route);
+        routes.len() != len
+    }
+
+    /// Returns the handler for the given contract address.
+    fn handler_for(&self, contract_address: &str) -> SharedEventHandler {
+        self.routes
+            .read()
+            .expect("Routing table lock poisoned")
+            .iter()
+            .find(|(r, _)| r.matches(contract_address))
+            .map(|(_, h)| Arc::clone(h))
+            .unwrap_or_else(|| Arc::clone(&self.fallback))
+    }
+
+    /// Returns all the registered handlers, without duplicates.
+    fn all_handlers(&self) -> Vec<SharedEventHandler> {
+        let mut handlers: Vec<SharedEventHandler> = vec![Arc::clone(&self.fallback)];
+
+        for (_, h) in self
+            .routes
+            .read()
+            .expect("Routing table lock poisoned")
+            .iter()
+        {
+            if !handlers.iter().any(|e| Arc::ptr_eq(e, h)) {
+                handlers.push(Arc::clone(h));
+            }
+        }
+
+        handlers
+    }
+}
+
+#[async_trait]
+impl EventHandler for RoutingEventHandler {
+    async fn on_block_processed(&self, block_number: u64, indexation_progress: f64) {
+        for h in self.all_handlers() {
+            h.on_block_processed(block_number, indexation_progress)
+                .await;
+        }
+    }
+
+    async fn on_block_processing(&self, block_timestamp: u64, block_number: Option<u64>) {
+        for h in self.all_handlers() {
+            h.on_block_processing(block_timestamp, block_number).await;
+        }
+    }
+
+    async fn on_indexation_range_completed(&self) {
+        for h in self.all_handlers() {
+            h.on_indexation_range_completed().await;
+        }
+    }
+
+    async fn on_token_registered(&self, token: TokenInfo) {
+        self.handler_for(&token.contract_address)
+            .on_token_registered(token)
+            .await;
+    }
+
+    async fn on_event_registered(&self, event: TokenEvent) {
+        let contract_address = match &event {
+            TokenEvent::Transfer(e) => e.contract_address.clone(),
+            TokenEvent::Sale(e) => e.nft_contract_address.clone(),
+        };
+
+        self.handler_for(&contract_address)
+            .on_event_registered(event)
+            .await;
+    }
+
+    async fn on_token_event(&self, event: &TokenEvent, token: &TokenInfo) {
+        self.handler_for(&token.contract_address)
+            .on_token_event(event, token)
+            .await;
+    }
+
+    async fn on_new_latest_block(&self, block_number: u64) {
+        for h in self.all_handlers() {
+            h.on_new_latest_block(block_number).await;
+        }
+    }
+
+    async fn on_pending_transition(&self, pending_ts: u64, latest_block_number: u64) {
+        for h in self.all_handlers() {
+            h.on_pending_transition(pending_ts, latest_block_number)
+                .await;
+        }
+    }
+
+    async fn on_event_error(&self, event: &FailedEvent) {
+        self.handler_for(&event.contract_address)
+            .on_event_error(event)
+            .await;
+    }
+
+    async fn on_rpc_retry(&self, block: u64, attempt: u32, error: &IndexerError) {
+        for h in self.all_handlers() {
+            h.on_rpc_retry(block, attempt, error).await;
+        }
+    }
+
+    async fn on_watchdog_trigger(&self, stalled_for: Duration) {
+        for h in self.all_handlers() {
+            h.on_watchdog_trigger(stalled_for).await;
+        }
+    }
+
+    async fn on_storage_write_failure(&self, block: u64, error: &StorageError, retry_count: u32) {
+        for h in self.all_handlers() {
+            h.on_storage_write_failure(block, error, retry_count).await;
+        }
+    }
+
+    async fn on_block_collections_summary(
+        &self,
+        block_number: u64,
+        collections: Vec<CollectionActivity>,
+    ) {
+        for h in self.all_handlers() {
+            h.on_block_collections_summary(block_number, collections.clone())
+                .await;
+        }
+    }
+
+    async fn on_lag_update(&self, lag: IndexerLag) {
+        for h in self.all_handlers() {
+            h.on_lag_update(lag).await;
+        }
+    }
+
+    async fn on_range_chunk_completed(&self, report: &RangeChunkReport) {
+        for h in self.all_handlers() {
+            h.on_range_chunk_completed(report).await;
+        }
+    }
+
+    async fn on_transaction_events(
+        &self,
+        tx_hash: FieldElement,
+        block_number: Option<u64>,
+        events: &[TokenEvent],
+    ) {
+        for h in self.all_handlers() {
+            h.on_transaction_events(tx_hash, block_number, events).await;
+        }
+    }
+
+    async fn on_block_failed(&self, block_number: u64, error: &IndexerError) {
+        for h in self.all_handlers() {
+            h.on_block_failed(block_number, error).await;
+        }
+    }
+
+    async fn on_block_skipped(&self, block_number: u64, reason: SkipReason) {
+        for h in self.all_handlers() {
+            h.on_block_skipped(block_number, reason).await;
+        }
+    }
+
+    async fn on_contract_circuit_open(&self, contract_address: FieldElement, failure_count: u32) {
+        self.handler_for(&to_hex_str(&contract_address))
+            .on_contract_circuit_open(contract_address, failure_count)
+            .await;
+    }
+
+    async fn on_contract_reclassified(
+        &self,
+        contract_address: FieldElement,
+        old: &ContractType,
+        new: &ContractType,
+    ) {
+        self.handler_for(&to_hex_str(&contract_address))
+            .on_contract_reclassified(contract_address, old, new)
+            .await;
+    }
+
+    async fn on_migration_progress(&self, processed: u64, total: u64) {
+        for h in self.all_handlers() {
+            h.on_migration_progress(processed, total).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[derive(Default)]
+    struct CountingHandler {
+        tokens: AtomicU64,
+        latest_blocks: AtomicU64,
+    }
+
+    #[async_trait]
+    impl EventHandler for CountingHandler {
+        async fn on_token_registered(&self, _token: TokenInfo) {
+            self.tokens.fetch_add(1, Ordering::SeqCst);
+        }
+
+        async fn on_new_latest_block(&self, _block_number: u64) {
+            self.latest_blocks.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn token(contract_address: &str) -> TokenInfo {
+        TokenInfo {
+            contract_address: contract_address.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routing_by_address_and_prefix() {
+        let fallback = Arc::new(CountingHandler::default());
+        let gaming = Arc::new(CountingHandler::default());
+        let art = Arc::new(CountingHandler::default());
+
+        let gaming_address = FieldElement::from_hex_be("0x1234").unwrap();
+
+        let router = RoutingEventHandler::new(fallback.clone())
+            .with_route(
+                Route::Addresses(HashSet::from([gaming_address])),
+                gaming.clone(),
+            )
+            .with_route(Route::Prefix("0x0abc".to_string()), art.clone());
+        // Same handler on a second route must not duplicate broadcasts.
+        router.add_route(Route::Prefix("0x0def".to_string()), art.clone());
+
+        router
+            .on_token_registered(token(
+                "0x0000000000000000000000000000000000000000000000000000000000001234",
+            ))
+            .await;
+        router
+            .on_token_registered(token(
+                "0x0ABC000000000000000000000000000000000000000000000000000000000001",
+            ))
+            .await;
+        router
+            .on_token_registered(token(
+                "0x0000000000000000000000000000000000000000000000000000000000009999",
+            ))
+            .await;
+
+        assert_eq!(gaming.tokens.load(Ordering::SeqCst), 1);
+        assert_eq!(art.tokens.load(Ordering::SeqCst), 1);
+        assert_eq!(fallback.tokens.load(Ordering::SeqCst), 1);
+
+        router.on_new_latest_block(1).await;
+        assert_eq!(fallback.latest_blocks.load(Ordering::SeqCst), 1);
+        assert_eq!(gaming.latest_blocks.load(Ordering::SeqCst), 1);
+        assert_eq!(art.latest_blocks.load(Ordering::SeqCst), 1);
+
+        // Once removed, the events go to the fallback.
+        assert!(router.remove_route(&Route::Prefix("0x0abc".to_string())));
+        router
+            .on_token_registered(token(
+                "0x0abc000000000000000000000000000000000000000000000000000000000001",
+            ))
+            .await;
+        assert_eq!(art.tokens.load(Ordering::SeqCst), 1);
+        assert_eq!(fallback.tokens.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,23 +1,76 @@
+pub mod attribution;
+pub mod clock;
+pub mod compression;
+pub mod config;
+mod diagnostics;
 pub mod event_handler;
+mod maintenance;
 pub mod managers;
+mod pending;
+mod range;
+mod report;
 pub mod storage;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
-use crate::storage::types::BlockIndexingStatus;
 use anyhow::Result;
 use ark_starknet::client::{StarknetClient, StarknetClientError};
 use ark_starknet::format::to_hex_str;
+use ark_starknet::CairoU256;
+pub use attribution::{default_attribution_rules, Attribution, AttributionRule};
+pub use clock::{Clock, SystemClock};
+pub use config::{
+    CircuitBreakerConfig, ConfigError, ForcePolicy, LogDetail, PendingPolling, PendingWatchdog,
+    PontosConfig, ProcessingStrictness, DEFAULT_CHAIN_ID, DEFAULT_RANGE_CHUNK_BLOCKS,
+};
+use dashmap::{DashMap, DashSet};
 use event_handler::EventHandler;
-use managers::{BlockManager, ContractManager, EventManager, PendingBlockData, TokenManager};
+pub use maintenance::TokenMetadataPatch;
+pub use managers::{
+    BlockContext, BlockRef, EstimateResult, PendingBlockSnapshot, RpcPermits, SchemaIssue,
+    SkipReason, TokenQuery,
+};
+use managers::{
+    BlockManager, ContractManager, EventManager, PendingBlockData, SupplyDeltas, TokenManager,
+};
+pub use range::RangeOptions;
+use range::WarmBlock;
+pub use report::{
+    BlockCompleted, BlockDiagnosis, CollectionActivity, ContractEventCount, DecodedTokenEvent,
+    HealthStatus, IndexerLag, IndexingReport, MigrationStats, PontosStatistics, PontosStatus,
+    PreflightFinding, PreflightReport, RangeChunkReport, RangeEstimate, ReindexReport,
+    SampledRangeEstimate, SkipCounts, ValidationError,
+};
 use starknet::core::types::*;
+use starknet::core::utils::starknet_keccak;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
-use storage::types::{ContractType, StorageError};
+use std::time::{Duration, Instant};
+use storage::types::{
+    CollectionStats, ContractType, DecodedTransfer, EventType, FailedEvent, IndexerInfo,
+    QuarantinedEvent, StorageError, TokenEvent,
+};
 use storage::Storage;
-use tokio::sync::RwLock as AsyncRwLock;
+use tokio::sync::{mpsc, watch, RwLock as AsyncRwLock};
 use tracing::{debug, error, info, trace, warn};
 
 pub type IndexerResult<T> = Result<T, IndexerError>;
 
+/// Log target of the per-transaction logs of `index_pending`.
+pub const PENDING_TX_LOG_TARGET: &str = "pontos::pending::tx";
+
+/// Log target of the per-event logs.
+pub const EVENTS_LOG_TARGET: &str = "pontos::events";
+
+/// Retries of the processing of an event failing to be written into the storage.
+pub const STORAGE_WRITE_RETRIES: u32 = 3;
+
+/// Delay before retrying the processing of an event failing to be written.
+const STORAGE_WRITE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 const ELEMENT_MARKETPLACE_EVENT_HEX: &str =
     "0x351e5a57ea6ca22e3e3cd212680ef7f3b57404609bda942a5e75ba4724b55e0";
 
@@ -33,6 +86,43 @@ pub enum IndexerError {
     StorageError(StorageError),
     Starknet(StarknetClientError),
     Anyhow(String),
+    /// The block was already terminated by an other indexer version.
+    VersionConflict {
+        block: u64,
+        existing_version: String,
+        current_version: String,
+    },
+    /// The block was written by an other writer since its etag was read,
+    /// see `BlockManager::set_block_info`.
+    OptimisticLockFailed {
+        block: u64,
+        expected_etag: u64,
+        found_etag: u64,
+    },
+    /// `index_pending` is already running on this instance.
+    PendingLoopAlreadyRunning,
+    /// Transfer of an ERC721 token after its burn, revealing missed
+    /// events or a reorg. The transfer is added to the dead-letter queue.
+    BurnedTokenTransfer {
+        contract_address: String,
+        token_id_hex: String,
+        burned_at_block: Option<u64>,
+    },
+    /// Critical inconsistencies between the storage and the chain
+    /// were found by the preflight check.
+    PreflightFailed(Vec<PreflightFinding>),
+    /// An event of the block failed to be processed with
+    /// `ProcessingStrictness::Strict`, and the block was marked as failed.
+    BlockFailed {
+        block: u64,
+        error: String,
+    },
+    /// The block was held by `index_pending` of this instance,
+    /// and was not promoted to latest while the range waited for it.
+    PendingBlockHeld {
+        block: u64,
+        waited: Duration,
+    },
 }
 
 impl From<StorageError> for IndexerError {
@@ -59,26 +149,224 @@ impl fmt::Display for IndexerError {
             IndexerError::StorageError(e) => write!(f, "Storage Error occurred: {}", e),
             IndexerError::Starknet(e) => write!(f, "Starknet Error occurred: {}", e),
             IndexerError::Anyhow(s) => write!(f, "An error occurred: {}", s),
+            IndexerError::VersionConflict {
+                block,
+                existing_version,
+                current_version,
+            } => write!(
+                f,
+                "Block {} already terminated by version {} (current version: {})",
+                block, existing_version, current_version
+            ),
+            IndexerError::OptimisticLockFailed {
+                block,
+                expected_etag,
+                found_etag,
+            } => write!(
+                f,
+                "Block {} was modified concurrently (expected etag {}, found {})",
+                block, expected_etag, found_etag
+            ),
+            IndexerError::PendingLoopAlreadyRunning => {
+                write!(f, "The pending loop is already running on this instance")
+            }
+            IndexerError::BurnedTokenTransfer {
+                contract_address,
+                token_id_hex,
+                burned_at_block,
+            } => write!(
+                f,
+                "Transfer of burned token {} of contract {} (burned at block {:?})",
+                token_id_hex, contract_address, burned_at_block
+            ),
+            IndexerError::PreflightFailed(findings) => {
+                write!(f, "Preflight check failed: {:?}", findings)
+            }
+            IndexerError::BlockFailed { block, error } => {
+                write!(f, "Block {} failed: {}", block, error)
+            }
+            IndexerError::PendingBlockHeld { block, waited } => write!(
+                f,
+                "Block {} held by the pending loop for {:?}, not promoted to latest",
+                block, waited
+            ),
         }
     }
 }
 
 impl std::error::Error for IndexerError {}
 
-pub struct PontosConfig {
-    pub indexer_version: String,
-    pub indexer_identifier: String,
+/// Events of a batch to be decoded together, grouped by contract.
+/// The events of a contract are decoded at once by
+/// `Pontos::take_decoded_transfer`, when the contract is identified.
+#[derive(Debug, Default)]
+struct TransferBatches {
+    /// Indexes of the events of each contract, not decoded yet.
+    pending: HashMap<FieldElement, Vec<usize>>,
+    /// Transfers decoded ahead, by index of their event.
+    decoded: HashMap<usize, DecodedTransfer>,
+}
+
+impl TransferBatches {
+    /// Groups the events which may be transfers of NFT contracts.
+    fn new(events: &[EmittedEvent]) -> Self {
+        let mut batches = Self::default();
+        for (index, e) in events.iter().enumerate() {
+            if !is_marketplace_contract(&e.from_address) {
+                batches
+                    .pending
+                    .entry(e.from_address)
+                    .or_default()
+                    .push(index);
+            }
+        }
+        batches
+    }
+}
+
+/// Activity of the collections accumulated while processing the events
+/// of a single block, which may be processed in several batches.
+#[derive(Debug, Default)]
+struct BlockActivity {
+    collections: HashMap<String, (CollectionActivity, HashSet<String>)>,
+}
+
+impl BlockActivity {
+    /// Counts the given registered transfer.
+    fn track(&mut self, event: &storage::types::TokenTransferEvent) {
+        let (activity, tokens) = self
+            .collections
+            .entry(event.contract_address.clone())
+            .or_insert_with(|| {
+                (
+                    CollectionActivity {
+                        contract_address: event.contract_address.clone(),
+                        ..Default::default()
+                    },
+                    HashSet::new(),
+                )
+            });
+
+        match event.event_type {
+            EventType::Mint => activity.mints += 1,
+            EventType::Burn => activity.burns += 1,
+            _ => activity.transfers += 1,
+        }
+
+        if tokens.insert(event.token_id_hex.clone()) {
+            activity.distinct_tokens += 1;
+        }
+    }
+
+    /// Returns the activity of each collection, sorted by contract address.
+    fn into_summary(self) -> Vec<CollectionActivity> {
+        let mut summary: Vec<CollectionActivity> = self
+            .collections
+            .into_values()
+            .map(|(activity, _)| activity)
+            .collect();
+        summary.sort_by(|a, b| a.contract_address.cmp(&b.contract_address));
+        summary
+    }
 }
 
+/// The indexer.
+///
+/// A single instance can be shared (in an `Arc`) by several tasks, each running
+/// `index_block_range` on its own range, while one task runs `index_pending`.
+/// The caches (contracts, layouts) are concurrent, and the pending block data
+/// is only written by `index_pending`. The invariants are:
+/// * `index_pending` runs at most once at a time on an instance.
+/// * the block held by `index_pending`, pending or being promoted to latest,
+///   is never indexed by `index_block_range`, which waits for the promotion.
+///   Once promoted, the block is terminated by `index_pending`, and skipped
+///   by `index_block_range`.
+/// * the ranges of concurrent `index_block_range` don't overlap.
 pub struct Pontos<S: Storage, C: StarknetClient, E: EventHandler> {
     client: Arc<C>,
+    /// Bounds the concurrent calls to the node, shared with the managers.
+    rpc_permits: RpcPermits,
+    /// Rules attributing the token events emitted on behalf of a collection.
+    attribution_rules: Vec<Arc<dyn AttributionRule>>,
     event_handler: Arc<E>,
     config: PontosConfig,
     block_manager: Arc<BlockManager<S>>,
     event_manager: Arc<EventManager<S>>,
     token_manager: Arc<TokenManager<S, C>>,
-    contract_manager: Arc<AsyncRwLock<ContractManager<S, C>>>,
+    contract_manager: Arc<ContractManager<S, C>>,
     pending_cache: Arc<AsyncRwLock<PendingBlockData>>,
+    /// Interval in milliseconds currently used by `index_pending`.
+    pending_poll_interval_ms: AtomicU64,
+    discarded_events: AtomicU64,
+    log_detail: AtomicU8,
+    suppressed_logs: AtomicU64,
+    storage: Arc<S>,
+    started_at: Instant,
+    /// Clock of the waits, see `PontosConfig::clock`.
+    clock: Arc<dyn Clock>,
+    /// Highest block indexed plus one, 0 if no block was indexed yet.
+    last_indexed_block: AtomicU64,
+    pending_loop_running: Arc<AtomicBool>,
+    /// Last block accepted on the chain plus one, 0 if not known yet.
+    chain_head: AtomicU64,
+    /// Milliseconds since `started_at` plus one of the last dedicated
+    /// fetch of the chain head, 0 if never fetched.
+    chain_head_refreshed_ms: AtomicU64,
+    /// Timestamp of the pending block indexed by `index_pending`.
+    pending_timestamp: AtomicU64,
+    pending_unprocessed_txs: AtomicU64,
+    /// Blocks fetched by `warm_up`, by block number.
+    warm_blocks: DashMap<u64, WarmBlock>,
+    /// Set once the preflight check required by the configuration has passed.
+    preflight_passed: AtomicBool,
+    /// Contracts which events are not indexed, but added to the dead-letter queue.
+    paused_contracts: DashSet<FieldElement>,
+    /// Consecutive failures of the contracts not paused, for the circuit breaker.
+    contract_failures: DashMap<FieldElement, u32>,
+    /// Set by `shutdown`, stops the indexing loops after their current block or tick.
+    shutdown: watch::Sender<bool>,
+    /// Held shared by each running indexing loop, and exclusively by
+    /// `shutdown` to wait for the loops to return.
+    active_loops: AsyncRwLock<()>,
+    /// Milliseconds since `started_at` of the last successful tick
+    /// of the pending loop, checked by the pending watchdog.
+    pending_last_tick_ms: AtomicU64,
+    /// Changed by the pending watchdog to restart the pending loop.
+    pending_restart: watch::Sender<()>,
+}
+
+/// Returns true if the error is due to an event already indexed,
+/// which must not be reprocessed.
+fn is_already_indexed(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<StorageError>(),
+        Some(StorageError::AlreadyExists(_))
+    )
+}
+
+/// Returns the storage error of a failed write, if the error comes from
+/// the storage. An event already indexed is not considered as a failure.
+fn storage_write_error(error: &anyhow::Error) -> Option<&StorageError> {
+    match error.downcast_ref::<StorageError>() {
+        Some(StorageError::AlreadyExists(_)) | None => None,
+        Some(e) => Some(e),
+    }
+}
+
+/// Returns true if the given address is one of the supported marketplaces.
+fn is_marketplace_contract(address: &FieldElement) -> bool {
+    let marketplace_contracts = [
+        FieldElement::from_hex_be(
+            "0x04d8bb956e6bd7a50fcb8b49d8e9fd8269cfadbeb73f457fd6d3fc1dff4b879e", // Element Marketplace
+        )
+        .unwrap(),
+        FieldElement::from_hex_be(
+            "0x008755a98ccf7d25e69aa90ef3b73b07c470ba4ec6391b0b0c7c598f992c3fee", // Ventory Marketplace
+        )
+        .unwrap(),
+    ];
+
+    marketplace_contracts.contains(address)
 }
 
 impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C, E> {
@@ -88,295 +376,313 @@ impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C,
         event_handler: Arc<E>,
         config: PontosConfig,
     ) -> Self {
+        let pending_poll_interval_ms = config.pending_polling.base_interval().as_millis() as u64;
+        let identification_strategy = config.identification_strategy.clone();
+        let log_detail = config.log_detail;
+        let processing_backoff = config.processing_backoff;
+        let collection_cache_size = config.collection_cache_size.unwrap_or_default();
+        let rpc_permits = RpcPermits::new(config.rpc_max_concurrent_calls);
+        let clock = config
+            .clock
+            .clone()
+            .unwrap_or_else(|| Arc::new(SystemClock));
+        let attribution_rules = config
+            .attribution_rules
+            .clone()
+            .unwrap_or_else(default_attribution_rules);
+        let event_manager = EventManager::new(Arc::clone(&storage))
+            .with_key_denylist(config.event_key_denylist.clone());
+        let started_at = clock.now();
+        let shutdown = watch::channel(false).0;
+        let block_manager = BlockManager::new(Arc::clone(&storage))
+            .with_processing_backoff(processing_backoff)
+            .with_selector_hash(event_manager.selector_hash())
+            .with_reindex_on_selector_change(config.reindex_on_selector_change)
+            .with_clock(Arc::clone(&clock));
+
         Pontos {
             config,
             client: Arc::clone(&client),
             event_handler: Arc::clone(&event_handler),
-            block_manager: Arc::new(BlockManager::new(Arc::clone(&storage))),
-            event_manager: Arc::new(EventManager::new(Arc::clone(&storage))),
-            token_manager: Arc::new(TokenManager::new(Arc::clone(&storage), Arc::clone(&client))),
-            // Contract manager has internal cache, so some functions are using `&mut self`.
-            // For this reason, we must protect the write operations in order to share
-            // the cache with any possible thread using `index_block_range` of this instance.
-            contract_manager: Arc::new(AsyncRwLock::new(ContractManager::new(
-                Arc::clone(&storage),
-                Arc::clone(&client),
-            ))),
+            block_manager: Arc::new(block_manager),
+            event_manager: Arc::new(event_manager),
+            token_manager: Arc::new(
+                TokenManager::new(Arc::clone(&storage), Arc::clone(&client))
+                    .with_rpc_permits(rpc_permits.clone()),
+            ),
+            // Contract manager has an internal concurrent cache, and can be shared
+            // without lock with any possible thread using `index_block_range` of this instance.
+            contract_manager: Arc::new(
+                ContractManager::new(
+                    Arc::clone(&storage),
+                    Arc::clone(&client),
+                    identification_strategy,
+                )
+                .with_rpc_permits(rpc_permits.clone())
+                .with_cache_size(collection_cache_size),
+            ),
+            rpc_permits,
+            attribution_rules,
             pending_cache: Arc::new(AsyncRwLock::new(PendingBlockData::new())),
+            pending_poll_interval_ms: AtomicU64::new(pending_poll_interval_ms),
+            discarded_events: AtomicU64::new(0),
+            log_detail: AtomicU8::new(log_detail as u8),
+            suppressed_logs: AtomicU64::new(0),
+            storage,
+            started_at,
+            clock,
+            last_indexed_block: AtomicU64::new(0),
+            pending_loop_running: Arc::new(AtomicBool::new(false)),
+            chain_head: AtomicU64::new(0),
+            chain_head_refreshed_ms: AtomicU64::new(0),
+            pending_timestamp: AtomicU64::new(0),
+            pending_unprocessed_txs: AtomicU64::new(0),
+            warm_blocks: DashMap::new(),
+            preflight_passed: AtomicBool::new(false),
+            paused_contracts: DashSet::new(),
+            contract_failures: DashMap::new(),
+            shutdown,
+            active_loops: AsyncRwLock::new(()),
+            pending_last_tick_ms: AtomicU64::new(0),
+            pending_restart: watch::channel(()).0,
         }
     }
 
-    /// Starts a loop to only index the pending block.
-    pub async fn index_pending(&self) -> IndexerResult<()> {
-        loop {
-            let mut cache = self.pending_cache.write().await;
-
-            let (pending_ts, txs) = match self
-                .client
-                .block_txs_hashes(BlockId::Tag(BlockTag::Pending))
-                .await
-            {
-                Ok((ts, txs)) => (ts, txs),
-                Err(e) => {
-                    error!("Error while fetching pending block txs: {:?}", e);
-                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                    continue;
-                }
-            };
-
-            if cache.get_timestamp() == 0 {
-                cache.set_timestamp(pending_ts);
-            }
-
-            debug!("Pending block {} with {} txs", pending_ts, txs.len());
-
-            let previous_loop_ts = cache.get_timestamp();
-
-            // If the timestamp is different from the previous loop,
-            // we must first ensure we've fetched and processed all the transactions
-            // of the previous pending block, which is now the "Latest".
-            if pending_ts != previous_loop_ts {
-                debug!("ts differ! {} {}", pending_ts, previous_loop_ts);
-                // Get the latest block number, generated by the sequencer, which is
-                // expected to be the one we just processed.
-                let block_number = match self.client.block_number().await {
-                    Ok(n) => n,
-                    Err(e) => {
-                        error!("Error while fetching latest block number: {:?}", e);
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                        continue;
-                    }
-                };
+    /// Returns a copy of the configuration of this instance,
+    /// to be modified and given to `Pontos::with_config`.
+    pub fn clone_config(&self) -> PontosConfig {
+        self.config.clone()
+    }
 
-                self.event_handler.on_new_latest_block(block_number).await;
+    /// Creates a new instance sharing the client, the storage and the event
+    /// handler of this instance, with the given configuration.
+    /// The caches and the runtime state of the new instance start empty.
+    pub fn with_config(&self, config: PontosConfig) -> Self {
+        Self::new(
+            Arc::clone(&self.client),
+            Arc::clone(&self.storage),
+            Arc::clone(&self.event_handler),
+            config,
+        )
+    }
 
-                info!(
-                    "Pending block {} is now latest block number #{}",
-                    previous_loop_ts, block_number
-                );
+    /// Returns a handle to query the on-chain state of the tokens,
+    /// sharing the client and the contracts cache of this instance.
+    pub fn token_query(&self) -> TokenQuery<S, C> {
+        TokenQuery::new(
+            Arc::clone(&self.token_manager),
+            Arc::clone(&self.contract_manager),
+        )
+    }
 
-                // Setup the local variables to directly start the pending block
-                // indexation instead of waiting the next tick.
-                cache.set_timestamp(pending_ts);
-                cache.clear_tx_hashes();
+    /// Sends the block completed to the observer of the range, if any.
+    async fn notify_observer(
+        &self,
+        observer: Option<&mpsc::Sender<IndexerResult<BlockCompleted>>>,
+        completed: BlockCompleted,
+    ) {
+        if let Some(tx) = observer {
+            if tx.send(Ok(completed)).await.is_err() {
+                debug!("Block range observer closed");
             }
-
-            // TODO: make this configurable?
-            tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
         }
     }
 
-    pub async fn index_contract_events(
-        &self,
-        from_block: Option<BlockId>,
-        to_block: Option<BlockId>,
-        contract_address: FieldElement,
-        chain_id: &str,
-    ) -> IndexerResult<()> {
-        let mut continuation_token: Option<String> = None;
+    /// Returns the chain id of the events indexed by `index_pending`.
+    fn chain_id(&self) -> &str {
+        self.config.chain_id.as_deref().unwrap_or(DEFAULT_CHAIN_ID)
+    }
 
-        loop {
-            let result = self
-                .client
-                .fetch_events(
-                    from_block,
-                    to_block,
-                    self.event_manager.keys_selector(),
-                    Some(contract_address),
-                    continuation_token,
-                )
-                .await?;
-
-            let mut current_block_number: u64 = 0;
-            let mut current_block_timestamp: u64 = 0;
-
-            for (block_number, events) in result.events {
-                if current_block_number != block_number {
-                    current_block_number = block_number;
-
-                    match self.client.block_time(BlockId::Number(block_number)).await {
-                        Ok(ts) => {
-                            current_block_timestamp = ts;
-                            self.process_events(events, current_block_timestamp, chain_id)
-                                .await?;
-                        }
-                        Err(e) => {
-                            error!("Error while fetching block timestamp: {:?}", e);
-                        }
-                    };
-                } else {
-                    self.process_events(events, current_block_timestamp, chain_id)
-                        .await?;
-                }
-            }
+    /// Returns the time elapsed since the creation of the instance.
+    fn uptime(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.started_at)
+    }
 
-            if result.continuation_token.is_none() {
-                break;
-            } else {
-                continuation_token = result.continuation_token;
-                continue;
-            }
-        }
+    /// Records a block number known to be accepted on the chain.
+    fn observe_chain_head(&self, block_number: u64) {
+        self.chain_head
+            .fetch_max(block_number + 1, Ordering::Relaxed);
+    }
 
-        Ok(())
+    /// Changes the detail of the per-transaction and per-event logs,
+    /// while indexing.
+    pub fn set_log_detail(&self, detail: LogDetail) {
+        self.log_detail.store(detail as u8, Ordering::Relaxed);
     }
 
-    /// If "Latest" is used for the `to_block`,
-    /// this function will only index the latest block
-    /// that is not pending.
-    /// If you use this on latest, be sure to don't have any
-    /// other pontos instance running `index_pending` as you may
-    /// deal with overlaps or at least check db registers first.
-    pub async fn index_block_range(
-        &self,
-        from_block: BlockId,
-        to_block: BlockId,
-        do_force: bool,
-        chain_id: &str,
-    ) -> IndexerResult<()> {
-        let mut current_u64 = self.client.block_id_to_u64(&from_block).await?;
-        let to_u64 = self.client.block_id_to_u64(&to_block).await?;
-        let from_u64 = current_u64;
-
-        // Some contracts are causing too much recursion for the Cairo VM.
-        // This is restarting the full node (Juno) as it is OOM and is shutdown by the OS.
-        // To mitigate this problem before scaling the full node up,
-        // we setup a `max_attempt` to reach the full node before skipping
-        // the entire block.
-        // Currently, we observed that the node almost always reponds after the
-        // second attempt.
-        let max_attempt = 5;
-        let mut attempt = 0;
+    /// Pauses the indexation of the contract, while indexing.
+    /// The events of a paused contract are added to the dead-letter queue,
+    /// to be reprocessed with `Pontos::reprocess_failed_events` once resumed.
+    pub fn pause_contract(&self, contract_address: FieldElement) {
+        self.paused_contracts.insert(contract_address);
+    }
 
-        loop {
-            trace!("Indexing block range: {} {}", current_u64, to_u64);
+    /// Resumes the indexation of a paused contract.
+    /// Returns false if the contract was not paused.
+    pub fn resume_contract(&self, contract_address: FieldElement) -> bool {
+        self.contract_failures.remove(&contract_address);
+        self.paused_contracts.remove(&contract_address).is_some()
+    }
 
-            if current_u64 > to_u64 {
-                info!("End of indexing block range");
-                break;
-            }
+    /// Returns the contracts currently paused, manually or by the circuit breaker.
+    pub fn paused_contracts(&self) -> Vec<FieldElement> {
+        self.paused_contracts.iter().map(|a| *a).collect()
+    }
 
-            let block_ts = match self.client.block_time(BlockId::Number(current_u64)).await {
-                Ok(ts) => ts,
-                Err(e) => {
-                    error!(
-                        "Attempt #{} - Couldn't get timestamp for block {}: {:?}",
-                        attempt + 1,
-                        current_u64,
-                        e
-                    );
-                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                    attempt += 1;
-
-                    if attempt > max_attempt {
-                        warn!(
-                            "Skipping block {} as timestamp is not available",
-                            current_u64
-                        );
-                        current_u64 += 1;
-                    }
+    fn log_detail(&self) -> LogDetail {
+        LogDetail::from(self.log_detail.load(Ordering::Relaxed))
+    }
 
-                    continue;
-                }
-            };
+    /// Returns true if a log requiring the given detail must be emitted,
+    /// or counts it as suppressed.
+    fn should_log(&self, detail: LogDetail) -> bool {
+        if self.log_detail() >= detail {
+            true
+        } else {
+            self.suppressed_logs.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
 
-            if self
-                .block_manager
-                .should_skip_indexing(
-                    current_u64,
-                    block_ts,
-                    self.config.indexer_version.clone(),
-                    do_force,
-                )
-                .await?
-            {
-                info!("Skipping block {}", current_u64);
-                current_u64 += 1;
-                continue;
-            }
+    /// Stops gracefully the indexing loops running on this instance:
+    /// `index_pending` returns after its current tick, and each
+    /// `index_block_range` after its current block, with the blocks indexed.
+    /// Waits for the loops to return, so their storage writes are completed,
+    /// then drains the pending cache and records the instance as inactive
+    /// for `list_active_indexers`.
+    ///
+    /// The loops started after the shutdown return immediately: a new
+    /// instance must be created to index again.
+    pub async fn shutdown(&self) -> IndexerResult<()> {
+        info!("Shutting down the indexing loops");
+        self.shutdown.send_replace(true);
+
+        // Each loop holds a shared guard until it returns.
+        drop(self.active_loops.write().await);
+
+        self.drain_pending_cache().await;
+
+        let info = IndexerInfo {
+            last_heartbeat: self.clock.unix_time().as_millis() as u64,
+            inactive: true,
+            ..self.indexer_info()
+        };
+        self.storage.register_indexer(&info).await?;
 
-            self.event_handler
-                .on_block_processing(block_ts, Some(current_u64))
-                .await;
+        info!("Indexing loops stopped");
+        Ok(())
+    }
 
-            // Set block as processing.
-            self.block_manager
-                .set_block_info(
-                    current_u64,
-                    block_ts,
-                    self.config.indexer_version.clone(),
-                    self.config.indexer_identifier.clone(),
-                    BlockIndexingStatus::Processing,
-                )
-                .await?;
+    /// Returns true once `shutdown` was called.
+    fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
 
-            let blocks_events = match self
-                .client
-                .fetch_all_block_events(
-                    BlockId::Number(current_u64),
-                    self.event_manager.keys_selector(),
-                )
-                .await
-            {
-                Ok(events) => events,
-                Err(e) => {
-                    error!("Error while fetching events: {:?}", e);
-                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                    continue;
-                }
-            };
+    /// Waits for `duration` on the clock, or until `shutdown` is called.
+    async fn pause(&self, duration: Duration) {
+        let mut shutdown = self.shutdown.subscribe();
+        if *shutdown.borrow_and_update() {
+            return;
+        }
 
-            let total_events_count: usize = blocks_events.values().map(|events| events.len()).sum();
-            info!(
-                "✨ Processing block {}. Total Events Count: {}.",
-                current_u64, total_events_count
-            );
+        tokio::select! {
+            _ = self.clock.sleep(duration) => {}
+            _ = shutdown.changed() => {}
+        }
+    }
 
-            for (_, events) in blocks_events {
-                self.process_events(events, block_ts, chain_id).await?;
-            }
+    /// Recomputes the supply of the collection from the mints and burns
+    /// stored, to repair a supply that may have drifted.
+    /// Returns the new supply.
+    pub async fn recount_collection_supply(
+        &self,
+        contract_address: FieldElement,
+    ) -> IndexerResult<i64> {
+        Ok(self
+            .token_manager
+            .recount_collection_supply(&contract_address)
+            .await?)
+    }
 
-            self.block_manager
-                .set_block_info(
-                    current_u64,
-                    block_ts,
-                    self.config.indexer_version.clone(),
-                    self.config.indexer_identifier.clone(),
-                    BlockIndexingStatus::Terminated,
-                )
-                .await?;
+    /// Returns the total supply reported by the collection contract,
+    /// cached when the contract was identified, or fetched and cached.
+    /// Returns `None` if the contract doesn't expose its total supply.
+    pub async fn get_total_supply(
+        &self,
+        contract_address: FieldElement,
+    ) -> IndexerResult<Option<u64>> {
+        Ok(self
+            .token_manager
+            .get_total_supply(contract_address)
+            .await?)
+    }
 
-            let progress = if to_u64 == from_u64 {
-                if current_u64 == to_u64 {
-                    100.0
-                } else {
-                    0.0
-                }
-            } else {
-                ((current_u64 - from_u64) as f64 / (to_u64 - from_u64) as f64) * 100.0
-            };
+    /// Returns all the events indexed for the token, in the order of the blocks,
+    /// to display its provenance. See `Storage::get_events_for_token`.
+    pub async fn get_token_history(
+        &self,
+        contract_address: FieldElement,
+        token_id: &CairoU256,
+    ) -> IndexerResult<Vec<TokenEvent>> {
+        Ok(self
+            .token_manager
+            .get_token_history(contract_address, token_id)
+            .await?)
+    }
 
-            self.event_handler
-                .on_block_processed(current_u64, progress)
-                .await;
+    /// Returns the counters of the collection maintained while indexing,
+    /// `None` if no event of the collection was indexed yet.
+    pub async fn get_collection_stats(
+        &self,
+        contract_address: FieldElement,
+    ) -> IndexerResult<Option<CollectionStats>> {
+        Ok(self
+            .token_manager
+            .get_collection_stats(contract_address)
+            .await?)
+    }
 
-            current_u64 += 1;
-        }
+    /// Serializes the types of the contracts identified so far to JSON,
+    /// to be loaded later with `Pontos::load_contract_types`.
+    pub fn serialize_contract_types(&self) -> Result<String, serde_json::Error> {
+        self.contract_manager.serialize()
+    }
 
-        self.event_handler.on_indexation_range_completed().await;
+    /// Pre-populates the contracts cache from a JSON file built with
+    /// `Pontos::serialize_contract_types`. The contracts loaded are identified
+    /// without any call to the node. Returns the number of contracts loaded.
+    pub fn load_contract_types(&self, json: &str) -> Result<usize, serde_json::Error> {
+        self.contract_manager.load_from_json(json)
+    }
 
-        Ok(())
+    /// Span wrapping an indexing loop, carrying the identity of the instance.
+    /// The tags are formatted as `key=value` pairs sorted by key.
+    fn indexer_span(&self, mode: &'static str) -> tracing::Span {
+        let mut tags: Vec<String> = self
+            .config
+            .indexer_tags
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        tags.sort();
+
+        tracing::info_span!(
+            "pontos",
+            mode,
+            identifier = %self.config.indexer_identifier,
+            tags = %tags.join(","),
+        )
     }
 
     async fn process_element_sale(
         &self,
         event: EmittedEvent,
-        block_timestamp: u64,
+        block: &BlockContext,
         chain_id: &str,
-    ) -> Result<()> {
+    ) -> Result<Option<TokenEvent>> {
         let mut token_sale_event = self
             .event_manager
-            .format_element_sale_event(&event, block_timestamp)
+            .format_element_sale_event(&event, block)
             .await?;
 
         let contract_addr = FieldElement::from_hex_be(
@@ -389,9 +695,7 @@ impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C,
 
         let contract_type = match self
             .contract_manager
-            .write()
-            .await
-            .identify_contract(contract_addr, block_timestamp, chain_id)
+            .identify_contract(contract_addr, block.timestamp, chain_id)
             .await
         {
             Ok(info) => info,
@@ -400,7 +704,7 @@ impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C,
                     "Error while identifying contract {}: {:?}",
                     token_sale_event.nft_contract_address, e
                 );
-                return Ok(());
+                return Ok(None);
             }
         };
 
@@ -409,28 +713,28 @@ impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C,
                 "Contract identified as OTHER: {}",
                 token_sale_event.nft_contract_address
             );
-            return Ok(());
+            return Ok(None);
         }
 
         token_sale_event.nft_type = Some(contract_type.to_string());
         self.event_manager
-            .register_sale_event(&token_sale_event, block_timestamp)
+            .register_sale_event(&token_sale_event, block.timestamp)
             .await?;
 
-        Ok(())
+        Ok(Some(TokenEvent::Sale(token_sale_event)))
     }
 
     async fn process_ventory_sale_or_accepted_offer_event(
         &self,
         event: EmittedEvent,
-        block_timestamp: u64,
+        block: &BlockContext,
         chain_id: &str,
-    ) -> Result<()> {
+    ) -> Result<Option<TokenEvent>> {
         info!("Processing Ventory Sale or Accepted Offer event...");
 
         let mut token_sale_event = self
             .event_manager
-            .format_ventory_sale_or_accepted_offer_event(&event, block_timestamp)
+            .format_ventory_sale_or_accepted_offer_event(&event, block)
             .await?;
 
         let contract_addr = FieldElement::from_hex_be(
@@ -443,9 +747,7 @@ impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C,
 
         let contract_type = match self
             .contract_manager
-            .write()
-            .await
-            .identify_contract(contract_addr, block_timestamp, chain_id)
+            .identify_contract(contract_addr, block.timestamp, chain_id)
             .await
         {
             Ok(info) => info,
@@ -454,7 +756,7 @@ impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C,
                     "Error while identifying contract {}: {:?}",
                     token_sale_event.nft_contract_address, e
                 );
-                return Ok(());
+                return Ok(None);
             }
         };
 
@@ -463,23 +765,23 @@ impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C,
                 "Contract identified as OTHER: {}",
                 token_sale_event.nft_contract_address
             );
-            return Ok(());
+            return Ok(None);
         }
 
         token_sale_event.nft_type = Some(contract_type.to_string());
         self.event_manager
-            .register_sale_event(&token_sale_event, block_timestamp)
+            .register_sale_event(&token_sale_event, block.timestamp)
             .await?;
 
-        Ok(())
+        Ok(Some(TokenEvent::Sale(token_sale_event)))
     }
 
     async fn process_marketplace_event(
         &self,
         event: EmittedEvent,
-        block_timestamp: u64,
+        block: &BlockContext,
         chain_id: &str,
-    ) -> Result<()> {
+    ) -> Result<Option<TokenEvent>> {
         let element_sale_event_name = FieldElement::from_hex_be(ELEMENT_MARKETPLACE_EVENT_HEX)?;
         let ventory_sale_event_name = FieldElement::from_hex_be(VENTORY_MARKETPLACE_EVENT_HEX)?;
         let ventory_offer_accepted_event_name =
@@ -490,115 +792,2202 @@ impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C,
 
             match event_name {
                 name if name == &element_sale_event_name => {
-                    self.process_element_sale(event, block_timestamp, chain_id)
-                        .await?
+                    return self.process_element_sale(event, block, chain_id).await;
                 }
                 name if name == &ventory_sale_event_name
                     || name == &ventory_offer_accepted_event_name =>
                 {
-                    self.process_ventory_sale_or_accepted_offer_event(
-                        event,
-                        block_timestamp,
-                        chain_id,
-                    )
-                    .await?
+                    return self
+                        .process_ventory_sale_or_accepted_offer_event(event, block, chain_id)
+                        .await;
                 }
                 _ => (),
             }
         }
 
-        Ok(())
+        Ok(None)
     }
 
+    /// Processes a transfer event, decoded ahead by `take_decoded_transfer` if given,
+    /// with the batch decoding of `EventManager::format_event_batch`.
+    #[allow(clippy::too_many_arguments)]
     async fn process_nft_transfers(
         &self,
-        event: EmittedEvent,
-        block_timestamp: u64,
+        event: &EmittedEvent,
+        decoded: Option<&DecodedTransfer>,
+        block: &BlockContext,
         contract_address: FieldElement,
         chain_id: &str,
-    ) -> Result<()> {
+        supply_deltas: &mut SupplyDeltas,
+        activity: &mut BlockActivity,
+    ) -> Result<Option<TokenEvent>> {
         let contract_address_hex = to_hex_str(&contract_address);
-        let contract_type = self
-            .contract_manager
-            .write()
-            .await
-            .identify_contract(contract_address, block_timestamp, chain_id)
-            .await
-            .map_err(|e| {
-                error!(
-                    "Error while identifying contract {}: {:?}",
-                    contract_address_hex, e
-                );
-                e
-            })?;
+        let contract_type = match decoded {
+            Some(transfer) => transfer.contract_type.clone(),
+            None => self
+                .contract_manager
+                .identify_contract_from_event(event, block.timestamp, chain_id)
+                .await
+                .map_err(|e| {
+                    error!(
+                        "Error while identifying contract {}: {:?}",
+                        contract_address_hex, e
+                    );
+                    e
+                })?,
+        };
 
         if contract_type == ContractType::Other {
-            debug!("Contract identified as OTHER: {}", contract_address_hex);
-            return Ok(());
+            if self.should_log(LogDetail::Normal) {
+                debug!(
+                    target: EVENTS_LOG_TARGET,
+                    "Contract identified as OTHER: {}", contract_address_hex
+                );
+            }
+            return Ok(None);
         }
 
-        info!(
-            "Processing event... Block Id: {:?}, Tx Hash: 0x{:064x}, contract_type: {:?}",
-            event.block_number, event.transaction_hash, contract_type
-        );
+        if self.should_log(LogDetail::Normal) {
+            info!(
+                target: EVENTS_LOG_TARGET,
+                "Processing event... Block: {:?}, Tx Hash: 0x{:064x}, contract_type: {:?}",
+                block.number, event.transaction_hash, contract_type
+            );
+        }
+
+        if self.log_detail() >= LogDetail::Verbose {
+            debug!(target: EVENTS_LOG_TARGET, "Event content: {:?}", event);
+        }
+
+        // The event is kept typed until registered, the strings
+        // are only rendered for the events not registered yet.
+        let transfer = match decoded {
+            Some(transfer) => transfer.clone(),
+            None => self
+                .event_manager
+                .decode_event(event, contract_type, block)?,
+        };
 
-        let (token_id, token_event) = self
+        self.token_manager.check_not_burned(&transfer).await?;
+
+        let registered = self
             .event_manager
-            .format_and_register_event(&event, contract_type, block_timestamp)
+            .register_decoded_event(&transfer)
             .await
             .map_err(|err| {
                 error!("Error while registering event {:?}\n{:?}", err, event);
                 err
             })?;
 
-        self.token_manager
-            .format_and_register_token(&token_id, &token_event, block_timestamp, event.block_number)
+        // Fetched twice, or registered by a concurrent indexer.
+        let Some(token_event) = registered else {
+            debug!(
+                target: EVENTS_LOG_TARGET,
+                "Event 0x{:064x} already registered, skipped", transfer.event_id
+            );
+            return Ok(None);
+        };
+        let token_id = transfer.token_id;
+
+        TokenManager::<S, C>::track_supply(supply_deltas, &token_event);
+        activity.track(&token_event);
+
+        let token = self
+            .token_manager
+            .format_and_register_token(&token_id, &token_event, block)
             .await
             .map_err(|err| {
                 error!("Can't format token {:?}\ntevent: {:?}", err, token_event);
                 err
             })?;
 
-        Ok(())
+        let token_event = TokenEvent::Transfer(token_event);
+        if let Some(token) = token {
+            self.event_handler
+                .on_token_event(&token_event, &token)
+                .await;
+        }
+
+        Ok(Some(token_event))
     }
 
-    /// Inner function to process events.
-    async fn process_events(
+    /// Processes a single event, according to its emitter.
+    /// Returns the token event registered, if any.
+    async fn process_event(
         &self,
-        events: Vec<EmittedEvent>,
-        block_timestamp: u64,
+        event: &EmittedEvent,
+        decoded: Option<&DecodedTransfer>,
+        block: &BlockContext,
         chain_id: &str,
-    ) -> IndexerResult<()> {
-        let marketplace_contracts = [
-            FieldElement::from_hex_be(
-                "0x04d8bb956e6bd7a50fcb8b49d8e9fd8269cfadbeb73f457fd6d3fc1dff4b879e", // Element Marketplace
-            )
-            .unwrap(),
-            FieldElement::from_hex_be(
-                "0x008755a98ccf7d25e69aa90ef3b73b07c470ba4ec6391b0b0c7c598f992c3fee", // Ventory Marketplace
+        supply_deltas: &mut SupplyDeltas,
+        activity: &mut BlockActivity,
+    ) -> Result<Option<TokenEvent>> {
+        let contract_address = event.from_address;
+
+        if is_marketplace_contract(&contract_address) {
+            self.process_marketplace_event(event.clone(), block, chain_id)
+                .await
+        } else if !EventManager::<S>::has_supported_shape(event) {
+            if self.should_log(LogDetail::Normal) {
+                trace!(
+                    target: EVENTS_LOG_TARGET,
+                    "Discarding event with unsupported layout: {:?}", event
+                );
+            }
+            self.discarded_events.fetch_add(1, Ordering::Relaxed);
+            Ok(None)
+        } else {
+            self.process_nft_transfers(
+                event,
+                decoded,
+                block,
+                contract_address,
+                chain_id,
+                supply_deltas,
+                activity,
             )
-            .unwrap(),
-        ];
+            .await
+        }
+    }
 
-        for e in events {
-            let contract_address = e.from_address;
-            let is_marketplace_event = marketplace_contracts.contains(&contract_address);
+    /// Counts a consecutive failure of the contract, and pauses the contract
+    /// once the threshold of the circuit breaker is reached.
+    async fn track_contract_failure(&self, contract_address: FieldElement) {
+        let threshold = self.config.circuit_breaker.failure_threshold;
+        if threshold == 0 {
+            return;
+        }
 
-            if is_marketplace_event {
-                if let Err(e) = self
-                    .process_marketplace_event(e, block_timestamp, chain_id)
-                    .await
-                {
-                    error!("Error while processing marketplace event: {:?}", e);
-                }
-            } else if let Err(e) = self
-                .process_nft_transfers(e, block_timestamp, contract_address, chain_id)
+        let failure_count = {
+            let mut count = self.contract_failures.entry(contract_address).or_insert(0);
+            *count += 1;
+            *count
+        };
+
+        if failure_count < threshold {
+            return;
+        }
+
+        self.contract_failures.remove(&contract_address);
+        self.paused_contracts.insert(contract_address);
+
+        warn!(
+            "Contract 0x{:064x} paused after {} consecutive failures",
+            contract_address, failure_count
+        );
+
+        self.event_handler
+            .on_contract_circuit_open(contract_address, failure_count)
+            .await;
+    }
+
+    /// Adds the event to the dead-letter queue, and notifies the event handler.
+    async fn register_failed_event(
+        &self,
+        event: &EmittedEvent,
+        block_timestamp: u64,
+        chain_id: &str,
+        error: &anyhow::Error,
+    ) {
+        let payload = match serde_json::to_string(event) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Can't serialize failed event: {:?}", e);
+                return;
+            }
+        };
+
+        let id =
+            starknet_keccak(format!("{}:{}:{}", chain_id, block_timestamp, payload).as_bytes());
+
+        let failed_event = FailedEvent {
+            id: to_hex_str(&id),
+            contract_address: to_hex_str(&event.from_address),
+            transaction_hash: to_hex_str(&event.transaction_hash),
+            chain_id: chain_id.to_string(),
+            block_timestamp,
+            payload,
+            error: error.to_string(),
+        };
+
+        match self.storage.register_failed_event(&failed_event).await {
+            Ok(()) | Err(StorageError::AlreadyExists(_)) => {
+                self.event_handler.on_event_error(&failed_event).await
+            }
+            Err(e) => error!("Can't register failed event {:?}: {:?}", failed_event, e),
+        }
+    }
+
+    /// Processes a single event, retrying up to `STORAGE_WRITE_RETRIES` times
+    /// when a write into the storage fails. The event handler is notified
+    /// of each storage failure.
+    async fn process_event_with_retries(
+        &self,
+        event: &EmittedEvent,
+        decoded: Option<&DecodedTransfer>,
+        block: &BlockContext,
+        chain_id: &str,
+        supply_deltas: &mut SupplyDeltas,
+        activity: &mut BlockActivity,
+    ) -> Result<Option<TokenEvent>> {
+        let mut retry_count = 0;
+
+        loop {
+            let err = match self
+                .process_event(event, decoded, block, chain_id, supply_deltas, activity)
                 .await
             {
-                error!("Error while processing NFT transfers: {:?}", e);
+                Ok(registered) => return Ok(registered),
+                Err(err) => err,
+            };
+
+            let storage_error = match storage_write_error(&err) {
+                Some(e) => e,
+                None => return Err(err),
+            };
+
+            self.event_handler
+                .on_storage_write_failure(
+                    block.block_number().unwrap_or_default(),
+                    storage_error,
+                    retry_count,
+                )
+                .await;
+
+            if retry_count == STORAGE_WRITE_RETRIES {
+                return Err(err);
             }
+
+            retry_count += 1;
+            warn!(
+                "Storage write failed, retrying event of tx 0x{:064x} ({}/{}): {:?}",
+                event.transaction_hash, retry_count, STORAGE_WRITE_RETRIES, storage_error
+            );
+            self.clock.sleep(STORAGE_WRITE_RETRY_DELAY).await;
         }
+    }
 
-        Ok(())
+    /// Inner function to process events of the given block.
+    /// The block context is used rather than the block data of
+    /// the events, the blocks being not always processed in order.
+    /// Events failing to be processed, and events of paused contracts,
+    /// are added to the dead-letter queue.
+    async fn process_events(
+        &self,
+        events: Vec<EmittedEvent>,
+        block: &BlockContext,
+        chain_id: &str,
+    ) -> IndexerResult<()> {
+        self.process_block_events(events, block, chain_id, &mut BlockActivity::default())
+            .await
+    }
+
+    /// Processes a batch of events of the given block,
+    /// accumulating the activity of the collections of the block.
+    ///
+    /// With `ProcessingStrictness::Strict`, the processing of an accepted
+    /// block is aborted with `IndexerError::BlockFailed` on the first event failing.
+    ///
+    /// The events of a transaction being contiguous, `EventHandler::on_transaction_events`
+    /// is called once the last event of each transaction is processed.
+    async fn process_block_events(
+        &self,
+        events: Vec<EmittedEvent>,
+        block: &BlockContext,
+        chain_id: &str,
+        activity: &mut BlockActivity,
+    ) -> IndexerResult<()> {
+        // Supply variations are applied once for all the events.
+        let mut supply_deltas = SupplyDeltas::new();
+        let strict_block = match block.block_number() {
+            Some(n) if self.config.processing_strictness == ProcessingStrictness::Strict => Some(n),
+            _ => None,
+        };
+        let block_failed = |err: &anyhow::Error| {
+            strict_block.map_or(Ok(()), |block| {
+                Err(IndexerError::BlockFailed {
+                    block,
+                    error: err.to_string(),
+                })
+            })
+        };
+
+        let mut batches = TransferBatches::new(&events);
+
+        // Token events registered for the transaction being processed.
+        let mut transaction: Option<(FieldElement, Vec<TokenEvent>)> = None;
+
+        for (index, emitted) in events.iter().enumerate() {
+            self.heartbeat(block, index).await;
+
+            if transaction
+                .as_ref()
+                .is_some_and(|(tx_hash, _)| *tx_hash != emitted.transaction_hash)
+            {
+                self.notify_transaction_events(transaction.take(), block)
+                    .await;
+            }
+
+            let attributed = match self.attribute_event(&events, index, block, chain_id).await {
+                Ok(Some(attributed)) => attributed,
+                Ok(None) => continue,
+                Err(err) => {
+                    error!("Error while attributing event: {:?}", err);
+                    self.register_failed_event(emitted, block.timestamp, chain_id, &err)
+                        .await;
+                    block_failed(&err)?;
+                    continue;
+                }
+            };
+            let decoded = match attributed {
+                Cow::Borrowed(_) => self.take_decoded_transfer(&events, index, block, &mut batches),
+                Cow::Owned(_) => None,
+            };
+            let e = attributed.as_ref();
+
+            if self.paused_contracts.contains(&e.from_address) {
+                let err = anyhow::anyhow!("Contract 0x{:064x} is paused", e.from_address);
+                self.register_failed_event(e, block.timestamp, chain_id, &err)
+                    .await;
+                block_failed(&err)?;
+                continue;
+            }
+
+            match self
+                .process_event_with_retries(
+                    e,
+                    decoded.as_ref(),
+                    block,
+                    chain_id,
+                    &mut supply_deltas,
+                    activity,
+                )
+                .await
+            {
+                Ok(registered) => {
+                    self.contract_failures.remove(&e.from_address);
+                    let (_, tx_events) =
+                        transaction.get_or_insert_with(|| (e.transaction_hash, vec![]));
+                    tx_events.extend(registered);
+                }
+                Err(err) => {
+                    error!("Error while processing event: {:?}", err);
+
+                    if !is_already_indexed(&err) {
+                        self.register_failed_event(e, block.timestamp, chain_id, &err)
+                            .await;
+                        self.track_contract_failure(e.from_address).await;
+                        block_failed(&err)?;
+                    }
+                }
+            }
+        }
+
+        self.notify_transaction_events(transaction, block).await;
+        self.token_manager
+            .flush_supply(supply_deltas, block.block_number())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records a heartbeat of the block every `PontosConfig::heartbeat_interval`
+    /// events processed, the pending block excepted.
+    /// A heartbeat failing is only logged, the processing goes on.
+    async fn heartbeat(&self, block: &BlockContext, processed_events: usize) {
+        let interval = self.config.heartbeat_interval;
+        let Some(block_number) = block.block_number() else {
+            return;
+        };
+        if interval == 0 || processed_events == 0 || processed_events % interval != 0 {
+            return;
+        }
+
+        if let Err(e) = self.block_manager.heartbeat(block_number).await {
+            warn!("Heartbeat of block {} failed: {:?}", block_number, e);
+        }
+    }
+
+    /// Attributes the event at `index` to its collection with the attribution
+    /// rules, if a rule matches the event and its emitter is not a NFT contract.
+    /// Returns `None` if the event was quarantined, as no collection could
+    /// be resolved confidently.
+    async fn attribute_event<'a>(
+        &self,
+        events: &'a [EmittedEvent],
+        index: usize,
+        block: &BlockContext,
+        chain_id: &str,
+    ) -> Result<Option<Cow<'a, EmittedEvent>>> {
+        let event = &events[index];
+        if is_marketplace_contract(&event.from_address) {
+            return Ok(Some(Cow::Borrowed(event)));
+        }
+
+        let tx_events = attribution::transaction_events(events, index);
+        let Some((rule, attribution)) =
+            self.attribution_rules
+                .iter()
+                .find_map(|rule| match rule.attribute(event, tx_events) {
+                    Attribution::NotApplicable => None,
+                    attribution => Some((rule, attribution)),
+                })
+        else {
+            return Ok(Some(Cow::Borrowed(event)));
+        };
+
+        let emitter_type = self
+            .contract_manager
+            .identify_contract_from_event(event, block.timestamp, chain_id)
+            .await?;
+        if emitter_type != ContractType::Other {
+            return Ok(Some(Cow::Borrowed(event)));
+        }
+
+        let reason = match attribution {
+            Attribution::Resolved(attributed) => {
+                let collection = attributed.from_address;
+                let collection_type = self
+                    .contract_manager
+                    .identify_contract(collection, block.timestamp, chain_id)
+                    .await?;
+
+                if collection_type != ContractType::Other {
+                    debug!(
+                        target: EVENTS_LOG_TARGET,
+                        "Event of tx 0x{:064x} emitted by 0x{:064x} attributed to 0x{:064x} ({})",
+                        event.transaction_hash,
+                        event.from_address,
+                        collection,
+                        rule.name()
+                    );
+                    return Ok(Some(Cow::Owned(attributed)));
+                }
+
+                format!("Contract 0x{:064x} is not a NFT contract", collection)
+            }
+            Attribution::Unresolved(reason) => reason,
+            Attribution::NotApplicable => unreachable!("Filtered above"),
+        };
+
+        self.quarantine_event(event, block.timestamp, chain_id, rule.name(), &reason)
+            .await?;
+        Ok(None)
+    }
+
+    /// Records the event in the quarantine of the storage, for a manual review.
+    async fn quarantine_event(
+        &self,
+        event: &EmittedEvent,
+        block_timestamp: u64,
+        chain_id: &str,
+        rule: &str,
+        reason: &str,
+    ) -> Result<()> {
+        let payload = serde_json::to_string(event)?;
+        let id =
+            starknet_keccak(format!("{}:{}:{}", chain_id, block_timestamp, payload).as_bytes());
+
+        warn!(
+            "Event of tx 0x{:064x} emitted by 0x{:064x} quarantined ({}): {}",
+            event.transaction_hash, event.from_address, rule, reason
+        );
+
+        self.storage
+            .register_quarantined_event(&QuarantinedEvent {
+                id: to_hex_str(&id),
+                emitter_address: to_hex_str(&event.from_address),
+                transaction_hash: to_hex_str(&event.transaction_hash),
+                chain_id: chain_id.to_string(),
+                block_timestamp,
+                payload,
+                rule: rule.to_string(),
+                reason: reason.to_string(),
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns the transfer of the event at `index` decoded ahead, if any.
+    /// Once the contract of the event is identified as a NFT contract,
+    /// its events not processed yet are decoded in a single batch.
+    fn take_decoded_transfer(
+        &self,
+        events: &[EmittedEvent],
+        index: usize,
+        block: &BlockContext,
+        batches: &mut TransferBatches,
+    ) -> Option<DecodedTransfer> {
+        let address = events[index].from_address;
+        let contract_type = self
+            .contract_manager
+            .cached_contract_type(address)
+            .filter(|t| *t != ContractType::Other);
+
+        if let Some(contract_type) = contract_type {
+            if let Some(indexes) = batches.pending.remove(&address) {
+                let indexes: Vec<usize> = indexes.into_iter().filter(|i| *i >= index).collect();
+                let transfers = self.event_manager.decode_event_batch(
+                    indexes.iter().map(|i| &events[*i]),
+                    contract_type,
+                    block,
+                );
+                for (i, transfer) in indexes.into_iter().zip(transfers) {
+                    if let Ok(transfer) = transfer {
+                        batches.decoded.insert(i, transfer);
+                    }
+                }
+            }
+        }
+
+        batches.decoded.remove(&index)
+    }
+
+    /// Calls `EventHandler::on_transaction_events` with the token events
+    /// registered for the transaction, if any.
+    async fn notify_transaction_events(
+        &self,
+        transaction: Option<(FieldElement, Vec<TokenEvent>)>,
+        block: &BlockContext,
+    ) {
+        if let Some((tx_hash, events)) = transaction.filter(|(_, events)| !events.is_empty()) {
+            self.event_handler
+                .on_transaction_events(tx_hash, block.block_number(), &events)
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::types::{BlockIndexingStatus, EventType, TokenInfo};
+    use crate::storage::MockStorage;
+    use ark_starknet::client::MockStarknetClient;
+    use starknet::macros::selector;
+    use std::collections::HashMap;
+
+    pub(crate) struct TestEventHandler;
+
+    impl EventHandler for TestEventHandler {}
+
+    #[derive(Default)]
+    pub(crate) struct CountingEventHandler {
+        pub(crate) token_events: AtomicU64,
+    }
+
+    #[async_trait::async_trait]
+    impl EventHandler for CountingEventHandler {
+        async fn on_token_event(&self, _event: &TokenEvent, _token: &TokenInfo) {
+            self.token_events.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Storage accepting any write, where contracts are ERC721
+    /// and where blocks were never indexed.
+    pub(crate) fn indexing_storage() -> MockStorage {
+        let mut storage = MockStorage::default();
+        storage
+            .expect_register_indexer()
+            .returning(|_| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_get_contract_type_overrides()
+            .returning(|| Box::pin(futures::future::ready(Ok(vec![]))));
+        storage
+            .expect_get_contract_type()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(Some(ContractType::ERC721)))));
+        storage
+            .expect_get_block_info()
+            .returning(|_| Box::pin(futures::future::ready(Ok(None))));
+        storage
+            .expect_set_block_info()
+            .returning(|_, _, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_clean_block()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_upsert_decoded_event()
+            .returning(|e| Box::pin(futures::future::ready(Ok(Some(e.to_token_event())))));
+        storage
+            .expect_register_mint()
+            .returning(|_, _, _, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_get_token()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(None))));
+        storage
+            .expect_set_token_burned()
+            .returning(|_, _, _, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_adjust_collection_supply()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_get_collection_stats()
+            .returning(|_| Box::pin(futures::future::ready(Ok(None))));
+        storage
+            .expect_count_collection_tokens()
+            .returning(|_| Box::pin(futures::future::ready(Ok((0, 0)))));
+        storage
+            .expect_count_transfers_in_range()
+            .returning(|_, _, _| Box::pin(futures::future::ready(Ok(0))));
+        storage
+            .expect_upsert_collection_stats()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_begin_bulk_write()
+            .returning(|| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_end_bulk_write()
+            .returning(|| Box::pin(futures::future::ready(Ok(()))));
+        storage
+    }
+
+    pub(crate) fn config() -> PontosConfig {
+        PontosConfig {
+            indexer_version: "v0.0.1".to_string(),
+            indexer_identifier: "TASK#123".to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn transfer_event(
+        contract_address: FieldElement,
+        block_number: Option<u64>,
+    ) -> EmittedEvent {
+        EmittedEvent {
+            from_address: contract_address,
+            block_hash: None,
+            transaction_hash: FieldElement::from_hex_be("0x5432").unwrap(),
+            block_number,
+            keys: vec![selector!("Transfer")],
+            data: vec![
+                FieldElement::ZERO,
+                FieldElement::from_hex_be("0x5678").unwrap(),
+                FieldElement::ONE,
+                FieldElement::ZERO,
+            ],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_on_token_event_fires_once_on_force_reindex() {
+        let mut storage = indexing_storage();
+        // The token was registered by a previous indexation.
+        storage.expect_register_token().returning(|_, _| {
+            Box::pin(futures::future::ready(Err(StorageError::AlreadyExists(
+                "".to_string(),
+            ))))
+        });
+
+        let contract_address = FieldElement::from_hex_be("0x1234").unwrap();
+
+        let mut client = MockStarknetClient::default();
+        client.expect_block_id_to_u64().returning(|id| match id {
+            BlockId::Number(n) => Ok(*n),
+            _ => Ok(0),
+        });
+        client.expect_block_time().returning(|_| Ok(1000));
+        client
+            .expect_fetch_all_block_events()
+            .returning(move |_, _| {
+                Ok(HashMap::from([(
+                    1,
+                    vec![transfer_event(contract_address, Some(1))],
+                )]))
+            });
+        client
+            .expect_call_contract()
+            .returning(|_, _, _, _| Ok(vec![FieldElement::ONE]));
+
+        let handler = Arc::new(CountingEventHandler::default());
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::new(storage),
+            Arc::clone(&handler),
+            config(),
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(1), true, "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert_eq!(handler.token_events.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_collection_supply_on_force_reindex() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let mints = synthetic_block(1, 3, &contracts);
+
+        // Block 2 burns one of the tokens minted in block 1.
+        let mut burn = mints[0].clone();
+        burn.block_number = Some(2);
+        burn.transaction_hash = FieldElement::from(0xb042_u64);
+        burn.data.swap(0, 1);
+
+        let blocks = HashMap::from([(1, mints), (2, vec![burn])]);
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        let address = contracts[0].address;
+        let supply = |s: &InMemoryStorage| s.dump().supplies[&to_hex_str(&address)];
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(2), false, "SN_MAIN")
+            .await
+            .unwrap();
+        assert_eq!(supply(&storage), 2);
+
+        // Force re-indexing must not drift the supply.
+        for _ in 0..2 {
+            pontos
+                .index_block_range(BlockId::Number(1), BlockId::Number(2), true, "SN_MAIN")
+                .await
+                .unwrap();
+            assert_eq!(supply(&storage), 2);
+        }
+
+        pontos
+            .index_block_range(BlockId::Number(2), BlockId::Number(2), true, "SN_MAIN")
+            .await
+            .unwrap();
+        assert_eq!(supply(&storage), 2);
+
+        assert_eq!(pontos.recount_collection_supply(address).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_quiet_log_detail_counts_suppressed_logs() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = HashMap::from([(1, synthetic_block(1, 2, &contracts))]);
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::new(InMemoryStorage::new()),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        assert_eq!(pontos.status().log_detail, LogDetail::Normal);
+        pontos.set_log_detail(LogDetail::Quiet);
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(1), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let status = pontos.status();
+        assert_eq!(status.log_detail, LogDetail::Quiet);
+        assert_eq!(status.suppressed_logs, 2);
+    }
+
+    /// A transfer edge case, with the state expected to be stored once indexed.
+    struct TransferFixture {
+        name: &'static str,
+        from: u64,
+        to: u64,
+        token_id: u64,
+        event_type: EventType,
+        /// `None` if no token is expected.
+        owner: Option<ExpectedOwner>,
+        minted: bool,
+    }
+
+    enum ExpectedOwner {
+        /// Owner returned by the contract, if it supports `owner_of`.
+        Chain,
+        /// Recipient of the transfer, without call to the chain.
+        Recipient,
+    }
+
+    #[tokio::test]
+    async fn test_transfer_edge_cases() {
+        use crate::testing::{mock_client, synthetic_contracts, InMemoryStorage};
+
+        let fixtures = [
+            TransferFixture {
+                name: "mint of token id 0",
+                from: 0,
+                to: 0xacc0,
+                token_id: 0,
+                event_type: EventType::Mint,
+                owner: Some(ExpectedOwner::Chain),
+                minted: true,
+            },
+            TransferFixture {
+                name: "transfer to the sender",
+                from: 0xacc0,
+                to: 0xacc0,
+                token_id: 1,
+                event_type: EventType::Transfer,
+                owner: Some(ExpectedOwner::Recipient),
+                minted: false,
+            },
+            TransferFixture {
+                name: "transfer from and to the zero address",
+                from: 0,
+                to: 0,
+                token_id: 2,
+                event_type: EventType::Transfer,
+                owner: None,
+                minted: false,
+            },
+            TransferFixture {
+                name: "burn of token id 0",
+                from: 0xacc0,
+                to: 0,
+                token_id: 0,
+                event_type: EventType::Burn,
+                owner: Some(ExpectedOwner::Chain),
+                minted: false,
+            },
+        ];
+
+        for contract in synthetic_contracts(1, 1) {
+            let (keys, chain_owner) = match contract.contract_type {
+                ContractType::ERC721 => {
+                    (vec![selector!("Transfer")], to_hex_str(&FieldElement::ONE))
+                }
+                // ERC1155 contracts don't support `owner_of`.
+                _ => (vec![selector!("TransferSingle")], String::new()),
+            };
+
+            for fixture in &fixtures {
+                let context = format!("{} ({:?})", fixture.name, contract.contract_type);
+
+                let event = EmittedEvent {
+                    from_address: contract.address,
+                    block_hash: Some(FieldElement::ONE),
+                    transaction_hash: FieldElement::from(0x7a_u64),
+                    block_number: Some(1),
+                    keys: keys.clone(),
+                    data: vec![
+                        FieldElement::from(fixture.from),
+                        FieldElement::from(fixture.to),
+                        FieldElement::from(fixture.token_id),
+                        FieldElement::ZERO,
+                        FieldElement::ONE,
+                        FieldElement::ZERO,
+                    ],
+                };
+
+                let storage = Arc::new(InMemoryStorage::new());
+                let handler = Arc::new(CountingEventHandler::default());
+                let pontos = Pontos::new(
+                    Arc::new(mock_client(HashMap::new(), &[contract.clone()])),
+                    Arc::clone(&storage),
+                    Arc::clone(&handler),
+                    config(),
+                );
+
+                pontos
+                    .process_events(vec![event], &BlockContext::new(1, 1000), "SN_MAIN")
+                    .await
+                    .unwrap();
+
+                let data = storage.dump();
+                let contract_address = to_hex_str(&contract.address);
+                let token_key = (
+                    contract_address.clone(),
+                    to_hex_str(&FieldElement::from(fixture.token_id)),
+                );
+
+                // The event is always recorded.
+                assert!(data.failed_events.is_empty(), "{context}");
+                assert_eq!(data.transfer_events.len(), 1, "{context}");
+                let stored = data.transfer_events.values().next().unwrap();
+                assert_eq!(stored.event_type, fixture.event_type, "{context}");
+                assert_eq!(stored.token_id, fixture.token_id.to_string(), "{context}");
+                assert_eq!(stored.is_anomalous(), fixture.owner.is_none(), "{context}");
+
+                let expected_owner = fixture.owner.as_ref().map(|owner| match owner {
+                    ExpectedOwner::Chain => chain_owner.clone(),
+                    ExpectedOwner::Recipient => to_hex_str(&FieldElement::from(fixture.to)),
+                });
+                assert_eq!(
+                    data.tokens.get(&token_key).map(|t| t.owner.clone()),
+                    expected_owner,
+                    "{context}"
+                );
+                assert_eq!(
+                    handler.token_events.load(Ordering::SeqCst),
+                    u64::from(fixture.owner.is_some()),
+                    "{context}"
+                );
+
+                assert_eq!(
+                    data.mints.contains_key(&token_key),
+                    fixture.minted,
+                    "{context}"
+                );
+                assert_eq!(
+                    data.supplies
+                        .get(&contract_address)
+                        .copied()
+                        .unwrap_or_default(),
+                    stored.supply_delta(),
+                    "{context}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_on_rpc_retry() {
+        use crate::testing::{InMemoryStorage, ManualClock};
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct RetryRecorder {
+            retries: Mutex<Vec<(u64, u32)>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for RetryRecorder {
+            async fn on_rpc_retry(&self, block: u64, attempt: u32, _error: &IndexerError) {
+                self.retries.lock().unwrap().push((block, attempt));
+            }
+        }
+
+        let mut client = MockStarknetClient::default();
+        client.expect_block_id_to_u64().returning(|id| match id {
+            BlockId::Number(n) => Ok(*n),
+            _ => Ok(0),
+        });
+        // The timestamp and the events of the block are both available
+        // at the second attempt.
+        client
+            .expect_block_time()
+            .times(1)
+            .returning(|_| Err(StarknetClientError::Other("timeout".to_string())));
+        client.expect_block_time().returning(|_| Ok(1000));
+        client
+            .expect_fetch_all_block_events()
+            .times(1)
+            .returning(|_, _| Err(StarknetClientError::Other("timeout".to_string())));
+        client
+            .expect_fetch_all_block_events()
+            .returning(|_, _| Ok(HashMap::new()));
+
+        let handler = Arc::new(RetryRecorder::default());
+        let clock = Arc::new(ManualClock::new());
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::new(InMemoryStorage::new()),
+            Arc::clone(&handler),
+            PontosConfig {
+                clock: Some(Arc::clone(&clock) as Arc<dyn Clock>),
+                ..config()
+            },
+        );
+
+        let start = clock.now();
+        clock
+            .drive(pontos.index_block_range(
+                BlockId::Number(7),
+                BlockId::Number(7),
+                false,
+                "SN_MAIN",
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(*handler.retries.lock().unwrap(), vec![(7, 1), (7, 1)]);
+        // A wait before each retry.
+        assert_eq!(clock.sleeps(), 2);
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_contract_circuit_breaker() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct CircuitRecorder {
+            opened: Mutex<Vec<(FieldElement, u32)>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for CircuitRecorder {
+            async fn on_contract_circuit_open(
+                &self,
+                contract_address: FieldElement,
+                failure_count: u32,
+            ) {
+                self.opened
+                    .lock()
+                    .unwrap()
+                    .push((contract_address, failure_count));
+            }
+        }
+
+        let failing = || {
+            Box::pin(futures::future::ready(Err(StorageError::DatabaseError(
+                "".to_string(),
+            ))))
+        };
+
+        // The success of the second registration resets the breaker,
+        // which opens after the fourth one. Each failing registration
+        // is retried before being counted as a failure.
+        let attempts = (STORAGE_WRITE_RETRIES + 1) as usize;
+        let mut storage = indexing_storage();
+        storage
+            .expect_register_token()
+            .times(attempts)
+            .returning(move |_, _| failing());
+        storage
+            .expect_register_token()
+            .times(1)
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_register_token()
+            .times(2 * attempts)
+            .returning(move |_, _| failing());
+        storage
+            .expect_register_failed_event()
+            .times(4)
+            .returning(|_| Box::pin(futures::future::ready(Ok(()))));
+
+        let mut client = MockStarknetClient::default();
+        client
+            .expect_call_contract()
+            .returning(|_, _, _, _| Ok(vec![FieldElement::ONE]));
+
+        let handler = Arc::new(CircuitRecorder::default());
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::new(storage),
+            Arc::clone(&handler),
+            PontosConfig {
+                circuit_breaker: CircuitBreakerConfig {
+                    failure_threshold: 2,
+                },
+                ..config()
+            },
+        );
+
+        let contract_address = FieldElement::from_hex_be("0x1234").unwrap();
+        let events = (0..5_u64)
+            .map(|i| {
+                let mut event = transfer_event(contract_address, Some(1));
+                event.transaction_hash = FieldElement::from(i);
+                event
+            })
+            .collect();
+
+        pontos
+            .process_events(events, &BlockContext::new(1, 1000), "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert_eq!(*handler.opened.lock().unwrap(), vec![(contract_address, 2)]);
+        assert_eq!(pontos.paused_contracts(), vec![contract_address]);
+
+        assert!(pontos.resume_contract(contract_address));
+        assert!(pontos.paused_contracts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_storage_write_failure_retries() {
+        use crate::testing::ManualClock;
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct StorageFailureRecorder {
+            failures: Mutex<Vec<(u64, u32)>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for StorageFailureRecorder {
+            async fn on_storage_write_failure(
+                &self,
+                block: u64,
+                _error: &StorageError,
+                retry_count: u32,
+            ) {
+                self.failures.lock().unwrap().push((block, retry_count));
+            }
+        }
+
+        let failing = || {
+            Box::pin(futures::future::ready(Err(StorageError::DatabaseError(
+                "".to_string(),
+            ))))
+        };
+
+        // The first event succeeds at the second retry,
+        // the second one exhausts the retries.
+        let mut storage = indexing_storage();
+        storage
+            .expect_register_token()
+            .times(2)
+            .returning(move |_, _| failing());
+        storage
+            .expect_register_token()
+            .times(1)
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_register_token()
+            .times((STORAGE_WRITE_RETRIES + 1) as usize)
+            .returning(move |_, _| failing());
+        storage
+            .expect_register_failed_event()
+            .times(1)
+            .returning(|_| Box::pin(futures::future::ready(Ok(()))));
+
+        let mut client = MockStarknetClient::default();
+        client
+            .expect_call_contract()
+            .returning(|_, _, _, _| Ok(vec![FieldElement::ONE]));
+
+        let handler = Arc::new(StorageFailureRecorder::default());
+        let clock = Arc::new(ManualClock::new());
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::new(storage),
+            Arc::clone(&handler),
+            PontosConfig {
+                clock: Some(Arc::clone(&clock) as Arc<dyn Clock>),
+                ..config()
+            },
+        );
+
+        let contract_address = FieldElement::from_hex_be("0x1234").unwrap();
+        let events = (0..2_u64)
+            .map(|i| {
+                let mut event = transfer_event(contract_address, Some(7));
+                event.transaction_hash = FieldElement::from(i);
+                event
+            })
+            .collect();
+
+        let start = clock.now();
+        clock
+            .drive(pontos.process_events(events, &BlockContext::new(7, 1000), "SN_MAIN"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *handler.failures.lock().unwrap(),
+            vec![(7, 0), (7, 1), (7, 0), (7, 1), (7, 2), (7, 3)]
+        );
+        // No wait after the last attempt.
+        assert_eq!(clock.now() - start, STORAGE_WRITE_RETRY_DELAY * 5);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_write_hints_around_block_writes() {
+        let mut seq = mockall::Sequence::new();
+
+        let mut storage = MockStorage::default();
+        storage
+            .expect_register_indexer()
+            .returning(|_| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_get_contract_type_overrides()
+            .returning(|| Box::pin(futures::future::ready(Ok(vec![]))));
+        storage
+            .expect_get_contract_type()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(Some(ContractType::ERC721)))));
+        storage
+            .expect_get_block_info()
+            .returning(|_| Box::pin(futures::future::ready(Ok(None))));
+        storage
+            .expect_set_block_info()
+            .returning(|_, _, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_begin_bulk_write()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_upsert_decoded_event()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|e| Box::pin(futures::future::ready(Ok(Some(e.to_token_event())))));
+        storage
+            .expect_register_token()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_register_mint()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_adjust_collection_supply()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_get_collection_stats()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Box::pin(futures::future::ready(Ok(None))));
+        storage
+            .expect_count_collection_tokens()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Box::pin(futures::future::ready(Ok((1, 1)))));
+        storage
+            .expect_count_transfers_in_range()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Box::pin(futures::future::ready(Ok(1))));
+        storage
+            .expect_upsert_collection_stats()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+        storage
+            .expect_end_bulk_write()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Box::pin(futures::future::ready(Ok(()))));
+
+        let contract_address = FieldElement::from_hex_be("0x1234").unwrap();
+
+        let mut client = MockStarknetClient::default();
+        client.expect_block_id_to_u64().returning(|id| match id {
+            BlockId::Number(n) => Ok(*n),
+            _ => Ok(0),
+        });
+        client.expect_block_time().returning(|_| Ok(1000));
+        client
+            .expect_fetch_all_block_events()
+            .returning(move |_, _| {
+                Ok(HashMap::from([(
+                    1,
+                    vec![transfer_event(contract_address, Some(1))],
+                )]))
+            });
+        client
+            .expect_call_contract()
+            .returning(|_, _, _, _| Ok(vec![FieldElement::ONE]));
+
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::new(storage),
+            Arc::new(TestEventHandler),
+            config(),
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(1), false, "SN_MAIN")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_block_collections_summary() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct SummaryRecorder {
+            summaries: Mutex<Vec<(u64, Vec<CollectionActivity>)>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for SummaryRecorder {
+            async fn on_block_collections_summary(
+                &self,
+                block_number: u64,
+                collections: Vec<CollectionActivity>,
+            ) {
+                self.summaries
+                    .lock()
+                    .unwrap()
+                    .push((block_number, collections));
+            }
+        }
+
+        let mut storage = indexing_storage();
+        storage
+            .expect_register_token()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+
+        let contract_a = FieldElement::from_hex_be("0x1234").unwrap();
+        let contract_b = FieldElement::from_hex_be("0x5678").unwrap();
+        let transfer = |contract: FieldElement, tx: u64, from: u64, to: u64, token: u64| {
+            let mut event = transfer_event(contract, Some(1));
+            event.transaction_hash = FieldElement::from(tx);
+            event.data = vec![
+                FieldElement::from(from),
+                FieldElement::from(to),
+                FieldElement::from(token),
+                FieldElement::ZERO,
+            ];
+            event
+        };
+
+        // The events of the block are processed in two batches.
+        let batches = HashMap::from([
+            (
+                1,
+                vec![
+                    transfer(contract_a, 1, 0, 0x10, 1),
+                    transfer(contract_b, 2, 0, 0x10, 1),
+                ],
+            ),
+            (
+                2,
+                vec![
+                    transfer(contract_a, 3, 0x10, 0x11, 1),
+                    transfer(contract_a, 4, 0x11, 0, 2),
+                ],
+            ),
+        ]);
+
+        let mut client = MockStarknetClient::default();
+        client.expect_block_id_to_u64().returning(|id| match id {
+            BlockId::Number(n) => Ok(*n),
+            _ => Ok(0),
+        });
+        client.expect_block_time().returning(|_| Ok(1000));
+        client
+            .expect_fetch_all_block_events()
+            .returning(move |_, _| Ok(batches.clone()));
+        client
+            .expect_call_contract()
+            .returning(|_, _, _, _| Ok(vec![FieldElement::ONE]));
+
+        let handler = Arc::new(SummaryRecorder::default());
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::new(storage),
+            Arc::clone(&handler),
+            config(),
+        );
+
+        for _ in 0..2 {
+            pontos
+                .index_block_range(BlockId::Number(1), BlockId::Number(1), true, "SN_MAIN")
+                .await
+                .unwrap();
+        }
+
+        let expected = vec![
+            CollectionActivity {
+                contract_address: to_hex_str(&contract_a),
+                transfers: 1,
+                mints: 1,
+                burns: 1,
+                distinct_tokens: 2,
+            },
+            CollectionActivity {
+                contract_address: to_hex_str(&contract_b),
+                transfers: 0,
+                mints: 1,
+                burns: 0,
+                distinct_tokens: 1,
+            },
+        ];
+
+        // The activity is not accumulated from a block to the next one.
+        assert_eq!(
+            *handler.summaries.lock().unwrap(),
+            vec![(1, expected.clone()), (1, expected)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transfer_of_burned_token() {
+        use crate::testing::{mock_client, synthetic_contracts, InMemoryStorage};
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct ErrorRecorder {
+            errors: Mutex<Vec<FailedEvent>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for ErrorRecorder {
+            async fn on_event_error(&self, event: &FailedEvent) {
+                self.errors.lock().unwrap().push(event.clone());
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let contract_address = contracts[0].address;
+        let transfer = |tx: u64, from: u64, to: u64| EmittedEvent {
+            from_address: contract_address,
+            block_hash: None,
+            transaction_hash: FieldElement::from(tx),
+            block_number: None,
+            keys: vec![selector!("Transfer")],
+            data: vec![
+                FieldElement::from(from),
+                FieldElement::from(to),
+                FieldElement::ONE,
+                FieldElement::ZERO,
+            ],
+        };
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let handler = Arc::new(ErrorRecorder::default());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(HashMap::new(), &contracts)),
+            Arc::clone(&storage),
+            Arc::clone(&handler),
+            config(),
+        );
+
+        let token_key = (
+            to_hex_str(&contract_address),
+            to_hex_str(&FieldElement::ONE),
+        );
+
+        // Mint, transfer and burn.
+        for (block, (tx, from, to)) in [(1, 0, 0xa), (2, 0xa, 0xb), (3, 0xb, 0)]
+            .into_iter()
+            .enumerate()
+        {
+            let block = block as u64 + 1;
+            pontos
+                .process_events(
+                    vec![transfer(tx, from, to)],
+                    &BlockContext::new(block, 1000 + block),
+                    "SN_MAIN",
+                )
+                .await
+                .unwrap();
+        }
+
+        let token = storage.dump().tokens[&token_key].clone();
+        assert!(token.is_burned);
+        assert_eq!(token.burned_at_block, Some(3));
+
+        // The block of the burn can be re-indexed.
+        pontos
+            .process_events(
+                vec![transfer(2, 0xa, 0xb)],
+                &BlockContext::new(3, 1003),
+                "SN_MAIN",
+            )
+            .await
+            .unwrap();
+        assert!(handler.errors.lock().unwrap().is_empty());
+
+        // A later transfer is rejected.
+        pontos
+            .process_events(
+                vec![transfer(4, 0xb, 0xc)],
+                &BlockContext::new(4, 1004),
+                "SN_MAIN",
+            )
+            .await
+            .unwrap();
+
+        let errors = handler.errors.lock().unwrap().clone();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].error.contains("Transfer of burned token"));
+
+        let data = storage.dump();
+        assert_eq!(data.failed_events.len(), 1);
+        assert!(!data
+            .transfer_events
+            .values()
+            .any(|e| e.transaction_hash == to_hex_str(&FieldElement::from(4_u64))));
+        assert!(data.tokens[&token_key].is_burned);
+    }
+
+    #[test]
+    fn test_with_config() {
+        let pontos = Pontos::new(
+            Arc::new(MockStarknetClient::default()),
+            Arc::new(MockStorage::default()),
+            Arc::new(TestEventHandler),
+            config(),
+        );
+
+        let experiment = pontos.with_config(PontosConfig {
+            indexer_version: "v0.0.2".to_string(),
+            ..pontos.clone_config()
+        });
+
+        assert_eq!(experiment.config.indexer_version, "v0.0.2");
+        assert_eq!(experiment.config.indexer_identifier, "TASK#123");
+        assert!(Arc::ptr_eq(&pontos.client, &experiment.client));
+        assert!(Arc::ptr_eq(&pontos.storage, &experiment.storage));
+        assert_eq!(pontos.clone_config().indexer_version, "v0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_event_registered_once() {
+        use crate::testing::{mock_client, synthetic_block, synthetic_contracts, InMemoryStorage};
+
+        let contracts = synthetic_contracts(1, 0);
+        // The same event returned twice by the node.
+        let mut events = synthetic_block(1, 2, &contracts);
+        events.push(events[0].clone());
+        let blocks = HashMap::from([(1, events)]);
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let handler = Arc::new(CountingEventHandler::default());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::clone(&handler),
+            config(),
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(1), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert_eq!(storage.dump().transfer_events.len(), 2);
+        assert_eq!(handler.token_events.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_processing_strictness() {
+        use crate::testing::{mock_client, synthetic_contracts, InMemoryStorage};
+
+        #[derive(Default)]
+        struct FailureRecorder {
+            failed_blocks: std::sync::Mutex<Vec<u64>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for FailureRecorder {
+            async fn on_block_failed(&self, block_number: u64, _error: &IndexerError) {
+                self.failed_blocks.lock().unwrap().push(block_number);
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let contract_address = contracts[0].address;
+        let transfer = |tx: u64, from: u64, to: u64, token_id: u64| EmittedEvent {
+            from_address: contract_address,
+            block_hash: None,
+            transaction_hash: FieldElement::from(tx),
+            block_number: None,
+            keys: vec![selector!("Transfer")],
+            data: vec![
+                FieldElement::from(from),
+                FieldElement::from(to),
+                FieldElement::from(token_id),
+                FieldElement::ZERO,
+            ],
+        };
+
+        // The transfer of the token burned in block 1 fails in block 2.
+        let blocks = HashMap::from([
+            (1, vec![transfer(1, 0, 0xa, 1), transfer(2, 0xa, 0, 1)]),
+            (2, vec![transfer(3, 0xa, 0xb, 1), transfer(4, 0, 0xa, 2)]),
+            (3, vec![transfer(5, 0, 0xa, 3)]),
+        ]);
+        let token_registered = |storage: &InMemoryStorage, token_id: u64| {
+            storage.dump().tokens.contains_key(&(
+                to_hex_str(&contract_address),
+                to_hex_str(&FieldElement::from(token_id)),
+            ))
+        };
+        let status =
+            |storage: &InMemoryStorage, block: u64| storage.dump().blocks[&block].1.status.clone();
+
+        for strictness in [ProcessingStrictness::Lenient, ProcessingStrictness::Strict] {
+            let storage = Arc::new(InMemoryStorage::new());
+            let handler = Arc::new(FailureRecorder::default());
+            let pontos = Pontos::new(
+                Arc::new(mock_client(blocks.clone(), &contracts)),
+                Arc::clone(&storage),
+                Arc::clone(&handler),
+                PontosConfig {
+                    processing_strictness: strictness,
+                    ..config()
+                },
+            );
+
+            let report = pontos
+                .index_block_range_with_force_policy(
+                    BlockId::Number(1),
+                    BlockId::Number(3),
+                    &ForcePolicy::Never,
+                    "SN_MAIN",
+                )
+                .await
+                .unwrap();
+
+            // The failed event is in the dead-letter queue in both modes.
+            assert_eq!(storage.dump().failed_events.len(), 1);
+            assert_eq!(status(&storage, 3), BlockIndexingStatus::Terminated);
+            assert!(token_registered(&storage, 3));
+
+            if strictness == ProcessingStrictness::Lenient {
+                assert_eq!(report.failed_blocks, 0);
+                assert_eq!(status(&storage, 2), BlockIndexingStatus::Terminated);
+                assert!(token_registered(&storage, 2));
+                assert!(handler.failed_blocks.lock().unwrap().is_empty());
+                continue;
+            }
+
+            assert_eq!(report.failed_blocks, 1);
+            assert_eq!(status(&storage, 2), BlockIndexingStatus::Failed);
+            assert!(!token_registered(&storage, 2));
+            assert_eq!(*handler.failed_blocks.lock().unwrap(), vec![2]);
+
+            // The failed block is indexed again by the next run, the terminated ones are skipped.
+            let report = pontos
+                .index_block_range_with_force_policy(
+                    BlockId::Number(1),
+                    BlockId::Number(3),
+                    &ForcePolicy::Never,
+                    "SN_MAIN",
+                )
+                .await
+                .unwrap();
+            assert_eq!(report.skipped_blocks, 2);
+            assert_eq!(report.failed_blocks, 1);
+            assert_eq!(*handler.failed_blocks.lock().unwrap(), vec![2, 2]);
+
+            // Aborting on the first failed block.
+            let storage = Arc::new(InMemoryStorage::new());
+            let pontos = Pontos::new(
+                Arc::new(mock_client(blocks.clone(), &contracts)),
+                Arc::clone(&storage),
+                Arc::new(FailureRecorder::default()),
+                PontosConfig {
+                    processing_strictness: strictness,
+                    abort_on_failed_block: true,
+                    ..config()
+                },
+            );
+
+            let result = pontos
+                .index_block_range(BlockId::Number(1), BlockId::Number(3), false, "SN_MAIN")
+                .await;
+            assert!(matches!(
+                result,
+                Err(IndexerError::BlockFailed { block: 2, .. })
+            ));
+            assert_eq!(status(&storage, 2), BlockIndexingStatus::Failed);
+            assert!(!storage.dump().blocks.contains_key(&3));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_events_per_block() {
+        use crate::testing::{mock_client, synthetic_block, synthetic_contracts, InMemoryStorage};
+
+        #[derive(Default)]
+        struct FailureRecorder {
+            failed_blocks: std::sync::Mutex<Vec<u64>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for FailureRecorder {
+            async fn on_block_failed(&self, block_number: u64, _error: &IndexerError) {
+                self.failed_blocks.lock().unwrap().push(block_number);
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = HashMap::from([
+            (1, synthetic_block(1, 3, &contracts)),
+            (2, synthetic_block(2, 2, &contracts)),
+        ]);
+        let storage = Arc::new(InMemoryStorage::new());
+        let handler = Arc::new(FailureRecorder::default());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::clone(&handler),
+            PontosConfig {
+                max_events_per_block: Some(2),
+                ..config()
+            },
+        );
+
+        let report = pontos
+            .index_block_range_with_force_policy(
+                BlockId::Number(1),
+                BlockId::Number(2),
+                &ForcePolicy::Never,
+                "SN_MAIN",
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(report.failed_blocks, 1);
+        assert_eq!(*handler.failed_blocks.lock().unwrap(), vec![1]);
+        let data = storage.dump();
+        assert_eq!(data.blocks[&1].1.status, BlockIndexingStatus::Failed);
+        assert_eq!(data.blocks[&2].1.status, BlockIndexingStatus::Terminated);
+        assert!(data
+            .transfer_events
+            .values()
+            .all(|e| e.block_number == Some(2)));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_events() {
+        use crate::testing::{mock_client, synthetic_block, synthetic_contracts, InMemoryStorage};
+        use std::sync::Mutex;
+
+        type Transaction = (FieldElement, Option<u64>, Vec<String>);
+
+        #[derive(Default)]
+        struct TransactionRecorder {
+            transactions: Mutex<Vec<Transaction>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for TransactionRecorder {
+            async fn on_transaction_events(
+                &self,
+                tx_hash: FieldElement,
+                block_number: Option<u64>,
+                events: &[TokenEvent],
+            ) {
+                let tokens = events
+                    .iter()
+                    .map(|e| match e {
+                        TokenEvent::Transfer(t) => t.token_id_hex.clone(),
+                        TokenEvent::Sale(s) => s.token_id_hex.clone(),
+                    })
+                    .collect();
+                self.transactions
+                    .lock()
+                    .unwrap()
+                    .push((tx_hash, block_number, tokens));
+            }
+        }
+
+        // A transaction moving tokens of an ERC721 and an ERC1155 contract,
+        // followed by a transaction with a single event.
+        let contracts = synthetic_contracts(1, 1);
+        let (sweep, single) = (FieldElement::from(0x5eeb_u64), FieldElement::from(0x1_u64));
+        let mut events = synthetic_block(1, 4, &contracts);
+        for (event, tx_hash) in events.iter_mut().zip([sweep, sweep, sweep, single]) {
+            event.transaction_hash = tx_hash;
+        }
+        let token_ids: Vec<String> = events.iter().map(|e| to_hex_str(&e.data[2])).collect();
+        let expected = |block_number| {
+            vec![
+                (sweep, block_number, token_ids[..3].to_vec()),
+                (single, block_number, token_ids[3..].to_vec()),
+            ]
+        };
+
+        // Range path.
+        let handler = Arc::new(TransactionRecorder::default());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(
+                HashMap::from([(1, events.clone())]),
+                &contracts,
+            )),
+            Arc::new(InMemoryStorage::new()),
+            Arc::clone(&handler),
+            config(),
+        );
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(1), false, "SN_MAIN")
+            .await
+            .unwrap();
+        assert_eq!(*handler.transactions.lock().unwrap(), expected(Some(1)));
+
+        // Pending path, processing the events of each transaction.
+        let handler = Arc::new(TransactionRecorder::default());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(HashMap::new(), &contracts)),
+            Arc::new(InMemoryStorage::new()),
+            Arc::clone(&handler),
+            config(),
+        );
+        for tx_events in [events[..3].to_vec(), events[3..].to_vec()] {
+            pontos
+                .process_events(tx_events, &BlockContext::pending(1000), "SN_MAIN")
+                .await
+                .unwrap();
+        }
+        assert_eq!(*handler.transactions.lock().unwrap(), expected(None));
+    }
+
+    #[tokio::test]
+    async fn test_reindex_on_selector_change() {
+        use crate::storage::types::BlockInfo;
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks: HashMap<u64, Vec<EmittedEvent>> = (1..=3)
+            .map(|n| (n, synthetic_block(n, 2, &contracts)))
+            .collect();
+        let storage = Arc::new(InMemoryStorage::new());
+        let index = |config: PontosConfig| {
+            let pontos = Pontos::new(
+                Arc::new(mock_client(blocks.clone(), &contracts)),
+                Arc::clone(&storage),
+                Arc::new(NoopEventHandler),
+                config,
+            );
+            async move {
+                pontos
+                    .index_block_range_with_force_policy(
+                        BlockId::Number(1),
+                        BlockId::Number(3),
+                        &ForcePolicy::Never,
+                        "SN_MAIN",
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        index(config()).await;
+        let current = EventManager::new(Arc::clone(&storage)).selector_hash();
+        for n in 1..=3 {
+            assert_eq!(
+                storage.dump().blocks[&n].1.selector_hash.as_ref(),
+                Some(&current)
+            );
+        }
+
+        // Block 1 terminated before the selectors were recorded,
+        // block 2 terminated before a new event type was filtered.
+        for (n, selector_hash) in [(1, None), (2, Some("0x1234".to_string()))] {
+            let (timestamp, info) = storage.dump().blocks[&n].clone();
+            storage
+                .set_block_info(
+                    n,
+                    timestamp,
+                    BlockInfo {
+                        selector_hash,
+                        ..info
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        // Without the mode, the blocks are kept.
+        let report = index(config()).await;
+        assert_eq!(report.skipped_blocks, 3);
+        assert_eq!(storage.dump().blocks[&1].1.selector_hash, None);
+
+        // Only the outdated blocks are revisited.
+        let report = index(PontosConfig {
+            reindex_on_selector_change: true,
+            ..config()
+        })
+        .await;
+        assert_eq!(report.reindexed_blocks, 2);
+        assert_eq!(report.skipped_blocks, 1);
+        for n in 1..=3 {
+            assert_eq!(
+                storage.dump().blocks[&n].1.selector_hash.as_ref(),
+                Some(&current)
+            );
+            assert_eq!(storage.count_block_events(n).await.unwrap(), 2);
+        }
+
+        // Once revisited, the blocks are up to date.
+        let report = index(PontosConfig {
+            reindex_on_selector_change: true,
+            ..config()
+        })
+        .await;
+        assert_eq!(report.skipped_blocks, 3);
+    }
+
+    #[tokio::test]
+    async fn test_on_block_skipped() {
+        use crate::storage::types::BlockInfo;
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_block_timestamp, synthetic_contracts,
+            InMemoryStorage,
+        };
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct SkipRecorder {
+            skips: Mutex<Vec<(u64, SkipReason)>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for SkipRecorder {
+            async fn on_block_skipped(&self, block_number: u64, reason: SkipReason) {
+                self.skips.lock().unwrap().push((block_number, reason));
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = (1..=4)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        // A staging deployment indexed the block 2 into the same storage.
+        let storage = Arc::new(InMemoryStorage::new());
+        for (n, version, identifier) in [
+            (1, "v0.0.1", "TASK#123"),
+            (2, "v0.0.1", "STAGING"),
+            (3, "v0.0.2", "TASK#123"),
+        ] {
+            storage
+                .set_block_info(
+                    n,
+                    synthetic_block_timestamp(n),
+                    BlockInfo {
+                        indexer_version: version.to_string(),
+                        indexer_identifier: identifier.to_string(),
+                        status: BlockIndexingStatus::Terminated,
+                        block_number: n,
+                        selector_hash: None,
+                        last_heartbeat_at: 0,
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        let handler = Arc::new(SkipRecorder::default());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::clone(&handler),
+            config(),
+        );
+
+        let report = pontos
+            .index_block_range_with_force_policy(
+                BlockId::Number(1),
+                BlockId::Number(4),
+                &ForcePolicy::Never,
+                "SN_MAIN",
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *handler.skips.lock().unwrap(),
+            vec![
+                (1, SkipReason::SameVersionTerminated),
+                (2, SkipReason::OtherIdentifier),
+                (3, SkipReason::OtherVersionPolicy),
+            ]
+        );
+        assert_eq!(report.indexed_blocks, 1);
+        assert_eq!(report.skipped_blocks, 3);
+        assert_eq!(
+            report.skips,
+            SkipCounts {
+                same_version_terminated: 1,
+                other_identifier: 1,
+                other_version_policy: 1,
+                force_overridden: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_attribute_wrapped_transfers() {
+        use crate::testing::{
+            mock_client, synthetic_contracts, wrapped_transfer, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let collection = contracts[0].address;
+        let account = FieldElement::from(0xacc_u64);
+        let not_a_collection = FieldElement::from(0x20_u64);
+        let blocks = HashMap::from([
+            (
+                1,
+                vec![wrapped_transfer(1, account, collection, FieldElement::ONE)],
+            ),
+            (
+                2,
+                vec![wrapped_transfer(
+                    2,
+                    account,
+                    not_a_collection,
+                    FieldElement::TWO,
+                )],
+            ),
+        ]);
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks.clone(), &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(2), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let data = storage.dump();
+        assert_eq!(data.transfer_events.len(), 1);
+        let transfer = data.transfer_events.values().next().unwrap();
+        assert_eq!(transfer.contract_address, to_hex_str(&collection));
+        assert_eq!(transfer.to_address, to_hex_str(&account));
+
+        let quarantined = storage.get_quarantined_events(10).await.unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].emitter_address, to_hex_str(&account));
+        assert_eq!(quarantined[0].rule, "embedded_collection_key");
+        assert!(quarantined[0]
+            .reason
+            .contains(&format!("0x{:064x}", not_a_collection)));
+
+        // Without any rule, the wrapped transfers are discarded.
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                attribution_rules: Some(vec![]),
+                ..config()
+            },
+        );
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(2), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert!(storage.dump().transfer_events.is_empty());
+        assert!(storage.get_quarantined_events(10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_block_heartbeat() {
+        use crate::testing::{mock_client, synthetic_block, synthetic_contracts, InMemoryStorage};
+        use std::sync::Mutex;
+
+        /// Records the heartbeat of the block 1 seen by each token event.
+        struct HeartbeatRecorder {
+            storage: Arc<InMemoryStorage>,
+            heartbeats: Mutex<Vec<u64>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for HeartbeatRecorder {
+            async fn on_token_event(&self, _event: &TokenEvent, _token: &TokenInfo) {
+                let info = self.storage.get_block_info(1).await.unwrap().unwrap();
+                assert_eq!(info.status, BlockIndexingStatus::Processing);
+                self.heartbeats.lock().unwrap().push(info.last_heartbeat_at);
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = HashMap::from([(1, synthetic_block(1, 5, &contracts))]);
+        let storage = Arc::new(InMemoryStorage::new());
+        let handler = Arc::new(HeartbeatRecorder {
+            storage: Arc::clone(&storage),
+            heartbeats: Mutex::new(vec![]),
+        });
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::clone(&handler),
+            PontosConfig {
+                heartbeat_interval: 2,
+                ..config()
+            },
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(1), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        // Heartbeats before the third and the fifth events.
+        let heartbeats = handler.heartbeats.lock().unwrap().clone();
+        assert_eq!(heartbeats.len(), 5);
+        assert_eq!(heartbeats[0..2], [0, 0]);
+        assert!(heartbeats[2] > 0);
+        assert_eq!(heartbeats[3], heartbeats[2]);
+        assert!(heartbeats[4] >= heartbeats[3]);
+
+        assert!(matches!(
+            pontos.block_manager.heartbeat(2).await,
+            Err(IndexerError::StorageError(StorageError::NotFound { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, ManualClock,
+            NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = (1..=2)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+        let mut client = mock_client(blocks, &contracts);
+        client
+            .expect_block_txs_hashes()
+            .returning(|_| Ok((5000, vec![])));
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let clock = Arc::new(ManualClock::new());
+        let pontos = Arc::new(Pontos::new(
+            Arc::new(client),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                continuous_mode: true,
+                clock: Some(Arc::clone(&clock) as Arc<dyn Clock>),
+                ..config()
+            },
+        ));
+        let pending = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move { pontos.index_pending().await }
+        });
+        let range = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move {
+                pontos
+                    .index_block_range(
+                        BlockId::Number(1),
+                        BlockId::Tag(BlockTag::Latest),
+                        false,
+                        "SN_MAIN",
+                    )
+                    .await
+            }
+        });
+
+        // Both loops are waiting for their next tick, without the clock advancing.
+        clock.wait_for_sleeps(2).await;
+        assert_eq!(pontos.pending_snapshot().await.timestamp, 5000);
+        assert_eq!(pontos.list_active_indexers().await.unwrap().len(), 1);
+
+        pontos.shutdown().await.unwrap();
+
+        pending.await.unwrap().unwrap();
+        range.await.unwrap().unwrap();
+        assert_eq!(pontos.pending_snapshot().await.timestamp, 0);
+        assert!(pontos.list_active_indexers().await.unwrap().is_empty());
+        let info = storage.dump().indexers["TASK#123"].clone();
+        assert!(info.inactive);
+        assert_eq!(info.last_block, Some(2));
+
+        // The loops started after the shutdown return immediately.
+        pontos.index_pending().await.unwrap();
+        assert_eq!(clock.sleeps(), 2);
     }
 }
@@ -0,0 +1,1095 @@
+//! Maintenance of the indexed data: re-indexation, retention, migrations,
+//! reclassification of the contracts and corrections of the metadata.
+use crate::event_handler::EventHandler;
+use crate::managers::{BlockContext, SupplyDeltas};
+use crate::storage::types::{
+    ContractType, MetadataField, MetadataPatchRecord, TokenTransferEvent, VacuumStats,
+};
+use crate::storage::Storage;
+use crate::{
+    is_already_indexed, BlockActivity, IndexerError, IndexerResult, MigrationStats, Pontos,
+    ReindexReport,
+};
+use ark_starknet::client::StarknetClient;
+use ark_starknet::format::to_hex_str;
+use starknet::core::types::*;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use tracing::{error, info, warn};
+
+/// Blocks re-indexed at once by `Pontos::reindex_contract_in_range`,
+/// the progress being saved after each chunk.
+const REINDEX_CHUNK_BLOCKS: u64 = 100;
+
+/// Number of events read at once by `Pontos::apply_migration`.
+const MIGRATION_PAGE_EVENTS: usize = 500;
+
+/// An off-chain correction of a token metadata field,
+/// applied by `Pontos::apply_token_metadata_patch`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenMetadataPatch {
+    pub contract: FieldElement,
+    pub token_id: FieldElement,
+    pub field: MetadataField,
+    pub value: serde_json::Value,
+}
+
+impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C, E> {
+    /// Runs the periodic maintenance of this instance: vacuums the storage,
+    /// flushes the blocks fetched by `warm_up` that are older than the next
+    /// block to index, and renews the heartbeat for `list_active_indexers`.
+    pub async fn maintenance(&self) -> IndexerResult<VacuumStats> {
+        let stats = self.storage.vacuum().await?;
+
+        let next_block = self.last_indexed_block.load(Ordering::Relaxed);
+        let warm_count = self.warm_blocks.len();
+        self.warm_blocks.retain(|n, _| *n >= next_block);
+        let flushed = warm_count - self.warm_blocks.len();
+
+        self.register_indexer().await;
+
+        info!(
+            "Maintenance done: {} rows removed, {} bytes freed, {} warm blocks flushed",
+            stats.rows_removed, stats.bytes_freed, flushed
+        );
+
+        Ok(stats)
+    }
+
+    /// Forgets the contract type memoized for the class hash, to fix a
+    /// misclassification. The contracts of this class hash identified
+    /// from now on are probed again.
+    pub async fn invalidate_class_hash(&self, class_hash: FieldElement) -> IndexerResult<()> {
+        Ok(self
+            .contract_manager
+            .invalidate_class_hash(class_hash)
+            .await?)
+    }
+
+    /// Overrides the type of a contract, misclassified by the identification.
+    ///
+    /// A contract classified as `Other` and reclassified as an NFT contract
+    /// had its past events discarded: it is marked to be backfilled, and
+    /// backfilled immediately if `PontosConfig::backfill_reclassified_contracts`
+    /// is set. `EventHandler::on_contract_reclassified` is called if the
+    /// type changed.
+    pub async fn reclassify_contract(
+        &self,
+        contract_address: FieldElement,
+        contract_type: ContractType,
+        chain_id: &str,
+    ) -> IndexerResult<()> {
+        let old = self
+            .contract_manager
+            .set_contract_type(contract_address, contract_type.clone(), chain_id)
+            .await?
+            .unwrap_or(ContractType::Other);
+
+        if old == contract_type {
+            return Ok(());
+        }
+
+        info!(
+            "Contract [0x{:064x}] reclassified from {} to {}",
+            contract_address,
+            old.to_string(),
+            contract_type.to_string()
+        );
+
+        if old == ContractType::Other {
+            self.storage
+                .set_contract_backfill(&to_hex_str(&contract_address), true)
+                .await?;
+        }
+
+        self.event_handler
+            .on_contract_reclassified(contract_address, &old, &contract_type)
+            .await;
+
+        if old == ContractType::Other && self.config.backfill_reclassified_contracts {
+            self.backfill_contract(contract_address, chain_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Overrides permanently the type of a contract, whatever the identification
+    /// finds. The override is stored, and the overrides of the storage are loaded
+    /// by each instance before its first identification, so they survive restarts.
+    /// The contract is reclassified as with `reclassify_contract`.
+    pub async fn register_contract_type_override(
+        &self,
+        contract_address: FieldElement,
+        contract_type: ContractType,
+        chain_id: &str,
+    ) -> IndexerResult<()> {
+        self.contract_manager
+            .register_override(contract_address, contract_type.clone())
+            .await?;
+
+        self.reclassify_contract(contract_address, contract_type, chain_id)
+            .await
+    }
+
+    /// Identifies the contract again on the chain, ignoring the cached type,
+    /// and reclassifies it with `reclassify_contract` if its type changed.
+    /// Returns the new type.
+    pub async fn reprobe_contract(
+        &self,
+        contract_address: FieldElement,
+        chain_id: &str,
+    ) -> IndexerResult<ContractType> {
+        let contract_type = self
+            .contract_manager
+            .get_contract_type(contract_address)
+            .await?;
+
+        self.reclassify_contract(contract_address, contract_type.clone(), chain_id)
+            .await?;
+
+        Ok(contract_type)
+    }
+
+    /// Backfills the contracts marked by `reclassify_contract`, and returns
+    /// the number of contracts backfilled.
+    pub async fn backfill_contracts(&self, chain_id: &str) -> IndexerResult<usize> {
+        let contracts = self.storage.get_contracts_to_backfill().await?;
+
+        for address in &contracts {
+            let contract_address = FieldElement::from_hex_be(address)
+                .map_err(|e| IndexerError::Anyhow(format!("Invalid contract address: {}", e)))?;
+            self.backfill_contract(contract_address, chain_id).await?;
+        }
+
+        Ok(contracts.len())
+    }
+
+    /// Indexes the events of the contract over the blocks already terminated,
+    /// using the per-contract indexing path, and unmarks the contract.
+    /// The events already registered are ignored.
+    async fn backfill_contract(
+        &self,
+        contract_address: FieldElement,
+        chain_id: &str,
+    ) -> IndexerResult<()> {
+        if let Some((last_block, _)) = self.storage.get_last_terminated_block().await? {
+            info!(
+                "Backfilling contract [0x{:064x}] up to block {}",
+                contract_address, last_block
+            );
+            self.index_contract_events(
+                Some(BlockId::Number(0)),
+                Some(BlockId::Number(last_block)),
+                contract_address,
+                chain_id,
+            )
+            .await?;
+        }
+
+        self.storage
+            .set_contract_backfill(&to_hex_str(&contract_address), false)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Reconciles the owners stored for the given tokens with the chain,
+    /// see `TokenManager::sync_ownership_from_chain`.
+    /// Returns the number of owners corrected.
+    pub async fn sync_ownership_from_chain(
+        &self,
+        contract: FieldElement,
+        token_ids: Vec<FieldElement>,
+    ) -> IndexerResult<usize> {
+        self.token_manager
+            .sync_ownership_from_chain(contract, token_ids)
+            .await
+    }
+
+    /// Applies off-chain corrections to the metadata of registered tokens,
+    /// in a single bulk write. Each patch is recorded in the audit log of
+    /// the storage, to tell the patched values apart from the indexed ones.
+    ///
+    /// The patches of unknown tokens, or failing to be written, are skipped
+    /// with a warning. Returns the number of patches applied.
+    pub async fn apply_token_metadata_patch(
+        &self,
+        patches: Vec<TokenMetadataPatch>,
+    ) -> IndexerResult<usize> {
+        let applied_at = self.clock.unix_time().as_secs();
+        let mut applied = 0;
+
+        self.storage.begin_bulk_write().await?;
+
+        for patch in patches {
+            let contract_address = to_hex_str(&patch.contract);
+            let token_id_hex = to_hex_str(&patch.token_id);
+
+            match self
+                .storage
+                .get_token(&contract_address, &token_id_hex)
+                .await
+            {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    warn!(
+                        "Metadata patch ignored, token {} {} is not registered",
+                        contract_address, token_id_hex
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Metadata patch ignored, token {} {} not readable: {:?}",
+                        contract_address, token_id_hex, e
+                    );
+                    continue;
+                }
+            }
+
+            let record = MetadataPatchRecord {
+                contract_address,
+                token_id_hex,
+                field: patch.field,
+                value: match patch.value {
+                    serde_json::Value::String(s) => s,
+                    v => v.to_string(),
+                },
+                applied_at,
+            };
+
+            match self.storage.apply_metadata_patch(&record).await {
+                Ok(()) => applied += 1,
+                Err(e) => warn!(
+                    "Metadata patch of token {} {} failed: {:?}",
+                    record.contract_address, record.token_id_hex, e
+                ),
+            }
+        }
+
+        self.storage.end_bulk_write().await?;
+
+        Ok(applied)
+    }
+
+    /// Re-indexes the events of a single contract in the block range
+    /// `[from_block, to_block]`, leaving the data of the other contracts
+    /// and the blocks info untouched.
+    ///
+    /// The events of the contract and the tokens minted in the range are purged,
+    /// then the events of the contract are fetched and processed again.
+    /// The progress is saved for the contract after each chunk of blocks: calling
+    /// this function again after an interruption resumes from the last chunk not
+    /// completed. The report only covers the chunks processed by this call.
+    pub async fn reindex_contract_in_range(
+        &self,
+        contract_address: FieldElement,
+        from_block: u64,
+        to_block: u64,
+        chain_id: &str,
+    ) -> IndexerResult<ReindexReport> {
+        let address_hex = to_hex_str(&contract_address);
+        let mut report = ReindexReport::default();
+
+        let mut current = match self.storage.get_reindex_cursor(&address_hex).await? {
+            Some(cursor) if cursor > from_block && cursor <= to_block => {
+                info!(
+                    "Resuming re-indexation of {} from block {}",
+                    address_hex, cursor
+                );
+                cursor
+            }
+            _ => from_block,
+        };
+
+        while current <= to_block {
+            let chunk_end = to_block.min(current.saturating_add(REINDEX_CHUNK_BLOCKS - 1));
+            self.storage
+                .set_reindex_cursor(&address_hex, Some(current))
+                .await?;
+
+            let purged = self
+                .storage
+                .purge_contract_range(&address_hex, current, chunk_end)
+                .await?;
+
+            self.index_contract_events(
+                Some(BlockId::Number(current)),
+                Some(BlockId::Number(chunk_end)),
+                contract_address,
+                chain_id,
+            )
+            .await?;
+
+            report.purged_events += purged.events;
+            report.purged_tokens += purged.tokens;
+            report.registered_events += self
+                .storage
+                .count_transfers_in_blocks(&address_hex, current, chunk_end)
+                .await?;
+
+            current = chunk_end + 1;
+        }
+
+        self.storage.set_reindex_cursor(&address_hex, None).await?;
+
+        Ok(report)
+    }
+
+    /// Registers again the tokens of the transfer events stored for the blocks
+    /// `from_block..=to_block`, in block order, without any call to the node:
+    /// the events are read from the storage, and the owner of a token is the
+    /// recipient of its last event. This re-derives the state of the tokens
+    /// after a fix of their registration. The counters of the updated
+    /// collections are counted again.
+    pub async fn replay_from_event_log(&self, from_block: u64, to_block: u64) -> IndexerResult<()> {
+        let mut supply_deltas = SupplyDeltas::new();
+        let mut replayed = 0;
+
+        for block_number in from_block..=to_block {
+            if self.is_shutting_down() {
+                info!("Shutdown requested before replaying block {}", block_number);
+                break;
+            }
+
+            let events = self.storage.get_block_transfer_events(block_number).await?;
+            for event in events {
+                let block = BlockContext::new(block_number, event.timestamp);
+                self.token_manager
+                    .replay_token_event(&event, &block)
+                    .await?;
+                // The events are already counted in the supply.
+                supply_deltas
+                    .entry(event.contract_address.clone())
+                    .or_default();
+                replayed += 1;
+            }
+        }
+
+        self.token_manager
+            .flush_supply(supply_deltas, Some(to_block))
+            .await?;
+        info!(
+            "Replayed {} events of blocks {} to {}",
+            replayed, from_block, to_block
+        );
+
+        Ok(())
+    }
+
+    /// Applies `migration_fn` to each transfer event stored, in event id order,
+    /// and writes back the events it changed. This fixes the events registered
+    /// by a faulty version of the indexer without fetching them again; the tokens
+    /// can then be derived again from the events with `replay_from_event_log`.
+    ///
+    /// An event the migration fails for, or whose id is changed by the migration,
+    /// is logged and left unchanged. `EventHandler::on_migration_progress` is
+    /// called after each page of `MIGRATION_PAGE_EVENTS` events.
+    pub async fn apply_migration<F, Fut>(&self, migration_fn: F) -> IndexerResult<MigrationStats>
+    where
+        F: Fn(TokenTransferEvent) -> Fut,
+        Fut: Future<Output = IndexerResult<TokenTransferEvent>>,
+    {
+        let total_events = self.storage.count_transfer_events().await?;
+        let mut stats = MigrationStats::default();
+        let mut after: Option<String> = None;
+
+        loop {
+            if self.is_shutting_down() {
+                info!("Shutdown requested after migrating {} events", stats.total);
+                break;
+            }
+
+            let events = self
+                .storage
+                .get_transfer_events_after(after.as_deref(), MIGRATION_PAGE_EVENTS)
+                .await?;
+            let Some(last) = events.last() else {
+                break;
+            };
+            after = Some(last.event_id.clone());
+
+            for event in events {
+                stats.total += 1;
+                let original = event.clone();
+                match migration_fn(event).await {
+                    Ok(migrated) if migrated == original => {}
+                    Ok(migrated) if migrated.event_id != original.event_id => {
+                        warn!(
+                            "Migration changed the id of event {} to {}, event left unchanged",
+                            original.event_id, migrated.event_id
+                        );
+                        stats.failed += 1;
+                    }
+                    Ok(migrated) => match self.storage.replace_event(&migrated).await {
+                        Ok(()) => stats.updated += 1,
+                        Err(e) => {
+                            warn!("Can't write migrated event {}: {}", original.event_id, e);
+                            stats.failed += 1;
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Migration of event {} failed: {}", original.event_id, e);
+                        stats.failed += 1;
+                    }
+                }
+            }
+
+            self.event_handler
+                .on_migration_progress(stats.total, total_events)
+                .await;
+        }
+
+        info!(
+            "Migrated {} events: {} updated, {} failed",
+            stats.total, stats.updated, stats.failed
+        );
+
+        Ok(stats)
+    }
+
+    /// Prunes the blocks older than the retention window, if any.
+    pub(crate) async fn apply_retention(&self) -> IndexerResult<()> {
+        let retention = match self.config.retention_blocks {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        // The last indexed block is the next block to be indexed.
+        let prune_before = self
+            .last_indexed_block
+            .load(Ordering::Relaxed)
+            .saturating_sub(retention);
+        if prune_before == 0 {
+            return Ok(());
+        }
+
+        let pruned = self.storage.prune_blocks_before(prune_before).await?;
+        if pruned > 0 {
+            info!("Pruned {} blocks before block {}", pruned, prune_before);
+        }
+
+        Ok(())
+    }
+
+    /// Processes again up to `max_count` events of the dead-letter queue,
+    /// removing the ones successfully processed.
+    /// The events of paused contracts are also processed.
+    /// Returns the number of events successfully processed.
+    pub async fn reprocess_failed_events(&self, max_count: usize) -> IndexerResult<usize> {
+        let failed_events = self.storage.get_failed_events(max_count).await?;
+        let mut supply_deltas = SupplyDeltas::new();
+        let mut processed = 0;
+
+        for f in failed_events {
+            let event: EmittedEvent = match serde_json::from_str(&f.payload) {
+                Ok(e) => e,
+                Err(e) => {
+                    error!("Can't deserialize failed event {}: {:?}", f.id, e);
+                    continue;
+                }
+            };
+
+            let block = BlockContext::from_event(&event, f.block_timestamp);
+            match self
+                .process_event(
+                    &event,
+                    None,
+                    &block,
+                    &f.chain_id,
+                    &mut supply_deltas,
+                    &mut BlockActivity::default(),
+                )
+                .await
+            {
+                Ok(_) => {
+                    self.storage.remove_failed_event(&f.id).await?;
+                    processed += 1;
+                }
+                // The event was indexed in the meantime, for instance by a re-indexation.
+                Err(e) if is_already_indexed(&e) => {
+                    self.storage.remove_failed_event(&f.id).await?;
+                }
+                Err(e) => warn!("Failed event {} is still failing: {:?}", f.id, e),
+            }
+        }
+
+        self.token_manager.flush_supply(supply_deltas, None).await?;
+
+        Ok(processed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config, TestEventHandler};
+    use crate::PontosConfig;
+    use ark_starknet::client::{MockStarknetClient, StarknetClientError};
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_retention_blocks() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = (1..=5)
+            .map(|n| (n, synthetic_block(n, 2, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                retention_blocks: Some(2),
+                ..config()
+            },
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(5), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let data = storage.dump();
+        let mut blocks: Vec<u64> = data.blocks.keys().copied().collect();
+        blocks.sort();
+        assert_eq!(blocks, vec![4, 5]);
+        assert_eq!(data.transfer_events.len(), 4);
+        assert!(data
+            .transfer_events
+            .values()
+            .all(|e| e.block_number >= Some(4)));
+        // The tokens are kept.
+        assert_eq!(data.tokens.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_reprocess_failed_events() {
+        use crate::config::CollectionIdentificationStrategy;
+        use crate::testing::{synthetic_block, synthetic_contracts, InMemoryStorage};
+        use std::collections::HashSet;
+
+        let contracts = synthetic_contracts(1, 0);
+        let address = contracts[0].address;
+        let events = synthetic_block(1, 2, &contracts);
+
+        let mut client = MockStarknetClient::default();
+        client.expect_block_id_to_u64().returning(|id| match id {
+            BlockId::Number(n) => Ok(*n),
+            _ => Ok(0),
+        });
+        client.expect_block_time().returning(|_| Ok(1234));
+        client
+            .expect_fetch_all_block_events()
+            .returning(move |_, _| Ok(HashMap::from([(1, events.clone())])));
+        client
+            .expect_call_contract()
+            .returning(|_, _, _, _| Ok(vec![FieldElement::ONE]));
+
+        // The node is unavailable for the two events of the block.
+        let failures = AtomicU64::new(2);
+        client.expect_class_hash_at().returning(move |address, _| {
+            if failures.load(Ordering::SeqCst) > 0 {
+                failures.fetch_sub(1, Ordering::SeqCst);
+                Err(StarknetClientError::Other("Node unavailable".to_string()))
+            } else {
+                Ok(address)
+            }
+        });
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::clone(&storage),
+            Arc::new(TestEventHandler),
+            PontosConfig {
+                identification_strategy: CollectionIdentificationStrategy::ClassHash {
+                    erc721: HashSet::from([address]),
+                    erc1155: HashSet::new(),
+                },
+                ..config()
+            },
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(1), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let data = storage.dump();
+        assert_eq!(data.failed_events.len(), 2);
+        assert!(data.transfer_events.is_empty());
+
+        assert_eq!(pontos.reprocess_failed_events(1).await.unwrap(), 1);
+        assert_eq!(pontos.reprocess_failed_events(10).await.unwrap(), 1);
+
+        let data = storage.dump();
+        assert!(data.failed_events.is_empty());
+        assert_eq!(data.transfer_events.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reindex_contract_in_range() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(2, 0);
+        let blocks: HashMap<u64, _> = (1..=3)
+            .map(|b| (b, synthetic_block(b, 4, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(3), false, "SN_MAIN")
+            .await
+            .unwrap();
+        let before = storage.dump();
+
+        let address = contracts[0].address;
+        let report = pontos
+            .reindex_contract_in_range(address, 2, 3, "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            report,
+            ReindexReport {
+                purged_events: 4,
+                purged_tokens: 4,
+                registered_events: 4,
+            }
+        );
+
+        let after = storage.dump();
+        assert_eq!(after.transfer_events.len(), before.transfer_events.len());
+        assert_eq!(after.tokens.len(), before.tokens.len());
+        assert_eq!(after.blocks, before.blocks);
+        assert_eq!(after.supplies, before.supplies);
+        assert!(after.reindex_cursors.is_empty());
+
+        // An interrupted re-indexation resumes from its cursor.
+        storage
+            .set_reindex_cursor(&to_hex_str(&address), Some(3))
+            .await
+            .unwrap();
+        let report = pontos
+            .reindex_contract_in_range(address, 1, 3, "SN_MAIN")
+            .await
+            .unwrap();
+        assert_eq!(report.purged_events, 2);
+    }
+
+    #[tokio::test]
+    async fn test_reindex_contract_in_range_shared_timestamp() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks: HashMap<u64, _> = (1..=3)
+            .map(|b| (b, synthetic_block(b, 2, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(3), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        // The blocks 2 and 3 share their timestamp.
+        let events = storage.dump().transfer_events;
+        let block_2_ts = events
+            .values()
+            .find(|e| e.block_number == Some(2))
+            .unwrap()
+            .timestamp;
+        for event in events.values().filter(|e| e.block_number == Some(3)) {
+            let event = TokenTransferEvent {
+                timestamp: block_2_ts,
+                ..event.clone()
+            };
+            storage.replace_event(&event).await.unwrap();
+        }
+        let before = storage.dump();
+
+        let report = pontos
+            .reindex_contract_in_range(contracts[0].address, 2, 2, "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            report,
+            ReindexReport {
+                purged_events: 2,
+                purged_tokens: 2,
+                registered_events: 2,
+            }
+        );
+        let after = storage.dump();
+        assert_eq!(after.transfer_events.len(), before.transfer_events.len());
+        assert_eq!(after.tokens.len(), before.tokens.len());
+        assert_eq!(after.supplies, before.supplies);
+    }
+
+    #[tokio::test]
+    async fn test_backfill_reclassified_contract() {
+        use crate::testing::{mock_client, synthetic_block, synthetic_contracts, InMemoryStorage};
+
+        #[derive(Default)]
+        struct ReclassificationRecorder {
+            reclassified: std::sync::Mutex<Vec<(FieldElement, ContractType, ContractType)>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for ReclassificationRecorder {
+            async fn on_contract_reclassified(
+                &self,
+                contract_address: FieldElement,
+                old: &ContractType,
+                new: &ContractType,
+            ) {
+                self.reclassified.lock().unwrap().push((
+                    contract_address,
+                    old.clone(),
+                    new.clone(),
+                ));
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let address = contracts[0].address;
+        let blocks: HashMap<u64, Vec<EmittedEvent>> = (1..=3)
+            .map(|n| (n, synthetic_block(n, 2, &contracts)))
+            .collect();
+        let misclassified = format!(r#"{{"{}": "other"}}"#, to_hex_str(&address));
+
+        for auto_backfill in [false, true] {
+            let storage = Arc::new(InMemoryStorage::new());
+            let handler = Arc::new(ReclassificationRecorder::default());
+            let pontos = Pontos::new(
+                Arc::new(mock_client(blocks.clone(), &contracts)),
+                Arc::clone(&storage),
+                Arc::clone(&handler),
+                PontosConfig {
+                    backfill_reclassified_contracts: auto_backfill,
+                    ..config()
+                },
+            );
+
+            // The contract is classified as other, its events are discarded.
+            pontos.load_contract_types(&misclassified).unwrap();
+            pontos
+                .index_block_range(BlockId::Number(1), BlockId::Number(3), false, "SN_MAIN")
+                .await
+                .unwrap();
+            assert!(storage.dump().transfer_events.is_empty());
+
+            assert_eq!(
+                pontos.reprobe_contract(address, "SN_MAIN").await.unwrap(),
+                ContractType::ERC721
+            );
+            assert_eq!(
+                *handler.reclassified.lock().unwrap(),
+                vec![(address, ContractType::Other, ContractType::ERC721)]
+            );
+
+            if !auto_backfill {
+                let data = storage.dump();
+                assert!(data.transfer_events.is_empty());
+                assert_eq!(
+                    data.contracts_to_backfill.iter().collect::<Vec<_>>(),
+                    vec![&to_hex_str(&address)]
+                );
+                assert_eq!(pontos.backfill_contracts("SN_MAIN").await.unwrap(), 1);
+            }
+
+            let data = storage.dump();
+            assert_eq!(data.transfer_events.len(), 6);
+            assert!(data.contracts_to_backfill.is_empty());
+            assert_eq!(
+                data.contracts[&(to_hex_str(&address), "SN_MAIN".to_string())].contract_type,
+                ContractType::ERC721.to_string()
+            );
+
+            // Same type, nothing changes.
+            pontos.reprobe_contract(address, "SN_MAIN").await.unwrap();
+            assert_eq!(handler.reclassified.lock().unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_token_metadata_patch() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, ManualClock,
+            NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks: HashMap<u64, Vec<EmittedEvent>> =
+            HashMap::from([(1, synthetic_block(1, 2, &contracts))]);
+        let storage = Arc::new(InMemoryStorage::new());
+        let clock = ManualClock::new().with_unix_time(Duration::from_secs(1_800_000_000));
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                clock: Some(Arc::new(clock)),
+                ..config()
+            },
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(1), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let (contract_address, token_id_hex) = storage.dump().tokens.keys().next().unwrap().clone();
+        let contract = FieldElement::from_hex_be(&contract_address).unwrap();
+        let token_id = FieldElement::from_hex_be(&token_id_hex).unwrap();
+        let patch = |token_id, field, value| TokenMetadataPatch {
+            contract,
+            token_id,
+            field,
+            value,
+        };
+
+        let applied = pontos
+            .apply_token_metadata_patch(vec![
+                patch(
+                    token_id,
+                    MetadataField::Name,
+                    serde_json::json!("Fixed name"),
+                ),
+                patch(
+                    token_id,
+                    MetadataField::Attribute("Level".to_string()),
+                    serde_json::json!(3),
+                ),
+                // Unknown token, skipped.
+                patch(
+                    FieldElement::from(999_999_u64),
+                    MetadataField::Name,
+                    serde_json::json!("Unknown"),
+                ),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(applied, 2);
+
+        let data = storage.dump();
+        assert_eq!(
+            data.token_metadata.get(&(
+                contract_address.clone(),
+                token_id_hex.clone(),
+                "name".to_string()
+            )),
+            Some(&"Fixed name".to_string())
+        );
+        assert_eq!(
+            storage
+                .get_tokens_by_attribute(&contract_address, "Level", "3")
+                .await
+                .unwrap(),
+            vec![token_id_hex.clone()]
+        );
+
+        let audit = storage
+            .get_metadata_patches(&contract_address, &token_id_hex)
+            .await
+            .unwrap();
+        assert_eq!(
+            audit.iter().map(|p| p.field.clone()).collect::<Vec<_>>(),
+            vec![
+                MetadataField::Name,
+                MetadataField::Attribute("Level".to_string())
+            ]
+        );
+        assert!(audit.iter().all(|p| p.applied_at == 1_800_000_000));
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = (1..=4)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        pontos.warm_up(1, 4).await.unwrap();
+        pontos
+            .index_block_range(BlockId::Number(3), BlockId::Number(3), false, "SN_MAIN")
+            .await
+            .unwrap();
+        assert_eq!(pontos.warm_blocks.len(), 3);
+
+        let stats = pontos.maintenance().await.unwrap();
+        assert_eq!(stats, VacuumStats::default());
+
+        // Only the block 4, not indexed yet, is kept.
+        assert_eq!(
+            pontos
+                .warm_blocks
+                .iter()
+                .map(|b| *b.key())
+                .collect::<Vec<_>>(),
+            vec![4]
+        );
+        assert_eq!(storage.dump().indexers["TASK#123"].last_block, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_replay_from_event_log() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = HashMap::from([
+            (1, synthetic_block(1, 2, &contracts)),
+            (2, synthetic_block(2, 2, &contracts)),
+        ]);
+
+        let storage = Arc::new(InMemoryStorage::new());
+        Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        )
+        .index_block_range(BlockId::Number(1), BlockId::Number(2), false, "SN_MAIN")
+        .await
+        .unwrap();
+
+        // The client has no expectations: any call to the node panics.
+        let pontos = Pontos::new(
+            Arc::new(MockStarknetClient::default()),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+        pontos.replay_from_event_log(1, 2).await.unwrap();
+
+        let data = storage.dump();
+        assert_eq!(data.tokens.len(), 4);
+        for event in data.transfer_events.values() {
+            let token = &data.tokens[&(event.contract_address.clone(), event.token_id_hex.clone())];
+            assert_eq!(token.owner, event.to_address);
+        }
+
+        let stats = pontos
+            .get_collection_stats(contracts[0].address)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.total_tokens, 4);
+        assert_eq!(stats.total_transfers, 4);
+        assert_eq!(stats.last_updated_block, 2);
+    }
+
+    #[tokio::test]
+    async fn test_apply_migration() {
+        use crate::testing::{mock_client, synthetic_block, synthetic_contracts, InMemoryStorage};
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct MigrationRecorder {
+            progress: Mutex<Vec<(u64, u64)>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for MigrationRecorder {
+            async fn on_migration_progress(&self, processed: u64, total: u64) {
+                self.progress.lock().unwrap().push((processed, total));
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = HashMap::from([
+            (1, synthetic_block(1, 2, &contracts)),
+            (2, synthetic_block(2, 2, &contracts)),
+        ]);
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let handler = Arc::new(MigrationRecorder::default());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::clone(&handler),
+            config(),
+        );
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(2), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let mut ids: Vec<String> = storage.dump().transfer_events.into_keys().collect();
+        ids.sort();
+        let (failing, unchanged) = (ids[0].clone(), ids[1].clone());
+
+        let stats = pontos
+            .apply_migration(|mut event| {
+                let (failing, unchanged) = (failing.clone(), unchanged.clone());
+                async move {
+                    if event.event_id == failing {
+                        return Err(IndexerError::Anyhow("bad event".to_string()));
+                    }
+                    if event.event_id != unchanged {
+                        event.quantity = 7;
+                    }
+                    Ok(event)
+                }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            stats,
+            MigrationStats {
+                total: 4,
+                updated: 2,
+                failed: 1,
+            }
+        );
+        assert_eq!(*handler.progress.lock().unwrap(), vec![(4, 4)]);
+
+        let data = storage.dump();
+        assert_eq!(data.transfer_events[&failing].quantity, 1);
+        assert_eq!(data.transfer_events[&unchanged].quantity, 1);
+        assert_eq!(data.transfer_events[&ids[2]].quantity, 7);
+        assert_eq!(data.transfer_events[&ids[3]].quantity, 7);
+    }
+}
@@ -0,0 +1,70 @@
+use starknet::core::types::{EmittedEvent, FieldElement};
+
+/// Reference to the block of the processed events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockRef {
+    /// A block accepted on the chain, by its number.
+    Number(u64),
+    /// The pending block, which has no number yet.
+    Pending,
+}
+
+/// Block the processed events belong to.
+///
+/// The blocks are not always indexed in order (re-indexation, failed events,
+/// concurrent ranges...), so the managers rely on this context instead of
+/// the block data carried by the events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockContext {
+    pub number: BlockRef,
+    pub timestamp: u64,
+    pub is_pending: bool,
+    pub hash: Option<FieldElement>,
+}
+
+impl BlockContext {
+    /// Context of an accepted block.
+    pub fn new(number: u64, timestamp: u64) -> Self {
+        Self {
+            number: BlockRef::Number(number),
+            timestamp,
+            is_pending: false,
+            hash: None,
+        }
+    }
+
+    /// Context of the pending block.
+    pub fn pending(timestamp: u64) -> Self {
+        Self {
+            number: BlockRef::Pending,
+            timestamp,
+            is_pending: true,
+            hash: None,
+        }
+    }
+
+    /// Context of the block of the given event, the event being
+    /// pending if it has no block number.
+    pub fn from_event(event: &EmittedEvent, timestamp: u64) -> Self {
+        let context = match event.block_number {
+            Some(number) => Self::new(number, timestamp),
+            None => Self::pending(timestamp),
+        };
+
+        context.with_hash(event.block_hash)
+    }
+
+    /// Sets the block hash, if known.
+    pub fn with_hash(mut self, hash: Option<FieldElement>) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Returns the block number, `None` for the pending block.
+    pub fn block_number(&self) -> Option<u64> {
+        match self.number {
+            BlockRef::Number(number) => Some(number),
+            BlockRef::Pending => None,
+        }
+    }
+}
@@ -1,20 +1,183 @@
-use crate::storage::types::{BlockIndexingStatus, BlockInfo, StorageError};
+use crate::clock::{Clock, SystemClock};
+use crate::storage::types::{BlockDetails, BlockIndexingStatus, BlockInfo, StorageError};
 use crate::storage::Storage;
+use crate::ForcePolicy;
+use crate::{IndexerError, IndexerResult};
+use serde::Serialize;
 use starknet::core::types::FieldElement;
-use std::sync::Arc;
-use tracing::{debug, trace};
-use version_compare::{compare, Cmp};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, trace, warn};
+use version_compare::{compare, Cmp, Part, Version};
+
+/// Decision taken by `BlockManager::indexing_decision` for a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexingDecision {
+    /// The block is not indexed yet.
+    Index,
+    /// The block was already indexed, and was cleaned to be indexed again.
+    Reindex,
+    /// The block was already indexed, and must not be indexed again.
+    Skip(SkipReason),
+}
+
+/// Why a block already indexed is not indexed again,
+/// as reported by `EventHandler::on_block_skipped`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum SkipReason {
+    /// The block was indexed by this indexer, with the current version.
+    SameVersionTerminated,
+    /// The block was indexed by an indexer with an other identifier,
+    /// like an other deployment sharing the storage.
+    OtherIdentifier,
+    /// The block was indexed by an other version of this indexer,
+    /// which doesn't match the force policy.
+    OtherVersionPolicy,
+    /// The indexation was forced, but the block failed to be cleaned.
+    ForceOverridden,
+}
+
+/// Number of terminated blocks kept to compute the indexing rate.
+const INDEXED_BLOCKS_HISTORY: usize = 4096;
+
+/// Window of the indexing rate used by `estimate_blocks_remaining`.
+const ESTIMATE_WINDOW_SECS: u64 = 60;
+
+/// Progress estimate of a block range, by `BlockManager::estimate_blocks_remaining`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct EstimateResult {
+    /// Number of blocks of the range not terminated yet.
+    pub blocks_remaining: u64,
+    /// Blocks terminated per second, over the last `ESTIMATE_WINDOW_SECS` seconds.
+    pub rate_per_sec: f64,
+    /// Seconds left to index the remaining blocks at the current rate,
+    /// `None` if no block was terminated recently.
+    pub eta_secs: Option<u64>,
+}
 
 #[derive(Debug)]
 pub struct BlockManager<S: Storage> {
     storage: Arc<S>,
+    /// Circular buffer of the last terminated blocks,
+    /// as `(block_number, indexed_at_ms)`.
+    indexed_blocks: Mutex<VecDeque<(u64, u64)>>,
+    /// `(attempts, interval)` to wait for a block in processing.
+    processing_backoff: Option<(u32, Duration)>,
+    /// Hash of the current event selectors, recorded on terminated blocks.
+    selector_hash: Option<String>,
+    /// If true, the terminated blocks recorded with an other selector hash
+    /// are indexed again.
+    reindex_on_selector_change: bool,
+    /// Clock of the processing backoff.
+    clock: Arc<dyn Clock>,
 }
 
 impl<S: Storage> BlockManager<S> {
     pub fn new(storage: Arc<S>) -> Self {
         Self {
             storage: Arc::clone(&storage),
+            indexed_blocks: Mutex::new(VecDeque::with_capacity(INDEXED_BLOCKS_HISTORY)),
+            processing_backoff: None,
+            selector_hash: None,
+            reindex_on_selector_change: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Sets the clock of the processing backoff, of the indexing rate
+    /// and of the processing timestamps.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets the hash of the current event selectors, recorded on the blocks
+    /// terminated, see `EventManager::selector_hash`.
+    pub fn with_selector_hash(mut self, selector_hash: String) -> Self {
+        self.selector_hash = Some(selector_hash);
+        self
+    }
+
+    /// Sets if `indexing_decision` indexes again the blocks terminated with
+    /// an other selector hash, see `PontosConfig::reindex_on_selector_change`.
+    pub fn with_reindex_on_selector_change(mut self, reindex: bool) -> Self {
+        self.reindex_on_selector_change = reindex;
+        self
+    }
+
+    /// Sets the backoff of `indexing_decision` for the blocks in processing,
+    /// see `PontosConfig::processing_backoff`.
+    pub fn with_processing_backoff(mut self, backoff: Option<(u32, Duration)>) -> Self {
+        self.processing_backoff = backoff;
+        self
+    }
+
+    /// Returns the number of blocks terminated per second,
+    /// over the last `window_secs` seconds.
+    pub fn compute_indexing_rate(&self, window_secs: u64) -> f64 {
+        if window_secs == 0 {
+            return 0.0;
         }
+
+        let since_ms = self.now_ms().saturating_sub(window_secs * 1000);
+        let count = self
+            .indexed_blocks
+            .lock()
+            .expect("Indexed blocks lock poisoned")
+            .iter()
+            .filter(|(_, indexed_at_ms)| *indexed_at_ms >= since_ms)
+            .count();
+
+        count as f64 / window_secs as f64
+    }
+
+    /// Estimates the time left to index the blocks from `from` to `to` inclusive,
+    /// from the blocks not terminated yet and the current indexing rate.
+    pub async fn estimate_blocks_remaining(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> IndexerResult<EstimateResult> {
+        let total = to.saturating_sub(from) + u64::from(from <= to);
+        let terminated = self
+            .storage
+            .count_terminated_blocks_in_range(from, to)
+            .await?;
+        let blocks_remaining = total.saturating_sub(terminated);
+        let rate_per_sec = self.compute_indexing_rate(ESTIMATE_WINDOW_SECS);
+
+        let eta_secs = if blocks_remaining == 0 {
+            Some(0)
+        } else if rate_per_sec > 0.0 {
+            Some((blocks_remaining as f64 / rate_per_sec).ceil() as u64)
+        } else {
+            None
+        };
+
+        Ok(EstimateResult {
+            blocks_remaining,
+            rate_per_sec,
+            eta_secs,
+        })
+    }
+
+    /// Returns the time of the clock in milliseconds since the epoch.
+    fn now_ms(&self) -> u64 {
+        self.clock.unix_time().as_millis() as u64
+    }
+
+    fn record_indexed_block(&self, block_number: u64) {
+        let mut indexed_blocks = self
+            .indexed_blocks
+            .lock()
+            .expect("Indexed blocks lock poisoned");
+
+        if indexed_blocks.len() == INDEXED_BLOCKS_HISTORY {
+            indexed_blocks.pop_front();
+        }
+
+        indexed_blocks.push_back((block_number, self.now_ms()));
     }
 
     pub async fn clean_block(
@@ -27,8 +190,45 @@ impl<S: Storage> BlockManager<S> {
             .await
     }
 
+    /// Returns the number of blocks being processed, by any indexer.
+    pub async fn pending_block_count(&self) -> IndexerResult<u64> {
+        Ok(self.storage.count_processing_blocks().await?)
+    }
+
+    /// Returns the blocks in processing for more than `older_than_secs` seconds,
+    /// which are likely left by crashed workers.
+    pub async fn stuck_blocks(&self, older_than_secs: u64) -> IndexerResult<Vec<u64>> {
+        let started_before_ms = self
+            .now_ms()
+            .saturating_sub(older_than_secs.saturating_mul(1000));
+        Ok(self
+            .storage
+            .get_processing_blocks_started_before(started_before_ms)
+            .await?)
+    }
+
+    /// Records that this indexer is still processing the block, for the
+    /// monitoring to tell a slow block from a crashed indexer.
+    pub async fn heartbeat(&self, block: u64) -> IndexerResult<()> {
+        Ok(self
+            .storage
+            .set_block_heartbeat(block, self.now_ms())
+            .await?)
+    }
+
+    /// Returns the stored metadata of the given block, `None` if the block
+    /// was never indexed.
+    pub async fn get_block_info(&self, block_number: u64) -> IndexerResult<Option<BlockDetails>> {
+        Ok(self.storage.get_block_details(block_number).await?)
+    }
+
+    /// Returns the number of events stored for the given block.
+    pub async fn count_block_events(&self, block_number: u64) -> Result<u64, StorageError> {
+        self.storage.count_block_events(block_number).await
+    }
+
     /// Returns false if the given block number must be indexed.
-    /// True otherwise.
+    /// True otherwise. The reason of the skip is given by `indexing_decision`.
     pub async fn should_skip_indexing(
         &self,
         block_number: u64,
@@ -36,44 +236,167 @@ impl<S: Storage> BlockManager<S> {
         indexer_version: String,
         do_force: bool,
     ) -> Result<bool, StorageError> {
-        if do_force {
-            // Force indexing by cleaning the block, and return true.
-            match self
+        self.indexing_decision(
+            block_number,
+            block_timestamp,
+            &indexer_version,
+            "",
+            &ForcePolicy::from(do_force),
+        )
+        .await
+        .map(|d| matches!(d, IndexingDecision::Skip(_)))
+    }
+
+    /// Decides if the given block must be indexed, according to the force policy.
+    /// A block already indexed which must be indexed again is cleaned.
+    ///
+    /// With a processing backoff, a block in processing is waited for
+    /// before taking the decision.
+    ///
+    /// The identifier of the indexer doesn't change the decision, only the
+    /// reason of a skip: a block indexed by an other identifier is skipped
+    /// as any other block according to its version.
+    pub async fn indexing_decision(
+        &self,
+        block_number: u64,
+        block_timestamp: u64,
+        indexer_version: &str,
+        indexer_identifier: &str,
+        force: &ForcePolicy,
+    ) -> Result<IndexingDecision, StorageError> {
+        if let Some((attempts, interval)) = self.processing_backoff {
+            self.wait_processing_block(block_number, attempts, interval)
+                .await?;
+        }
+
+        if *force == ForcePolicy::Always {
+            // Force indexing by cleaning the block.
+            return match self
                 .storage
                 .clean_block(block_timestamp, Some(block_number))
                 .await
             {
-                Ok(()) => Ok(false),
-                Err(_) => Ok(true),
+                Ok(()) => Ok(IndexingDecision::Reindex),
+                Err(_) => Ok(IndexingDecision::Skip(SkipReason::ForceOverridden)),
+            };
+        }
+
+        let info = match self.storage.get_block_info(block_number).await {
+            Ok(Some(info)) => info,
+            Ok(None) => return Ok(IndexingDecision::Index),
+            Err(e) => return Err(e),
+        };
+
+        if info.status == BlockIndexingStatus::Failed {
+            debug!(
+                "Block {} failed previously, indexing it again",
+                block_number
+            );
+            return self
+                .storage
+                .clean_block(block_timestamp, Some(block_number))
+                .await
+                .map(|_| IndexingDecision::Reindex);
+        }
+
+        if self.is_selector_outdated(&info) {
+            debug!(
+                "Block {} terminated with selectors {:?}, indexing it again",
+                block_number, info.selector_hash
+            );
+            return self
+                .storage
+                .clean_block(block_timestamp, Some(block_number))
+                .await
+                .map(|_| IndexingDecision::Reindex);
+        }
+
+        trace!("Block {} already indexed", block_number);
+        debug!(
+            "Checking indexation version: current={:?}, last={:?}, force={:?}",
+            indexer_version, info.indexer_version, force
+        );
+
+        let reindex = match force {
+            // if the current version is greater, the block is indexed again.
+            ForcePolicy::Never | ForcePolicy::Always => {
+                matches!(compare(indexer_version, &info.indexer_version), Ok(Cmp::Gt))
             }
+            ForcePolicy::OlderThan(target) => is_older_version(&info.indexer_version, target),
+        };
+
+        if reindex {
+            self.storage
+                .clean_block(block_timestamp, Some(block_number))
+                .await
+                .map(|_| IndexingDecision::Reindex)
+        } else if info.indexer_identifier != indexer_identifier {
+            Ok(IndexingDecision::Skip(SkipReason::OtherIdentifier))
+        } else if info.indexer_version != indexer_version {
+            Ok(IndexingDecision::Skip(SkipReason::OtherVersionPolicy))
         } else {
+            Ok(IndexingDecision::Skip(SkipReason::SameVersionTerminated))
+        }
+    }
+
+    /// Returns true if the block was terminated with other selectors than
+    /// the current ones, and must be indexed again. Legacy blocks without
+    /// selector hash are considered outdated.
+    fn is_selector_outdated(&self, info: &BlockInfo) -> bool {
+        self.reindex_on_selector_change
+            && self.selector_hash.is_some()
+            && info.status == BlockIndexingStatus::Terminated
+            && info.selector_hash != self.selector_hash
+    }
+
+    /// Waits for a block in processing, possibly by a crashed peer, to leave
+    /// the processing status. The status is checked up to `attempts` times.
+    async fn wait_processing_block(
+        &self,
+        block_number: u64,
+        attempts: u32,
+        interval: Duration,
+    ) -> Result<(), StorageError> {
+        for attempt in 1..=attempts {
             match self.storage.get_block_info(block_number).await {
-                Ok(info) => {
-                    trace!("Block {} already indexed", block_number);
+                Ok(Some(info)) if info.status == BlockIndexingStatus::Processing => {
                     debug!(
-                        "Checking indexation version: current={:?}, last={:?}",
-                        indexer_version,
-                        info.indexer_version.clone()
+                        "Block {} in processing by {}, waiting ({}/{})",
+                        block_number, info.indexer_identifier, attempt, attempts
                     );
-
-                    // Compare the indexer versions.
-                    match compare(indexer_version.clone(), info.indexer_version.clone()) {
-                        // if the current version is greater, clean the block & return false we index the block
-                        Ok(Cmp::Gt) => self
-                            .storage
-                            .clean_block(block_timestamp, Some(block_number))
-                            .await
-                            .map(|_| false),
-                        // if the current version is equal, return false we skip the block indexation
-                        _ => Ok(true),
-                    }
+                    self.clock.sleep(interval).await;
                 }
-                Err(StorageError::NotFound(_s)) => Ok(false),
-                Err(e) => Err(e),
+                Ok(_) => return Ok(()),
+                Err(e) => return Err(e),
             }
         }
+
+        warn!(
+            "Block {} still in processing after {} attempts",
+            block_number, attempts
+        );
+
+        Ok(())
     }
 
+    /// Sets the block info for the given block number.
+    ///
+    /// If the block was already terminated by an other indexer version,
+    /// and the indexation is not forced, a `VersionConflict` error is returned
+    /// instead of silently overwriting the block info.
+    /// Returns the etag of the block, to be given to `set_block_info`.
+    /// 0 if the block is unknown.
+    pub async fn get_block_etag(&self, block_number: u64) -> IndexerResult<u64> {
+        Ok(self.storage.get_block_etag(block_number).await?)
+    }
+
+    /// Sets the status of the block.
+    ///
+    /// With an `etag`, read by `get_block_etag`, the block is only written if
+    /// no other writer wrote it since, and `IndexerError::OptimisticLockFailed`
+    /// is returned otherwise: the caller can read the block again and decide
+    /// to retry or abort.
+    #[allow(clippy::too_many_arguments)]
     pub async fn set_block_info(
         &self,
         block_number: u64,
@@ -81,23 +404,75 @@ impl<S: Storage> BlockManager<S> {
         indexer_version: String,
         indexer_identifier: String,
         status: BlockIndexingStatus,
-    ) -> Result<(), StorageError> {
-        self.storage
-            .set_block_info(
-                block_number,
-                block_timestamp,
-                BlockInfo {
-                    indexer_version,
-                    indexer_identifier,
-                    status,
-                    block_number,
-                },
-            )
-            .await?;
+        do_force: bool,
+        etag: Option<u64>,
+    ) -> IndexerResult<()> {
+        if !do_force {
+            match self.storage.get_block_info(block_number).await {
+                Ok(Some(info))
+                    if info.status == BlockIndexingStatus::Terminated
+                        && info.indexer_version != indexer_version =>
+                {
+                    return Err(IndexerError::VersionConflict {
+                        block: block_number,
+                        existing_version: info.indexer_version,
+                        current_version: indexer_version,
+                    });
+                }
+                Ok(_) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let info = BlockInfo {
+            indexer_version,
+            indexer_identifier,
+            status: status.clone(),
+            block_number,
+            selector_hash: (status == BlockIndexingStatus::Terminated)
+                .then(|| self.selector_hash.clone())
+                .flatten(),
+            last_heartbeat_at: 0,
+        };
+
+        match etag {
+            None => {
+                self.storage
+                    .set_block_info(block_number, block_timestamp, info)
+                    .await?
+            }
+            Some(expected_etag) => {
+                let found_etag = self
+                    .storage
+                    .set_block_info_if_etag(block_number, block_timestamp, info, expected_etag)
+                    .await?;
+                if found_etag != expected_etag {
+                    return Err(IndexerError::OptimisticLockFailed {
+                        block: block_number,
+                        expected_etag,
+                        found_etag,
+                    });
+                }
+            }
+        }
+
+        if status == BlockIndexingStatus::Terminated {
+            self.record_indexed_block(block_number);
+        }
+
         Ok(())
     }
 }
 
+/// Returns true if `version` is older than `target`. Legacy versions
+/// without any number, which can't be compared, are considered older.
+fn is_older_version(version: &str, target: &str) -> bool {
+    let has_number = Version::from(version)
+        .is_some_and(|v| v.parts().iter().any(|p| matches!(p, Part::Number(_))));
+
+    !has_number || matches!(compare(version, target), Ok(Cmp::Lt))
+}
+
 /// Data of the pending block being indexed.
 /// The vector of txs hashes are the hashes
 /// of the transactions already processed by the indexer.
@@ -105,6 +480,15 @@ impl<S: Storage> BlockManager<S> {
 pub struct PendingBlockData {
     timestamp: u64,
     txs_hashes: Vec<FieldElement>,
+    event_count: usize,
+}
+
+/// Point-in-time view of the [`PendingBlockData`], for debugging purposes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PendingBlockSnapshot {
+    pub timestamp: u64,
+    pub processed_tx_count: usize,
+    pub event_count: usize,
 }
 
 impl PendingBlockData {
@@ -112,6 +496,15 @@ impl PendingBlockData {
         PendingBlockData {
             timestamp: 0,
             txs_hashes: vec![],
+            event_count: 0,
+        }
+    }
+
+    pub fn snapshot(&self) -> PendingBlockSnapshot {
+        PendingBlockSnapshot {
+            timestamp: self.timestamp,
+            processed_tx_count: self.txs_hashes.len(),
+            event_count: self.event_count,
         }
     }
 
@@ -131,8 +524,14 @@ impl PendingBlockData {
         self.txs_hashes.contains(tx_hash)
     }
 
+    pub fn add_events_count(&mut self, count: usize) {
+        self.event_count += count;
+    }
+
+    /// Clears the processed transactions and the events count.
     pub fn clear_tx_hashes(&mut self) {
         self.txs_hashes.clear();
+        self.event_count = 0;
     }
 }
 
@@ -156,10 +555,10 @@ mod tests {
     async fn test_should_skip_indexing_not_found() {
         let mut mock_storage = MockStorage::default();
 
-        // Mock the get_block_info to return NotFound.
+        // Mock the get_block_info to return no block.
         mock_storage
             .expect_get_block_info()
-            .returning(|_| Box::pin(async { Err(StorageError::NotFound("".to_string())) }));
+            .returning(|_| Box::pin(async { Ok(None) }));
 
         let block_number = 3;
 
@@ -168,9 +567,7 @@ mod tests {
             .expect_clean_block()
             .returning(|_, _| Box::pin(async { Ok(()) }));
 
-        let manager = BlockManager {
-            storage: Arc::new(mock_storage),
-        };
+        let manager = BlockManager::new(Arc::new(mock_storage));
 
         // Should return false as the block is not found.
         let result = manager
@@ -178,7 +575,7 @@ mod tests {
             .await
             .unwrap();
 
-        assert!(result == false);
+        assert!(!result);
     }
 
     #[tokio::test]
@@ -195,14 +592,16 @@ mod tests {
             .expect_get_block_info()
             .returning(|block_number| {
                 Box::pin(futures::future::ready(if block_number == 1 {
-                    Ok(BlockInfo {
+                    Ok(Some(BlockInfo {
                         status: BlockIndexingStatus::Processing,
                         indexer_version: String::from("v0.0.1"),
                         indexer_identifier: String::from("TASK#123"),
                         block_number: 123,
-                    })
+                        selector_hash: None,
+                        last_heartbeat_at: 0,
+                    }))
                 } else {
-                    Err(StorageError::NotFound("".to_string()))
+                    Ok(None)
                 }))
             });
 
@@ -211,22 +610,531 @@ mod tests {
             .expect_clean_block()
             .returning(|_, _| Box::pin(async { Ok(()) }));
 
-        let manager = BlockManager {
-            storage: Arc::new(mock_storage),
-        };
+        let manager = BlockManager::new(Arc::new(mock_storage));
 
         // New version, should return true for indexing.
         let result = manager
             .should_skip_indexing(1, 0, "v0.0.2".to_string(), false)
             .await
             .unwrap();
-        assert!(result == false);
+        assert!(!result);
 
         // Force but same version, should return true for indexing.
         let result = manager
             .should_skip_indexing(2, 0, "v0.0.1".to_string(), true)
             .await
             .unwrap();
-        assert!(result == false);
+        assert!(!result);
+    }
+
+    #[tokio::test]
+    async fn test_set_block_info_version_conflict() {
+        let mut mock_storage = MockStorage::default();
+
+        mock_storage
+            .expect_get_block_info()
+            .returning(|block_number| {
+                Box::pin(futures::future::ready(Ok(Some(BlockInfo {
+                    status: BlockIndexingStatus::Terminated,
+                    indexer_version: String::from("v0.0.1"),
+                    indexer_identifier: String::from("TASK#123"),
+                    block_number,
+                    selector_hash: None,
+                    last_heartbeat_at: 0,
+                }))))
+            });
+
+        mock_storage
+            .expect_set_block_info()
+            .returning(|_, _, _| Box::pin(futures::future::ready(Ok(()))));
+
+        let manager = BlockManager::new(Arc::new(mock_storage));
+
+        // Other version without force, the conflict must be surfaced.
+        let result = manager
+            .set_block_info(
+                1,
+                0,
+                "v0.0.2".to_string(),
+                "TASK#456".to_string(),
+                BlockIndexingStatus::Processing,
+                false,
+                None,
+            )
+            .await;
+
+        match result {
+            Err(IndexerError::VersionConflict {
+                block,
+                existing_version,
+                current_version,
+            }) => {
+                assert_eq!(block, 1);
+                assert_eq!(existing_version, "v0.0.1");
+                assert_eq!(current_version, "v0.0.2");
+            }
+            _ => panic!("Expected a version conflict"),
+        }
+
+        // Same version, no conflict.
+        assert!(manager
+            .set_block_info(
+                1,
+                0,
+                "v0.0.1".to_string(),
+                "TASK#456".to_string(),
+                BlockIndexingStatus::Processing,
+                false,
+                None,
+            )
+            .await
+            .is_ok());
+
+        // Forced, no conflict.
+        assert!(manager
+            .set_block_info(
+                1,
+                0,
+                "v0.0.2".to_string(),
+                "TASK#456".to_string(),
+                BlockIndexingStatus::Processing,
+                true,
+                None,
+            )
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_pending_block_snapshot() {
+        let mut cache = PendingBlockData::new();
+        cache.set_timestamp(1234);
+        cache.add_tx_as_processed(&FieldElement::ONE);
+        cache.add_tx_as_processed(&FieldElement::TWO);
+        cache.add_events_count(3);
+
+        assert_eq!(
+            cache.snapshot(),
+            PendingBlockSnapshot {
+                timestamp: 1234,
+                processed_tx_count: 2,
+                event_count: 3,
+            }
+        );
+
+        cache.clear_tx_hashes();
+        let snapshot = cache.snapshot();
+        assert_eq!(snapshot.processed_tx_count, 0);
+        assert_eq!(snapshot.event_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_compute_indexing_rate() {
+        let mut mock_storage = MockStorage::default();
+        mock_storage
+            .expect_set_block_info()
+            .returning(|_, _, _| Box::pin(futures::future::ready(Ok(()))));
+
+        let manager = BlockManager::new(Arc::new(mock_storage));
+
+        for (block_number, status) in [
+            (1, BlockIndexingStatus::Terminated),
+            (2, BlockIndexingStatus::Processing),
+            (2, BlockIndexingStatus::Terminated),
+            (3, BlockIndexingStatus::Terminated),
+        ] {
+            manager
+                .set_block_info(
+                    block_number,
+                    0,
+                    "v0.0.1".to_string(),
+                    "TASK#123".to_string(),
+                    status,
+                    true,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(manager.compute_indexing_rate(10), 0.3);
+        assert_eq!(manager.compute_indexing_rate(0), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_estimate_blocks_remaining() {
+        let mut mock_storage = MockStorage::default();
+        mock_storage
+            .expect_set_block_info()
+            .returning(|_, _, _| Box::pin(futures::future::ready(Ok(()))));
+        mock_storage
+            .expect_count_terminated_blocks_in_range()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(30))));
+
+        let manager = BlockManager::new(Arc::new(mock_storage));
+
+        let estimate = manager.estimate_blocks_remaining(1, 100).await.unwrap();
+        assert_eq!(estimate.blocks_remaining, 70);
+        assert_eq!(estimate.rate_per_sec, 0.0);
+        assert_eq!(estimate.eta_secs, None);
+
+        for block_number in 1..=3 {
+            manager
+                .set_block_info(
+                    block_number,
+                    0,
+                    "v0.0.1".to_string(),
+                    "TASK#123".to_string(),
+                    BlockIndexingStatus::Terminated,
+                    true,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        // 3 blocks in 60 seconds: 20 seconds per block.
+        let estimate = manager.estimate_blocks_remaining(1, 100).await.unwrap();
+        assert_eq!(estimate.rate_per_sec, 0.05);
+        assert_eq!(estimate.eta_secs, Some(1400));
+
+        let estimate = manager.estimate_blocks_remaining(1, 30).await.unwrap();
+        assert_eq!(estimate.blocks_remaining, 0);
+        assert_eq!(estimate.eta_secs, Some(0));
+    }
+
+    #[test]
+    fn test_is_older_version() {
+        assert!(is_older_version("1.3.9", "1.4.0"));
+        assert!(is_older_version("v0.0.1", "1.4.0"));
+        assert!(!is_older_version("1.4.0", "1.4.0"));
+        assert!(!is_older_version("1.10.0", "1.4.0"));
+
+        // Legacy versions stored by old runs.
+        assert!(is_older_version("", "1.4.0"));
+        assert!(is_older_version("legacy", "1.4.0"));
+        assert!(is_older_version("dev-build", "1.4.0"));
+    }
+
+    #[tokio::test]
+    async fn test_processing_and_stuck_blocks() {
+        let manager = BlockManager::new(Arc::new(crate::storage::InMemoryStorage::new()));
+
+        for (block_number, status) in [
+            (1, BlockIndexingStatus::Processing),
+            (2, BlockIndexingStatus::Processing),
+            (3, BlockIndexingStatus::Terminated),
+        ] {
+            manager
+                .set_block_info(
+                    block_number,
+                    1000 + block_number,
+                    "v0.0.1".to_string(),
+                    "TASK#123".to_string(),
+                    status,
+                    false,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(manager.pending_block_count().await.unwrap(), 2);
+        assert!(manager.stuck_blocks(60).await.unwrap().is_empty());
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(manager.stuck_blocks(0).await.unwrap(), vec![1, 2]);
+
+        // Terminated blocks are no longer stuck.
+        manager
+            .set_block_info(
+                2,
+                1002,
+                "v0.0.1".to_string(),
+                "TASK#123".to_string(),
+                BlockIndexingStatus::Terminated,
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(manager.pending_block_count().await.unwrap(), 1);
+        assert_eq!(manager.stuck_blocks(0).await.unwrap(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_processing_backoff() {
+        let storage = Arc::new(crate::storage::InMemoryStorage::new());
+        let manager = Arc::new(
+            BlockManager::new(Arc::clone(&storage))
+                .with_processing_backoff(Some((50, Duration::from_millis(10)))),
+        );
+
+        for block_number in [1, 2] {
+            manager
+                .set_block_info(
+                    block_number,
+                    1000 + block_number,
+                    "v0.0.1".to_string(),
+                    "PEER".to_string(),
+                    BlockIndexingStatus::Processing,
+                    false,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        // The peer terminates the block while waiting: the block is skipped.
+        let peer = Arc::clone(&manager);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            peer.set_block_info(
+                1,
+                1001,
+                "v0.0.1".to_string(),
+                "PEER".to_string(),
+                BlockIndexingStatus::Terminated,
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+        });
+        assert_eq!(
+            manager
+                .indexing_decision(1, 1001, "v0.0.1", "PEER", &ForcePolicy::Never)
+                .await
+                .unwrap(),
+            IndexingDecision::Skip(SkipReason::SameVersionTerminated)
+        );
+        assert_eq!(
+            storage.dump().blocks[&1].1.status,
+            BlockIndexingStatus::Terminated
+        );
+
+        // The peer never recovers: the block is taken over when forced.
+        let manager = BlockManager::new(Arc::clone(&storage))
+            .with_processing_backoff(Some((2, Duration::from_millis(10))));
+        assert_eq!(
+            manager
+                .indexing_decision(2, 1002, "v0.0.1", "PEER", &ForcePolicy::Always)
+                .await
+                .unwrap(),
+            IndexingDecision::Reindex
+        );
+    }
+
+    #[tokio::test]
+    async fn test_processing_backoff_virtual_time() {
+        use crate::testing::ManualClock;
+
+        let storage = Arc::new(crate::storage::InMemoryStorage::new());
+        let clock = Arc::new(ManualClock::new());
+        let manager = Arc::new(
+            BlockManager::new(Arc::clone(&storage))
+                .with_processing_backoff(Some((3, Duration::from_secs(60))))
+                .with_clock(Arc::clone(&clock) as Arc<dyn Clock>),
+        );
+
+        manager
+            .set_block_info(
+                1,
+                1001,
+                "v0.0.1".to_string(),
+                "PEER".to_string(),
+                BlockIndexingStatus::Processing,
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+
+        // The peer never recovers: the block is checked at each attempt.
+        let start = clock.now();
+        assert_eq!(
+            clock
+                .drive(manager.indexing_decision(1, 1001, "v0.0.1", "PEER", &ForcePolicy::Never))
+                .await
+                .unwrap(),
+            IndexingDecision::Skip(SkipReason::SameVersionTerminated)
+        );
+        assert_eq!(clock.now() - start, Duration::from_secs(180));
+
+        // The peer terminates the block during the second wait.
+        let decision = tokio::spawn({
+            let manager = Arc::clone(&manager);
+            async move {
+                manager
+                    .indexing_decision(1, 1001, "v0.0.1", "PEER", &ForcePolicy::Never)
+                    .await
+            }
+        });
+        clock.wait_for_sleeps(4).await;
+        clock.advance(Duration::from_secs(60));
+        clock.wait_for_sleeps(5).await;
+        manager
+            .set_block_info(
+                1,
+                1001,
+                "v0.0.1".to_string(),
+                "PEER".to_string(),
+                BlockIndexingStatus::Terminated,
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(60));
+
+        assert_eq!(
+            decision.await.unwrap().unwrap(),
+            IndexingDecision::Skip(SkipReason::SameVersionTerminated)
+        );
+        assert_eq!(clock.sleeps(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_skip_reasons() {
+        use crate::storage::MockStorage;
+
+        let storage = Arc::new(crate::storage::InMemoryStorage::new());
+        for (block_number, version, identifier) in [
+            (1, "v0.0.1", "TASK#123"),
+            (2, "v0.0.1", "STAGING"),
+            (3, "v0.0.2", "TASK#123"),
+        ] {
+            storage
+                .set_block_info(
+                    block_number,
+                    1000 + block_number,
+                    BlockInfo {
+                        indexer_version: version.to_string(),
+                        indexer_identifier: identifier.to_string(),
+                        status: BlockIndexingStatus::Terminated,
+                        block_number,
+                        selector_hash: None,
+                        last_heartbeat_at: 0,
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        let manager = BlockManager::new(storage);
+        for (block_number, reason) in [
+            (1, SkipReason::SameVersionTerminated),
+            (2, SkipReason::OtherIdentifier),
+            (3, SkipReason::OtherVersionPolicy),
+        ] {
+            assert_eq!(
+                manager
+                    .indexing_decision(
+                        block_number,
+                        1000 + block_number,
+                        "v0.0.1",
+                        "TASK#123",
+                        &ForcePolicy::Never
+                    )
+                    .await
+                    .unwrap(),
+                IndexingDecision::Skip(reason)
+            );
+        }
+
+        let mut mock_storage = MockStorage::default();
+        mock_storage.expect_clean_block().returning(|_, _| {
+            Box::pin(futures::future::ready(Err(StorageError::DatabaseError(
+                "locked".to_string(),
+            ))))
+        });
+        let manager = BlockManager::new(Arc::new(mock_storage));
+        assert_eq!(
+            manager
+                .indexing_decision(1, 1001, "v0.0.1", "TASK#123", &ForcePolicy::Always)
+                .await
+                .unwrap(),
+            IndexingDecision::Skip(SkipReason::ForceOverridden)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_block_info_with_etag() {
+        let manager = BlockManager::new(Arc::new(crate::storage::InMemoryStorage::new()));
+        let set = |status, etag| {
+            manager.set_block_info(
+                1,
+                1001,
+                "v0.0.1".to_string(),
+                "TASK#123".to_string(),
+                status,
+                false,
+                etag,
+            )
+        };
+
+        // Two instances read the etag of the unknown block, the second one loses.
+        let etag = manager.get_block_etag(1).await.unwrap();
+        assert_eq!(etag, 0);
+        set(BlockIndexingStatus::Processing, Some(etag))
+            .await
+            .unwrap();
+        assert!(matches!(
+            set(BlockIndexingStatus::Processing, Some(etag)).await,
+            Err(IndexerError::OptimisticLockFailed {
+                block: 1,
+                expected_etag: 0,
+                found_etag: 1,
+            })
+        ));
+
+        let etag = manager.get_block_etag(1).await.unwrap();
+        set(BlockIndexingStatus::Terminated, Some(etag))
+            .await
+            .unwrap();
+        assert_eq!(manager.get_block_etag(1).await.unwrap(), 2);
+
+        // Writes without etag are not checked, but increment it.
+        set(BlockIndexingStatus::Terminated, None).await.unwrap();
+        assert_eq!(manager.get_block_etag(1).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stuck_blocks_virtual_time() {
+        use crate::testing::ManualClock;
+
+        let clock = Arc::new(ManualClock::new().with_unix_time(Duration::from_secs(1_000_000)));
+        clock.advance(Duration::from_secs(30));
+
+        let mut mock_storage = MockStorage::default();
+        mock_storage
+            .expect_get_processing_blocks_started_before()
+            .withf(|started_before_ms| *started_before_ms == (1_000_030 - 60) * 1000)
+            .times(1)
+            .returning(|_| Box::pin(async { Ok(vec![4, 7]) }));
+
+        let manager = BlockManager::new(Arc::new(mock_storage))
+            .with_clock(Arc::clone(&clock) as Arc<dyn Clock>);
+
+        assert_eq!(manager.stuck_blocks(60).await.unwrap(), vec![4, 7]);
+    }
+
+    #[test]
+    fn test_compute_indexing_rate_virtual_time() {
+        use crate::testing::ManualClock;
+
+        let clock = Arc::new(ManualClock::new().with_unix_time(Duration::from_secs(1_000_000)));
+        let manager = BlockManager::new(Arc::new(MockStorage::default()))
+            .with_clock(Arc::clone(&clock) as Arc<dyn Clock>);
+
+        (1..=3).for_each(|n| manager.record_indexed_block(n));
+        clock.advance(Duration::from_secs(30));
+        (4..=5).for_each(|n| manager.record_indexed_block(n));
+
+        assert_eq!(manager.compute_indexing_rate(10), 0.2);
+        assert_eq!(manager.compute_indexing_rate(60), 5.0 / 60.0);
+
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(manager.compute_indexing_rate(60), 0.0);
     }
 }
@@ -1,3 +1,6 @@
+use crate::config::CollectionIdentificationStrategy;
+use crate::managers::token_manager::call_total_supply;
+use crate::managers::RpcPermits;
 use crate::storage::{
     types::{ContractInfo, ContractType, StorageError},
     Storage,
@@ -8,39 +11,278 @@ use ark_starknet::{
     client::{StarknetClient, StarknetClientError},
     format::to_hex_str,
 };
+use dashmap::DashMap;
+use lru::LruCache;
 use starknet::core::{
-    types::{BlockId, BlockTag, FieldElement},
+    types::{BlockId, BlockTag, EmittedEvent, FieldElement},
     utils::get_selector_from_name,
 };
-use std::collections::HashMap;
-use std::sync::Arc;
+use starknet::macros::selector;
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
 use tracing::{error, info, trace};
 
+/// SRC5 interface id of ERC721.
+const SRC5_IERC721_ID: &str = "0x33eb2f84c309543403fd69f0d0f363781ef06ef6faeb0131ff16ea3175bd943";
+/// SRC5 interface id of ERC1155.
+const SRC5_IERC1155_ID: &str = "0x6114a8f75559e1b39fcba08ce02961a1aa082d9256a158dd3e64964e4b1b52";
+/// Legacy ERC165 interface id of ERC721.
+const ERC165_IERC721_ID: &str = "0x80ac58cd";
+/// Legacy ERC165 interface id of ERC1155.
+const ERC165_IERC1155_ID: &str = "0xd9b67a26";
+
+/// Type of a contract in the cache.
+#[derive(Debug, Clone)]
+struct CachedType {
+    contract_type: ContractType,
+    /// False for the types loaded from JSON, not registered in the storage.
+    stored: bool,
+}
+
+/// A cache with contract address mapped to its type.
+enum ContractCache {
+    /// The map is sharded internally, which allows concurrent
+    /// identifications without locking the whole manager.
+    Unbounded(DashMap<FieldElement, CachedType>),
+    /// Keeps the most recently accessed contracts, up to its capacity.
+    Bounded(Mutex<LruCache<FieldElement, CachedType>>),
+}
+
+impl ContractCache {
+    fn new(capacity: Option<NonZeroUsize>) -> Self {
+        match capacity {
+            Some(capacity) => ContractCache::Bounded(Mutex::new(LruCache::new(capacity))),
+            None => ContractCache::Unbounded(DashMap::new()),
+        }
+    }
+
+    fn bounded(
+        cache: &Mutex<LruCache<FieldElement, CachedType>>,
+    ) -> std::sync::MutexGuard<'_, LruCache<FieldElement, CachedType>> {
+        cache.lock().expect("Contract cache lock poisoned")
+    }
+
+    fn get(&self, address: &FieldElement) -> Option<ContractType> {
+        match self {
+            ContractCache::Unbounded(cache) => cache.get(address).map(|c| c.contract_type.clone()),
+            ContractCache::Bounded(cache) => Self::bounded(cache)
+                .get(address)
+                .map(|c| c.contract_type.clone()),
+        }
+    }
+
+    /// Caches the type of the contract. Returns the type it replaces, if any,
+    /// and the contract evicted to make room if it was not stored.
+    fn insert(
+        &self,
+        address: FieldElement,
+        contract_type: ContractType,
+        stored: bool,
+    ) -> (Option<ContractType>, Option<(FieldElement, ContractType)>) {
+        let cached = CachedType {
+            contract_type,
+            stored,
+        };
+
+        match self {
+            ContractCache::Unbounded(cache) => {
+                (cache.insert(address, cached).map(|c| c.contract_type), None)
+            }
+            ContractCache::Bounded(cache) => match Self::bounded(cache).push(address, cached) {
+                Some((a, c)) if a == address => (Some(c.contract_type), None),
+                Some((a, c)) => (None, (!c.stored).then_some((a, c.contract_type))),
+                None => (None, None),
+            },
+        }
+    }
+
+    /// Returns the number of contracts which can be added without eviction.
+    fn free_space(&self) -> usize {
+        match self {
+            ContractCache::Unbounded(_) => usize::MAX,
+            ContractCache::Bounded(cache) => {
+                let cache = Self::bounded(cache);
+                cache.cap().get() - cache.len()
+            }
+        }
+    }
+
+    fn entries(&self) -> Vec<(FieldElement, ContractType)> {
+        match self {
+            ContractCache::Unbounded(cache) => cache
+                .iter()
+                .map(|c| (*c.key(), c.value().contract_type.clone()))
+                .collect(),
+            ContractCache::Bounded(cache) => Self::bounded(cache)
+                .iter()
+                .map(|(a, c)| (*a, c.contract_type.clone()))
+                .collect(),
+        }
+    }
+}
+
 pub struct ContractManager<S: Storage, C: StarknetClient> {
     storage: Arc<S>,
     client: Arc<C>,
-    /// A cache with contract address mapped to its type.
-    cache: HashMap<FieldElement, ContractType>,
+    cache: ContractCache,
+    /// A cache with class hash mapped to the type of its contracts,
+    /// backed by the storage. Contracts sharing a class hash already
+    /// classified are identified without probing.
+    class_hashes: DashMap<FieldElement, ContractType>,
+    /// Contract types overridden by an admin, backed by the storage.
+    /// They take precedence over the cache and the identification.
+    overrides: DashMap<FieldElement, ContractType>,
+    /// Initialized once the overrides were loaded from the storage.
+    overrides_loaded: OnceCell<()>,
+    /// Strategy used to identify the contracts not known yet.
+    strategy: CollectionIdentificationStrategy,
+    /// Bounds the concurrent calls to the node.
+    rpc_permits: RpcPermits,
 }
 
 impl<S: Storage, C: StarknetClient> ContractManager<S, C> {
     /// Initializes a new instance.
-    pub fn new(storage: Arc<S>, client: Arc<C>) -> Self {
+    pub fn new(
+        storage: Arc<S>,
+        client: Arc<C>,
+        strategy: CollectionIdentificationStrategy,
+    ) -> Self {
         Self {
             storage,
             client,
-            cache: HashMap::new(),
+            cache: ContractCache::new(None),
+            class_hashes: DashMap::new(),
+            overrides: DashMap::new(),
+            overrides_loaded: OnceCell::new(),
+            strategy,
+            rpc_permits: RpcPermits::default(),
         }
     }
 
+    /// Sets the permits bounding the concurrent calls to the node,
+    /// shared with the other components of the instance.
+    pub fn with_rpc_permits(mut self, rpc_permits: RpcPermits) -> Self {
+        self.rpc_permits = rpc_permits;
+        self
+    }
+
+    /// Bounds the cache of the contracts types to the given number of
+    /// contracts, evicting the least recently accessed ones. Unbounded if 0.
+    /// Must be set before any contract is cached.
+    pub fn with_cache_size(mut self, size: usize) -> Self {
+        self.cache = ContractCache::new(NonZeroUsize::new(size));
+        self
+    }
+
+    /// Caches the type of the contract, and returns the type it replaces.
+    /// A contract evicted from a bounded cache which was not registered in the
+    /// storage yet, as loaded from JSON, is registered before being dropped.
+    async fn cache_contract_type(
+        &self,
+        address: FieldElement,
+        contract_type: ContractType,
+        chain_id: &str,
+    ) -> Option<ContractType> {
+        let (previous, evicted) = self.cache.insert(address, contract_type, true);
+
+        if let Some((evicted_address, evicted_type)) = evicted {
+            let info = ContractInfo {
+                contract_address: to_hex_str(&evicted_address),
+                contract_type: evicted_type.to_string(),
+                name: None,
+                symbol: None,
+                image: None,
+                chain_id: chain_id.to_string(),
+            };
+
+            if let Err(e) = self
+                .storage
+                .register_contract_info(&info, 0, chain_id)
+                .await
+            {
+                error!(
+                    "Failed to store evicted contract info for [0x{:064x}]: {:?}",
+                    evicted_address, e
+                );
+            }
+        }
+
+        previous
+    }
+
+    /// Loads the contract type overrides registered in the storage,
+    /// replacing the overrides known so far. Returns the number of overrides loaded.
+    pub async fn load_from_storage(&self) -> Result<usize, StorageError> {
+        let overrides = self.storage.get_contract_type_overrides().await?;
+
+        let overrides = overrides
+            .into_iter()
+            .map(|(address, contract_type)| {
+                FieldElement::from_hex_be(&address)
+                    .map(|a| (a, contract_type))
+                    .map_err(|e| {
+                        StorageError::DatabaseError(format!(
+                            "Invalid overridden contract address {}: {}",
+                            address, e
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+
+        self.overrides.clear();
+        let count = overrides.len();
+        for (address, contract_type) in overrides {
+            self.overrides.insert(address, contract_type);
+        }
+
+        Ok(count)
+    }
+
+    /// Loads the overrides from the storage on the first identification.
+    async fn ensure_overrides_loaded(&self) -> Result<(), StorageError> {
+        self.overrides_loaded
+            .get_or_try_init(|| async {
+                let count = self.load_from_storage().await?;
+                info!("{} contract type override(s) loaded", count);
+                Ok(())
+            })
+            .await
+            .map(|_| ())
+    }
+
+    /// Overrides permanently the type of the contract, in the storage and in memory.
+    pub async fn register_override(
+        &self,
+        address: FieldElement,
+        contract_type: ContractType,
+    ) -> Result<(), StorageError> {
+        self.ensure_overrides_loaded().await?;
+        self.storage
+            .register_contract_type_override(address, contract_type.clone())
+            .await?;
+        self.overrides.insert(address, contract_type);
+        Ok(())
+    }
+
+    /// Returns the type overridden for the contract, if any.
+    fn override_for(&self, address: FieldElement) -> Option<ContractType> {
+        self.overrides.get(&address).map(|c| c.clone())
+    }
+
     /// Gets the contract info from local cache, or fetch is from the DB.
     async fn get_cached_or_fetch_info(
-        &mut self,
+        &self,
         address: FieldElement,
         chain_id: &str,
     ) -> Result<ContractType, StorageError> {
+        if let Some(contract_type) = self.override_for(address) {
+            return Ok(contract_type);
+        }
+
         if let Some(contract_type) = self.cache.get(&address) {
-            return Ok(contract_type.clone());
+            return Ok(contract_type);
         }
 
         trace!("Cache miss for contract {:#064x}", address);
@@ -48,9 +290,14 @@ impl<S: Storage, C: StarknetClient> ContractManager<S, C> {
         let contract_type = self
             .storage
             .get_contract_type(&to_hex_str(&address), chain_id)
-            .await?;
+            .await?
+            .ok_or_else(|| StorageError::NotFound {
+                entity: "contract",
+                key: to_hex_str(&address),
+            })?;
 
-        self.cache.insert(address, contract_type.clone()); // Adding to the cache
+        self.cache_contract_type(address, contract_type.clone(), chain_id)
+            .await;
 
         Ok(contract_type)
     }
@@ -67,11 +314,36 @@ impl<S: Storage, C: StarknetClient> ContractManager<S, C> {
     /// # Returns
     /// * `Result<ContractType>` - The type of the contract if identified successfully.
     pub async fn identify_contract(
-        &mut self,
+        &self,
+        address: FieldElement,
+        block_timestamp: u64,
+        chain_id: &str,
+    ) -> Result<ContractType> {
+        self.identify(address, None, block_timestamp, chain_id)
+            .await
+    }
+
+    /// Identifies the contract which emitted the given event and caches its info.
+    /// The event is used by the `EventPatternMatching` strategy.
+    pub async fn identify_contract_from_event(
+        &self,
+        event: &EmittedEvent,
+        block_timestamp: u64,
+        chain_id: &str,
+    ) -> Result<ContractType> {
+        self.identify(event.from_address, Some(event), block_timestamp, chain_id)
+            .await
+    }
+
+    async fn identify(
+        &self,
         address: FieldElement,
+        event: Option<&EmittedEvent>,
         block_timestamp: u64,
         chain_id: &str,
     ) -> Result<ContractType> {
+        self.ensure_overrides_loaded().await?;
+
         match self.get_cached_or_fetch_info(address, chain_id).await {
             Ok(contract_type) => Ok(contract_type),
             Err(_) => {
@@ -80,9 +352,10 @@ impl<S: Storage, C: StarknetClient> ContractManager<S, C> {
                 }
 
                 // If the contract info is not cached, identify and cache it.
-                let contract_type = self.get_contract_type(address).await?;
+                let contract_type = self.detect_contract_type(address, event, true).await?;
 
-                self.cache.insert(address, contract_type.clone());
+                self.cache_contract_type(address, contract_type.clone(), chain_id)
+                    .await;
 
                 let name = self
                     .get_contract_property_string(
@@ -112,6 +385,10 @@ impl<S: Storage, C: StarknetClient> ContractManager<S, C> {
                     symbol
                 );
 
+                if contract_type != ContractType::Other {
+                    self.cache_total_supply(address).await;
+                }
+
                 let info = ContractInfo {
                     contract_address: to_hex_str(&address),
                     contract_type: contract_type.to_string(),
@@ -137,12 +414,387 @@ impl<S: Storage, C: StarknetClient> ContractManager<S, C> {
         }
     }
 
+    /// Fetches and caches the total supply of the collection,
+    /// to be available without any call once the contract is identified.
+    async fn cache_total_supply(&self, address: FieldElement) {
+        let total_supply =
+            match call_total_supply(self.client.as_ref(), &self.rpc_permits, address).await {
+                Some(s) => s,
+                None => return,
+            };
+
+        if let Err(e) = self
+            .storage
+            .set_total_supply(&to_hex_str(&address), total_supply)
+            .await
+        {
+            error!(
+                "Failed to store total supply for [0x{:064x}]: {:?}",
+                address, e
+            );
+        }
+    }
+
+    /// Replaces the type of the contract, in the cache and in the storage.
+    /// Returns the previous type, `None` if the contract was not identified yet.
+    pub async fn set_contract_type(
+        &self,
+        address: FieldElement,
+        contract_type: ContractType,
+        chain_id: &str,
+    ) -> Result<Option<ContractType>> {
+        let address_hex = to_hex_str(&address);
+
+        let previous = match self
+            .cache_contract_type(address, contract_type.clone(), chain_id)
+            .await
+        {
+            Some(previous) => Some(previous),
+            None => self
+                .storage
+                .get_contract_type(&address_hex, chain_id)
+                .await
+                .ok()
+                .flatten(),
+        };
+
+        match self
+            .storage
+            .set_contract_type(&address_hex, chain_id, &contract_type)
+            .await
+        {
+            Ok(()) => {}
+            Err(StorageError::NotFound { .. }) => {
+                let info = ContractInfo {
+                    contract_address: address_hex,
+                    contract_type: contract_type.to_string(),
+                    name: None,
+                    symbol: None,
+                    image: None,
+                    chain_id: chain_id.to_string(),
+                };
+                self.storage
+                    .register_contract_info(&info, 0, chain_id)
+                    .await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(previous)
+    }
+
+    /// Identifies the contract again on the chain, bypassing the cache and the
+    /// storage, for a contract upgraded to another interface. The new type
+    /// replaces the cached and stored ones, unless the contract is overridden.
+    pub async fn refresh_contract_type(
+        &self,
+        address: FieldElement,
+        chain_id: &str,
+    ) -> Result<ContractType> {
+        let contract_type = self.get_contract_type(address).await?;
+        self.set_contract_type(address, contract_type.clone(), chain_id)
+            .await?;
+        Ok(contract_type)
+    }
+
+    /// Returns the contract type from the overrides and the local cache only.
+    pub fn cached_contract_type(&self, address: FieldElement) -> Option<ContractType> {
+        self.override_for(address)
+            .or_else(|| self.cache.get(&address))
+    }
+
+    /// Gets the contract type from the local cache, the storage or the chain,
+    /// without caching nor storing the contract info if it was not known yet.
+    pub async fn peek_contract_type(
+        &self,
+        address: FieldElement,
+        chain_id: &str,
+    ) -> Result<ContractType> {
+        self.ensure_overrides_loaded().await?;
+
+        if let Some(contract_type) = self.cached_contract_type(address) {
+            return Ok(contract_type);
+        }
+
+        if let Ok(Some(contract_type)) = self
+            .storage
+            .get_contract_type(&to_hex_str(&address), chain_id)
+            .await
+        {
+            return Ok(contract_type);
+        }
+
+        self.get_contract_type(address).await
+    }
+
+    /// Returns the type of the contract, using the configured identification strategy.
+    /// The class hashes memoized are used, but the classification of a new
+    /// class hash is not memoized. An overridden contract is not identified.
+    pub async fn get_contract_type(&self, contract_address: FieldElement) -> Result<ContractType> {
+        self.ensure_overrides_loaded().await?;
+
+        if let Some(contract_type) = self.override_for(contract_address) {
+            return Ok(contract_type);
+        }
+
+        self.detect_contract_type(contract_address, None, false)
+            .await
+    }
+
+    /// Dispatches the identification to the configured strategy.
+    /// If `memoize` is true, the classification of a new class hash is memoized.
+    async fn detect_contract_type(
+        &self,
+        contract_address: FieldElement,
+        event: Option<&EmittedEvent>,
+        memoize: bool,
+    ) -> Result<ContractType> {
+        match &self.strategy {
+            CollectionIdentificationStrategy::EntrypointProbing
+            | CollectionIdentificationStrategy::InterfaceProbing => {
+                self.probe_with_class_hash_memo(contract_address, memoize)
+                    .await
+            }
+            CollectionIdentificationStrategy::ClassHash { erc721, erc1155 } => {
+                let class_hash = self
+                    .rpc_permits
+                    .call(
+                        self.client
+                            .class_hash_at(contract_address, BlockId::Tag(BlockTag::Pending)),
+                    )
+                    .await?;
+
+                if erc721.contains(&class_hash) {
+                    Ok(ContractType::ERC721)
+                } else if erc1155.contains(&class_hash) {
+                    Ok(ContractType::ERC1155)
+                } else {
+                    Ok(ContractType::Other)
+                }
+            }
+            CollectionIdentificationStrategy::EventPatternMatching => {
+                match event.and_then(Self::contract_type_from_event_layout) {
+                    Some(contract_type) => Ok(contract_type),
+                    None => {
+                        self.probe_with_class_hash_memo(contract_address, memoize)
+                            .await
+                    }
+                }
+            }
+        }
+    }
+
+    /// Probes the contract, unless its class hash was already classified.
+    /// If `memoize` is true, the classification is then memoized for the class hash.
+    /// If the class hash can't be fetched, the contract is probed without memo.
+    async fn probe_with_class_hash_memo(
+        &self,
+        contract_address: FieldElement,
+        memoize: bool,
+    ) -> Result<ContractType> {
+        let class_hash = match self
+            .rpc_permits
+            .call(
+                self.client
+                    .class_hash_at(contract_address, BlockId::Tag(BlockTag::Pending)),
+            )
+            .await
+        {
+            Ok(class_hash) => class_hash,
+            Err(e) => {
+                trace!(
+                    "Can't get class hash of contract {:#064x}: {:?}",
+                    contract_address,
+                    e
+                );
+                return self.probe(contract_address).await;
+            }
+        };
+
+        if let Some(contract_type) = self.get_class_hash_type(class_hash).await {
+            trace!(
+                "Contract {:#064x} classified from its class hash {:#064x}",
+                contract_address,
+                class_hash
+            );
+            return Ok(contract_type);
+        }
+
+        let contract_type = self.probe(contract_address).await?;
+
+        if !memoize {
+            return Ok(contract_type);
+        }
+
+        self.class_hashes.insert(class_hash, contract_type.clone());
+
+        if let Err(e) = self
+            .storage
+            .set_class_hash_type(&to_hex_str(&class_hash), Some(contract_type.clone()))
+            .await
+        {
+            error!(
+                "Failed to store contract type of class hash [0x{:064x}]: {:?}",
+                class_hash, e
+            );
+        }
+
+        Ok(contract_type)
+    }
+
+    /// Gets the memoized type of the class hash from the local cache, or from the storage.
+    async fn get_class_hash_type(&self, class_hash: FieldElement) -> Option<ContractType> {
+        if let Some(contract_type) = self.class_hashes.get(&class_hash).map(|c| c.clone()) {
+            return Some(contract_type);
+        }
+
+        let contract_type = self
+            .storage
+            .get_class_hash_type(&to_hex_str(&class_hash))
+            .await
+            .ok()
+            .flatten()?;
+
+        self.class_hashes.insert(class_hash, contract_type.clone());
+
+        Some(contract_type)
+    }
+
+    /// Serializes the cached contracts types to JSON,
+    /// as a map of the contracts addresses to their type.
+    pub fn serialize(&self) -> Result<String, serde_json::Error> {
+        let types: BTreeMap<String, ContractType> = self
+            .cache
+            .entries()
+            .into_iter()
+            .map(|(address, contract_type)| (to_hex_str(&address), contract_type))
+            .collect();
+
+        serde_json::to_string(&types)
+    }
+
+    /// Pre-populates the cache with the contracts types serialized by `serialize`.
+    /// The contracts loaded are identified without any call to the node.
+    /// Nothing is loaded if the JSON is invalid. With a bounded cache, only the
+    /// contracts fitting in its free space are loaded, without evicting any.
+    /// Returns the number of contracts loaded.
+    pub fn load_from_json(&self, json: &str) -> Result<usize, serde_json::Error> {
+        let types: HashMap<String, ContractType> = serde_json::from_str(json)?;
+
+        let types = types
+            .into_iter()
+            .map(|(address, contract_type)| {
+                FieldElement::from_hex_be(&address)
+                    .map(|a| (a, contract_type))
+                    .map_err(|e| {
+                        serde::de::Error::custom(format!(
+                            "Invalid contract address {}: {}",
+                            address, e
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+
+        let count = types.len().min(self.cache.free_space());
+        for (address, contract_type) in types.into_iter().take(count) {
+            self.cache.insert(address, contract_type, false);
+        }
+
+        Ok(count)
+    }
+
+    /// Forgets the type memoized for the class hash, if a misclassification
+    /// was discovered. The next contracts of this class hash will be probed again.
+    /// Contracts already identified keep their stored type.
+    pub async fn invalidate_class_hash(&self, class_hash: FieldElement) -> Result<()> {
+        self.class_hashes.remove(&class_hash);
+
+        self.storage
+            .set_class_hash_type(&to_hex_str(&class_hash), None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Probes the contract entrypoints, according to the configured strategy.
+    async fn probe(&self, contract_address: FieldElement) -> Result<ContractType> {
+        if self.strategy != CollectionIdentificationStrategy::InterfaceProbing {
+            return self.probe_entrypoints(contract_address).await;
+        }
+
+        if self
+            .supports_any_interface(contract_address, &[SRC5_IERC721_ID, ERC165_IERC721_ID])
+            .await
+        {
+            Ok(ContractType::ERC721)
+        } else if self
+            .supports_any_interface(contract_address, &[SRC5_IERC1155_ID, ERC165_IERC1155_ID])
+            .await
+        {
+            Ok(ContractType::ERC1155)
+        } else {
+            Ok(ContractType::Other)
+        }
+    }
+
+    /// Infers the contract type from the layout of an emitted event.
+    /// Returns `None` if the layout is ambiguous.
+    pub fn contract_type_from_event_layout(event: &EmittedEvent) -> Option<ContractType> {
+        let selector = event.keys.first()?;
+
+        if *selector == selector!("TransferSingle") || *selector == selector!("TransferBatch") {
+            Some(ContractType::ERC1155)
+        } else if *selector == selector!("Transfer")
+            && event.keys.len() == 5
+            && event.data.is_empty()
+        {
+            // Cairo 1 ERC721: selector, from, to and u256 token id in keys.
+            // ERC20 have the amount in data.
+            Some(ContractType::ERC721)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the contract supports at least one of the given interfaces,
+    /// trying both `supports_interface` and `supportsInterface` entrypoints.
+    async fn supports_any_interface(
+        &self,
+        contract_address: FieldElement,
+        interface_ids: &[&str],
+    ) -> bool {
+        let block = BlockId::Tag(BlockTag::Pending);
+
+        for interface_id in interface_ids {
+            let interface_id = match FieldElement::from_hex_be(interface_id) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+
+            for selector_name in ["supports_interface", "supportsInterface"] {
+                if let Ok(res) = self
+                    .get_contract_response(
+                        contract_address,
+                        selector_name,
+                        vec![interface_id],
+                        block,
+                    )
+                    .await
+                {
+                    if res.first() == Some(&FieldElement::ONE) {
+                        return true;
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
     /// Verifies if the contract is an ERC721, ERC1155 or an other type.
     /// `owner_of` is specific to ERC721.
     /// `balance_of` is specific to ERC1155 and different from ERC20 as 2 arguments are expected.
-    pub async fn get_contract_type(&self, contract_address: FieldElement) -> Result<ContractType> {
-        let _block = BlockId::Tag(BlockTag::Pending);
-
+    async fn probe_entrypoints(&self, contract_address: FieldElement) -> Result<ContractType> {
         if self.is_erc721(contract_address).await? {
             Ok(ContractType::ERC721)
         } else if self.is_erc1155(contract_address).await? {
@@ -239,15 +891,15 @@ impl<S: Storage, C: StarknetClient> ContractManager<S, C> {
         calldata: Vec<FieldElement>,
         block: BlockId,
     ) -> Result<Vec<FieldElement>, StarknetClientError> {
-        self.client
-            .call_contract(
+        self.rpc_permits
+            .call(self.client.call_contract(
                 contract_address,
                 get_selector_from_name(selector_name).map_err(|_| {
                     StarknetClientError::Other(format!("Invalid selector: {}", selector_name))
                 })?,
                 calldata,
                 block,
-            )
+            ))
             .await
     }
 
@@ -259,15 +911,15 @@ impl<S: Storage, C: StarknetClient> ContractManager<S, C> {
         block: BlockId,
     ) -> Result<String, StarknetClientError> {
         let response = self
-            .client
-            .call_contract(
+            .rpc_permits
+            .call(self.client.call_contract(
                 contract_address,
                 get_selector_from_name(selector_name).map_err(|_| {
                     StarknetClientError::Other(format!("Invalid selector: {}", selector_name))
                 })?,
                 calldata,
                 block,
-            )
+            ))
             .await?;
 
         parse_cairo_string(response).map_err(|e| {
@@ -275,3 +927,425 @@ impl<S: Storage, C: StarknetClient> ContractManager<S, C> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MockStorage;
+    use ark_starknet::client::MockStarknetClient;
+
+    /// A mocked storage without any contract type override.
+    fn storage_without_overrides() -> MockStorage {
+        let mut storage = MockStorage::default();
+        storage
+            .expect_get_contract_type_overrides()
+            .returning(|| Box::pin(futures::future::ready(Ok(vec![]))));
+        storage
+    }
+
+    #[tokio::test]
+    async fn test_contract_types_json_round_trip() {
+        // Only the overrides are expected, any other call would panic.
+        let manager = ContractManager::new(
+            Arc::new(storage_without_overrides()),
+            Arc::new(MockStarknetClient::default()),
+            CollectionIdentificationStrategy::default(),
+        );
+
+        let json = r#"{"0x1234": "e_r_c721", "0x5678": "e_r_c1155", "0x9": "other"}"#;
+        assert_eq!(manager.load_from_json(json).unwrap(), 3);
+
+        let address = FieldElement::from_hex_be("0x1234").unwrap();
+        assert_eq!(
+            manager
+                .identify_contract(address, 0, "SN_MAIN")
+                .await
+                .unwrap(),
+            ContractType::ERC721
+        );
+
+        let other = ContractManager::new(
+            Arc::new(MockStorage::default()),
+            Arc::new(MockStarknetClient::default()),
+            CollectionIdentificationStrategy::default(),
+        );
+        other.load_from_json(&manager.serialize().unwrap()).unwrap();
+        assert_eq!(other.serialize().unwrap(), manager.serialize().unwrap());
+        assert_eq!(other.cache.get(&address), Some(ContractType::ERC721));
+
+        // Nothing is loaded from an invalid file.
+        assert!(other
+            .load_from_json(r#"{"0x1": "other", "not an address": "other"}"#)
+            .is_err());
+        assert!(other.cache.get(&FieldElement::ONE).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bounded_cache_evicts_least_recently_used() {
+        let mut mock_storage = storage_without_overrides();
+        mock_storage
+            .expect_get_contract_type()
+            .times(1)
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(Some(ContractType::ERC721)))));
+        // Only the contract loaded from JSON is registered when evicted.
+        mock_storage
+            .expect_register_contract_info()
+            .withf(|info, _, chain_id| {
+                info.contract_address == to_hex_str(&FieldElement::ONE)
+                    && info.contract_type == ContractType::Other.to_string()
+                    && chain_id == "SN_MAIN"
+            })
+            .times(1)
+            .returning(|_, _, _| Box::pin(futures::future::ready(Ok(()))));
+
+        let manager = ContractManager::new(
+            Arc::new(mock_storage),
+            Arc::new(MockStarknetClient::default()),
+            CollectionIdentificationStrategy::default(),
+        )
+        .with_cache_size(2);
+
+        let json = r#"{"0x1": "other", "0x2": "e_r_c1155"}"#;
+        assert_eq!(manager.load_from_json(json).unwrap(), 2);
+        // The cache is full.
+        assert_eq!(manager.load_from_json(r#"{"0x3": "other"}"#).unwrap(), 0);
+
+        let identify = |address: u64| manager.identify_contract(address.into(), 0, "SN_MAIN");
+        assert_eq!(identify(2).await.unwrap(), ContractType::ERC1155);
+        assert_eq!(identify(0x10).await.unwrap(), ContractType::ERC721);
+
+        assert_eq!(manager.cached_contract_type(FieldElement::ONE), None);
+        assert_eq!(
+            manager.cached_contract_type(FieldElement::TWO),
+            Some(ContractType::ERC1155)
+        );
+        assert_eq!(
+            manager.cached_contract_type(FieldElement::from(0x10_u64)),
+            Some(ContractType::ERC721)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_identify_contract_concurrently_uses_cache() {
+        let mut mock_storage = storage_without_overrides();
+        mock_storage
+            .expect_get_contract_type()
+            .times(1)
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(Some(ContractType::ERC721)))));
+
+        let manager = Arc::new(ContractManager::new(
+            Arc::new(mock_storage),
+            Arc::new(MockStarknetClient::default()),
+            CollectionIdentificationStrategy::default(),
+        ));
+
+        let address = FieldElement::from_hex_be("0x1234").unwrap();
+
+        // First call populates the cache from the storage.
+        assert_eq!(
+            manager
+                .identify_contract(address, 0, "SN_MAIN")
+                .await
+                .unwrap(),
+            ContractType::ERC721
+        );
+
+        let handles = (0..4).map(|_| {
+            let manager = Arc::clone(&manager);
+            tokio::spawn(async move { manager.identify_contract(address, 0, "SN_MAIN").await })
+        });
+
+        for result in futures::future::join_all(handles).await {
+            assert_eq!(result.unwrap().unwrap(), ContractType::ERC721);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_class_hash_strategy() {
+        let erc721_class = FieldElement::from_hex_be("0x721").unwrap();
+
+        let mut mock_client = MockStarknetClient::default();
+        mock_client
+            .expect_class_hash_at()
+            .returning(move |address, _| {
+                if address == FieldElement::ONE {
+                    Ok(erc721_class)
+                } else {
+                    Ok(FieldElement::TWO)
+                }
+            });
+
+        let manager = ContractManager::new(
+            Arc::new(storage_without_overrides()),
+            Arc::new(mock_client),
+            CollectionIdentificationStrategy::ClassHash {
+                erc721: std::collections::HashSet::from([erc721_class]),
+                erc1155: std::collections::HashSet::new(),
+            },
+        );
+
+        assert_eq!(
+            manager.get_contract_type(FieldElement::ONE).await.unwrap(),
+            ContractType::ERC721
+        );
+        assert_eq!(
+            manager.get_contract_type(FieldElement::TWO).await.unwrap(),
+            ContractType::Other
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refresh_contract_type() {
+        use crate::storage::InMemoryStorage;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let erc721_class = FieldElement::from_hex_be("0x721").unwrap();
+        let upgraded = Arc::new(AtomicBool::new(false));
+        let is_upgraded = Arc::clone(&upgraded);
+
+        let mut mock_client = MockStarknetClient::default();
+        mock_client.expect_class_hash_at().returning(move |_, _| {
+            if is_upgraded.load(Ordering::SeqCst) {
+                Ok(erc721_class)
+            } else {
+                Ok(FieldElement::TWO)
+            }
+        });
+        mock_client
+            .expect_call_contract()
+            .returning(|_, _, _, _| Err(StarknetClientError::EntrypointNotFound("".to_string())));
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let manager = ContractManager::new(
+            Arc::clone(&storage),
+            Arc::new(mock_client),
+            CollectionIdentificationStrategy::ClassHash {
+                erc721: std::collections::HashSet::from([erc721_class]),
+                erc1155: std::collections::HashSet::new(),
+            },
+        );
+
+        assert_eq!(
+            manager
+                .identify_contract(FieldElement::ONE, 0, "SN_MAIN")
+                .await
+                .unwrap(),
+            ContractType::Other
+        );
+
+        // The cached type is kept until the contract is refreshed.
+        upgraded.store(true, Ordering::SeqCst);
+        assert_eq!(
+            manager
+                .identify_contract(FieldElement::ONE, 0, "SN_MAIN")
+                .await
+                .unwrap(),
+            ContractType::Other
+        );
+        assert_eq!(
+            manager
+                .refresh_contract_type(FieldElement::ONE, "SN_MAIN")
+                .await
+                .unwrap(),
+            ContractType::ERC721
+        );
+        assert_eq!(
+            manager.cached_contract_type(FieldElement::ONE),
+            Some(ContractType::ERC721)
+        );
+        assert_eq!(
+            storage
+                .get_contract_type(&to_hex_str(&FieldElement::ONE), "SN_MAIN")
+                .await
+                .unwrap(),
+            Some(ContractType::ERC721)
+        );
+    }
+
+    #[test]
+    fn test_contract_type_from_event_layout() {
+        let mut event = EmittedEvent {
+            from_address: FieldElement::ONE,
+            block_hash: None,
+            transaction_hash: FieldElement::ONE,
+            block_number: None,
+            keys: vec![
+                selector!("Transfer"),
+                FieldElement::ZERO,
+                FieldElement::ONE,
+                FieldElement::ONE,
+                FieldElement::ZERO,
+            ],
+            data: vec![],
+        };
+
+        assert_eq!(
+            ContractManager::<MockStorage, MockStarknetClient>::contract_type_from_event_layout(
+                &event
+            ),
+            Some(ContractType::ERC721)
+        );
+
+        // Cairo 0 layout is ambiguous with ERC20.
+        event.data = event.keys.drain(1..).collect();
+        assert_eq!(
+            ContractManager::<MockStorage, MockStarknetClient>::contract_type_from_event_layout(
+                &event
+            ),
+            None
+        );
+
+        event.keys = vec![selector!("TransferSingle")];
+        assert_eq!(
+            ContractManager::<MockStorage, MockStarknetClient>::contract_type_from_event_layout(
+                &event
+            ),
+            Some(ContractType::ERC1155)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_class_hash_memo() {
+        use crate::storage::InMemoryStorage;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let class_hash = FieldElement::from_hex_be("0x721").unwrap();
+
+        let probes = Arc::new(AtomicU64::new(0));
+        let probes_count = Arc::clone(&probes);
+
+        let mut mock_client = MockStarknetClient::default();
+        mock_client
+            .expect_class_hash_at()
+            .returning(move |_, _| Ok(class_hash));
+        mock_client
+            .expect_call_contract()
+            .returning(move |_, selector, _, _| {
+                if selector == selector!("ownerOf") {
+                    probes_count.fetch_add(1, Ordering::SeqCst);
+                    Ok(vec![FieldElement::ONE])
+                } else if selector == selector!("totalSupply") {
+                    Ok(vec![FieldElement::from(7_u64), FieldElement::ZERO])
+                } else {
+                    Err(StarknetClientError::EntrypointNotFound("".to_string()))
+                }
+            });
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let manager = ContractManager::new(
+            Arc::clone(&storage),
+            Arc::new(mock_client),
+            CollectionIdentificationStrategy::EntrypointProbing,
+        );
+
+        // Both contracts share the same class hash, only the first one is probed.
+        for address in [FieldElement::ONE, FieldElement::TWO] {
+            assert_eq!(
+                manager
+                    .identify_contract(address, 0, "SN_MAIN")
+                    .await
+                    .unwrap(),
+                ContractType::ERC721
+            );
+        }
+        assert_eq!(probes.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            storage
+                .dump()
+                .class_hash_types
+                .get(&to_hex_str(&class_hash)),
+            Some(&ContractType::ERC721)
+        );
+        // Total supply is cached at identification time.
+        assert_eq!(
+            storage
+                .dump()
+                .total_supplies
+                .get(&to_hex_str(&FieldElement::ONE)),
+            Some(&7)
+        );
+
+        // The memo survives a restart.
+        let restarted = ContractManager::new(
+            Arc::clone(&storage),
+            Arc::clone(&manager.client),
+            CollectionIdentificationStrategy::EntrypointProbing,
+        );
+        restarted
+            .identify_contract(FieldElement::THREE, 0, "SN_MAIN")
+            .await
+            .unwrap();
+        assert_eq!(probes.load(Ordering::SeqCst), 1);
+
+        restarted.invalidate_class_hash(class_hash).await.unwrap();
+        assert!(storage.dump().class_hash_types.is_empty());
+
+        restarted
+            .identify_contract(FieldElement::from(4_u64), 0, "SN_MAIN")
+            .await
+            .unwrap();
+        assert_eq!(probes.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_contract_type_overrides_survive_restart() {
+        use crate::storage::InMemoryStorage;
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let address = FieldElement::from_hex_be("0x1234").unwrap();
+        storage
+            .register_contract_info(
+                &ContractInfo {
+                    contract_address: to_hex_str(&address),
+                    contract_type: ContractType::Other.to_string(),
+                    chain_id: "SN_MAIN".to_string(),
+                    ..Default::default()
+                },
+                0,
+                "SN_MAIN",
+            )
+            .await
+            .unwrap();
+
+        // No expectation is set on the client, the node is never called.
+        let manager = ContractManager::new(
+            Arc::clone(&storage),
+            Arc::new(MockStarknetClient::default()),
+            CollectionIdentificationStrategy::default(),
+        );
+        assert_eq!(
+            manager
+                .identify_contract(address, 0, "SN_MAIN")
+                .await
+                .unwrap(),
+            ContractType::Other
+        );
+        manager
+            .register_override(address, ContractType::ERC1155)
+            .await
+            .unwrap();
+        assert_eq!(
+            manager
+                .identify_contract(address, 0, "SN_MAIN")
+                .await
+                .unwrap(),
+            ContractType::ERC1155
+        );
+
+        let restarted = ContractManager::new(
+            Arc::clone(&storage),
+            Arc::new(MockStarknetClient::default()),
+            CollectionIdentificationStrategy::default(),
+        );
+        assert_eq!(restarted.cached_contract_type(address), None);
+        assert_eq!(
+            restarted.get_contract_type(address).await.unwrap(),
+            ContractType::ERC1155
+        );
+        assert_eq!(restarted.load_from_storage().await.unwrap(), 1);
+        assert_eq!(
+            restarted.cached_contract_type(address),
+            Some(ContractType::ERC1155)
+        );
+    }
+}
@@ -1,24 +1,54 @@
-use crate::storage::types::{EventType, TokenSaleEvent, TokenTransferEvent};
+use crate::managers::BlockContext;
+use crate::storage::types::{
+    DecodedTransfer, EventType, TokenEvent, TokenSaleEvent, TokenTransferEvent, TransferLayout,
+};
 use crate::storage::Storage;
 use crate::{
-    ContractType, VENTORY_MARKETPLACE_EVENT_HEX, VENTORY_MARKETPLACE_OFFER_ACCEPTED_EVENT_HEX,
+    ContractType, IndexerResult, ELEMENT_MARKETPLACE_EVENT_HEX, EVENTS_LOG_TARGET,
+    VENTORY_MARKETPLACE_EVENT_HEX, VENTORY_MARKETPLACE_OFFER_ACCEPTED_EVENT_HEX,
 };
 use anyhow::{anyhow, Result};
 use ark_starknet::{format::to_hex_str, CairoU256};
+use dashmap::DashMap;
+use serde::Serialize;
+use starknet::core::crypto::compute_hash_on_elements;
 use starknet::core::types::{EmittedEvent, FieldElement};
 use starknet::core::utils::starknet_keccak;
 use starknet::macros::selector;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
-use tracing::trace;
+use tracing::{trace, warn};
 
 const TRANSFER_SELECTOR: FieldElement = selector!("Transfer");
+const ERC1155_TRANSFER_SELECTORS: [FieldElement; 2] =
+    [selector!("TransferSingle"), selector!("TransferBatch")];
+/// Felts of the transfer info: from, to and the u256 token id.
+const TRANSFER_INFO_FELTS: usize = 4;
 const ELEMENT_NFT_MARKETPLACE_HEX: &str =
     "0x351e5a57ea6ca22e3e3cd212680ef7f3b57404609bda942a5e75ba4724b55e0";
 
+/// Layouts tried in order when the layout of a contract is not known yet.
+const TRANSFER_LAYOUTS: [TransferLayout; 2] = [TransferLayout::Keys, TransferLayout::Data];
+
+/// Stored event which does not match the current schema,
+/// reported by `EventManager::validate_event_schema`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum SchemaIssue {
+    /// A field required by the current schema is empty.
+    MissingField {
+        event_id: String,
+        field: &'static str,
+    },
+}
+
 #[derive(Debug)]
 pub struct EventManager<S: Storage> {
     storage: Arc<S>,
+    /// Transfer layout detected for each contract address.
+    layouts: DashMap<FieldElement, TransferLayout>,
+    /// Selectors removed from `keys_selector`.
+    key_denylist: HashSet<FieldElement>,
 }
 
 impl<S: Storage> EventManager<S> {
@@ -26,7 +56,23 @@ impl<S: Storage> EventManager<S> {
     pub fn new(storage: Arc<S>) -> Self {
         EventManager {
             storage: Arc::clone(&storage),
+            layouts: DashMap::new(),
+            key_denylist: HashSet::new(),
+        }
+    }
+
+    /// Removes the given selectors from `keys_selector`, see
+    /// `PontosConfig::event_key_denylist`. A denylist removing all the
+    /// selectors is ignored, an empty filter matching all the events.
+    pub fn with_key_denylist(mut self, key_denylist: HashSet<FieldElement>) -> Self {
+        self.key_denylist = key_denylist;
+
+        if self.keys_selector().map_or(true, |keys| keys[0].is_empty()) {
+            warn!("The event key denylist removes all the selectors, ignored");
+            self.key_denylist.clear();
         }
+
+        self
     }
 
     /// Returns the selectors used to filter events.
@@ -41,12 +87,101 @@ impl<S: Storage> EventManager<S> {
             FieldElement::from_hex_be(VENTORY_MARKETPLACE_OFFER_ACCEPTED_EVENT_HEX)
                 .expect("Failed to parse ventory accepted offer selector");
 
-        Some(vec![vec![
+        Some(vec![[
             TRANSFER_SELECTOR,
             element_nft_marketplace,
             ventory_nft_marketplace,
             ventory_accepted_offer_event,
-        ]])
+        ]
+        .into_iter()
+        .filter(|selector| !self.key_denylist.contains(selector))
+        .collect()])
+    }
+
+    /// Returns a hash of the selectors used to filter events,
+    /// independent of their order.
+    pub fn selector_hash(&self) -> String {
+        let mut selectors: Vec<FieldElement> = self
+            .keys_selector()
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .collect();
+        selectors.sort();
+        selectors.dedup();
+
+        to_hex_str(&compute_hash_on_elements(&selectors))
+    }
+
+    /// Sets the block number of the events of the pending block
+    /// identified by its timestamp, now promoted to latest.
+    pub async fn finalize_pending_events(
+        &self,
+        block_timestamp: u64,
+        block_number: u64,
+    ) -> Result<()> {
+        Ok(self
+            .storage
+            .finalize_pending_events(block_timestamp, block_number)
+            .await?)
+    }
+
+    /// Returns all the indexed events of the given transaction.
+    pub async fn events_for_transaction(
+        &self,
+        transaction_hash: FieldElement,
+    ) -> Result<Vec<TokenEvent>> {
+        Ok(self
+            .storage
+            .get_event_by_tx_hash(&to_hex_str(&transaction_hash))
+            .await?)
+    }
+
+    /// Returns the number of events indexed in each block of `from..=to`,
+    /// as `(block_number, event_count)` sorted by block number.
+    /// The blocks without events are omitted.
+    pub async fn block_event_histogram(&self, from: u64, to: u64) -> Result<Vec<(u64, u64)>> {
+        Ok(self.storage.count_events_by_block(from, to).await?)
+    }
+
+    /// Checks up to `sample_size` stored transfer events of the contract
+    /// against the current schema, and returns the fields left empty by the
+    /// versions which did not record them, before a migration.
+    pub async fn validate_event_schema(
+        &self,
+        contract: FieldElement,
+        sample_size: usize,
+    ) -> IndexerResult<Vec<SchemaIssue>> {
+        let events = self
+            .storage
+            .get_contract_transfer_events(&to_hex_str(&contract), sample_size)
+            .await?;
+
+        let mut issues = vec![];
+        for event in events {
+            let fields = [
+                ("event_id", event.event_id.is_empty()),
+                ("transaction_hash", event.transaction_hash.is_empty()),
+                ("from_address", event.from_address.is_empty()),
+                ("to_address", event.to_address.is_empty()),
+                ("token_id", event.token_id.is_empty()),
+                ("token_id_hex", event.token_id_hex.is_empty()),
+                ("contract_type", event.contract_type.is_empty()),
+                ("event_type", event.event_type == EventType::Uninitialized),
+            ];
+
+            issues.extend(
+                fields
+                    .into_iter()
+                    .filter(|(_, missing)| *missing)
+                    .map(|(field, _)| SchemaIssue::MissingField {
+                        event_id: event.event_id.clone(),
+                        field,
+                    }),
+            );
+        }
+
+        Ok(issues)
     }
 
     pub async fn register_sale_event(
@@ -63,7 +198,7 @@ impl<S: Storage> EventManager<S> {
     pub async fn format_ventory_sale_or_accepted_offer_event(
         &self,
         event: &EmittedEvent,
-        block_timestamp: u64,
+        block: &BlockContext,
     ) -> Result<TokenSaleEvent> {
         let _listing_counter = event
             .data
@@ -101,12 +236,12 @@ impl<S: Storage> EventManager<S> {
             high: 0,
         };
 
-        let event_id = Self::get_event_id(&token_id, seller, buyer, block_timestamp, event);
+        let event_id = Self::get_event_id(&token_id, seller, buyer, block.timestamp, event);
 
         Ok(TokenSaleEvent {
             event_id: to_hex_str(&event_id),
             event_type: EventType::Sale,
-            block_number: event.block_number,
+            block_number: block.block_number(),
             from_address: to_hex_str(seller),
             to_address: to_hex_str(buyer),
             nft_contract_address: to_hex_str(asset_contract),
@@ -114,7 +249,7 @@ impl<S: Storage> EventManager<S> {
             transaction_hash: to_hex_str(&event.transaction_hash),
             token_id_hex: token_id.to_hex(),
             token_id: token_id.to_decimal(false),
-            timestamp: block_timestamp,
+            timestamp: block.timestamp,
             updated_at: Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
             quantity: 1,
             currency_address: None,
@@ -127,7 +262,7 @@ impl<S: Storage> EventManager<S> {
     pub async fn format_element_sale_event(
         &self,
         event: &EmittedEvent,
-        block_timestamp: u64,
+        block: &BlockContext,
     ) -> Result<TokenSaleEvent> {
         if event.keys.len() < 4 {
             return Err(anyhow!("Can't find event data into this event"));
@@ -208,14 +343,14 @@ impl<S: Storage> EventManager<S> {
             &token_id,
             maker_address,
             taker_address,
-            block_timestamp,
+            block.timestamp,
             event,
         );
 
         Ok(TokenSaleEvent {
             event_id: to_hex_str(&event_id),
             event_type: EventType::Sale,
-            block_number: event.block_number,
+            block_number: block.block_number(),
             from_address: to_hex_str(maker_address),
             to_address: to_hex_str(taker_address),
             nft_contract_address: to_hex_str(nft_contract_address),
@@ -223,7 +358,7 @@ impl<S: Storage> EventManager<S> {
             transaction_hash: to_hex_str(&event.transaction_hash),
             token_id_hex: token_id.to_hex(),
             token_id: token_id.to_decimal(false),
-            timestamp: block_timestamp,
+            timestamp: block.timestamp,
             updated_at: Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
             quantity: (*quantity)
                 .try_into()
@@ -235,68 +370,244 @@ impl<S: Storage> EventManager<S> {
         })
     }
 
+    /// Formats a token transfer event based on the event content,
+    /// without registering it into the storage.
+    /// Returns the token_id if the event were identified.
+    pub fn format_transfer_event(
+        event: &EmittedEvent,
+        contract_type: ContractType,
+        block: &BlockContext,
+    ) -> Result<(CairoU256, TokenTransferEvent)> {
+        let transfer =
+            Self::decode_transfer_event_in_layout(event, None, contract_type, block, now_secs())?;
+        let token_event = transfer.to_token_event();
+
+        Ok((transfer.token_id, token_event))
+    }
+
+    /// Decodes a transfer event into typed values, as `format_transfer_event`
+    /// without rendering any string.
+    pub fn decode_transfer_event(
+        event: &EmittedEvent,
+        contract_type: ContractType,
+        block: &BlockContext,
+    ) -> Result<DecodedTransfer> {
+        Self::decode_transfer_event_in_layout(event, None, contract_type, block, now_secs())
+    }
+
+    /// Decodes a transfer event into typed values, without formatting
+    /// any string, trying first the layout already known for the contract, if any.
+    fn decode_transfer_event_in_layout(
+        event: &EmittedEvent,
+        known_layout: Option<TransferLayout>,
+        contract_type: ContractType,
+        block: &BlockContext,
+        updated_at: u64,
+    ) -> Result<DecodedTransfer> {
+        trace!(
+            target: EVENTS_LOG_TARGET,
+            "Decode transfer event: event={:?}, contract_type={:?}, block={:?}",
+            event,
+            contract_type,
+            block
+        );
+
+        let (layout, (from, to, token_id)) = Self::decode_transfer_info(event, known_layout)
+            .ok_or_else(|| anyhow!("Can't find event data into this event"))?;
+
+        Ok(DecodedTransfer {
+            timestamp: block.timestamp,
+            from_address: from,
+            to_address: to,
+            contract_address: event.from_address,
+            transaction_hash: event.transaction_hash,
+            event_id: Self::get_event_id(&token_id, &from, &to, block.timestamp, event),
+            token_id,
+            event_type: Self::get_event_type(from, to),
+            block_number: block.block_number(),
+            quantity: Self::get_transfer_quantity(event, &contract_type),
+            contract_type,
+            layout,
+            updated_at,
+        })
+    }
+
     /// Formats & register a token event based on the event content.
     /// Returns the token_id if the event were identified.
     pub async fn format_and_register_event(
         &self,
         event: &EmittedEvent,
         contract_type: ContractType,
-        block_timestamp: u64,
+        block: &BlockContext,
     ) -> Result<(CairoU256, TokenTransferEvent)> {
-        let mut token_event = TokenTransferEvent::default();
+        let (token_id, token_event) = self.format_event(event, contract_type, block)?;
+        if !self.register_event(&token_event).await? {
+            trace!(
+                target: EVENTS_LOG_TARGET,
+                "Event {} already registered",
+                token_event.event_id
+            );
+        }
 
-        trace!(
-            "Format transfer event to insert: event={:?}, contract_type={:?}, timestamp={}",
+        Ok((token_id, token_event))
+    }
+
+    /// Formats a token event based on the event content, memoizing
+    /// the layout of the transfers of the contract.
+    pub fn format_event(
+        &self,
+        event: &EmittedEvent,
+        contract_type: ContractType,
+        block: &BlockContext,
+    ) -> Result<(CairoU256, TokenTransferEvent)> {
+        let transfer = self.decode_event(event, contract_type, block)?;
+        let token_event = transfer.to_token_event();
+
+        Ok((transfer.token_id, token_event))
+    }
+
+    /// Decodes a transfer event into typed values, memoizing
+    /// the layout of the transfers of the contract.
+    /// The event is rendered into strings by `register_decoded_event`.
+    pub fn decode_event(
+        &self,
+        event: &EmittedEvent,
+        contract_type: ContractType,
+        block: &BlockContext,
+    ) -> Result<DecodedTransfer> {
+        let known_layout = self.layouts.get(&event.from_address).map(|l| *l);
+        let transfer = Self::decode_transfer_event_in_layout(
             event,
+            known_layout,
             contract_type,
-            block_timestamp
-        );
+            block,
+            now_secs(),
+        )?;
+
+        if known_layout != Some(transfer.layout) {
+            self.memoize_layout(event.from_address, transfer.layout);
+        }
+
+        Ok(transfer)
+    }
+
+    /// Decodes transfer events of contracts of the same type in the given
+    /// block, as `decode_event` does for each of them. The layout of a
+    /// contract is looked up once for its consecutive events, and the
+    /// update time once for the batch.
+    pub fn decode_event_batch<'a>(
+        &self,
+        events: impl IntoIterator<Item = &'a EmittedEvent>,
+        contract_type: ContractType,
+        block: &BlockContext,
+    ) -> Vec<Result<DecodedTransfer>> {
+        let updated_at = now_secs();
+        let mut known: Option<(FieldElement, Option<TransferLayout>)> = None;
+
+        events
+            .into_iter()
+            .map(|event| {
+                let known_layout = match known {
+                    Some((address, layout)) if address == event.from_address => layout,
+                    _ => self.layouts.get(&event.from_address).map(|l| *l),
+                };
+
+                let transfer = Self::decode_transfer_event_in_layout(
+                    event,
+                    known_layout,
+                    contract_type.clone(),
+                    block,
+                    updated_at,
+                );
+
+                let layout = match &transfer {
+                    Ok(t) if known_layout != Some(t.layout) => {
+                        self.memoize_layout(event.from_address, t.layout);
+                        Some(t.layout)
+                    }
+                    _ => known_layout,
+                };
+                known = Some((event.from_address, layout));
+
+                transfer
+            })
+            .collect()
+    }
+
+    /// Formats transfer events of a single block emitted by contracts of
+    /// the same type, as `format_event` does for each of them, amortizing
+    /// the lookups of `decode_event_batch`. Fails on the first event
+    /// which can't be decoded.
+    pub async fn format_event_batch(
+        &self,
+        events: Vec<EmittedEvent>,
+        contract_type: ContractType,
+        block_timestamp: u64,
+    ) -> IndexerResult<Vec<TokenEvent>> {
+        let Some(first) = events.first() else {
+            return Ok(vec![]);
+        };
+        let block = BlockContext::from_event(first, block_timestamp);
 
-        // As cairo didn't have keys before, we first check if the data
-        // contains the info. If not, we check into the keys, skipping the first
-        // element which is the selector.
-        let event_info: (FieldElement, FieldElement, CairoU256) =
-            if let Some(d_info) = Self::get_event_info_from_felts(&event.data) {
-                d_info
-            } else if let Some(k_info) = Self::get_event_info_from_felts(&event.keys[1..]) {
-                k_info
-            } else {
-                return Err(anyhow!("Can't find event data into this event"));
-            };
-
-        let (from, to, token_id) = event_info;
-
-        let event_id = Self::get_event_id(&token_id, &from, &to, block_timestamp, event);
-
-        token_event.from_address = to_hex_str(&from);
-        token_event.to_address = to_hex_str(&to);
-        token_event.contract_address = to_hex_str(&event.from_address);
-        token_event.transaction_hash = to_hex_str(&event.transaction_hash);
-        token_event.token_id_hex = token_id.to_hex();
-        token_event.token_id = token_id.to_decimal(false);
-        token_event.timestamp = block_timestamp;
-        token_event.event_type = Self::get_event_type(from, to);
-        token_event.event_id = to_hex_str(&event_id);
-        token_event.block_number = event.block_number;
-        token_event.contract_type = contract_type.to_string();
-        token_event.updated_at = Some(
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+        self.decode_event_batch(&events, contract_type, &block)
+            .into_iter()
+            .map(|transfer| Ok(TokenEvent::Transfer(transfer?.to_token_event())))
+            .collect()
+    }
+
+    fn memoize_layout(&self, contract_address: FieldElement, layout: TransferLayout) {
+        trace!(
+            target: EVENTS_LOG_TARGET,
+            "Transfer layout of contract 0x{:064x}: {:?}",
+            contract_address,
+            layout
         );
+        self.layouts.insert(contract_address, layout);
+    }
 
-        trace!("Registering event: {:?}", token_event);
+    /// Registers a token event formatted with `format_event`.
+    /// An event already registered is ignored, and false is returned.
+    pub async fn register_event(&self, token_event: &TokenTransferEvent) -> Result<bool> {
+        trace!(target: EVENTS_LOG_TARGET, "Registering event: {:?}", token_event);
 
-        self.storage
-            .register_transfer_event(&token_event, block_timestamp)
-            .await?;
+        Ok(self.storage.upsert_event(token_event).await?)
+    }
+
+    /// Registers a transfer decoded with `decode_event`.
+    /// Returns the event rendered as stored, or `None` if
+    /// the event was already registered.
+    pub async fn register_decoded_event(
+        &self,
+        transfer: &DecodedTransfer,
+    ) -> Result<Option<TokenTransferEvent>> {
+        trace!(target: EVENTS_LOG_TARGET, "Registering event: {:?}", transfer);
+
+        Ok(self.storage.upsert_decoded_event(transfer).await?)
+    }
 
-        Ok((token_id, token_event.clone()))
+    /// Returns the number of tokens transferred by the event.
+    /// Only ERC1155 single transfers can transfer more than one token,
+    /// the value being the last u256 of the event data.
+    pub fn get_transfer_quantity(event: &EmittedEvent, contract_type: &ContractType) -> u64 {
+        let is_single_transfer = event.keys.first() == Some(&selector!("TransferSingle"));
+
+        if *contract_type != ContractType::ERC1155 || !is_single_transfer || event.data.len() < 2 {
+            return 1;
+        }
+
+        // The high part is ignored, such quantities are not realistic.
+        event.data[event.data.len() - 2]
+            .try_into()
+            .unwrap_or(u64::MAX)
     }
 
+    /// Returns the type of a transfer from its sender and recipient.
+    /// A transfer from and to the zero address is neither a mint
+    /// nor a burn, to keep the collection supply unchanged.
     pub fn get_event_type(from: FieldElement, to: FieldElement) -> EventType {
-        if from == FieldElement::ZERO {
+        if from == FieldElement::ZERO && to == FieldElement::ZERO {
+            EventType::Transfer
+        } else if from == FieldElement::ZERO {
             EventType::Mint
         } else if to == FieldElement::ZERO {
             EventType::Burn
@@ -305,6 +616,48 @@ impl<S: Storage> EventManager<S> {
         }
     }
 
+    /// Classifies an event from its keys only, without any storage nor call.
+    ///
+    /// The sales of the supported marketplaces are classified by their selector.
+    /// The transfers are classified as mint, burn or transfer when their addresses
+    /// are in the keys (Cairo 1 `Transfer` with the token id in the keys, and
+    /// ERC1155 transfers after the operator).
+    /// Returns `None` for any other event, including the transfers having
+    /// their addresses in the data (Cairo 0), which can't be classified from
+    /// their keys.
+    pub fn event_type_from_keys(keys: &[FieldElement]) -> Option<EventType> {
+        let selector = keys.first()?;
+
+        let is_sale = [
+            ELEMENT_MARKETPLACE_EVENT_HEX,
+            VENTORY_MARKETPLACE_EVENT_HEX,
+            VENTORY_MARKETPLACE_OFFER_ACCEPTED_EVENT_HEX,
+        ]
+        .iter()
+        .any(|hex| FieldElement::from_hex_be(hex).ok().as_ref() == Some(selector));
+        if is_sale {
+            return Some(EventType::Sale);
+        }
+
+        // Index of the from address in the keys. The token id of a `Transfer`
+        // must also be in the keys, to exclude the ERC20 transfers.
+        let from_index = if *selector == TRANSFER_SELECTOR {
+            if keys.len() <= TRANSFER_INFO_FELTS {
+                return None;
+            }
+            1
+        } else if ERC1155_TRANSFER_SELECTORS.contains(selector) {
+            2
+        } else {
+            return None;
+        };
+
+        match keys.get(from_index..from_index + 2) {
+            Some([from, to]) => Some(Self::get_event_type(*from, *to)),
+            _ => None,
+        }
+    }
+
     /// Returns the event id as a field element.
     /// We enforce everything to be a field element to have fix
     /// bytes lengths, and ease the re-computation of this value
@@ -327,30 +680,90 @@ impl<S: Storage> EventManager<S> {
         starknet_keccak(&bytes)
     }
 
+    /// Returns the felts holding the transfer info (from, to, token_id)
+    /// in the given layout, if the event has enough of them.
+    /// In the keys, the first element is skipped as it is the selector.
+    ///
+    /// This defines the layouts accepted by the decoders, and is also
+    /// used by `has_supported_shape` to discard events early.
+    fn transfer_info_felts(
+        event: &EmittedEvent,
+        layout: TransferLayout,
+    ) -> Option<&[FieldElement]> {
+        match layout {
+            TransferLayout::Keys if event.keys.len() > TRANSFER_INFO_FELTS => {
+                Some(&event.keys[1..])
+            }
+            TransferLayout::Data if event.data.len() >= TRANSFER_INFO_FELTS => Some(&event.data),
+            _ => None,
+        }
+    }
+
+    /// Decodes the transfer info (from, to, token_id), with the first layout
+    /// giving plausible info: the known layout of the contract if any,
+    /// then the keys and finally the data.
+    /// Returns the layout used with the info.
+    fn decode_transfer_info(
+        event: &EmittedEvent,
+        known_layout: Option<TransferLayout>,
+    ) -> Option<(TransferLayout, (FieldElement, FieldElement, CairoU256))> {
+        known_layout
+            .into_iter()
+            .chain(TRANSFER_LAYOUTS)
+            .find_map(|layout| {
+                Self::transfer_info_felts(event, layout)
+                    .and_then(Self::get_event_info_from_felts)
+                    .map(|info| (layout, info))
+            })
+    }
+
+    /// Returns false if the keys and data arity of the event can't match
+    /// any transfer supported by the decoders, without any call to the node.
+    /// The check is conservative: ERC1155 transfers are always accepted.
+    pub fn has_supported_shape(event: &EmittedEvent) -> bool {
+        let is_erc1155 = event
+            .keys
+            .first()
+            .map_or(false, |k| ERC1155_TRANSFER_SELECTORS.contains(k));
+
+        is_erc1155
+            || TRANSFER_LAYOUTS
+                .iter()
+                .any(|layout| Self::transfer_info_felts(event, *layout).is_some())
+    }
+
     /// Returns the event info from vector of felts.
     /// Event info are (from, to, token_id).
     ///
     /// This methods considers that the info of the
     /// event is starting at index 0 of the input vector.
+    /// Returns `None` if the token id is not a valid u256 pair,
+    /// meaning the felts are not a transfer info.
     fn get_event_info_from_felts(
         felts: &[FieldElement],
     ) -> Option<(FieldElement, FieldElement, CairoU256)> {
-        if felts.len() < 4 {
+        if felts.len() < TRANSFER_INFO_FELTS {
             return None;
         }
         let from = felts[0];
         let to = felts[1];
 
-        // Safe to unwrap, as emitted events follow cairo sequencer specification.
         let token_id = CairoU256 {
-            low: felts[2].try_into().unwrap(),
-            high: felts[3].try_into().unwrap(),
+            low: felts[2].try_into().ok()?,
+            high: felts[3].try_into().ok()?,
         };
 
         Some((from, to, token_id))
     }
 }
 
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -371,7 +784,7 @@ mod tests {
         EmittedEvent {
             from_address: FieldElement::from_hex_be("0x0").unwrap(),
             block_hash: Some(block_hash),
-            transaction_hash: transaction_hash,
+            transaction_hash,
             block_number: Some(111),
             keys: vec![
                 TRANSFER_SELECTOR,
@@ -392,17 +805,17 @@ mod tests {
         let mut storage = MockStorage::default();
 
         storage
-            .expect_register_transfer_event()
-            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+            .expect_upsert_event()
+            .returning(|_| Box::pin(futures::future::ready(Ok(true))));
 
         let manager = EventManager::new(Arc::new(storage));
 
         let sample_event = setup_sample_event();
         let contract_type = ContractType::ERC721;
-        let timestamp = 1234567890;
+        let block = BlockContext::new(111, 1234567890);
 
         let result = manager
-            .format_and_register_event(&sample_event, contract_type, timestamp)
+            .format_and_register_event(&sample_event, contract_type, &block)
             .await;
 
         assert!(result.is_ok());
@@ -415,14 +828,82 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_format_event_batch() {
+        let manager = EventManager::new(Arc::new(MockStorage::default()));
+
+        // Two contracts, with the transfer info in the keys and in the data.
+        let in_keys = EmittedEvent {
+            keys: vec![
+                TRANSFER_SELECTOR,
+                FieldElement::from_hex_be("0x1234").unwrap(),
+                FieldElement::from_hex_be("0x5678").unwrap(),
+                FieldElement::from(7_u64),
+                FieldElement::ZERO,
+            ],
+            data: vec![],
+            ..setup_sample_event()
+        };
+        let in_data = EmittedEvent {
+            from_address: FieldElement::ONE,
+            keys: vec![TRANSFER_SELECTOR],
+            ..setup_sample_event()
+        };
+        let events = vec![in_keys.clone(), in_keys, in_data.clone(), in_data];
+
+        let batch = manager
+            .format_event_batch(events.clone(), ContractType::ERC721, 1234567890)
+            .await
+            .unwrap();
+
+        let block = BlockContext::new(111, 1234567890);
+        let expected: Vec<String> = events
+            .iter()
+            .map(|e| {
+                EventManager::<MockStorage>::format_transfer_event(e, ContractType::ERC721, &block)
+                    .unwrap()
+                    .1
+                    .event_id
+            })
+            .collect();
+        let ids: Vec<String> = batch
+            .iter()
+            .map(|e| match e {
+                TokenEvent::Transfer(t) => t.event_id.clone(),
+                TokenEvent::Sale(_) => panic!("Unexpected sale"),
+            })
+            .collect();
+        assert_eq!(ids, expected);
+        assert_eq!(
+            manager.layouts.get(&FieldElement::ONE).map(|l| *l),
+            Some(TransferLayout::Data)
+        );
+
+        // An event which can't be decoded fails the batch.
+        let invalid = EmittedEvent {
+            keys: vec![TRANSFER_SELECTOR],
+            data: vec![],
+            ..setup_sample_event()
+        };
+        assert!(manager
+            .format_event_batch(vec![invalid], ContractType::ERC721, 1234567890)
+            .await
+            .is_err());
+        assert!(manager
+            .format_event_batch(vec![], ContractType::ERC721, 1234567890)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
     #[tokio::test]
     async fn test_format_event_data_extraction_from_data() {
         // Initialize a MockStorage and the EventManager
         let mut storage = MockStorage::default();
 
         storage
-            .expect_register_transfer_event()
-            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+            .expect_upsert_event()
+            .returning(|_| Box::pin(futures::future::ready(Ok(true))));
 
         let manager = EventManager::new(Arc::new(storage));
 
@@ -445,11 +926,11 @@ mod tests {
         };
 
         let contract_type = ContractType::ERC721;
-        let timestamp = 1234567890;
+        let block = BlockContext::new(111, 1234567890);
 
         // Call the `format_event` function
         let result = manager
-            .format_and_register_event(&sample_event, contract_type, timestamp)
+            .format_and_register_event(&sample_event, contract_type, &block)
             .await;
 
         // Assertions
@@ -469,6 +950,135 @@ mod tests {
         assert_eq!(token_id.high, 121314_u128);
     }
 
+    #[test]
+    fn test_format_transfer_event_without_storage() {
+        // No expectation is set on the storage, any write would panic.
+        let sample_event = setup_sample_event();
+
+        let (token_id, token_event) = EventManager::<MockStorage>::format_transfer_event(
+            &sample_event,
+            ContractType::ERC721,
+            &BlockContext::new(111, 1234567890),
+        )
+        .unwrap();
+
+        assert_eq!(token_id.low, 91011_u128);
+        assert_eq!(token_event.event_type, EventType::Transfer);
+        assert_eq!(token_event.contract_type, "ERC721");
+        assert_eq!(token_event.block_number, Some(111));
+    }
+
+    #[test]
+    fn test_event_type_from_keys() {
+        let classify = EventManager::<MockStorage>::event_type_from_keys;
+        let (zero, a, b) = (FieldElement::ZERO, FieldElement::ONE, FieldElement::TWO);
+        let id = [FieldElement::from(7_u64), FieldElement::ZERO];
+
+        let transfer = |from, to| [&[TRANSFER_SELECTOR, from, to][..], &id[..]].concat();
+        assert_eq!(classify(&transfer(zero, a)), Some(EventType::Mint));
+        assert_eq!(classify(&transfer(a, zero)), Some(EventType::Burn));
+        assert_eq!(classify(&transfer(a, b)), Some(EventType::Transfer));
+
+        // ERC1155, the operator being first.
+        let single = [selector!("TransferSingle"), b, zero, a];
+        assert_eq!(classify(&single), Some(EventType::Mint));
+
+        let sale = FieldElement::from_hex_be(VENTORY_MARKETPLACE_EVENT_HEX).unwrap();
+        assert_eq!(classify(&[sale]), Some(EventType::Sale));
+
+        // Cairo 0 transfer and ERC20 transfer.
+        assert_eq!(classify(&[TRANSFER_SELECTOR]), None);
+        assert_eq!(classify(&[TRANSFER_SELECTOR, a, b]), None);
+        assert_eq!(classify(&[selector!("Approval"), a, b]), None);
+        assert_eq!(classify(&[]), None);
+    }
+
+    #[test]
+    fn test_format_transfer_event_uses_block_context() {
+        // The event data of the block is ignored, the block being re-indexed.
+        let sample_event = setup_sample_event();
+
+        let (_, token_event) = EventManager::<MockStorage>::format_transfer_event(
+            &sample_event,
+            ContractType::ERC721,
+            &BlockContext::new(42, 1000),
+        )
+        .unwrap();
+
+        assert_eq!(token_event.block_number, Some(42));
+        assert_eq!(token_event.timestamp, 1000);
+
+        let (_, token_event) = EventManager::<MockStorage>::format_transfer_event(
+            &sample_event,
+            ContractType::ERC721,
+            &BlockContext::pending(2000),
+        )
+        .unwrap();
+
+        assert_eq!(token_event.block_number, None);
+        assert_eq!(token_event.timestamp, 2000);
+    }
+
+    #[tokio::test]
+    async fn test_events_for_transaction() {
+        let mut storage = MockStorage::default();
+
+        storage
+            .expect_get_event_by_tx_hash()
+            .withf(|tx_hash| {
+                tx_hash == "0x0000000000000000000000000000000000000000000000000000000000001538"
+            })
+            .returning(|tx_hash| {
+                let event = TokenEvent::Transfer(TokenTransferEvent {
+                    transaction_hash: tx_hash.to_string(),
+                    ..Default::default()
+                });
+                Box::pin(futures::future::ready(Ok(vec![event])))
+            });
+
+        let manager = EventManager::new(Arc::new(storage));
+
+        let events = manager
+            .events_for_transaction(FieldElement::from_dec_str("5432").unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn test_has_supported_shape() {
+        let mut event = setup_sample_event();
+        assert!(EventManager::<MockStorage>::has_supported_shape(&event));
+
+        // Cairo 1 layout, info in keys.
+        event.keys = vec![TRANSFER_SELECTOR; 5];
+        event.data = vec![];
+        assert!(EventManager::<MockStorage>::has_supported_shape(&event));
+
+        // ERC20 like transfer, with amount as a single felt.
+        event.keys = vec![TRANSFER_SELECTOR];
+        event.data = vec![FieldElement::ONE; 3];
+        assert!(!EventManager::<MockStorage>::has_supported_shape(&event));
+
+        event.keys = vec![];
+        event.data = vec![];
+        assert!(!EventManager::<MockStorage>::has_supported_shape(&event));
+
+        event.keys = vec![selector!("TransferBatch")];
+        assert!(EventManager::<MockStorage>::has_supported_shape(&event));
+    }
+
+    #[test]
+    fn test_selector_hash() {
+        let manager = EventManager::new(Arc::new(MockStorage::default()));
+
+        let hash = manager.selector_hash();
+        assert!(hash.starts_with("0x"));
+        assert_eq!(hash, manager.selector_hash());
+        assert_ne!(hash, to_hex_str(&compute_hash_on_elements(&[])));
+    }
+
     #[test]
     fn test_keys_selector() {
         let storage = Arc::new(MockStorage::default());
@@ -489,6 +1099,30 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_keys_selector_with_denylist() {
+        let element = FieldElement::from_hex_be(ELEMENT_NFT_MARKETPLACE_HEX).unwrap();
+        let manager = EventManager::new(Arc::new(MockStorage::default()))
+            .with_key_denylist(HashSet::from([element, selector!("Approval")]));
+
+        let keys = manager.keys_selector().unwrap();
+        assert_eq!(keys[0].len(), 3);
+        assert!(!keys[0].contains(&element));
+        assert_ne!(
+            manager.selector_hash(),
+            EventManager::new(Arc::new(MockStorage::default())).selector_hash()
+        );
+
+        // Denying all the selectors would fetch all the events.
+        let all = manager.keys_selector().unwrap()[0]
+            .iter()
+            .copied()
+            .chain([element])
+            .collect();
+        let manager = EventManager::new(Arc::new(MockStorage::default())).with_key_denylist(all);
+        assert_eq!(manager.keys_selector().unwrap()[0].len(), 4);
+    }
+
     /// Tests the `get_event_info_from_felts` method with correct input format and length.
     /// Ensures that the method correctly extracts and returns the event info.
     #[test]
@@ -510,7 +1144,7 @@ mod tests {
         let result = EventManager::<MockStorage>::get_event_info_from_felts(&sample_data);
 
         // Assert the output
-        assert_eq!(result.is_some(), true);
+        assert!(result.is_some());
         let (from, to, token_id) = result.unwrap();
         assert_eq!(from, from_value);
         assert_eq!(to, to_value);
@@ -532,6 +1166,204 @@ mod tests {
         let result = EventManager::<MockStorage>::get_event_info_from_felts(&sample_data);
 
         // Assert the output
-        assert_eq!(result.is_none(), true);
+        assert!(result.is_none());
+    }
+
+    /// Cairo 1 ERC721 transfer: from, to and the u256 token id in keys.
+    fn keyed_transfer(token_id_low: u128, token_id_high: u128) -> EmittedEvent {
+        EmittedEvent {
+            from_address: FieldElement::from_hex_be("0x721").unwrap(),
+            block_hash: Some(FieldElement::ONE),
+            transaction_hash: FieldElement::from_hex_be("0x5432").unwrap(),
+            block_number: Some(111),
+            keys: vec![
+                TRANSFER_SELECTOR,
+                FieldElement::from_hex_be("0x1234").unwrap(),
+                FieldElement::from_hex_be("0x5678").unwrap(),
+                token_id_low.into(),
+                token_id_high.into(),
+            ],
+            data: vec![],
+        }
+    }
+
+    /// Cairo 0 ERC721 transfer: from, to and the u256 token id in data.
+    fn data_transfer(token_id_low: u128, token_id_high: u128) -> EmittedEvent {
+        EmittedEvent {
+            keys: vec![TRANSFER_SELECTOR],
+            data: vec![
+                FieldElement::from_hex_be("0x1234").unwrap(),
+                FieldElement::from_hex_be("0x5678").unwrap(),
+                token_id_low.into(),
+                token_id_high.into(),
+            ],
+            ..keyed_transfer(0, 0)
+        }
+    }
+
+    #[test]
+    fn test_transfer_layouts() {
+        let fixtures = [
+            (keyed_transfer(7, 1), TransferLayout::Keys),
+            (data_transfer(7, 1), TransferLayout::Data),
+        ];
+
+        for (event, layout) in fixtures {
+            let (token_id, token_event) = EventManager::<MockStorage>::format_transfer_event(
+                &event,
+                ContractType::ERC721,
+                &BlockContext::new(111, 1234567890),
+            )
+            .unwrap();
+
+            assert_eq!(token_event.layout, Some(layout));
+            assert_eq!(token_id.low, 7);
+            assert_eq!(token_id.high, 1);
+            // 2^128 + 7.
+            assert_eq!(
+                token_event.token_id,
+                "340282366920938463463374607431768211463"
+            );
+            assert_eq!(
+                token_event.from_address,
+                to_hex_str(&FieldElement::from_hex_be("0x1234").unwrap())
+            );
+            assert_eq!(
+                token_event.to_address,
+                to_hex_str(&FieldElement::from_hex_be("0x5678").unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn test_transfer_layout_fallback_to_data() {
+        // Enough keys, but the token id in keys is not a valid u256.
+        let mut event = data_transfer(7, 1);
+        event.keys = vec![
+            TRANSFER_SELECTOR,
+            FieldElement::ONE,
+            FieldElement::TWO,
+            FieldElement::MAX,
+            FieldElement::ZERO,
+        ];
+
+        let (token_id, token_event) = EventManager::<MockStorage>::format_transfer_event(
+            &event,
+            ContractType::ERC721,
+            &BlockContext::new(111, 1234567890),
+        )
+        .unwrap();
+
+        assert_eq!(token_event.layout, Some(TransferLayout::Data));
+        assert_eq!(token_id.low, 7);
+        assert_eq!(token_id.high, 1);
+
+        // No layout can be decoded.
+        event.data = vec![];
+        assert!(EventManager::<MockStorage>::format_transfer_event(
+            &event,
+            ContractType::ERC721,
+            &BlockContext::new(111, 1234567890),
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_layout_cached_per_contract() {
+        let mut storage = MockStorage::default();
+        storage
+            .expect_upsert_event()
+            .returning(|_| Box::pin(futures::future::ready(Ok(true))));
+
+        let manager = EventManager::new(Arc::new(storage));
+
+        // An event which can be decoded with both layouts.
+        let mut ambiguous = data_transfer(7, 1);
+        ambiguous.keys = keyed_transfer(8, 2).keys;
+
+        let (_, token_event) = manager
+            .format_and_register_event(
+                &data_transfer(7, 1),
+                ContractType::ERC721,
+                &BlockContext::new(111, 1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(token_event.layout, Some(TransferLayout::Data));
+
+        // The contract is known to use the data layout.
+        let (token_id, token_event) = manager
+            .format_and_register_event(&ambiguous, ContractType::ERC721, &BlockContext::new(111, 1))
+            .await
+            .unwrap();
+        assert_eq!(token_event.layout, Some(TransferLayout::Data));
+        assert_eq!((token_id.low, token_id.high), (7, 1));
+
+        // Other contracts try the keys first.
+        ambiguous.from_address = FieldElement::from_hex_be("0x722").unwrap();
+        let (token_id, token_event) = manager
+            .format_and_register_event(&ambiguous, ContractType::ERC721, &BlockContext::new(111, 1))
+            .await
+            .unwrap();
+        assert_eq!(token_event.layout, Some(TransferLayout::Keys));
+        assert_eq!((token_id.low, token_id.high), (8, 2));
+    }
+
+    #[tokio::test]
+    async fn test_validate_event_schema() {
+        use crate::storage::InMemoryStorage;
+
+        let contract = FieldElement::from_hex_be("0x1234").unwrap();
+        let event = |event_id: &str| TokenTransferEvent {
+            event_id: event_id.to_string(),
+            contract_address: to_hex_str(&contract),
+            transaction_hash: "0xabc".to_string(),
+            from_address: "0x0".to_string(),
+            to_address: "0x1".to_string(),
+            token_id: "1".to_string(),
+            token_id_hex: "0x1".to_string(),
+            contract_type: "erc721".to_string(),
+            event_type: EventType::Mint,
+            ..Default::default()
+        };
+
+        let storage = Arc::new(InMemoryStorage::new());
+        for e in [
+            event("0xa"),
+            TokenTransferEvent {
+                token_id_hex: String::new(),
+                event_type: EventType::Uninitialized,
+                ..event("0xb")
+            },
+            TokenTransferEvent {
+                contract_type: String::new(),
+                ..event("0xc")
+            },
+        ] {
+            storage.register_transfer_event(&e, 0).await.unwrap();
+        }
+
+        let manager = EventManager::new(storage);
+        let issue = |event_id: &str, field| SchemaIssue::MissingField {
+            event_id: event_id.to_string(),
+            field,
+        };
+        assert_eq!(
+            manager.validate_event_schema(contract, 10).await.unwrap(),
+            vec![
+                issue("0xb", "token_id_hex"),
+                issue("0xb", "event_type"),
+                issue("0xc", "contract_type"),
+            ]
+        );
+        assert_eq!(
+            manager.validate_event_schema(contract, 2).await.unwrap(),
+            vec![issue("0xb", "token_id_hex"), issue("0xb", "event_type")]
+        );
+        assert!(manager
+            .validate_event_schema(FieldElement::ONE, 10)
+            .await
+            .unwrap()
+            .is_empty());
     }
 }
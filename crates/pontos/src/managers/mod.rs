@@ -1,11 +1,23 @@
+pub mod block_context;
+pub use block_context::{BlockContext, BlockRef};
+
 pub mod contract_manager;
 pub use contract_manager::ContractManager;
 
 pub mod event_manager;
-pub use event_manager::EventManager;
+pub use event_manager::{EventManager, SchemaIssue};
 
 pub mod token_manager;
-pub use token_manager::TokenManager;
+pub use token_manager::{SupplyDeltas, TokenManager};
+
+pub mod token_query;
+pub use token_query::TokenQuery;
+
+pub mod rpc_permits;
+pub use rpc_permits::RpcPermits;
 
 pub mod block_manager;
-pub use block_manager::{BlockManager, PendingBlockData};
+pub use block_manager::{
+    BlockManager, EstimateResult, IndexingDecision, PendingBlockData, PendingBlockSnapshot,
+    SkipReason,
+};
@@ -0,0 +1,77 @@
+//! Bound on the concurrent calls to the Starknet node.
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Permits shared by all the components of a Pontos instance calling the node
+/// (indexing loops, contracts identification, tokens queries...), bounding
+/// the number of calls running concurrently, see
+/// `PontosConfig::rpc_max_concurrent_calls`.
+///
+/// Cloning shares the same permits. The default instance is unbounded.
+#[derive(Debug, Clone, Default)]
+pub struct RpcPermits {
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl RpcPermits {
+    /// Initializes the permits for at most `max_concurrent_calls` calls,
+    /// unbounded if 0.
+    pub fn new(max_concurrent_calls: usize) -> Self {
+        Self {
+            semaphore: (max_concurrent_calls > 0)
+                .then(|| Arc::new(Semaphore::new(max_concurrent_calls))),
+        }
+    }
+
+    /// Waits for a permit, released when dropped.
+    /// Returns `None` if the calls are unbounded.
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.semaphore {
+            // The semaphore is never closed.
+            Some(semaphore) => semaphore.acquire().await.ok(),
+            None => None,
+        }
+    }
+
+    /// Runs the call once a permit is acquired.
+    /// The permit must not be held while running other calls.
+    pub async fn call<F: Future>(&self, call: F) -> F::Output {
+        let _permit = self.acquire().await;
+        call.await
+    }
+
+    /// Returns the number of calls which can start without waiting,
+    /// `None` if the calls are unbounded.
+    pub fn available(&self) -> Option<usize> {
+        self.semaphore.as_ref().map(|s| s.available_permits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_rpc_permits_bound_concurrency() {
+        let permits = RpcPermits::new(2);
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+
+        let call = || {
+            permits.call(async {
+                let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(n, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            })
+        };
+        futures::future::join_all((0..8).map(|_| call())).await;
+
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        assert_eq!(permits.available(), Some(2));
+        assert_eq!(RpcPermits::new(0).available(), None);
+    }
+}
@@ -1,17 +1,31 @@
-use crate::storage::types::{EventType, TokenInfo, TokenMintInfo, TokenTransferEvent};
+use crate::managers::{BlockContext, RpcPermits};
+use crate::storage::types::{
+    CollectionStats, ContractType, DecodedTransfer, EventType, StorageError, TokenEvent, TokenInfo,
+    TokenMintInfo, TokenTransferEvent,
+};
 use crate::storage::Storage;
+use crate::{IndexerError, IndexerResult};
 use anyhow::{anyhow, Result};
+use ark_starknet::cairo_string_parser::parse_cairo_string;
 use ark_starknet::client::StarknetClient;
 use ark_starknet::format::to_hex_str;
 use ark_starknet::CairoU256;
 use starknet::core::types::*;
 use starknet::macros::selector;
+use std::collections::HashMap;
 use std::sync::Arc;
+use tracing::warn;
+
+/// Variations of the collections supply, by contract address,
+/// accumulated while processing a batch of events.
+/// Every collection updated by the batch has an entry.
+pub type SupplyDeltas = HashMap<String, i64>;
 
 #[derive(Debug)]
 pub struct TokenManager<S: Storage, C: StarknetClient> {
     storage: Arc<S>,
     client: Arc<C>,
+    rpc_permits: RpcPermits,
 }
 
 impl<S: Storage, C: StarknetClient> TokenManager<S, C> {
@@ -20,47 +34,144 @@ impl<S: Storage, C: StarknetClient> TokenManager<S, C> {
         Self {
             storage: Arc::clone(&storage),
             client: Arc::clone(&client),
+            rpc_permits: RpcPermits::default(),
         }
     }
 
+    /// Sets the permits bounding the concurrent calls to the node,
+    /// shared with the other components of the instance.
+    pub fn with_rpc_permits(mut self, rpc_permits: RpcPermits) -> Self {
+        self.rpc_permits = rpc_permits;
+        self
+    }
+
     /// Formats a token registry from the token event data.
+    /// A token already registered is not considered as an error,
+    /// to support the re-indexation of blocks.
+    ///
+    /// Edge cases are handled as follow:
+    /// * a transfer from and to the zero address is anomalous,
+    ///   no token is registered and `None` is returned.
+    /// * a transfer to its own sender doesn't change the ownership,
+    ///   the owner of a new token being the recipient without any call to the chain.
+    /// * the token id 0 is a valid token id.
+    /// * an ERC721 token transferred to the zero address is marked as burned,
+    ///   and is no longer burned if minted again. ERC1155 tokens are never marked,
+    ///   the balances not being tracked.
+    ///
+    /// Returns the registered token.
     pub async fn format_and_register_token(
         &self,
         token_id: &CairoU256,
         event: &TokenTransferEvent,
-        block_timestamp: u64,
-        block_number: Option<u64>,
-    ) -> Result<()> {
+        block: &BlockContext,
+    ) -> Result<Option<TokenInfo>> {
+        if event.is_anomalous() {
+            warn!(
+                "Anomalous transfer from and to the zero address, no token registered: contract={}, token_id={}, tx={}",
+                event.contract_address, event.token_id_hex, event.transaction_hash
+            );
+            return Ok(None);
+        }
+
+        let owner = if event.is_self_transfer() {
+            event.to_address.clone()
+        } else {
+            self.get_token_owner(
+                FieldElement::from_hex_be(&event.contract_address)
+                    .expect("Contract address bad format"),
+                token_id.low.into(),
+                token_id.high.into(),
+            )
+            .await
+            .ok()
+            .and_then(|owner| owner.first().map(to_hex_str))
+            .unwrap_or_default()
+        };
+
+        self.register_token_state(event, block, owner, false)
+            .await
+            .map(Some)
+    }
+
+    /// Same as `format_and_register_token`, without any call to the chain:
+    /// the owner of the token is the recipient of the event. The events of a
+    /// token being replayed in order, the owner of an ERC721 token already
+    /// registered is updated to the recipient of its last event.
+    pub async fn replay_token_event(
+        &self,
+        event: &TokenTransferEvent,
+        block: &BlockContext,
+    ) -> Result<Option<TokenInfo>> {
+        if event.is_anomalous() {
+            return Ok(None);
+        }
+
+        self.register_token_state(event, block, event.to_address.clone(), true)
+            .await
+            .map(Some)
+    }
+
+    /// Registers the token of the event with the given owner, and records
+    /// its burn and mint. If `update_owner` is true, the owner of an ERC721
+    /// token already registered is set to the given one.
+    async fn register_token_state(
+        &self,
+        event: &TokenTransferEvent,
+        block: &BlockContext,
+        owner: String,
+        update_owner: bool,
+    ) -> Result<TokenInfo> {
         let mut token = TokenInfo {
             contract_address: event.contract_address.clone(),
             token_id: event.token_id.clone(),
             chain_id: event.chain_id.clone(),
             token_id_hex: event.token_id_hex.clone(),
+            owner,
             ..Default::default()
         };
 
-        let token_owner_raw_result = self
-            .get_token_owner(
-                FieldElement::from_hex_be(&event.contract_address)
-                    .expect("Contract address bad format"),
-                token_id.low.into(),
-                token_id.high.into(),
-            )
-            .await;
+        let is_new = match self.storage.register_token(&token, block.timestamp).await {
+            Ok(()) => true,
+            Err(StorageError::AlreadyExists(_)) => false,
+            Err(e) => return Err(e.into()),
+        };
 
-        token.owner = token_owner_raw_result
-            .ok()
-            .and_then(|owner| owner.first().map(to_hex_str))
-            .unwrap_or_default();
+        if update_owner && !is_new && event.contract_type == ContractType::ERC721.to_string() {
+            self.storage
+                .set_token_owner(&token.contract_address, &token.token_id_hex, &token.owner)
+                .await?;
+        }
 
-        self.storage.register_token(&token, block_timestamp).await?;
+        if event.contract_type == ContractType::ERC721.to_string() {
+            match event.event_type {
+                EventType::Burn => {
+                    self.storage
+                        .set_token_burned(
+                            &token.contract_address,
+                            &token.token_id_hex,
+                            true,
+                            block.block_number(),
+                        )
+                        .await?;
+                    token.is_burned = true;
+                    token.burned_at_block = block.block_number();
+                }
+                EventType::Mint if !is_new => {
+                    self.storage
+                        .set_token_burned(&token.contract_address, &token.token_id_hex, false, None)
+                        .await?;
+                }
+                _ => (),
+            }
+        }
 
         if event.event_type == EventType::Mint {
             let info = TokenMintInfo {
                 address: event.to_address.clone(),
-                timestamp: event.timestamp,
+                timestamp: block.timestamp,
                 transaction_hash: event.transaction_hash.clone(),
-                block_number,
+                block_number: block.block_number(),
             };
 
             self.storage
@@ -73,9 +184,237 @@ impl<S: Storage, C: StarknetClient> TokenManager<S, C> {
                 .await?;
         }
 
+        Ok(token)
+    }
+
+    /// Returns a `BurnedTokenTransfer` error if the event transfers an ERC721 token
+    /// burned in a previous block. The transfers of the block of the burn are
+    /// accepted, for the block to be re-indexed.
+    pub async fn check_not_burned(&self, event: &DecodedTransfer) -> Result<()> {
+        if event.contract_type != ContractType::ERC721 || event.event_type == EventType::Mint {
+            return Ok(());
+        }
+
+        let contract_address = to_hex_str(&event.contract_address);
+        let token_id_hex = event.token_id_hex();
+        let token = match self
+            .storage
+            .get_token(&contract_address, &token_id_hex)
+            .await?
+        {
+            Some(token) if token.is_burned => token,
+            _ => return Ok(()),
+        };
+
+        let is_after_burn = match (event.block_number, token.burned_at_block) {
+            (Some(block), Some(burned_at_block)) => block > burned_at_block,
+            // Pending transfer after a burn in an accepted block.
+            (None, Some(_)) => true,
+            // The burn is in the pending block, the order is not known.
+            (_, None) => false,
+        };
+
+        if is_after_burn {
+            warn!(
+                "Transfer of burned token: contract={}, token_id={}, tx=0x{:064x}",
+                contract_address, token_id_hex, event.transaction_hash
+            );
+            return Err(IndexerError::BurnedTokenTransfer {
+                contract_address,
+                token_id_hex,
+                burned_at_block: token.burned_at_block,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Accumulates the supply variation caused by the given event.
+    pub fn track_supply(deltas: &mut SupplyDeltas, event: &TokenTransferEvent) {
+        *deltas.entry(event.contract_address.clone()).or_default() += event.supply_delta();
+    }
+
+    /// Applies the accumulated supply variations to the storage, and updates
+    /// the counters of the collections, as of `block_number` if accepted.
+    pub async fn flush_supply(
+        &self,
+        deltas: SupplyDeltas,
+        block_number: Option<u64>,
+    ) -> Result<()> {
+        for (contract_address, delta) in deltas {
+            if delta != 0 {
+                self.storage
+                    .adjust_collection_supply(&contract_address, delta)
+                    .await?;
+            }
+
+            self.update_collection_stats(&contract_address, block_number)
+                .await?;
+        }
+
         Ok(())
     }
 
+    /// Counts again the tokens, holders and transfers of the collection,
+    /// and records them as the collection counters.
+    async fn update_collection_stats(
+        &self,
+        contract_address: &str,
+        block_number: Option<u64>,
+    ) -> Result<()> {
+        let previous = self
+            .storage
+            .get_collection_stats(contract_address)
+            .await?
+            .unwrap_or_default();
+        let (total_tokens, unique_holders) = self
+            .storage
+            .count_collection_tokens(contract_address)
+            .await?;
+        let total_transfers = self
+            .storage
+            .count_transfers_in_range(contract_address, 0, i64::MAX as u64)
+            .await?;
+
+        let stats = CollectionStats {
+            total_tokens,
+            unique_holders,
+            total_transfers,
+            last_updated_block: block_number.map_or(previous.last_updated_block, |n| {
+                n.max(previous.last_updated_block)
+            }),
+        };
+
+        Ok(self
+            .storage
+            .upsert_collection_stats(contract_address, &stats)
+            .await?)
+    }
+
+    /// Returns the counters of the collection, `None` if the collection
+    /// was never updated.
+    pub async fn get_collection_stats(
+        &self,
+        contract_address: FieldElement,
+    ) -> Result<Option<CollectionStats>> {
+        Ok(self
+            .storage
+            .get_collection_stats(&to_hex_str(&contract_address))
+            .await?)
+    }
+
+    /// Recomputes the supply of the collection from the stored events.
+    pub async fn recount_collection_supply(&self, contract_address: &FieldElement) -> Result<i64> {
+        Ok(self
+            .storage
+            .recount_collection_supply(&to_hex_str(contract_address))
+            .await?)
+    }
+
+    /// Returns the provenance of the token: all its events indexed (mint,
+    /// transfers, sales, burn), in the order of the blocks.
+    pub async fn get_token_history(
+        &self,
+        contract_address: FieldElement,
+        token_id: &CairoU256,
+    ) -> Result<Vec<TokenEvent>> {
+        Ok(self
+            .storage
+            .get_events_for_token(&to_hex_str(&contract_address), &token_id.to_hex())
+            .await?)
+    }
+
+    /// Returns the total supply of the collection, from the storage if cached,
+    /// or from the contract. The value from the contract is cached.
+    /// Returns `None` if the contract doesn't expose its total supply.
+    pub async fn get_total_supply(&self, contract_address: FieldElement) -> Result<Option<u64>> {
+        let contract_address_hex = to_hex_str(&contract_address);
+
+        if let Some(total_supply) = self.storage.get_total_supply(&contract_address_hex).await? {
+            return Ok(Some(total_supply));
+        }
+
+        let total_supply =
+            call_total_supply(self.client.as_ref(), &self.rpc_permits, contract_address).await;
+
+        if let Some(total_supply) = total_supply {
+            self.storage
+                .set_total_supply(&contract_address_hex, total_supply)
+                .await?;
+        }
+
+        Ok(total_supply)
+    }
+
+    /// Compares the owner stored for each token with the owner returned by
+    /// the chain, and updates the storage where they differ. Meant to repair
+    /// the storage after a bug or a reorg, it is never run automatically.
+    ///
+    /// The tokens not registered, or which owner can't be fetched
+    /// (burned tokens for instance), are skipped.
+    /// Returns the number of owners corrected.
+    pub async fn sync_ownership_from_chain(
+        &self,
+        contract: FieldElement,
+        token_ids: Vec<FieldElement>,
+    ) -> IndexerResult<usize> {
+        let contract_address = to_hex_str(&contract);
+        let mut corrections = 0;
+
+        for token_id in token_ids {
+            let token_id_hex = to_hex_str(&token_id);
+            let token = match self
+                .storage
+                .get_token(&contract_address, &token_id_hex)
+                .await?
+            {
+                Some(token) => token,
+                None => {
+                    warn!(
+                        "Token {} {} not registered, ownership not synced",
+                        contract_address, token_id_hex
+                    );
+                    continue;
+                }
+            };
+
+            let bytes = token_id.to_bytes_be();
+            let (high, low) = bytes.split_at(16);
+            let owner = match self
+                .get_token_owner(
+                    contract,
+                    u128::from_be_bytes(low.try_into().unwrap()).into(),
+                    u128::from_be_bytes(high.try_into().unwrap()).into(),
+                )
+                .await
+                .map(|owner| owner.first().map(to_hex_str))
+            {
+                Ok(Some(owner)) => owner,
+                Ok(None) | Err(_) => {
+                    warn!(
+                        "Owner of token {} {} not available on chain, ownership not synced",
+                        contract_address, token_id_hex
+                    );
+                    continue;
+                }
+            };
+
+            if owner != token.owner {
+                warn!(
+                    "Owner of token {} {} corrected: stored={}, chain={}",
+                    contract_address, token_id_hex, token.owner, owner
+                );
+                self.storage
+                    .set_token_owner(&contract_address, &token_id_hex, &owner)
+                    .await?;
+                corrections += 1;
+            }
+        }
+
+        Ok(corrections)
+    }
+
     /// Retrieves the token owner for the last block.
     pub async fn get_token_owner(
         &self,
@@ -83,18 +422,34 @@ impl<S: Storage, C: StarknetClient> TokenManager<S, C> {
         token_id_low: FieldElement,
         token_id_high: FieldElement,
     ) -> Result<Vec<FieldElement>> {
-        let block = BlockId::Tag(BlockTag::Pending);
+        self.get_token_owner_at(
+            contract_address,
+            token_id_low,
+            token_id_high,
+            BlockId::Tag(BlockTag::Pending),
+        )
+        .await
+    }
+
+    /// Retrieves the token owner at the given block.
+    pub async fn get_token_owner_at(
+        &self,
+        contract_address: FieldElement,
+        token_id_low: FieldElement,
+        token_id_high: FieldElement,
+        block: BlockId,
+    ) -> Result<Vec<FieldElement>> {
         let selectors = vec![selector!("owner_of"), selector!("ownerOf")];
 
         for selector in selectors {
             if let Ok(res) = self
-                .client
-                .call_contract(
+                .rpc_permits
+                .call(self.client.call_contract(
                     contract_address,
                     selector,
                     vec![token_id_low, token_id_high],
                     block,
-                )
+                ))
                 .await
             {
                 return Ok(res);
@@ -103,6 +458,69 @@ impl<S: Storage, C: StarknetClient> TokenManager<S, C> {
 
         Err(anyhow!("Failed to get token owner from chain"))
     }
+
+    /// Retrieves the token URI for the last block.
+    pub async fn get_token_uri(
+        &self,
+        contract_address: FieldElement,
+        token_id_low: FieldElement,
+        token_id_high: FieldElement,
+    ) -> Result<String> {
+        let block = BlockId::Tag(BlockTag::Pending);
+        let selectors = vec![
+            selector!("token_uri"),
+            selector!("tokenURI"),
+            selector!("tokenUri"),
+        ];
+
+        for selector in selectors {
+            if let Ok(res) = self
+                .rpc_permits
+                .call(self.client.call_contract(
+                    contract_address,
+                    selector,
+                    vec![token_id_low, token_id_high],
+                    block,
+                ))
+                .await
+            {
+                return parse_cairo_string(res)
+                    .map_err(|e| anyhow!("Impossible to decode token URI: {:?}", e));
+            }
+        }
+
+        Err(anyhow!("Failed to get token URI from chain"))
+    }
+}
+
+/// Calls the `totalSupply` entrypoint of the contract.
+/// Returns `None` if the contract doesn't expose it. A supply
+/// which doesn't fit in a u64 is saturated.
+pub(crate) async fn call_total_supply<C: StarknetClient>(
+    client: &C,
+    rpc_permits: &RpcPermits,
+    contract_address: FieldElement,
+) -> Option<u64> {
+    let block = BlockId::Tag(BlockTag::Pending);
+    let selectors = vec![selector!("totalSupply"), selector!("total_supply")];
+
+    for selector in selectors {
+        if let Ok(res) = rpc_permits
+            .call(client.call_contract(contract_address, selector, vec![], block))
+            .await
+        {
+            let low = res.first()?;
+            let high = res.get(1).copied().unwrap_or(FieldElement::ZERO);
+
+            return if high == FieldElement::ZERO {
+                Some(u64::try_from(*low).unwrap_or(u64::MAX))
+            } else {
+                Some(u64::MAX)
+            };
+        }
+    }
+
+    None
 }
 
 #[cfg(test)]
@@ -137,4 +555,196 @@ mod tests {
         assert_eq!(owners.len(), 1);
         assert_eq!(owners[0], FieldElement::from_dec_str("1").unwrap());
     }
+
+    #[tokio::test]
+    async fn test_get_total_supply() {
+        let mut mock_client = MockStarknetClient::default();
+        mock_client
+            .expect_call_contract()
+            .withf(|_, selector, _, _| *selector == selector!("totalSupply"))
+            .times(1)
+            .returning(|_, _, _, _| Ok(vec![FieldElement::from(42_u64), FieldElement::ZERO]));
+
+        let storage = Arc::new(crate::storage::InMemoryStorage::new());
+        let token_manager = TokenManager::new(Arc::clone(&storage), Arc::new(mock_client));
+
+        let contract_address = FieldElement::from_hex_be("0x1234").unwrap();
+
+        // The second call is served from the storage.
+        for _ in 0..2 {
+            assert_eq!(
+                token_manager
+                    .get_total_supply(contract_address)
+                    .await
+                    .unwrap(),
+                Some(42)
+            );
+        }
+
+        assert_eq!(
+            storage.dump().total_supplies[&to_hex_str(&contract_address)],
+            42
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_total_supply_not_exposed() {
+        let mut mock_storage = MockStorage::default();
+        mock_storage
+            .expect_get_total_supply()
+            .returning(|_| Box::pin(futures::future::ready(Ok(None))));
+
+        let mut mock_client = MockStarknetClient::default();
+        mock_client.expect_call_contract().returning(|_, _, _, _| {
+            Err(ark_starknet::client::StarknetClientError::EntrypointNotFound("".to_string()))
+        });
+
+        let token_manager = TokenManager::new(Arc::new(mock_storage), Arc::new(mock_client));
+
+        assert_eq!(
+            token_manager
+                .get_total_supply(FieldElement::ONE)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_ownership_from_chain() {
+        let contract_address = FieldElement::from_hex_be("0x1234").unwrap();
+        let chain_owner = FieldElement::from_hex_be("0xb").unwrap();
+
+        let mut mock_client = MockStarknetClient::default();
+        mock_client
+            .expect_call_contract()
+            .returning(move |_, _, calldata, _| match calldata[0] {
+                // The token 3 is burned, `ownerOf` reverts.
+                low if low == FieldElement::THREE => Err(
+                    ark_starknet::client::StarknetClientError::Other("burned".to_string()),
+                ),
+                _ => Ok(vec![chain_owner]),
+            });
+
+        let storage = Arc::new(crate::storage::InMemoryStorage::new());
+        for (token_id, owner) in [(1_u64, "0xa"), (2, "0xb"), (3, "0xa")] {
+            let owner = FieldElement::from_hex_be(owner).unwrap();
+            storage
+                .register_token(
+                    &TokenInfo {
+                        contract_address: to_hex_str(&contract_address),
+                        token_id_hex: to_hex_str(&FieldElement::from(token_id)),
+                        owner: to_hex_str(&owner),
+                        ..Default::default()
+                    },
+                    0,
+                )
+                .await
+                .unwrap();
+        }
+
+        let token_manager = TokenManager::new(Arc::clone(&storage), Arc::new(mock_client));
+
+        // Token 4 is not registered.
+        let token_ids = (1..=4_u64).map(FieldElement::from).collect();
+        assert_eq!(
+            token_manager
+                .sync_ownership_from_chain(contract_address, token_ids)
+                .await
+                .unwrap(),
+            1
+        );
+
+        let owner = |token_id: u64| {
+            storage.dump().tokens[&(
+                to_hex_str(&contract_address),
+                to_hex_str(&FieldElement::from(token_id)),
+            )]
+                .owner
+                .clone()
+        };
+        assert_eq!(owner(1), to_hex_str(&chain_owner));
+        assert_eq!(owner(2), to_hex_str(&chain_owner));
+        assert_eq!(
+            owner(3),
+            to_hex_str(&FieldElement::from_hex_be("0xa").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flush_supply_updates_collection_stats() {
+        let contract_address = FieldElement::from_hex_be("0x1").unwrap();
+        let contract_address_hex = to_hex_str(&contract_address);
+
+        let storage = Arc::new(crate::storage::InMemoryStorage::new());
+        for (token_id, owner, is_burned) in [
+            ("0x1", "0xa", false),
+            ("0x2", "0xb", false),
+            ("0x3", "0xa", false),
+            ("0x4", "0xc", true),
+        ] {
+            storage
+                .register_token(
+                    &TokenInfo {
+                        contract_address: contract_address_hex.clone(),
+                        token_id_hex: token_id.to_string(),
+                        owner: owner.to_string(),
+                        is_burned,
+                        ..Default::default()
+                    },
+                    0,
+                )
+                .await
+                .unwrap();
+        }
+        for (event_id, ts) in [("0xe1", 10), ("0xe2", 20)] {
+            let event = TokenTransferEvent {
+                event_id: event_id.to_string(),
+                contract_address: contract_address_hex.clone(),
+                timestamp: ts,
+                ..Default::default()
+            };
+            storage.register_transfer_event(&event, ts).await.unwrap();
+        }
+
+        let token_manager = TokenManager::new(
+            Arc::clone(&storage),
+            Arc::new(MockStarknetClient::default()),
+        );
+        assert_eq!(
+            token_manager
+                .get_collection_stats(contract_address)
+                .await
+                .unwrap(),
+            None
+        );
+
+        let deltas = SupplyDeltas::from([(contract_address_hex.clone(), 0)]);
+        token_manager.flush_supply(deltas, Some(12)).await.unwrap();
+
+        let expected = CollectionStats {
+            total_tokens: 3,
+            unique_holders: 2,
+            total_transfers: 2,
+            last_updated_block: 12,
+        };
+        assert_eq!(
+            token_manager
+                .get_collection_stats(contract_address)
+                .await
+                .unwrap(),
+            Some(expected)
+        );
+
+        // The pending block keeps the last accepted block.
+        let deltas = SupplyDeltas::from([(contract_address_hex, 0)]);
+        token_manager.flush_supply(deltas, None).await.unwrap();
+        assert_eq!(
+            token_manager
+                .get_collection_stats(contract_address)
+                .await
+                .unwrap(),
+            Some(expected)
+        );
+    }
 }
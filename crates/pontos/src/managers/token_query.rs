@@ -0,0 +1,89 @@
+use crate::managers::{ContractManager, TokenManager};
+use crate::storage::types::ContractType;
+use crate::storage::Storage;
+use anyhow::Result;
+use ark_starknet::client::StarknetClient;
+use ark_starknet::CairoU256;
+use starknet::core::types::*;
+use std::sync::Arc;
+
+/// Handle to query the on-chain state of the tokens, returned by
+/// `Pontos::token_query`.
+///
+/// The queries go through the client and the contracts cache of the
+/// Pontos instance. The handle is cheap to clone, and holds no lock
+/// of the instance: it can be used from the `EventHandler` callbacks,
+/// including those called by `index_pending`.
+pub struct TokenQuery<S: Storage, C: StarknetClient> {
+    token_manager: Arc<TokenManager<S, C>>,
+    contract_manager: Arc<ContractManager<S, C>>,
+}
+
+impl<S: Storage, C: StarknetClient> Clone for TokenQuery<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            token_manager: Arc::clone(&self.token_manager),
+            contract_manager: Arc::clone(&self.contract_manager),
+        }
+    }
+}
+
+impl<S: Storage, C: StarknetClient> TokenQuery<S, C> {
+    pub(crate) fn new(
+        token_manager: Arc<TokenManager<S, C>>,
+        contract_manager: Arc<ContractManager<S, C>>,
+    ) -> Self {
+        Self {
+            token_manager,
+            contract_manager,
+        }
+    }
+
+    /// Returns the owner of the token at the given block.
+    pub async fn owner_of(
+        &self,
+        contract_address: FieldElement,
+        token_id: &CairoU256,
+        block: BlockId,
+    ) -> Result<FieldElement> {
+        let owner = self
+            .token_manager
+            .get_token_owner_at(
+                contract_address,
+                token_id.low.into(),
+                token_id.high.into(),
+                block,
+            )
+            .await?;
+
+        owner
+            .first()
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Empty owner returned by the chain"))
+    }
+
+    /// Returns the URI of the token for the last block.
+    pub async fn token_uri(
+        &self,
+        contract_address: FieldElement,
+        token_id: &CairoU256,
+    ) -> Result<String> {
+        self.token_manager
+            .get_token_uri(contract_address, token_id.low.into(), token_id.high.into())
+            .await
+    }
+
+    /// Returns the type of the contract, from the contracts cache if already
+    /// identified by the indexer, or identified on the chain otherwise.
+    /// A contract identified by this call is not cached.
+    pub async fn contract_type(&self, contract_address: FieldElement) -> Result<ContractType> {
+        match self.contract_manager.cached_contract_type(contract_address) {
+            Some(contract_type) => Ok(contract_type),
+            None => {
+                self.contract_manager
+                    .get_contract_type(contract_address)
+                    .await
+            }
+        }
+    }
+}
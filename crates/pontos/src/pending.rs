@@ -0,0 +1,762 @@
+//! Indexation of the pending block, and the watchdog restarting its loop.
+use crate::config::{LogDetail, PendingWatchdog};
+use crate::event_handler::EventHandler;
+use crate::managers::{BlockContext, PendingBlockData, PendingBlockSnapshot};
+use crate::storage::types::BlockIndexingStatus;
+use crate::storage::Storage;
+use crate::{IndexerError, IndexerResult, Pontos, PENDING_TX_LOG_TARGET};
+use ark_starknet::client::StarknetClient;
+use starknet::core::types::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, trace, warn, Instrument};
+
+/// Sets the flag while alive, and resets it when dropped,
+/// even if the owning future returns early or is cancelled.
+struct RunningFlag(Arc<AtomicBool>);
+
+impl RunningFlag {
+    /// Sets the flag, or returns `None` if the flag is already set.
+    fn try_set(flag: &Arc<AtomicBool>) -> Option<Self> {
+        flag.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| Self(Arc::clone(flag)))
+    }
+}
+
+impl Drop for RunningFlag {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C, E> {
+    /// Watchdog of the pending loop, see `PendingWatchdog`.
+    /// Never returns, run alongside the pending loop by `index_pending`.
+    async fn pending_watchdog(&self, watchdog: PendingWatchdog) {
+        loop {
+            self.clock.sleep(watchdog.interval).await;
+
+            let now_ms = self.uptime().as_millis() as u64;
+            let stalled_for = Duration::from_millis(
+                now_ms.saturating_sub(self.pending_last_tick_ms.load(Ordering::SeqCst)),
+            );
+            if stalled_for < watchdog.timeout {
+                continue;
+            }
+
+            warn!(
+                "Pending loop without a successful tick for {:?}{}",
+                stalled_for,
+                if watchdog.restart {
+                    ", restarting it"
+                } else {
+                    ""
+                }
+            );
+            self.event_handler.on_watchdog_trigger(stalled_for).await;
+            if watchdog.restart {
+                self.pending_restart.send_replace(());
+            }
+            // Triggered again only after an other timeout.
+            self.pending_last_tick_ms.store(now_ms, Ordering::SeqCst);
+        }
+    }
+
+    /// Records a successful tick of the pending loop, for the watchdog.
+    fn record_pending_tick(&self) {
+        self.pending_last_tick_ms
+            .store(self.uptime().as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Empties the pending cache once the pending loop has returned.
+    /// The events of the transactions already processed stay in the storage
+    /// with the timestamp of the pending block, which was not promoted yet.
+    pub(crate) async fn drain_pending_cache(&self) -> PendingBlockSnapshot {
+        let mut cache = self.pending_cache.write().await;
+        let drained = cache.snapshot();
+        *cache = PendingBlockData::new();
+        self.pending_timestamp.store(0, Ordering::Relaxed);
+        self.pending_unprocessed_txs.store(0, Ordering::Relaxed);
+
+        if drained.processed_tx_count > 0 {
+            info!(
+                "Pending block {} drained with {} txs processed ({} events)",
+                drained.timestamp, drained.processed_tx_count, drained.event_count
+            );
+        }
+
+        drained
+    }
+
+    /// Returns a snapshot of the pending block being indexed.
+    /// Waits for the current tick of `index_pending` to complete, if any.
+    pub async fn pending_snapshot(&self) -> PendingBlockSnapshot {
+        self.pending_cache.read().await.snapshot()
+    }
+
+    /// Starts a loop to only index the pending block.
+    ///
+    /// The events of the pending block are fetched transaction by transaction
+    /// using the receipts, as the pending block has no number yet, and indexed
+    /// with the chain id of `PontosConfig::chain_id`.
+    /// Once promoted to latest, the block is marked as terminated, and is
+    /// skipped by `index_block_range` like any block it has indexed. A block
+    /// some transactions of which could not be fetched or stored is not
+    /// terminated, to be indexed again by `index_block_range`.
+    /// Returns `PendingLoopAlreadyRunning` if the loop is already running
+    /// on this instance.
+    ///
+    /// With `PontosConfig::pending_watchdog`, the watchdog runs alongside
+    /// the loop, and stops with it.
+    pub async fn index_pending(&self) -> IndexerResult<()> {
+        let pending = async {
+            match self.config.pending_watchdog {
+                Some(watchdog) => tokio::select! {
+                    r = self.pending_loop() => r,
+                    () = self.pending_watchdog(watchdog) => Ok(()),
+                },
+                None => self.pending_loop().await,
+            }
+        };
+
+        pending.instrument(self.indexer_span("pending")).await
+    }
+
+    async fn pending_loop(&self) -> IndexerResult<()> {
+        let _running = RunningFlag::try_set(&self.pending_loop_running)
+            .ok_or(IndexerError::PendingLoopAlreadyRunning)?;
+        let _active = self.active_loops.read().await;
+        self.ensure_preflight().await?;
+        let mut interval = self.config.pending_polling.base_interval();
+        let mut previous_txs_count: Option<usize> = None;
+        // Number of consecutive failed calls to the node, and last latest block seen.
+        let mut attempt: u32 = 0;
+        let mut latest_block: Option<u64> = None;
+        let mut restart = self.pending_restart.subscribe();
+        restart.borrow_and_update();
+        self.record_pending_tick();
+
+        loop {
+            if self.is_shutting_down() {
+                info!("Pending loop stopped by shutdown");
+                return Ok(());
+            }
+
+            if restart.has_changed().unwrap_or(false) {
+                restart.borrow_and_update();
+                warn!("Pending loop restarted by the watchdog");
+                interval = self.config.pending_polling.base_interval();
+                previous_txs_count = None;
+                attempt = 0;
+                latest_block = None;
+            }
+
+            let pending_block = latest_block.map_or(0, |n| n + 1);
+
+            let (pending_ts, txs) = match self
+                .rpc_permits
+                .call(
+                    self.client
+                        .block_txs_hashes(BlockId::Tag(BlockTag::Pending)),
+                )
+                .await
+            {
+                Ok((ts, txs)) => (ts, txs),
+                Err(e) => {
+                    error!("Error while fetching pending block txs: {:?}", e);
+                    attempt += 1;
+                    self.event_handler
+                        .on_rpc_retry(pending_block, attempt, &e.into())
+                        .await;
+                    self.pause(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            debug!("Pending block {} with {} txs", pending_ts, txs.len());
+
+            // The cache is only locked once the blocks are fetched, for the
+            // pauses after a failed call to never hold it.
+            let previous_loop_ts = match self.pending_cache.read().await.get_timestamp() {
+                0 => pending_ts,
+                ts => ts,
+            };
+
+            let has_changed =
+                pending_ts != previous_loop_ts || previous_txs_count != Some(txs.len());
+            previous_txs_count = Some(txs.len());
+
+            // If the timestamp is different from the previous loop,
+            // we must first ensure we've fetched and processed all the transactions
+            // of the previous pending block, which is now the "Latest".
+            let latest = if pending_ts != previous_loop_ts {
+                debug!("ts differ! {} {}", pending_ts, previous_loop_ts);
+                // Get the latest block number, generated by the sequencer, which is
+                // expected to be the one we just processed.
+                let block_number = match self.rpc_permits.call(self.client.block_number()).await {
+                    Ok(n) => n,
+                    Err(e) => {
+                        error!("Error while fetching latest block number: {:?}", e);
+                        attempt += 1;
+                        self.event_handler
+                            .on_rpc_retry(pending_block, attempt, &e.into())
+                            .await;
+                        self.pause(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                latest_block = Some(block_number);
+                self.observe_chain_head(block_number);
+
+                let latest_txs = self
+                    .rpc_permits
+                    .call(self.client.block_txs_hashes(BlockId::Number(block_number)))
+                    .await;
+                Some((block_number, latest_txs))
+            } else {
+                None
+            };
+
+            let mut cache = self.pending_cache.write().await;
+            if cache.get_timestamp() == 0 {
+                cache.set_timestamp(pending_ts);
+            }
+
+            if let Some((block_number, latest_txs)) = latest {
+                // Process the transactions of the previous pending block
+                // that were included after our last tick. The block timestamp
+                // is `None` if some of its events may be missing.
+                let latest_ts = match latest_txs {
+                    Ok((latest_ts, latest_txs)) => {
+                        let unprocessed = self
+                            .process_pending_txs(&mut cache, latest_txs, previous_loop_ts)
+                            .await;
+                        (unprocessed == 0).then_some(latest_ts)
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error while fetching txs of latest block #{}: {:?}",
+                            block_number, e
+                        );
+                        None
+                    }
+                };
+
+                // The events left pending are indexed again by `index_block_range`.
+                let latest_ts = match self
+                    .event_manager
+                    .finalize_pending_events(previous_loop_ts, block_number)
+                    .await
+                {
+                    Ok(()) => latest_ts,
+                    Err(e) => {
+                        error!(
+                            "Error while finalizing the events of block #{}: {:?}",
+                            block_number, e
+                        );
+                        None
+                    }
+                };
+
+                // Terminates the block as `index_block_range` does, for the block
+                // to be skipped if a range including it is indexed later.
+                // An incomplete block is left to `index_block_range`.
+                if let Some(latest_ts) = latest_ts {
+                    match self
+                        .block_manager
+                        .set_block_info(
+                            block_number,
+                            latest_ts,
+                            self.config.indexer_version.clone(),
+                            self.config.indexer_identifier.clone(),
+                            BlockIndexingStatus::Terminated,
+                            false,
+                            None,
+                        )
+                        .await
+                    {
+                        Ok(()) => {}
+                        Err(IndexerError::VersionConflict {
+                            existing_version, ..
+                        }) => {
+                            warn!(
+                                "Block #{} already terminated by indexer version {}",
+                                block_number, existing_version
+                            );
+                        }
+                        Err(e) => {
+                            error!("Error while terminating block #{}: {:?}", block_number, e);
+                        }
+                    }
+                } else {
+                    warn!(
+                        "Block #{} may be missing events, not terminated",
+                        block_number
+                    );
+                }
+
+                self.event_handler
+                    .on_pending_transition(previous_loop_ts, block_number)
+                    .await;
+                self.event_handler.on_new_latest_block(block_number).await;
+
+                info!(
+                    "Pending block {} is now latest block number #{}",
+                    previous_loop_ts, block_number
+                );
+
+                // Setup the local variables to directly start the pending block
+                // indexation instead of waiting the next tick.
+                cache.set_timestamp(pending_ts);
+                cache.clear_tx_hashes();
+            }
+
+            attempt = 0;
+
+            let unprocessed = self.process_pending_txs(&mut cache, txs, pending_ts).await;
+            self.pending_timestamp.store(pending_ts, Ordering::Relaxed);
+            self.pending_unprocessed_txs
+                .store(unprocessed as u64, Ordering::Relaxed);
+
+            interval = self
+                .config
+                .pending_polling
+                .next_interval(interval, has_changed);
+            self.pending_poll_interval_ms
+                .store(interval.as_millis() as u64, Ordering::Relaxed);
+            self.record_pending_tick();
+
+            // Release the cache while waiting, so it can be inspected.
+            drop(cache);
+
+            self.report_lag().await;
+            self.register_indexer().await;
+
+            self.pause(self.config.pending_polling.with_jitter(interval))
+                .await;
+        }
+    }
+
+    /// Processes the events of the given transactions that
+    /// were not already processed for the current pending block.
+    /// Returns the number of transactions left unprocessed, to be
+    /// processed again at the next tick.
+    async fn process_pending_txs(
+        &self,
+        cache: &mut PendingBlockData,
+        txs: Vec<FieldElement>,
+        block_timestamp: u64,
+    ) -> usize {
+        let chain_id = self.chain_id();
+        let mut unprocessed = 0;
+
+        for tx_hash in txs {
+            if cache.is_tx_processed(&tx_hash) {
+                continue;
+            }
+
+            if self.should_log(LogDetail::Normal) {
+                trace!(target: PENDING_TX_LOG_TARGET, "Processing pending tx 0x{:064x}", tx_hash);
+            }
+
+            let events = match self
+                .rpc_permits
+                .call(
+                    self.client
+                        .events_from_tx_receipt(tx_hash, self.event_manager.keys_selector()),
+                )
+                .await
+            {
+                Ok(events) => events,
+                Err(e) => {
+                    // The tx will be processed again at the next tick.
+                    error!(
+                        "Error while fetching tx receipt 0x{:064x}: {:?}",
+                        tx_hash, e
+                    );
+                    unprocessed += 1;
+                    continue;
+                }
+            };
+
+            let events_count = events.len();
+            if let Err(e) = self
+                .process_events(events, &BlockContext::pending(block_timestamp), chain_id)
+                .await
+            {
+                error!(
+                    "Error while processing pending tx 0x{:064x}: {:?}",
+                    tx_hash, e
+                );
+                unprocessed += 1;
+                continue;
+            }
+
+            cache.add_tx_as_processed(&tx_hash);
+            cache.add_events_count(events_count);
+        }
+
+        unprocessed
+    }
+
+    /// Returns true if the block of the given timestamp is held by `index_pending`,
+    /// as the pending block or while being promoted to latest.
+    /// Waits for the current tick of `index_pending` to complete, if any.
+    pub(crate) async fn is_held_by_pending(&self, block_timestamp: u64) -> bool {
+        self.pending_loop_running.load(Ordering::Relaxed)
+            && self.pending_cache.read().await.get_timestamp() == block_timestamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config, indexing_storage, transfer_event, CountingEventHandler};
+    use crate::{Clock, PendingPolling, PontosConfig};
+    use ark_starknet::client::{MockStarknetClient, StarknetClientError};
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_on_token_event_fires_once_for_pending() {
+        let mut storage = indexing_storage();
+        storage
+            .expect_register_token()
+            .returning(|_, _| Box::pin(futures::future::ready(Ok(()))));
+
+        let contract_address = FieldElement::from_hex_be("0x1234").unwrap();
+
+        let mut client = MockStarknetClient::default();
+        client
+            .expect_block_txs_hashes()
+            .returning(|_| Ok((1000, vec![FieldElement::from_hex_be("0x5432").unwrap()])));
+        client
+            .expect_events_from_tx_receipt()
+            .returning(move |_, _| Ok(vec![transfer_event(contract_address, None)]));
+        client
+            .expect_call_contract()
+            .returning(|_, _, _, _| Ok(vec![FieldElement::ONE]));
+
+        let handler = Arc::new(CountingEventHandler::default());
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::new(storage),
+            Arc::clone(&handler),
+            config(),
+        );
+
+        // The loop never ends, only the first ticks are executed.
+        let _ = tokio::time::timeout(Duration::from_millis(200), pontos.index_pending()).await;
+
+        assert_eq!(handler.token_events.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pending_loop_virtual_time() {
+        use crate::testing::{InMemoryStorage, ManualClock, NoopEventHandler};
+
+        // The pending block is promoted to the block 10 at the third tick.
+        let ticks = Arc::new(AtomicU64::new(0));
+        let mut client = MockStarknetClient::default();
+        let t = Arc::clone(&ticks);
+        client
+            .expect_block_txs_hashes()
+            .returning(move |id| match id {
+                BlockId::Tag(BlockTag::Pending) => {
+                    let tick = t.fetch_add(1, Ordering::SeqCst) + 1;
+                    Ok((if tick < 3 { 1000 } else { 1012 }, vec![]))
+                }
+                _ => Ok((1005, vec![])),
+            });
+        client.expect_block_number().returning(|| Ok(10));
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let clock = Arc::new(ManualClock::new());
+        let interval = Duration::from_secs(3600);
+        let pontos = Arc::new(Pontos::new(
+            Arc::new(client),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                pending_polling: PendingPolling::FixedInterval(interval),
+                clock: Some(Arc::clone(&clock) as Arc<dyn Clock>),
+                ..config()
+            },
+        ));
+        let task = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move { pontos.index_pending().await }
+        });
+
+        // The loop waits for the whole interval between two ticks.
+        clock.wait_for_sleeps(1).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 1);
+        clock.advance(interval - Duration::from_secs(1));
+        tokio::task::yield_now().await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(1));
+        clock.wait_for_sleeps(2).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 2);
+        assert!(storage.dump().blocks.is_empty());
+
+        // Rollover: the previous pending block is terminated,
+        // with the timestamp of the latest block.
+        clock.advance(interval);
+        clock.wait_for_sleeps(3).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 3);
+        let (timestamp, info) = storage.dump().blocks[&10].clone();
+        assert_eq!(timestamp, 1005);
+        assert_eq!(info.status, BlockIndexingStatus::Terminated);
+        assert_eq!(pontos.pending_snapshot().await.timestamp, 1012);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pending_rollover_not_terminated_on_txs_failure() {
+        use crate::testing::{InMemoryStorage, ManualClock, NoopEventHandler};
+
+        // The pending block is promoted to the block 10 at the second tick,
+        // and the transactions of the block 10 can't be fetched.
+        let ticks = Arc::new(AtomicU64::new(0));
+        let mut client = MockStarknetClient::default();
+        let t = Arc::clone(&ticks);
+        client
+            .expect_block_txs_hashes()
+            .returning(move |id| match id {
+                BlockId::Tag(BlockTag::Pending) => {
+                    let tick = t.fetch_add(1, Ordering::SeqCst) + 1;
+                    Ok((if tick < 2 { 1000 } else { 1012 }, vec![]))
+                }
+                _ => Err(StarknetClientError::Other("unavailable".to_string())),
+            });
+        client.expect_block_number().returning(|| Ok(10));
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let clock = Arc::new(ManualClock::new());
+        let interval = Duration::from_secs(3600);
+        let pontos = Arc::new(Pontos::new(
+            Arc::new(client),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                pending_polling: PendingPolling::FixedInterval(interval),
+                clock: Some(Arc::clone(&clock) as Arc<dyn Clock>),
+                ..config()
+            },
+        ));
+        let task = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move { pontos.index_pending().await }
+        });
+
+        clock.wait_for_sleeps(1).await;
+        clock.advance(interval);
+        clock.wait_for_sleeps(2).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 2);
+
+        // The block is left to `index_block_range`.
+        assert!(!storage.dump().blocks.contains_key(&10));
+        assert_eq!(pontos.pending_snapshot().await.timestamp, 1012);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pending_loop_continues_after_storage_error() {
+        use crate::storage::types::StorageError;
+        use crate::testing::{ManualClock, NoopEventHandler};
+
+        // The pending block is promoted to the block 10 at the second tick,
+        // and its events can't be finalized.
+        let ticks = Arc::new(AtomicU64::new(0));
+        let mut client = MockStarknetClient::default();
+        let t = Arc::clone(&ticks);
+        client
+            .expect_block_txs_hashes()
+            .returning(move |id| match id {
+                BlockId::Tag(BlockTag::Pending) => {
+                    let tick = t.fetch_add(1, Ordering::SeqCst) + 1;
+                    Ok((if tick < 2 { 1000 } else { 1012 }, vec![]))
+                }
+                _ => Ok((1005, vec![])),
+            });
+        client.expect_block_number().returning(|| Ok(10));
+
+        let mut storage = indexing_storage();
+        storage.expect_finalize_pending_events().returning(|_, _| {
+            Box::pin(futures::future::ready(Err(StorageError::DatabaseError(
+                "unavailable".to_string(),
+            ))))
+        });
+
+        let clock = Arc::new(ManualClock::new());
+        let interval = Duration::from_secs(3600);
+        let pontos = Arc::new(Pontos::new(
+            Arc::new(client),
+            Arc::new(storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                pending_polling: PendingPolling::FixedInterval(interval),
+                clock: Some(Arc::clone(&clock) as Arc<dyn Clock>),
+                ..config()
+            },
+        ));
+        let task = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move { pontos.index_pending().await }
+        });
+
+        clock.wait_for_sleeps(1).await;
+        clock.advance(interval);
+        clock.wait_for_sleeps(2).await;
+        clock.advance(interval);
+        clock.wait_for_sleeps(3).await;
+
+        // The loop kept ticking on the new pending block.
+        assert_eq!(ticks.load(Ordering::SeqCst), 3);
+        assert!(!task.is_finished());
+        assert_eq!(pontos.pending_snapshot().await.timestamp, 1012);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pending_cache_released_during_retry_pause() {
+        use crate::testing::{InMemoryStorage, ManualClock, NoopEventHandler};
+
+        // The pending block changes at the second tick,
+        // and the latest block number can't be fetched.
+        let ticks = Arc::new(AtomicU64::new(0));
+        let mut client = MockStarknetClient::default();
+        let t = Arc::clone(&ticks);
+        client.expect_block_txs_hashes().returning(move |_| {
+            let tick = t.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok((if tick < 2 { 1000 } else { 1012 }, vec![]))
+        });
+        client
+            .expect_block_number()
+            .returning(|| Err(StarknetClientError::Other("unavailable".to_string())));
+
+        let clock = Arc::new(ManualClock::new());
+        let interval = Duration::from_secs(3600);
+        let pontos = Arc::new(Pontos::new(
+            Arc::new(client),
+            Arc::new(InMemoryStorage::new()),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                pending_polling: PendingPolling::FixedInterval(interval),
+                clock: Some(Arc::clone(&clock) as Arc<dyn Clock>),
+                ..config()
+            },
+        ));
+        let task = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move { pontos.index_pending().await }
+        });
+
+        clock.wait_for_sleeps(1).await;
+        clock.advance(interval);
+        // The loop is paused after the failed call, the cache can be read.
+        clock.wait_for_sleeps(2).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 2);
+        let snapshot = tokio::time::timeout(Duration::from_secs(5), pontos.pending_snapshot())
+            .await
+            .expect("Pending cache held during the retry pause");
+        assert_eq!(snapshot.timestamp, 1000);
+        assert!(pontos.is_held_by_pending(1000).await);
+
+        task.abort();
+    }
+
+    #[test]
+    fn test_new_with_watchdog_outside_runtime() {
+        use crate::testing::{InMemoryStorage, NoopEventHandler};
+
+        // Nothing is spawned until `index_pending` is called.
+        let _pontos = Pontos::new(
+            Arc::new(MockStarknetClient::default()),
+            Arc::new(InMemoryStorage::new()),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                pending_watchdog: Some(PendingWatchdog {
+                    interval: Duration::from_secs(1),
+                    timeout: Duration::from_secs(5),
+                    restart: false,
+                }),
+                ..config()
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pending_watchdog() {
+        use crate::testing::{InMemoryStorage, ManualClock};
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct WatchdogRecorder {
+            triggers: Mutex<Vec<Duration>>,
+            attempts: Mutex<Vec<u32>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for WatchdogRecorder {
+            async fn on_watchdog_trigger(&self, stalled_for: Duration) {
+                self.triggers.lock().unwrap().push(stalled_for);
+            }
+
+            async fn on_rpc_retry(&self, _block: u64, attempt: u32, _error: &IndexerError) {
+                self.attempts.lock().unwrap().push(attempt);
+            }
+        }
+
+        // The node fails every call: the loop never completes a tick.
+        let mut client = MockStarknetClient::default();
+        client
+            .expect_block_txs_hashes()
+            .returning(|_| Err(StarknetClientError::Other("unavailable".to_string())));
+
+        let clock = Arc::new(ManualClock::new());
+        let handler = Arc::new(WatchdogRecorder::default());
+        let pontos = Arc::new(Pontos::new(
+            Arc::new(client),
+            Arc::new(InMemoryStorage::new()),
+            Arc::clone(&handler),
+            PontosConfig {
+                clock: Some(Arc::clone(&clock) as Arc<dyn Clock>),
+                pending_watchdog: Some(PendingWatchdog {
+                    interval: Duration::from_secs(1),
+                    timeout: Duration::from_secs(5),
+                    restart: true,
+                }),
+                ..config()
+            },
+        ));
+        let task = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move { pontos.index_pending().await }
+        });
+
+        // The retry pause of the loop and the check of the watchdog.
+        clock.wait_for_sleeps(2).await;
+        for step in 1..=12 {
+            clock.advance(Duration::from_secs(1));
+            clock.wait_for_sleeps(2 + 2 * step).await;
+        }
+
+        assert_eq!(
+            *handler.triggers.lock().unwrap(),
+            vec![Duration::from_secs(5), Duration::from_secs(5)]
+        );
+        // The retries start over after each restart.
+        let attempts = handler.attempts.lock().unwrap().clone();
+        assert_eq!(attempts.iter().filter(|a| **a == 1).count(), 3);
+
+        pontos.shutdown().await.unwrap();
+        task.await.unwrap().unwrap();
+    }
+}
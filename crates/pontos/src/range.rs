@@ -0,0 +1,2342 @@
+//! Indexation of block ranges: the options of `Pontos::index_block_range_with`
+//! and their presets, the estimates, and the fetch of the events of a block.
+use crate::attribution::Attribution;
+use crate::config::{ForcePolicy, DEFAULT_RANGE_CHUNK_BLOCKS};
+use crate::event_handler::EventHandler;
+use crate::managers::{BlockContext, IndexingDecision, SkipReason};
+use crate::storage::types::{BlockIndexingStatus, ContractType};
+use crate::storage::Storage;
+use crate::{
+    is_marketplace_contract, BlockActivity, BlockCompleted, ContractEventCount, IndexerError,
+    IndexerResult, IndexingReport, Pontos, RangeChunkReport, RangeEstimate, SampledRangeEstimate,
+};
+use anyhow::Result;
+use ark_starknet::client::{StarknetClient, StarknetClientError};
+use ark_starknet::format::to_hex_str;
+use futures::{Stream, StreamExt, TryStreamExt};
+use starknet::core::types::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, error, info, trace, warn, Instrument};
+
+/// Contracts with the most events reported by `Pontos::estimate_range`.
+const ESTIMATE_TOP_CONTRACTS: usize = 10;
+
+/// Size of the blocks buckets reported by `Pontos::estimate_range`.
+const ESTIMATE_BUCKET_BLOCKS: u64 = 1000;
+
+/// Blocks fetched by `Pontos::index_block_range_estimate_only`.
+const ESTIMATE_SAMPLE_BLOCKS: u64 = 10;
+
+/// Approximate size of a stored transfer event, with its token row
+/// and indexes, used by `Pontos::index_block_range_estimate_only`.
+const ESTIMATE_EVENT_BYTES: u64 = 1024;
+
+/// Blocks fetched concurrently by `Pontos::warm_up`.
+const WARM_UP_CONCURRENCY: usize = 8;
+
+/// Style of the bar of `Pontos::index_block_range_with_progress_bar`:
+/// `[###    ] 45% (block 450/1000, 1234 events, 12.3 blk/s, ETA 00:04:12)`.
+#[cfg(feature = "cli")]
+fn progress_style() -> indicatif::ProgressStyle {
+    indicatif::ProgressStyle::with_template(
+        "[{bar:20}] {percent}% (block {pos}/{len}, {msg}, {rate}, ETA {eta_precise})",
+    )
+    .expect("Invalid progress bar template")
+    .progress_chars("# ")
+    .with_key(
+        "rate",
+        |state: &indicatif::ProgressState, w: &mut dyn std::fmt::Write| {
+            let _ = write!(w, "{:.1} blk/s", state.per_sec());
+        },
+    )
+}
+
+/// Longest wait of `Pontos::index_block_range` for a block held by
+/// `index_pending` to be promoted, before returning `PendingBlockHeld`.
+const PENDING_HOLD_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Where the range indexing fetches the events of a block from.
+#[derive(Clone)]
+enum EventSource {
+    /// The events matching the indexed selectors, from the client.
+    Selectors,
+    /// All the events of the block, filtered by emitter,
+    /// see `Pontos::index_block_range_with_pre_filter`.
+    PreFiltered,
+    /// The events matching the indexed selectors, from the fallback client
+    /// when the client has none for a block with transactions,
+    /// see `Pontos::index_block_range_with_storage_fallback`.
+    WithFallback(Arc<dyn StarknetClient + Send + Sync>),
+}
+
+/// Options of `Pontos::index_block_range_with`, which can be combined.
+/// The variants of `index_block_range` are presets of those options.
+#[derive(Clone)]
+pub struct RangeOptions {
+    from_block: BlockId,
+    to_block: BlockId,
+    chain_id: String,
+    force: ForcePolicy,
+    permits: Option<Arc<Semaphore>>,
+    deadline: Option<Instant>,
+    observer: Option<mpsc::Sender<IndexerResult<BlockCompleted>>>,
+    source: EventSource,
+}
+
+impl RangeOptions {
+    /// Indexes the blocks `from_block..=to_block`, skipping the blocks
+    /// already indexed, as `index_block_range` does without `do_force`.
+    pub fn new(from_block: BlockId, to_block: BlockId, chain_id: &str) -> Self {
+        Self {
+            from_block,
+            to_block,
+            chain_id: chain_id.to_string(),
+            force: ForcePolicy::default(),
+            permits: None,
+            deadline: None,
+            observer: None,
+            source: EventSource::Selectors,
+        }
+    }
+
+    /// Indexes again the blocks already indexed if `do_force` is true.
+    pub fn with_force(self, do_force: bool) -> Self {
+        self.with_force_policy(do_force.into())
+    }
+
+    /// Decides which blocks already indexed are indexed again,
+    /// see `Pontos::index_block_range_with_force_policy`.
+    pub fn with_force_policy(mut self, force: ForcePolicy) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Holds a permit of the semaphore while each block is indexed,
+    /// see `Pontos::index_block_range_with_concurrency_limit`.
+    pub fn with_concurrency_limit(mut self, permits: Arc<Semaphore>) -> Self {
+        self.permits = Some(permits);
+        self
+    }
+
+    /// Starts no new block once the deadline is reached,
+    /// see `Pontos::index_block_range_bounded`.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Sends each block indexed to `tx`, and the error interrupting the range,
+    /// see `Pontos::index_block_range_with_observer`.
+    pub fn with_observer(mut self, tx: mpsc::Sender<IndexerResult<BlockCompleted>>) -> Self {
+        self.observer = Some(tx);
+        self
+    }
+
+    /// Keeps only the events of the emitters identified as NFT contracts,
+    /// see `Pontos::index_block_range_with_pre_filter`.
+    /// Replaces the fallback client if any.
+    pub fn with_pre_filter(mut self) -> Self {
+        self.source = EventSource::PreFiltered;
+        self
+    }
+
+    /// Fetches the events missing from the client from `fallback`,
+    /// see `Pontos::index_block_range_with_storage_fallback`.
+    /// Replaces the pre-filter if any.
+    pub fn with_storage_fallback<C2>(mut self, fallback: Arc<C2>) -> Self
+    where
+        C2: StarknetClient + Send + Sync + 'static,
+    {
+        self.source = EventSource::WithFallback(fallback);
+        self
+    }
+}
+
+/// Block fetched ahead by `Pontos::warm_up`, consumed by `index_block_range`.
+#[derive(Debug)]
+pub(crate) struct WarmBlock {
+    timestamp: u64,
+    events: HashMap<u64, Vec<EmittedEvent>>,
+}
+
+/// Key of the cursor of a chunked block range, stored with the re-indexation
+/// cursors which are keyed by contract address.
+fn range_cursor_key(indexer_identifier: &str, from_block: u64, to_block: u64) -> String {
+    format!("range:{}:{}:{}", indexer_identifier, from_block, to_block)
+}
+
+/// Block preceding `current_block` if a block of the range starting at
+/// `from_block` was reached, as reported in `IndexingReport::last_block`.
+fn last_reached_block(from_block: u64, current_block: u64) -> Option<u64> {
+    current_block.checked_sub(1).filter(|b| *b >= from_block)
+}
+
+/// Identifies an event among the events of a block fetched with different filters.
+fn event_order_key(
+    event: &EmittedEvent,
+) -> (FieldElement, FieldElement, &[FieldElement], &[FieldElement]) {
+    (
+        event.transaction_hash,
+        event.from_address,
+        &event.keys,
+        &event.data,
+    )
+}
+
+impl<S: Storage, C: StarknetClient, E: EventHandler + Send + Sync> Pontos<S, C, E> {
+    /// Counts the events matching the indexed selectors in the block range,
+    /// by contract and by bucket of blocks, to estimate the cost of indexing it.
+    ///
+    /// Nothing is identified nor written: the estimation can be cancelled
+    /// at any time by dropping the returned future.
+    pub async fn estimate_range(
+        &self,
+        from_block: BlockId,
+        to_block: BlockId,
+    ) -> IndexerResult<RangeEstimate> {
+        let from_u64 = self
+            .rpc_permits
+            .call(self.client.block_id_to_u64(&from_block))
+            .await?;
+        let to_u64 = self
+            .rpc_permits
+            .call(self.client.block_id_to_u64(&to_block))
+            .await?;
+
+        let mut estimate = RangeEstimate {
+            from_block: from_u64,
+            to_block: to_u64,
+            ..Default::default()
+        };
+
+        if from_u64 > to_u64 {
+            return Ok(estimate);
+        }
+
+        let mut contracts: HashMap<FieldElement, u64> = HashMap::new();
+        let mut blocks: HashSet<u64> = HashSet::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let result = self
+                .rpc_permits
+                .call(self.client.fetch_events(
+                    Some(BlockId::Number(from_u64)),
+                    Some(BlockId::Number(to_u64)),
+                    self.event_manager.keys_selector(),
+                    None,
+                    continuation_token,
+                ))
+                .await?;
+
+            for (block_number, events) in result.events {
+                if events.is_empty() {
+                    continue;
+                }
+
+                blocks.insert(block_number);
+
+                let bucket = block_number - block_number % ESTIMATE_BUCKET_BLOCKS;
+                *estimate.events_per_bucket.entry(bucket).or_default() += events.len() as u64;
+                estimate.total_events += events.len() as u64;
+
+                for e in events {
+                    *contracts.entry(e.from_address).or_default() += 1;
+                }
+            }
+
+            continuation_token = result.continuation_token;
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+
+        let mut contracts: Vec<(FieldElement, u64)> = contracts.into_iter().collect();
+        contracts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        estimate.top_contracts = contracts
+            .into_iter()
+            .take(ESTIMATE_TOP_CONTRACTS)
+            .map(|(address, events)| ContractEventCount {
+                contract_address: to_hex_str(&address),
+                events,
+            })
+            .collect();
+
+        estimate.blocks_with_events = blocks.len() as u64;
+        estimate.blocks_with_events_density =
+            estimate.blocks_with_events as f64 / (to_u64 - from_u64 + 1) as f64;
+
+        Ok(estimate)
+    }
+
+    /// Estimates the events and the storage needed to index the blocks from
+    /// `from` to `to` inclusive, for capacity planning before a backfill.
+    ///
+    /// Only `ESTIMATE_SAMPLE_BLOCKS` evenly spaced blocks are fetched, and their
+    /// average event count is extrapolated to the blocks not terminated yet.
+    /// The relative error is about the coefficient of variation of the events
+    /// per block divided by the square root of the sample size: around ±30%
+    /// when the event counts vary as much as their mean, but a range with a few
+    /// very busy blocks (a mint, an airdrop) can be off by an order of magnitude.
+    /// The storage size is a rough figure of `ESTIMATE_EVENT_BYTES` per event.
+    ///
+    /// Nothing is identified nor written.
+    pub async fn index_block_range_estimate_only(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> IndexerResult<SampledRangeEstimate> {
+        if from > to {
+            return Ok(SampledRangeEstimate::default());
+        }
+
+        let total_blocks = to - from + 1;
+        let already_indexed_blocks = self
+            .storage
+            .count_terminated_blocks_in_range(from, to)
+            .await?;
+
+        let samples: Vec<u64> = if total_blocks <= ESTIMATE_SAMPLE_BLOCKS {
+            (from..=to).collect()
+        } else {
+            (0..ESTIMATE_SAMPLE_BLOCKS)
+                .map(|i| from + i * (total_blocks - 1) / (ESTIMATE_SAMPLE_BLOCKS - 1))
+                .collect()
+        };
+
+        let mut sampled_events = 0;
+        for block_number in &samples {
+            sampled_events += self
+                .rpc_permits
+                .call(self.client.fetch_all_block_events(
+                    BlockId::Number(*block_number),
+                    self.event_manager.keys_selector(),
+                ))
+                .await?
+                .into_values()
+                .map(|events| events.len() as u64)
+                .sum::<u64>();
+        }
+
+        let average = sampled_events as f64 / samples.len() as f64;
+        let estimated_new_events =
+            (average * total_blocks.saturating_sub(already_indexed_blocks) as f64).round() as u64;
+
+        Ok(SampledRangeEstimate {
+            total_blocks,
+            already_indexed_blocks,
+            estimated_new_events,
+            estimated_storage_bytes: estimated_new_events.saturating_mul(ESTIMATE_EVENT_BYTES),
+        })
+    }
+
+    pub async fn index_contract_events(
+        &self,
+        from_block: Option<BlockId>,
+        to_block: Option<BlockId>,
+        contract_address: FieldElement,
+        chain_id: &str,
+    ) -> IndexerResult<()> {
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let result = self
+                .rpc_permits
+                .call(self.client.fetch_events(
+                    from_block,
+                    to_block,
+                    self.event_manager.keys_selector(),
+                    Some(contract_address),
+                    continuation_token,
+                ))
+                .await?;
+
+            let mut current_block: Option<BlockContext> = None;
+
+            for (block_number, events) in result.events {
+                let block = match current_block {
+                    Some(block) if block.block_number() == Some(block_number) => block,
+                    _ => match self
+                        .rpc_permits
+                        .call(self.client.block_time(BlockId::Number(block_number)))
+                        .await
+                    {
+                        Ok(ts) => *current_block.insert(BlockContext::new(block_number, ts)),
+                        Err(e) => {
+                            error!("Error while fetching block timestamp: {:?}", e);
+                            continue;
+                        }
+                    },
+                };
+
+                self.process_events(events, &block, chain_id).await?;
+            }
+
+            if result.continuation_token.is_none() {
+                break;
+            } else {
+                continuation_token = result.continuation_token;
+                continue;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// If "Latest" is used for the `to_block`,
+    /// this function will only index the latest block
+    /// that is not pending.
+    /// With `PontosConfig::continuous_mode`, the function doesn't return
+    /// when the latest block is reached, and keeps indexing the new latest
+    /// blocks, polled at the base interval of `PontosConfig::pending_polling`.
+    /// The block currently held by `index_pending` of this instance is indexed
+    /// once promoted to latest, or `PendingBlockHeld` is returned if it is not
+    /// promoted within 10 minutes. If you use this on latest, be sure to don't have
+    /// any other pontos instance running `index_pending` as you may
+    /// deal with overlaps or at least check db registers first.
+    pub async fn index_block_range(
+        &self,
+        from_block: BlockId,
+        to_block: BlockId,
+        do_force: bool,
+        chain_id: &str,
+    ) -> IndexerResult<()> {
+        self.index_block_range_with(
+            RangeOptions::new(from_block, to_block, chain_id).with_force(do_force),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Same as `index_block_range`, with the given options,
+    /// and returns the blocks processed.
+    /// If an observer is set, the error interrupting the range is sent to it,
+    /// and only the message of the error is returned.
+    pub async fn index_block_range_with(
+        &self,
+        options: RangeOptions,
+    ) -> IndexerResult<IndexingReport> {
+        let indexed = self
+            .index_range(&options)
+            .instrument(self.indexer_span("range"))
+            .await;
+
+        match (indexed, &options.observer) {
+            (Err(e), Some(tx)) => {
+                let error = IndexerError::Anyhow(e.to_string());
+                if tx.send(Err(e)).await.is_err() {
+                    debug!("Block range observer closed");
+                }
+                Err(error)
+            }
+            (indexed, _) => indexed,
+        }
+    }
+
+    /// Same as `index_block_range`, from the genesis block (block 0, not 1)
+    /// up to `to_block`, to bootstrap a new index.
+    pub async fn index_block_range_from_genesis(
+        &self,
+        to_block: BlockId,
+        do_force: bool,
+        chain_id: &str,
+    ) -> IndexerResult<()> {
+        self.index_block_range(BlockId::Number(0), to_block, do_force, chain_id)
+            .await
+    }
+
+    /// Same as `index_block_range`, with a finer policy than `do_force` to
+    /// decide which blocks already indexed are indexed again, like the blocks
+    /// indexed by the versions preceding a fix.
+    pub async fn index_block_range_with_force_policy(
+        &self,
+        from_block: BlockId,
+        to_block: BlockId,
+        force: &ForcePolicy,
+        chain_id: &str,
+    ) -> IndexerResult<IndexingReport> {
+        self.index_block_range_with(
+            RangeOptions::new(from_block, to_block, chain_id).with_force_policy(force.clone()),
+        )
+        .await
+    }
+
+    /// Same as `index_block_range`, but no new block is started once the
+    /// `deadline` is reached, on the clock of the configuration. The block
+    /// being indexed at the deadline is completed before returning.
+    ///
+    /// Returns the last block indexed (or skipped as already indexed), to resume
+    /// from the next one, or `None` if the deadline is reached before the first
+    /// block, to resume from `from_block`.
+    pub async fn index_block_range_bounded(
+        &self,
+        from_block: BlockId,
+        to_block: BlockId,
+        do_force: bool,
+        chain_id: &str,
+        deadline: Instant,
+    ) -> IndexerResult<Option<u64>> {
+        self.index_block_range_with(
+            RangeOptions::new(from_block, to_block, chain_id)
+                .with_force(do_force)
+                .with_deadline(deadline),
+        )
+        .await
+        .map(|report| report.last_block)
+    }
+
+    /// Same as `index_block_range`, but a permit of the given semaphore is
+    /// held while each block is indexed. Sharing the semaphore between
+    /// several instances limits the number of blocks indexed at once against
+    /// a shared node. The permit is released while waiting before a retry.
+    pub async fn index_block_range_with_concurrency_limit(
+        &self,
+        from_block: BlockId,
+        to_block: BlockId,
+        do_force: bool,
+        chain_id: &str,
+        permits: Arc<Semaphore>,
+    ) -> IndexerResult<()> {
+        self.index_block_range_with(
+            RangeOptions::new(from_block, to_block, chain_id)
+                .with_force(do_force)
+                .with_concurrency_limit(permits),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Same as `index_block_range`, but each block indexed is sent to `tx`
+    /// as soon as it is terminated or marked as failed. The blocks skipped as
+    /// already indexed are not sent. An error interrupting the range is sent
+    /// last. Returns once all the results were sent: a full channel delays
+    /// the indexing, and a closed channel is ignored.
+    pub async fn index_block_range_with_observer(
+        &self,
+        from_block: BlockId,
+        to_block: BlockId,
+        do_force: bool,
+        chain_id: &str,
+        tx: mpsc::Sender<IndexerResult<BlockCompleted>>,
+    ) {
+        // The error is sent to the observer.
+        let _ = self
+            .index_block_range_with(
+                RangeOptions::new(from_block, to_block, chain_id)
+                    .with_force(do_force)
+                    .with_observer(tx),
+            )
+            .await;
+    }
+
+    /// Same as `index_block_range`, with a progress bar drawn on the standard
+    /// error, updated after each block terminated or marked as failed.
+    /// The bar is left on its own line once the range is done or failed.
+    #[cfg(feature = "cli")]
+    pub async fn index_block_range_with_progress_bar(
+        &self,
+        from_block: BlockId,
+        to_block: BlockId,
+        do_force: bool,
+        chain_id: &str,
+    ) -> IndexerResult<()> {
+        let from_u64 = self
+            .rpc_permits
+            .call(self.client.block_id_to_u64(&from_block))
+            .await?;
+        let to_u64 = self
+            .rpc_permits
+            .call(self.client.block_id_to_u64(&to_block))
+            .await?;
+
+        let total_blocks = to_u64.saturating_sub(from_u64) + u64::from(from_u64 <= to_u64);
+        let bar = indicatif::ProgressBar::new(total_blocks).with_style(progress_style());
+        bar.set_message("0 events");
+        let mut events: u64 = 0;
+        let mut result = Ok(());
+        let (tx, mut rx) = mpsc::channel(16);
+
+        tokio::join!(
+            self.index_block_range_with_observer(
+                BlockId::Number(from_u64),
+                BlockId::Number(to_u64),
+                do_force,
+                chain_id,
+                tx,
+            ),
+            async {
+                while let Some(completed) = rx.recv().await {
+                    match completed {
+                        Ok(completed) => {
+                            // The blocks skipped before count in the position.
+                            let position = completed.block_number.saturating_sub(from_u64) + 1;
+                            bar.set_position(bar.position().max(position).min(total_blocks));
+                            events += completed.event_count;
+                            bar.set_message(format!("{} events", events));
+                        }
+                        Err(e) => result = Err(e),
+                    }
+                }
+            }
+        );
+
+        match result {
+            Ok(()) => bar.finish(),
+            Err(_) => bar.abandon(),
+        }
+        result
+    }
+
+    /// Indexes the given blocks, which may be sparse, like the blocks where
+    /// a contract emitted events, with the pipeline of `index_block_range`.
+    ///
+    /// The blocks are consumed from the stream one at a time, and must be
+    /// in ascending order: a repeated block is skipped, and a block lower
+    /// than the previous one stops the indexation with an error.
+    pub async fn index_specific_blocks(
+        &self,
+        blocks: impl Stream<Item = u64> + Send,
+        do_force: bool,
+        chain_id: &str,
+    ) -> IndexerResult<()> {
+        let mut blocks = std::pin::pin!(blocks);
+        let mut previous: Option<u64> = None;
+
+        while let Some(block) = blocks.next().await {
+            match previous {
+                Some(p) if block == p => continue,
+                Some(p) if block < p => {
+                    return Err(IndexerError::Anyhow(format!(
+                        "Blocks to index are not sorted: {} after {}",
+                        block, p
+                    )));
+                }
+                _ => previous = Some(block),
+            }
+
+            if self.is_shutting_down() {
+                info!("Shutdown requested before indexing block {}", block);
+                break;
+            }
+
+            self.index_range(
+                &RangeOptions::new(BlockId::Number(block), BlockId::Number(block), chain_id)
+                    .with_force(do_force),
+            )
+            .instrument(self.indexer_span("blocks"))
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the blocks from `from` to `to` inclusive which are not terminated,
+    /// never indexed or left in processing or failed, in ascending order.
+    /// They can be given to `index_specific_blocks` to fill the gaps of a range.
+    pub async fn list_unindexed_blocks(&self, from: u64, to: u64) -> IndexerResult<Vec<u64>> {
+        if from > to {
+            return Ok(vec![]);
+        }
+
+        let terminated: HashSet<u64> = self
+            .storage
+            .get_terminated_blocks_in_range(from, to)
+            .await?
+            .into_iter()
+            .collect();
+
+        Ok((from..=to).filter(|n| !terminated.contains(n)).collect())
+    }
+
+    /// Same as `index_block_range`, but the emitters of the events of each block
+    /// are identified before the events are processed: only the events emitted
+    /// by the NFT contracts and the marketplaces, or matched by an attribution
+    /// rule, are kept. All the events of the block are first fetched without
+    /// key filter to find their emitters, then the events of each NFT contract
+    /// are fetched with the key and address filters.
+    pub async fn index_block_range_with_pre_filter(
+        &self,
+        from_block: BlockId,
+        to_block: BlockId,
+        do_force: bool,
+        chain_id: &str,
+    ) -> IndexerResult<()> {
+        self.index_block_range_with(
+            RangeOptions::new(from_block, to_block, chain_id)
+                .with_force(do_force)
+                .with_pre_filter(),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Same as `index_block_range`, but the events of the blocks the client
+    /// has no events for, while they have transactions, are fetched from
+    /// the `fallback` client. This is intended for a client connected to a
+    /// node missing the archival data of the old blocks. The client serving
+    /// each block is logged.
+    ///
+    /// The events of the blocks warmed up by `warm_up` are not fetched again.
+    pub async fn index_block_range_with_storage_fallback<C2>(
+        &self,
+        from_block: BlockId,
+        to_block: BlockId,
+        do_force: bool,
+        chain_id: &str,
+        fallback: Arc<C2>,
+    ) -> IndexerResult<()>
+    where
+        C2: StarknetClient + Send + Sync + 'static,
+    {
+        self.index_block_range_with(
+            RangeOptions::new(from_block, to_block, chain_id)
+                .with_force(do_force)
+                .with_storage_fallback(fallback),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Fetches the timestamps and the events of the blocks `from..=to`
+    /// concurrently, for the next `index_block_range` of this instance
+    /// covering those blocks to process them without waiting for the node.
+    ///
+    /// The fetched events are held in memory until their block is indexed
+    /// (or skipped): this is intended for small ranges. The blocks fetched
+    /// before an error are kept.
+    pub async fn warm_up(&self, from: u64, to: u64) -> IndexerResult<()> {
+        let keys = self.event_manager.keys_selector();
+
+        futures::stream::iter(from..=to)
+            .filter(|n| futures::future::ready(!self.warm_blocks.contains_key(n)))
+            .map(Ok)
+            .try_for_each_concurrent(WARM_UP_CONCURRENCY, |n| {
+                let keys = keys.clone();
+                async move {
+                    let block = BlockId::Number(n);
+                    let (timestamp, events) = tokio::try_join!(
+                        self.rpc_permits.call(self.client.block_time(block)),
+                        self.rpc_permits
+                            .call(self.client.fetch_all_block_events(block, keys)),
+                    )?;
+
+                    self.warm_blocks.insert(n, WarmBlock { timestamp, events });
+                    Ok::<(), IndexerError>(())
+                }
+            })
+            .await?;
+
+        let events: usize = self
+            .warm_blocks
+            .iter()
+            .filter(|b| (from..=to).contains(b.key()))
+            .map(|b| b.events.values().map(|e| e.len()).sum::<usize>())
+            .sum();
+        debug!(
+            "Warmed up blocks {} to {}, with {} events",
+            from, to, events
+        );
+
+        Ok(())
+    }
+
+    /// Indexes the block range, and returns the blocks processed.
+    async fn index_range(&self, options: &RangeOptions) -> IndexerResult<IndexingReport> {
+        let RangeOptions {
+            from_block,
+            to_block,
+            chain_id,
+            force,
+            permits,
+            deadline,
+            observer,
+            source,
+        } = options;
+        let chain_id = chain_id.as_str();
+        let observer = observer.as_ref();
+        let do_force = *force == ForcePolicy::Always;
+        let _active = self.active_loops.read().await;
+        self.ensure_preflight().await?;
+
+        let mut current_u64 = self
+            .rpc_permits
+            .call(self.client.block_id_to_u64(from_block))
+            .await?;
+        let mut to_u64 = self
+            .rpc_permits
+            .call(self.client.block_id_to_u64(to_block))
+            .await?;
+        let from_u64 = current_u64;
+        let to_latest = *to_block == BlockId::Tag(BlockTag::Latest);
+        let follow_latest = self.config.continuous_mode && to_latest;
+        if to_latest {
+            self.observe_chain_head(to_u64);
+        }
+
+        // Some contracts are causing too much recursion for the Cairo VM.
+        // This is restarting the full node (Juno) as it is OOM and is shutdown by the OS.
+        // To mitigate this problem before scaling the full node up,
+        // we setup a `max_attempt` to reach the full node before skipping
+        // the entire block.
+        // Currently, we observed that the node almost always reponds after the
+        // second attempt.
+        let max_attempt = 5;
+        let mut attempt: u32 = 0;
+        let mut fetch_attempt: u32 = 0;
+        // Block held by the pending loop, and start of the wait for its promotion.
+        let mut held_since: Option<(u64, Instant)> = None;
+
+        // Only the ranges larger than a chunk, with a fixed end, are chunked.
+        let chunk_blocks = self
+            .config
+            .range_chunk_blocks
+            .unwrap_or(DEFAULT_RANGE_CHUNK_BLOCKS)
+            .max(1);
+        let cursor_key =
+            (!follow_latest && to_u64 >= from_u64 && to_u64 - from_u64 >= chunk_blocks)
+                .then(|| range_cursor_key(&self.config.indexer_identifier, from_u64, to_u64));
+
+        if let Some(key) = &cursor_key {
+            match self.storage.get_reindex_cursor(key).await? {
+                Some(cursor) if cursor > from_u64 && cursor <= to_u64 => {
+                    info!("Resuming block range from block {}", cursor);
+                    current_u64 = cursor;
+                }
+                _ => {}
+            }
+        }
+
+        let mut chunk = RangeChunkReport {
+            from_block: current_u64,
+            to_block: to_u64.min(current_u64.saturating_add(chunk_blocks - 1)),
+            ..Default::default()
+        };
+        let mut report = IndexingReport::default();
+
+        loop {
+            trace!("Indexing block range: {} {}", current_u64, to_u64);
+
+            if let Some(key) = cursor_key
+                .as_deref()
+                .filter(|_| current_u64 > chunk.to_block)
+            {
+                info!(
+                    "Block range chunk {} - {} completed",
+                    chunk.from_block, chunk.to_block
+                );
+                self.storage
+                    .set_reindex_cursor(key, Some(current_u64))
+                    .await?;
+                self.event_handler.on_range_chunk_completed(&chunk).await;
+
+                chunk = RangeChunkReport {
+                    from_block: current_u64,
+                    to_block: to_u64.min(current_u64.saturating_add(chunk_blocks - 1)),
+                    ..Default::default()
+                };
+            }
+
+            if self.is_shutting_down() {
+                info!("Shutdown requested before indexing block {}", current_u64);
+                report.last_block = last_reached_block(from_u64, current_u64);
+                return Ok(report);
+            }
+
+            if deadline.is_some_and(|d| self.clock.now() >= d) {
+                info!("Deadline reached before indexing block {}", current_u64);
+                self.apply_retention().await?;
+                report.last_block = last_reached_block(from_u64, current_u64);
+                return Ok(report);
+            }
+
+            if current_u64 > to_u64 {
+                if !follow_latest {
+                    info!("End of indexing block range");
+                    break;
+                }
+
+                self.apply_retention().await?;
+                self.report_lag().await;
+                self.register_indexer().await;
+
+                self.pause(self.config.pending_polling.base_interval())
+                    .await;
+
+                match self
+                    .rpc_permits
+                    .call(self.client.block_id_to_u64(to_block))
+                    .await
+                {
+                    Ok(latest) => {
+                        to_u64 = latest;
+                        self.observe_chain_head(latest);
+                    }
+                    Err(e) => error!("Couldn't get the latest block: {:?}", e),
+                };
+
+                continue;
+            }
+
+            // Released at the end of the iteration.
+            let permit = match &permits {
+                Some(p) => Some(Arc::clone(p).acquire_owned().await.map_err(|e| {
+                    IndexerError::Anyhow(format!("Concurrency limit semaphore closed: {}", e))
+                })?),
+                None => None,
+            };
+
+            let warm_ts = self.warm_blocks.get(&current_u64).map(|b| b.timestamp);
+            let block_ts = match warm_ts {
+                Some(ts) => Ok(ts),
+                None => {
+                    self.rpc_permits
+                        .call(self.client.block_time(BlockId::Number(current_u64)))
+                        .await
+                }
+            };
+            let block_ts = match block_ts {
+                Ok(ts) => ts,
+                Err(e) => {
+                    error!(
+                        "Attempt #{} - Couldn't get timestamp for block {}: {:?}",
+                        attempt + 1,
+                        current_u64,
+                        e
+                    );
+                    attempt += 1;
+                    self.event_handler
+                        .on_rpc_retry(current_u64, attempt, &e.into())
+                        .await;
+                    drop(permit);
+                    self.pause(Duration::from_secs(1)).await;
+
+                    if attempt > max_attempt {
+                        warn!(
+                            "Skipping block {} as timestamp is not available",
+                            current_u64
+                        );
+                        current_u64 += 1;
+                    }
+
+                    continue;
+                }
+            };
+
+            if self.is_held_by_pending(block_ts).await {
+                let since = match held_since {
+                    Some((block, since)) if block == current_u64 => since,
+                    _ => held_since.insert((current_u64, self.clock.now())).1,
+                };
+                let waited = self.clock.now().saturating_duration_since(since);
+                if waited >= PENDING_HOLD_TIMEOUT {
+                    return Err(IndexerError::PendingBlockHeld {
+                        block: current_u64,
+                        waited,
+                    });
+                }
+
+                debug!(
+                    "Block {} is held by the pending loop, waiting for its promotion",
+                    current_u64
+                );
+                drop(permit);
+                self.pause(self.config.pending_polling.base_interval())
+                    .await;
+                continue;
+            }
+            held_since = None;
+
+            let decision = self
+                .block_manager
+                .indexing_decision(
+                    current_u64,
+                    block_ts,
+                    &self.config.indexer_version,
+                    &self.config.indexer_identifier,
+                    force,
+                )
+                .await?;
+            if let IndexingDecision::Skip(reason) = decision {
+                if reason == SkipReason::OtherIdentifier {
+                    warn!(
+                        "Skipping block {} indexed by an other indexer identifier",
+                        current_u64
+                    );
+                } else {
+                    info!("Skipping block {} ({:?})", current_u64, reason);
+                }
+                self.warm_blocks.remove(&current_u64);
+                chunk.skipped_blocks += 1;
+                report.skipped_blocks += 1;
+                report.skips.record(reason);
+                self.event_handler
+                    .on_block_skipped(current_u64, reason)
+                    .await;
+                current_u64 += 1;
+                continue;
+            }
+
+            let block_started_at = self.clock.now();
+            self.event_handler
+                .on_block_processing(block_ts, Some(current_u64))
+                .await;
+
+            // Set block as processing.
+            self.block_manager
+                .set_block_info(
+                    current_u64,
+                    block_ts,
+                    self.config.indexer_version.clone(),
+                    self.config.indexer_identifier.clone(),
+                    BlockIndexingStatus::Processing,
+                    do_force,
+                    None,
+                )
+                .await?;
+
+            let blocks_events = match (self.warm_blocks.remove(&current_u64), source) {
+                (Some((_, warm)), _) => Ok(warm.events),
+                (None, EventSource::PreFiltered) => {
+                    self.fetch_block_events_pre_filtered(current_u64, block_ts, chain_id)
+                        .await
+                }
+                (None, EventSource::WithFallback(fallback)) => {
+                    self.fetch_block_events_with_fallback(current_u64, fallback.as_ref())
+                        .await
+                }
+                (None, EventSource::Selectors) => {
+                    self.rpc_permits
+                        .call(self.client.fetch_all_block_events(
+                            BlockId::Number(current_u64),
+                            self.event_manager.keys_selector(),
+                        ))
+                        .await
+                }
+            };
+            let blocks_events = match blocks_events {
+                Ok(events) => {
+                    fetch_attempt = 0;
+                    events
+                }
+                Err(e) => {
+                    error!("Error while fetching events: {:?}", e);
+                    fetch_attempt += 1;
+                    self.event_handler
+                        .on_rpc_retry(current_u64, fetch_attempt, &e.into())
+                        .await;
+                    drop(permit);
+                    self.pause(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let total_events_count: usize = blocks_events.values().map(|events| events.len()).sum();
+            info!(
+                "✨ Processing block {}. Total Events Count: {}.",
+                current_u64, total_events_count
+            );
+
+            let span = tracing::info_span!(
+                "block",
+                number = current_u64,
+                events = total_events_count,
+                version = %self.config.indexer_version,
+                status = tracing::field::Empty,
+            );
+
+            let mut activity = BlockActivity::default();
+            let mut processed = Ok(());
+            match self.config.max_events_per_block {
+                Some(max) if total_events_count > max => {
+                    error!(
+                        "Block {} has {} events, more than the maximum of {}",
+                        current_u64, total_events_count, max
+                    );
+                    drop(blocks_events);
+                    processed = Err(IndexerError::BlockFailed {
+                        block: current_u64,
+                        error: format!(
+                            "{} events, more than the maximum of {}",
+                            total_events_count, max
+                        ),
+                    });
+                }
+                _ => {
+                    self.storage.begin_bulk_write().await?;
+
+                    let block = BlockContext::new(current_u64, block_ts);
+                    for (_, events) in blocks_events {
+                        processed = self
+                            .process_block_events(events, &block, chain_id, &mut activity)
+                            .instrument(span.clone())
+                            .await;
+                        if processed.is_err() {
+                            break;
+                        }
+                    }
+
+                    self.storage.end_bulk_write().await?;
+                }
+            }
+            if processed.is_err() {
+                span.record("status", "failed");
+            }
+            if let Err(e @ IndexerError::BlockFailed { .. }) = processed {
+                error!("Block {} failed: {}", current_u64, e);
+                self.block_manager
+                    .set_block_info(
+                        current_u64,
+                        block_ts,
+                        self.config.indexer_version.clone(),
+                        self.config.indexer_identifier.clone(),
+                        BlockIndexingStatus::Failed,
+                        do_force,
+                        None,
+                    )
+                    .await?;
+                self.event_handler.on_block_failed(current_u64, &e).await;
+                self.notify_observer(
+                    observer,
+                    BlockCompleted {
+                        block_number: current_u64,
+                        event_count: total_events_count as u64,
+                        duration_ms: self
+                            .clock
+                            .now()
+                            .saturating_duration_since(block_started_at)
+                            .as_millis() as u64,
+                        status: BlockIndexingStatus::Failed,
+                    },
+                )
+                .await;
+
+                if self.config.abort_on_failed_block {
+                    return Err(e);
+                }
+
+                report.failed_blocks += 1;
+                current_u64 += 1;
+                continue;
+            }
+            processed?;
+
+            self.event_handler
+                .on_block_collections_summary(current_u64, activity.into_summary())
+                .await;
+
+            self.block_manager
+                .set_block_info(
+                    current_u64,
+                    block_ts,
+                    self.config.indexer_version.clone(),
+                    self.config.indexer_identifier.clone(),
+                    BlockIndexingStatus::Terminated,
+                    do_force,
+                    None,
+                )
+                .await?;
+            span.record("status", "terminated");
+            chunk.indexed_blocks += 1;
+            report.indexed_blocks += 1;
+            if decision == IndexingDecision::Reindex {
+                chunk.reindexed_blocks += 1;
+                report.reindexed_blocks += 1;
+            }
+
+            self.last_indexed_block
+                .fetch_max(current_u64 + 1, Ordering::Relaxed);
+
+            let progress = if to_u64 == from_u64 {
+                if current_u64 == to_u64 {
+                    100.0
+                } else {
+                    0.0
+                }
+            } else {
+                ((current_u64 - from_u64) as f64 / (to_u64 - from_u64) as f64) * 100.0
+            };
+
+            self.event_handler
+                .on_block_processed(current_u64, progress)
+                .await;
+            self.notify_observer(
+                observer,
+                BlockCompleted {
+                    block_number: current_u64,
+                    event_count: total_events_count as u64,
+                    duration_ms: self
+                        .clock
+                        .now()
+                        .saturating_duration_since(block_started_at)
+                        .as_millis() as u64,
+                    status: BlockIndexingStatus::Terminated,
+                },
+            )
+            .await;
+            self.report_lag().await;
+            self.register_indexer().await;
+
+            current_u64 += 1;
+        }
+
+        if let Some(key) = &cursor_key {
+            self.storage.set_reindex_cursor(key, None).await?;
+        }
+
+        self.apply_retention().await?;
+        self.event_handler.on_indexation_range_completed().await;
+
+        report.last_block = last_reached_block(from_u64, current_u64);
+        Ok(report)
+    }
+
+    /// Fetches the events of the block matching the indexed selectors from the
+    /// client, or from the `fallback` client if the client has none while the
+    /// block has transactions. As every transaction emits at least the transfer
+    /// of its fee, which matches the `Transfer` selector, no events for a block
+    /// with transactions means the node is missing the data of the block.
+    async fn fetch_block_events_with_fallback(
+        &self,
+        block_number: u64,
+        fallback: &(dyn StarknetClient + Send + Sync),
+    ) -> Result<HashMap<u64, Vec<EmittedEvent>>, StarknetClientError> {
+        let block = BlockId::Number(block_number);
+        let keys = self.event_manager.keys_selector();
+
+        let events = self
+            .rpc_permits
+            .call(self.client.fetch_all_block_events(block, keys.clone()))
+            .await?;
+        if events.values().any(|events| !events.is_empty()) {
+            info!("Block {} served by the primary client", block_number);
+            return Ok(events);
+        }
+
+        let (_, transactions) = self
+            .rpc_permits
+            .call(self.client.block_txs_hashes(block))
+            .await?;
+        if transactions.is_empty() {
+            info!("Block {} served by the primary client", block_number);
+            return Ok(events);
+        }
+
+        warn!(
+            "Primary client has no events for block {} with {} transactions, using the fallback client",
+            block_number,
+            transactions.len()
+        );
+        let events = self
+            .rpc_permits
+            .call(fallback.fetch_all_block_events(block, keys))
+            .await?;
+        info!("Block {} served by the fallback client", block_number);
+
+        Ok(events)
+    }
+
+    /// Fetches all the events of the block, without key filter, to identify
+    /// their emitters, and keeps the events matching the indexed selectors which
+    /// are emitted by NFT contracts or marketplaces, or matched by an
+    /// attribution rule. An emitter which can't be identified is kept, for
+    /// its events to be handled by the processing.
+    async fn fetch_block_events_pre_filtered(
+        &self,
+        block_number: u64,
+        block_timestamp: u64,
+        chain_id: &str,
+    ) -> Result<HashMap<u64, Vec<EmittedEvent>>, StarknetClientError> {
+        let block = BlockId::Number(block_number);
+        let selectors: HashSet<FieldElement> = self
+            .event_manager
+            .keys_selector()
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect();
+
+        // All the events of the block, to find their emitters.
+        let events = self.fetch_all_events_of(block, None, None).await?;
+
+        // Each emitter is identified once, the first of its events
+        // being used by the `EventPatternMatching` strategy.
+        let mut emitters: HashMap<FieldElement, bool> = HashMap::new();
+        let mut nft_emitters = vec![];
+        let mut kept = vec![];
+
+        for (index, event) in events.iter().enumerate() {
+            if !event.keys.first().is_some_and(|k| selectors.contains(k)) {
+                continue;
+            }
+
+            let is_nft = match emitters.get(&event.from_address) {
+                Some(is_nft) => *is_nft,
+                None => {
+                    let is_nft = is_marketplace_contract(&event.from_address)
+                        || match self
+                            .contract_manager
+                            .identify_contract_from_event(event, block_timestamp, chain_id)
+                            .await
+                        {
+                            Ok(contract_type) => contract_type != ContractType::Other,
+                            Err(e) => {
+                                warn!(
+                                    "Couldn't identify emitter 0x{:064x}: {:?}",
+                                    event.from_address, e
+                                );
+                                true
+                            }
+                        };
+                    emitters.insert(event.from_address, is_nft);
+                    if is_nft {
+                        nft_emitters.push(event.from_address);
+                    }
+                    is_nft
+                }
+            };
+
+            // The events of the NFT contracts are fetched again below.
+            let tx_events = crate::attribution::transaction_events(&events, index);
+            if !is_nft
+                && self.attribution_rules.iter().any(|rule| {
+                    !matches!(rule.attribute(event, tx_events), Attribution::NotApplicable)
+                })
+            {
+                kept.push(event.clone());
+            }
+        }
+
+        // Only the events of the NFT contracts matching the indexed
+        // selectors are fetched, with the key and the address filters.
+        for address in nft_emitters {
+            kept.extend(
+                self.fetch_all_events_of(block, self.event_manager.keys_selector(), Some(address))
+                    .await?,
+            );
+        }
+
+        // The events are processed in the order they were emitted.
+        let order: HashMap<_, usize> = events
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, e)| (event_order_key(e), index))
+            .collect();
+        kept.sort_by_key(|e| order.get(&event_order_key(e)).copied());
+
+        debug!(
+            "Block {}: {} events kept out of {} by the pre-filter",
+            block_number,
+            kept.len(),
+            events.len()
+        );
+
+        Ok(HashMap::from([(block_number, kept)]))
+    }
+
+    /// Fetches all the pages of the events of the block
+    /// matching the given filters.
+    async fn fetch_all_events_of(
+        &self,
+        block: BlockId,
+        keys: Option<Vec<Vec<FieldElement>>>,
+        contract_address: Option<FieldElement>,
+    ) -> Result<Vec<EmittedEvent>, StarknetClientError> {
+        let mut events = vec![];
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let result = self
+                .rpc_permits
+                .call(self.client.fetch_events(
+                    Some(block),
+                    Some(block),
+                    keys.clone(),
+                    contract_address,
+                    continuation_token,
+                ))
+                .await?;
+
+            events.extend(result.events.into_values().flatten());
+
+            continuation_token = result.continuation_token;
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MockStorage;
+    use crate::tests::{config, TestEventHandler};
+    use crate::{PendingPolling, PontosConfig, SkipCounts};
+    use ark_starknet::client::MockStarknetClient;
+    use starknet::macros::selector;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_index_contract_events_from_genesis() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_block_timestamp, synthetic_contracts,
+            InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks: HashMap<u64, _> = (0..=1)
+            .map(|b| (b, synthetic_block(b, 2, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        pontos
+            .index_contract_events(
+                Some(BlockId::Number(0)),
+                Some(BlockId::Number(1)),
+                contracts[0].address,
+                "SN_MAIN",
+            )
+            .await
+            .unwrap();
+
+        let data = storage.dump();
+        assert_eq!(data.transfer_events.len(), 4);
+        for event in data.transfer_events.values() {
+            let block_number = event.block_number.unwrap();
+            assert_eq!(event.timestamp, synthetic_block_timestamp(block_number));
+        }
+        assert!(data
+            .transfer_events
+            .values()
+            .any(|e| e.block_number == Some(0)));
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_continuous_mode() {
+        use crate::testing::{InMemoryStorage, NoopEventHandler};
+
+        // The latest block moves forward each time it is requested, up to block 3.
+        let latest_queries = Arc::new(AtomicU64::new(0));
+        let queries = Arc::clone(&latest_queries);
+
+        let mut client = MockStarknetClient::default();
+        client
+            .expect_block_id_to_u64()
+            .returning(move |id| match id {
+                BlockId::Number(n) => Ok(*n),
+                _ => Ok(std::cmp::min(queries.fetch_add(1, Ordering::SeqCst) + 1, 3)),
+            });
+        client.expect_block_time().returning(|_| Ok(1000));
+        client
+            .expect_fetch_all_block_events()
+            .returning(|_, _| Ok(HashMap::new()));
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                pending_polling: PendingPolling::FixedInterval(Duration::from_millis(5)),
+                continuous_mode: true,
+                ..config()
+            },
+        );
+
+        let result = tokio::time::timeout(
+            Duration::from_millis(200),
+            pontos.index_block_range(
+                BlockId::Number(1),
+                BlockId::Tag(BlockTag::Latest),
+                false,
+                "SN_MAIN",
+            ),
+        )
+        .await;
+
+        // Still waiting for new blocks.
+        assert!(result.is_err());
+        assert!(latest_queries.load(Ordering::SeqCst) > 3);
+
+        let mut blocks: Vec<u64> = storage.dump().blocks.into_keys().collect();
+        blocks.sort();
+        assert_eq!(blocks, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_estimate_range() {
+        use crate::testing::{mock_client, synthetic_block, synthetic_contracts};
+
+        let contracts = synthetic_contracts(2, 1);
+        let blocks = HashMap::from([
+            (1, synthetic_block(1, 10, &contracts)),
+            (2, synthetic_block(2, 5, &contracts)),
+            (1500, synthetic_block(1500, 2, &contracts)),
+            (3000, synthetic_block(3000, 4, &contracts)),
+        ]);
+
+        // No expectation is set on the storage, any access would panic.
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::new(MockStorage::default()),
+            Arc::new(TestEventHandler),
+            config(),
+        );
+
+        let estimate = pontos
+            .estimate_range(BlockId::Number(1), BlockId::Number(1500))
+            .await
+            .unwrap();
+
+        assert_eq!(estimate.total_events, 17);
+        assert_eq!(estimate.blocks_with_events, 3);
+        assert_eq!(estimate.blocks_with_events_density, 3.0 / 1500.0);
+        assert_eq!(
+            estimate.events_per_bucket,
+            BTreeMap::from([(0, 15), (1000, 2)])
+        );
+        assert_eq!(
+            estimate.top_contracts,
+            vec![
+                ContractEventCount {
+                    contract_address: to_hex_str(&contracts[0].address),
+                    events: 7,
+                },
+                ContractEventCount {
+                    contract_address: to_hex_str(&contracts[1].address),
+                    events: 6,
+                },
+                ContractEventCount {
+                    contract_address: to_hex_str(&contracts[2].address),
+                    events: 4,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_estimate_only() {
+        use crate::storage::types::BlockInfo;
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = (1..=100)
+            .map(|n| (n, synthetic_block(n, 2, &contracts)))
+            .collect();
+        let storage = Arc::new(InMemoryStorage::new());
+        for n in 1..=25 {
+            let info = BlockInfo {
+                indexer_version: "v0".to_string(),
+                indexer_identifier: "test".to_string(),
+                status: BlockIndexingStatus::Terminated,
+                block_number: n,
+                selector_hash: None,
+                last_heartbeat_at: 0,
+            };
+            storage.set_block_info(n, n, info).await.unwrap();
+        }
+
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            storage,
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        assert_eq!(
+            pontos
+                .index_block_range_estimate_only(1, 100)
+                .await
+                .unwrap(),
+            SampledRangeEstimate {
+                total_blocks: 100,
+                already_indexed_blocks: 25,
+                estimated_new_events: 150,
+                estimated_storage_bytes: 150 * ESTIMATE_EVENT_BYTES,
+            }
+        );
+        assert_eq!(
+            pontos
+                .index_block_range_estimate_only(101, 104)
+                .await
+                .unwrap()
+                .estimated_new_events,
+            0
+        );
+        assert_eq!(
+            pontos.index_block_range_estimate_only(2, 1).await.unwrap(),
+            SampledRangeEstimate::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_from_genesis() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = (0..=2)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        pontos
+            .index_block_range_from_genesis(BlockId::Number(2), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let mut indexed: Vec<u64> = storage.dump().blocks.keys().copied().collect();
+        indexed.sort();
+        assert_eq!(indexed, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_with_concurrency_limit() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = HashMap::from([
+            (1, synthetic_block(1, 2, &contracts)),
+            (2, synthetic_block(2, 2, &contracts)),
+        ]);
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        // No permit is available until an other instance releases one.
+        let permits = Arc::new(Semaphore::new(0));
+
+        let index = pontos.index_block_range_with_concurrency_limit(
+            BlockId::Number(1),
+            BlockId::Number(2),
+            false,
+            "SN_MAIN",
+            Arc::clone(&permits),
+        );
+
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(storage.dump().blocks.is_empty());
+            permits.add_permits(1);
+        };
+
+        let (result, _) = tokio::join!(index, release);
+        result.unwrap();
+
+        assert_eq!(storage.dump().blocks.len(), 2);
+        assert_eq!(permits.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_bounded() {
+        use crate::testing::{mock_client, synthetic_block, synthetic_contracts, InMemoryStorage};
+
+        /// Makes each block last long enough for the deadline to be reached.
+        struct SlowHandler;
+
+        #[async_trait::async_trait]
+        impl EventHandler for SlowHandler {
+            async fn on_block_processed(&self, _block_number: u64, _indexation_progress: f64) {
+                tokio::time::sleep(Duration::from_millis(60)).await;
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks: HashMap<u64, Vec<EmittedEvent>> = (1..=3)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(SlowHandler),
+            config(),
+        );
+
+        // Deadline already reached, no block is started.
+        let last = pontos
+            .index_block_range_bounded(
+                BlockId::Number(1),
+                BlockId::Number(3),
+                false,
+                "SN_MAIN",
+                Instant::now(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(last, None);
+        assert!(storage.dump().blocks.is_empty());
+
+        // Nothing indexed from the genesis block is not reported as the block 0.
+        let last = pontos
+            .index_block_range_bounded(
+                BlockId::Number(0),
+                BlockId::Number(3),
+                false,
+                "SN_MAIN",
+                Instant::now(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(last, None);
+
+        // The first block is completed after the deadline.
+        let last = pontos
+            .index_block_range_bounded(
+                BlockId::Number(1),
+                BlockId::Number(3),
+                false,
+                "SN_MAIN",
+                Instant::now() + Duration::from_millis(30),
+            )
+            .await
+            .unwrap();
+        assert_eq!(last, Some(1));
+        assert_eq!(storage.dump().blocks.len(), 1);
+
+        // Resumed in the next window.
+        let last = pontos
+            .index_block_range_bounded(
+                BlockId::Number(last.unwrap() + 1),
+                BlockId::Number(3),
+                false,
+                "SN_MAIN",
+                Instant::now() + Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+        assert_eq!(last, Some(3));
+        assert_eq!(storage.dump().blocks.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_with_combined_options() {
+        use crate::testing::{mock_client, synthetic_block, synthetic_contracts, InMemoryStorage};
+
+        /// Makes each block last long enough for the deadline to be reached.
+        struct SlowHandler;
+
+        #[async_trait::async_trait]
+        impl EventHandler for SlowHandler {
+            async fn on_block_processed(&self, _block_number: u64, _indexation_progress: f64) {
+                tokio::time::sleep(Duration::from_millis(60)).await;
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks: HashMap<u64, Vec<EmittedEvent>> = (1..=3)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::new(InMemoryStorage::new()),
+            Arc::new(SlowHandler),
+            config(),
+        );
+
+        // A deadline and an observer, with a concurrency limit.
+        let (tx, mut rx) = mpsc::channel(16);
+        let report = pontos
+            .index_block_range_with(
+                RangeOptions::new(BlockId::Number(1), BlockId::Number(3), "SN_MAIN")
+                    .with_deadline(Instant::now() + Duration::from_millis(30))
+                    .with_observer(tx)
+                    .with_concurrency_limit(Arc::new(Semaphore::new(1))),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(report.last_block, Some(1));
+        assert_eq!(rx.recv().await.unwrap().unwrap().block_number, 1);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_chunked() {
+        use crate::storage::types::BlockInfo;
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_block_timestamp, synthetic_contracts,
+            InMemoryStorage,
+        };
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct ChunkRecorder {
+            chunks: Mutex<Vec<RangeChunkReport>>,
+        }
+
+        #[async_trait::async_trait]
+        impl EventHandler for ChunkRecorder {
+            async fn on_range_chunk_completed(&self, report: &RangeChunkReport) {
+                self.chunks.lock().unwrap().push(*report);
+            }
+        }
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks: HashMap<u64, Vec<EmittedEvent>> = (1..=5)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+        let chunk = |from_block, to_block, indexed_blocks, skipped_blocks| RangeChunkReport {
+            from_block,
+            to_block,
+            indexed_blocks,
+            skipped_blocks,
+            ..Default::default()
+        };
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let handler = Arc::new(ChunkRecorder::default());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks.clone(), &contracts)),
+            Arc::clone(&storage),
+            Arc::clone(&handler),
+            PontosConfig {
+                range_chunk_blocks: Some(2),
+                ..config()
+            },
+        );
+
+        storage
+            .set_block_info(
+                2,
+                synthetic_block_timestamp(2),
+                BlockInfo {
+                    indexer_version: "v0.0.1".to_string(),
+                    indexer_identifier: "TASK#123".to_string(),
+                    status: BlockIndexingStatus::Terminated,
+                    block_number: 2,
+                    selector_hash: None,
+                    last_heartbeat_at: 0,
+                },
+            )
+            .await
+            .unwrap();
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(5), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *handler.chunks.lock().unwrap(),
+            vec![chunk(1, 2, 1, 1), chunk(3, 4, 2, 0), chunk(5, 5, 1, 0)]
+        );
+        assert!(storage.dump().reindex_cursors.is_empty());
+
+        // A crashed run resumes from its last chunk.
+        let storage = Arc::new(InMemoryStorage::new());
+        storage
+            .set_reindex_cursor(&range_cursor_key("TASK#123", 1, 5), Some(5))
+            .await
+            .unwrap();
+
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(TestEventHandler),
+            PontosConfig {
+                range_chunk_blocks: Some(2),
+                ..config()
+            },
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(5), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let data = storage.dump();
+        assert_eq!(data.blocks.keys().collect::<Vec<_>>(), vec![&5]);
+        assert!(data.reindex_cursors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_warm_up() {
+        use crate::testing::{InMemoryStorage, NoopEventHandler};
+
+        // Each block is fetched once, by the warm up.
+        let mut client = MockStarknetClient::default();
+        client.expect_block_id_to_u64().returning(|id| match id {
+            BlockId::Number(n) => Ok(*n),
+            _ => Ok(3),
+        });
+        client
+            .expect_block_time()
+            .times(3)
+            .returning(|id| match id {
+                BlockId::Number(n) => Ok(1000 + n),
+                _ => Ok(0),
+            });
+        client
+            .expect_fetch_all_block_events()
+            .times(3)
+            .returning(|_, _| Ok(HashMap::new()));
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        pontos.warm_up(1, 3).await.unwrap();
+        assert_eq!(pontos.warm_blocks.len(), 3);
+
+        // Already warm, nothing is fetched.
+        pontos.warm_up(2, 3).await.unwrap();
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(3), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        assert!(pontos.warm_blocks.is_empty());
+        let data = storage.dump();
+        assert_eq!(data.blocks.len(), 3);
+        assert_eq!(data.blocks[&2].0, 1002);
+    }
+
+    #[tokio::test]
+    async fn test_force_policy_older_than() {
+        use crate::storage::types::BlockInfo;
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_block_timestamp, synthetic_contracts,
+            InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = (1..=5)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        for (n, version) in [(1, "1.3.0"), (2, "legacy"), (3, "1.4.0"), (4, "1.5.0")] {
+            storage
+                .set_block_info(
+                    n,
+                    synthetic_block_timestamp(n),
+                    BlockInfo {
+                        indexer_version: version.to_string(),
+                        indexer_identifier: "TASK#123".to_string(),
+                        status: BlockIndexingStatus::Terminated,
+                        block_number: n,
+                        selector_hash: None,
+                        last_heartbeat_at: 0,
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                indexer_version: "1.5.0".to_string(),
+                ..config()
+            },
+        );
+
+        let report = pontos
+            .index_block_range_with_force_policy(
+                BlockId::Number(1),
+                BlockId::Number(5),
+                &ForcePolicy::OlderThan("1.4.0".to_string()),
+                "SN_MAIN",
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            report,
+            IndexingReport {
+                last_block: Some(5),
+                indexed_blocks: 3,
+                reindexed_blocks: 2,
+                skipped_blocks: 2,
+                skips: SkipCounts {
+                    same_version_terminated: 1,
+                    other_version_policy: 1,
+                    ..Default::default()
+                },
+                failed_blocks: 0,
+            }
+        );
+
+        let data = storage.dump();
+        let version = |n: u64| data.blocks[&n].1.indexer_version.clone();
+        assert_eq!(version(1), "1.5.0");
+        assert_eq!(version(2), "1.5.0");
+        assert_eq!(version(3), "1.4.0");
+        assert_eq!(version(4), "1.5.0");
+        assert_eq!(version(5), "1.5.0");
+        // Only the events of the indexed blocks are registered.
+        assert_eq!(data.transfer_events.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_with_observer() {
+        use crate::storage::types::BlockInfo;
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_block_timestamp, synthetic_contracts,
+            InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 1);
+        let blocks = (1..=3)
+            .map(|n| (n, synthetic_block(n, n as usize, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        storage
+            .set_block_info(
+                2,
+                synthetic_block_timestamp(2),
+                BlockInfo {
+                    indexer_version: "v0.0.1".to_string(),
+                    indexer_identifier: "TASK#123".to_string(),
+                    status: BlockIndexingStatus::Terminated,
+                    block_number: 2,
+                    selector_hash: None,
+                    last_heartbeat_at: 0,
+                },
+            )
+            .await
+            .unwrap();
+
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        // A channel smaller than the range, consumed concurrently.
+        let (tx, mut rx) = mpsc::channel::<IndexerResult<BlockCompleted>>(1);
+        let consumer = tokio::spawn(async move {
+            let mut completed = vec![];
+            while let Some(result) = rx.recv().await {
+                completed.push(result.unwrap());
+            }
+            completed
+        });
+
+        pontos
+            .index_block_range_with_observer(
+                BlockId::Number(1),
+                BlockId::Number(3),
+                false,
+                "SN_MAIN",
+                tx,
+            )
+            .await;
+
+        let completed = consumer.await.unwrap();
+        assert_eq!(
+            completed
+                .iter()
+                .map(|c| (c.block_number, c.event_count, c.status.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, 1, BlockIndexingStatus::Terminated),
+                (3, 3, BlockIndexingStatus::Terminated),
+            ]
+        );
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_progress_style() {
+        use indicatif::{ProgressBar, ProgressDrawTarget, TermLike};
+        use std::sync::Mutex;
+
+        /// Terminal recording the strings drawn.
+        #[derive(Debug, Default)]
+        struct Lines(Arc<Mutex<Vec<String>>>);
+
+        impl TermLike for Lines {
+            fn width(&self) -> u16 {
+                200
+            }
+            fn move_cursor_up(&self, _n: usize) -> std::io::Result<()> {
+                Ok(())
+            }
+            fn move_cursor_down(&self, _n: usize) -> std::io::Result<()> {
+                Ok(())
+            }
+            fn move_cursor_right(&self, _n: usize) -> std::io::Result<()> {
+                Ok(())
+            }
+            fn move_cursor_left(&self, _n: usize) -> std::io::Result<()> {
+                Ok(())
+            }
+            fn write_line(&self, s: &str) -> std::io::Result<()> {
+                self.write_str(s)
+            }
+            fn write_str(&self, s: &str) -> std::io::Result<()> {
+                self.0.lock().unwrap().push(s.to_string());
+                Ok(())
+            }
+            fn clear_line(&self) -> std::io::Result<()> {
+                Ok(())
+            }
+            fn flush(&self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let lines = Arc::new(Mutex::new(vec![]));
+        let bar = ProgressBar::with_draw_target(
+            Some(1000),
+            ProgressDrawTarget::term_like(Box::new(Lines(Arc::clone(&lines)))),
+        )
+        .with_style(progress_style());
+        bar.set_message("1234 events");
+        bar.set_position(450);
+        // As on an error, the bar is left at its position.
+        bar.abandon();
+
+        let lines = lines.lock().unwrap();
+        let drawn = lines.iter().rfind(|l| l.starts_with('[')).unwrap();
+        assert!(
+            drawn.starts_with("[#########           ] 45% (block 450/1000, 1234 events, "),
+            "{}",
+            drawn
+        );
+        assert!(drawn.contains(" blk/s, ETA "), "{}", drawn);
+    }
+
+    #[cfg(feature = "cli")]
+    #[tokio::test]
+    async fn test_index_block_range_with_progress_bar() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = (1..=3)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        pontos
+            .index_block_range_with_progress_bar(
+                BlockId::Number(1),
+                BlockId::Number(3),
+                false,
+                "SN_MAIN",
+            )
+            .await
+            .unwrap();
+        assert_eq!(storage.dump().blocks.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_index_specific_blocks() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = (1..=6)
+            .map(|n| (n, synthetic_block(n, 1, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        pontos
+            .index_specific_blocks(futures::stream::iter([2, 4, 4, 5]), false, "SN_MAIN")
+            .await
+            .unwrap();
+        let indexed = |storage: &InMemoryStorage| {
+            let mut blocks: Vec<u64> = storage.dump().blocks.keys().copied().collect();
+            blocks.sort();
+            blocks
+        };
+        assert_eq!(indexed(&storage), vec![2, 4, 5]);
+
+        // The blocks preceding an unsorted block are indexed.
+        assert!(matches!(
+            pontos
+                .index_specific_blocks(futures::stream::iter([6, 1]), false, "SN_MAIN")
+                .await,
+            Err(IndexerError::Anyhow(_))
+        ));
+        assert_eq!(indexed(&storage), vec![2, 4, 5, 6]);
+
+        // The gaps are filled with the unindexed blocks.
+        let gaps = pontos.list_unindexed_blocks(1, 6).await.unwrap();
+        assert_eq!(gaps, vec![1, 3]);
+        pontos
+            .index_specific_blocks(futures::stream::iter(gaps), false, "SN_MAIN")
+            .await
+            .unwrap();
+        assert!(pontos.list_unindexed_blocks(1, 6).await.unwrap().is_empty());
+        assert!(pontos.list_unindexed_blocks(6, 1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_with_pre_filter() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_block_timestamp, synthetic_contracts,
+            wrapped_transfer, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+        let collection = contracts[0].address;
+        let account = FieldElement::from(0xacc_u64);
+        let erc20 = FieldElement::from(0x20_u64);
+
+        let mut events = synthetic_block(1, 2, &contracts);
+        let mints = events.clone();
+        let wrapped = wrapped_transfer(1, account, collection, FieldElement::ONE);
+        events.extend([
+            EmittedEvent {
+                from_address: erc20,
+                ..mints[0].clone()
+            },
+            EmittedEvent {
+                keys: vec![selector!("Approval")],
+                ..mints[1].clone()
+            },
+            wrapped.clone(),
+        ]);
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(HashMap::from([(1, events)]), &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        // The ERC20 transfer and the approval are filtered out.
+        let kept = pontos
+            .fetch_block_events_pre_filtered(1, synthetic_block_timestamp(1), "SN_MAIN")
+            .await
+            .unwrap();
+        assert_eq!(kept[&1], [mints, vec![wrapped]].concat());
+
+        pontos
+            .index_block_range_with_pre_filter(
+                BlockId::Number(1),
+                BlockId::Number(1),
+                false,
+                "SN_MAIN",
+            )
+            .await
+            .unwrap();
+
+        let data = storage.dump();
+        assert_eq!(data.transfer_events.len(), 3);
+        assert!(data
+            .transfer_events
+            .values()
+            .all(|e| e.contract_address == to_hex_str(&collection)));
+    }
+
+    #[tokio::test]
+    async fn test_index_block_range_with_storage_fallback() {
+        use crate::testing::{
+            mock_client, synthetic_block, synthetic_contracts, InMemoryStorage, NoopEventHandler,
+        };
+
+        let contracts = synthetic_contracts(1, 0);
+
+        // The primary client is missing the events of the block 2.
+        let mut primary = mock_client(
+            HashMap::from([(1, synthetic_block(1, 2, &contracts)), (2, vec![])]),
+            &contracts,
+        );
+        primary
+            .expect_block_txs_hashes()
+            .times(1)
+            .returning(|id| match id {
+                BlockId::Number(n) => Ok((n, vec![FieldElement::ONE])),
+                _ => Ok((0, vec![])),
+            });
+        let fallback = mock_client(
+            HashMap::from([(2, synthetic_block(2, 3, &contracts))]),
+            &contracts,
+        );
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(primary),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            config(),
+        );
+
+        pontos
+            .index_block_range_with_storage_fallback(
+                BlockId::Number(1),
+                BlockId::Number(2),
+                false,
+                "SN_MAIN",
+                Arc::new(fallback),
+            )
+            .await
+            .unwrap();
+
+        let data = storage.dump();
+        let count = |block: u64| {
+            data.transfer_events
+                .values()
+                .filter(|e| e.block_number == Some(block))
+                .count()
+        };
+        assert_eq!(count(1), 2);
+        assert_eq!(count(2), 3);
+    }
+}
@@ -0,0 +1,280 @@
+//! Reports returned by Pontos: outcome of the indexation of the ranges,
+//! status and statistics of the indexer, findings of the diagnostics.
+use crate::config::LogDetail;
+use crate::managers::SkipReason;
+use crate::storage::types::{BlockIndexingStatus, ContractType, TokenEvent};
+use anyhow::Result;
+use serde::Serialize;
+use starknet::core::types::*;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// A token event decoded from the chain, which is not persisted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedTokenEvent {
+    pub contract_type: ContractType,
+    pub event: TokenEvent,
+}
+
+/// Discrepancy found while validating indexed blocks against the node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// The number of events stored differs from the number of NFT events
+    /// returned by the node for this block.
+    EventCountMismatch { block: u64, stored: u64, live: u64 },
+}
+
+/// Inconsistency between the storage and the chain,
+/// as reported by `Pontos::preflight_check`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PreflightFinding {
+    /// The highest terminated block is above the chain head: the storage
+    /// was filled from another chain, or before a reorg. Critical.
+    TerminatedAboveChainHead { block: u64, chain_head: u64 },
+    /// The timestamp stored for the highest terminated block differs from
+    /// the timestamp of the block on the chain. Critical.
+    BlockTimestampMismatch { block: u64, stored: u64, chain: u64 },
+    /// Blocks left in processing by this indexer identifier, which was
+    /// interrupted while indexing them. Those blocks are skipped by
+    /// `index_block_range` unless forced. Critical.
+    InterruptedBlocks { blocks: Vec<u64> },
+    /// Blocks were indexed by a version more recent than the current one,
+    /// and won't be reindexed by this version.
+    NewerIndexerVersion { version: String },
+}
+
+impl PreflightFinding {
+    /// Returns true if indexing on top of this inconsistency corrupts the storage.
+    pub fn is_critical(&self) -> bool {
+        !matches!(self, PreflightFinding::NewerIndexerVersion { .. })
+    }
+}
+
+/// Consistency of the storage with the chain, as returned by `Pontos::preflight_check`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PreflightReport {
+    pub chain_head: u64,
+    /// Highest block marked as terminated in the storage.
+    pub last_terminated_block: Option<u64>,
+    /// Versions of the indexers which indexed the stored blocks.
+    pub indexer_versions: Vec<String>,
+    pub findings: Vec<PreflightFinding>,
+}
+
+impl PreflightReport {
+    /// Returns true if at least one of the findings is critical.
+    pub fn is_critical(&self) -> bool {
+        self.findings.iter().any(PreflightFinding::is_critical)
+    }
+}
+
+/// Runtime status of a Pontos instance, for debugging purposes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PontosStatus {
+    /// Interval currently used between two ticks of `index_pending`.
+    pub pending_poll_interval: Duration,
+    /// Events discarded before contract identification,
+    /// as their layout can't be a supported transfer.
+    pub discarded_events: u64,
+    /// Current detail of the per-transaction and per-event logs.
+    pub log_detail: LogDetail,
+    /// Per-transaction and per-event logs suppressed by the log detail.
+    pub suppressed_logs: u64,
+    /// How far behind the chain the indexer is.
+    pub lag: IndexerLag,
+}
+
+/// How far behind the chain the indexer is, as reported by `Pontos::status`
+/// and `EventHandler::on_lag_update`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IndexerLag {
+    /// Last block accepted on the chain, `None` if not known yet.
+    pub chain_head: Option<u64>,
+    /// Blocks between the chain head and the highest block terminated
+    /// by `index_block_range`, `None` if one of them is not known yet.
+    pub blocks_behind: Option<u64>,
+    /// Transactions of the pending block not processed yet by `index_pending`,
+    /// their receipt having failed to be fetched at the last tick.
+    pub pending_unprocessed_txs: u64,
+    /// Seconds elapsed since the timestamp of the pending block indexed
+    /// by `index_pending`, `None` if `index_pending` is not running.
+    pub pending_age_secs: Option<u64>,
+}
+
+/// Indexing throughput, as returned by `Pontos::statistics`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PontosStatistics {
+    /// Blocks terminated per second over the last `window`.
+    pub indexing_rate: f64,
+    /// Window used to compute the `indexing_rate`.
+    pub window: Duration,
+    /// Blocks in processing, by any indexer sharing the storage.
+    pub processing_blocks: u64,
+    /// Blocks in processing for more than `STUCK_BLOCK_SECS` seconds,
+    /// likely left by crashed workers.
+    pub stuck_blocks: Vec<u64>,
+}
+
+/// Result of `Pontos::reindex_contract_in_range`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct ReindexReport {
+    pub purged_events: u64,
+    pub purged_tokens: u64,
+    pub registered_events: u64,
+}
+
+/// A chunk of blocks completed by `Pontos::index_block_range`,
+/// as reported by `EventHandler::on_range_chunk_completed`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct RangeChunkReport {
+    pub from_block: u64,
+    pub to_block: u64,
+    /// Blocks indexed during this chunk.
+    pub indexed_blocks: u64,
+    /// Blocks indexed again among the `indexed_blocks`, as matching the force policy.
+    pub reindexed_blocks: u64,
+    /// Blocks skipped as already indexed.
+    pub skipped_blocks: u64,
+}
+
+/// Blocks processed by `Pontos::index_block_range_with_force_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct IndexingReport {
+    /// Last block indexed (or skipped as already indexed),
+    /// `None` if no block of the range was reached.
+    pub last_block: Option<u64>,
+    pub indexed_blocks: u64,
+    /// Blocks indexed again among the `indexed_blocks`, as matching the force policy.
+    /// With `ForcePolicy::Always`, all the indexed blocks are counted.
+    pub reindexed_blocks: u64,
+    /// Blocks skipped as already indexed, and not matching the force policy.
+    pub skipped_blocks: u64,
+    /// The `skipped_blocks` by reason.
+    pub skips: SkipCounts,
+    /// Blocks marked as failed, with `ProcessingStrictness::Strict`.
+    pub failed_blocks: u64,
+}
+
+/// A block indexed by `Pontos::index_block_range_with_observer`,
+/// terminated or marked as failed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BlockCompleted {
+    pub block_number: u64,
+    /// Events of the block fetched from the node.
+    pub event_count: u64,
+    /// Time spent indexing the block, on the clock of the configuration.
+    pub duration_ms: u64,
+    pub status: BlockIndexingStatus,
+}
+
+/// Blocks skipped by `index_block_range`, by `SkipReason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct SkipCounts {
+    pub same_version_terminated: u64,
+    pub other_identifier: u64,
+    pub other_version_policy: u64,
+    pub force_overridden: u64,
+}
+
+impl SkipCounts {
+    /// Counts a block skipped for the given reason.
+    pub fn record(&mut self, reason: SkipReason) {
+        let count = match reason {
+            SkipReason::SameVersionTerminated => &mut self.same_version_terminated,
+            SkipReason::OtherIdentifier => &mut self.other_identifier,
+            SkipReason::OtherVersionPolicy => &mut self.other_version_policy,
+            SkipReason::ForceOverridden => &mut self.force_overridden,
+        };
+        *count += 1;
+    }
+}
+
+/// Transfers of a collection in a block, as reported by
+/// `EventHandler::on_block_collections_summary`.
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct CollectionActivity {
+    pub contract_address: String,
+    /// Transfers which are neither mints nor burns.
+    pub transfers: u64,
+    pub mints: u64,
+    pub burns: u64,
+    /// Distinct tokens transferred, minted or burnt.
+    pub distinct_tokens: u64,
+}
+
+/// Events of a contract, as reported by `Pontos::estimate_range`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContractEventCount {
+    pub contract_address: String,
+    pub events: u64,
+}
+
+/// Events matching the indexed selectors in a block range,
+/// as returned by `Pontos::estimate_range`.
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct RangeEstimate {
+    pub from_block: u64,
+    pub to_block: u64,
+    pub total_events: u64,
+    /// Contracts with the most events, the most active first.
+    pub top_contracts: Vec<ContractEventCount>,
+    /// Events by bucket of `ESTIMATE_BUCKET_BLOCKS` blocks,
+    /// keyed by the first block of the bucket. Empty buckets are omitted.
+    pub events_per_bucket: BTreeMap<u64, u64>,
+    pub blocks_with_events: u64,
+    /// Ratio of the blocks of the range having at least one event.
+    pub blocks_with_events_density: f64,
+}
+
+/// Extrapolated cost of indexing a block range,
+/// as returned by `Pontos::index_block_range_estimate_only`.
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct SampledRangeEstimate {
+    pub total_blocks: u64,
+    /// Blocks of the range already terminated, which are not counted
+    /// in the estimated events.
+    pub already_indexed_blocks: u64,
+    pub estimated_new_events: u64,
+    /// `estimated_new_events` times `ESTIMATE_EVENT_BYTES`.
+    pub estimated_storage_bytes: u64,
+}
+
+/// Events walked through by `Pontos::apply_migration`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct MigrationStats {
+    pub total: u64,
+    /// Events changed by the migration, and written back.
+    pub updated: u64,
+    /// Events the migration or the write failed for, left unchanged.
+    pub failed: u64,
+}
+
+/// State of the event pipeline of a block, as returned by `Pontos::diagnose_block`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BlockDiagnosis {
+    pub block_number: u64,
+    /// Indexing status stored, `None` if the block was never indexed.
+    pub status: Option<BlockIndexingStatus>,
+    /// Transfer events stored for the block.
+    pub stored_events: u64,
+    /// Transfer events emitted by NFT contracts, returned by the node.
+    pub node_events: u64,
+    /// Ids of the events stored which are not returned by the node.
+    pub missing_on_node: Vec<String>,
+    /// Transfer events returned by the node which are not stored.
+    pub missing_in_storage: Vec<EmittedEvent>,
+    /// Type identified for each contract emitting events in the block,
+    /// or the error of the identification. The marketplaces are omitted.
+    pub contracts: BTreeMap<String, Result<ContractType, String>>,
+}
+
+/// Health of a Pontos instance, suitable for liveness and readiness probes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthStatus {
+    pub storage_ok: bool,
+    pub rpc_ok: bool,
+    /// Highest block indexed by `index_block_range` since the start.
+    pub last_indexed_block: Option<u64>,
+    pub pending_loop_running: bool,
+    pub uptime_secs: u64,
+}
@@ -0,0 +1,304 @@
+//! Export of the storage tables to CSV, for the data-warehouse pipelines
+//! ingesting CSV files (BigQuery, Snowflake...).
+//!
+//! The rows are read from the storage by pages with `Storage::export_rows`,
+//! and written as RFC 4180 CSV: a header row, fields quoted when needed,
+//! and lines terminated by CRLF. The stream can be compressed on the fly,
+//! see `Compression`.
+
+// The row builders are only used by the storage implementations.
+#![cfg_attr(
+    not(any(test, feature = "testing", feature = "sqlxdb")),
+    allow(dead_code)
+)]
+
+use crate::compression::{CompressedWriter, Compression};
+use crate::storage::types::{
+    BlockInfo, ExportKey, ExportTable, StorageError, TokenInfo, TokenSaleEvent, TokenTransferEvent,
+};
+use crate::storage::Storage;
+use async_trait::async_trait;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Number of rows read from the storage at once.
+const EXPORT_PAGE_ROWS: usize = 1000;
+
+/// Export of the storage tables, implemented for all the storages.
+#[async_trait]
+pub trait CsvExport: Storage {
+    /// Writes the rows of the table as CSV, with the columns documented in
+    /// `ExportTable`. The rows are streamed page by page through the given
+    /// compression, and the writer is flushed at the end. A compressed stream
+    /// is ended at the end, which also shuts the writer down.
+    /// Returns the number of rows written, header excluded.
+    async fn export_csv<W: AsyncWrite + Unpin + Send>(
+        &self,
+        table: ExportTable,
+        compression: Compression,
+        writer: W,
+    ) -> Result<u64, StorageError> {
+        let mut writer = CompressedWriter::new(writer, compression);
+        write_record(&mut writer, table.columns()).await?;
+
+        let mut count: u64 = 0;
+        let mut after: Option<ExportKey> = None;
+        loop {
+            let rows = self
+                .export_rows(table, after.as_ref(), EXPORT_PAGE_ROWS)
+                .await?;
+
+            for row in &rows {
+                write_record(&mut writer, row).await?;
+            }
+
+            count += rows.len() as u64;
+            match rows.last() {
+                Some(last) if rows.len() == EXPORT_PAGE_ROWS => {
+                    after = Some(row_key(table, last)?);
+                }
+                _ => break,
+            }
+        }
+
+        let end = match compression {
+            Compression::None => writer.flush().await,
+            Compression::Gzip | Compression::Zstd(_) => writer.shutdown().await,
+        };
+        end.map_err(|e| StorageError::ExportError(e.to_string()))?;
+
+        Ok(count)
+    }
+}
+
+impl<S: Storage + ?Sized> CsvExport for S {}
+
+/// Returns the sort key of a row of the table, from its columns.
+fn row_key(table: ExportTable, row: &[String]) -> Result<ExportKey, StorageError> {
+    let column = |i: usize| {
+        row.get(i)
+            .cloned()
+            .ok_or_else(|| StorageError::ExportError(format!("Row of {:?} too short", table)))
+    };
+
+    Ok(match table {
+        ExportTable::Tokens => ExportKey::Token {
+            contract_address: column(0)?,
+            token_id_hex: column(2)?,
+        },
+        ExportTable::Events | ExportTable::Transfers => ExportKey::Event(column(0)?),
+        ExportTable::Blocks => ExportKey::Block(
+            column(0)?
+                .parse()
+                .map_err(|e| StorageError::ExportError(format!("Invalid block number: {}", e)))?,
+        ),
+    })
+}
+
+async fn write_record<W: AsyncWrite + Unpin + Send, F: AsRef<str>>(
+    writer: &mut W,
+    fields: &[F],
+) -> Result<(), StorageError> {
+    let mut line = fields
+        .iter()
+        .map(|f| escape_field(f.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+
+    writer
+        .write_all(line.as_bytes())
+        .await
+        .map_err(|e| StorageError::ExportError(e.to_string()))
+}
+
+/// Quotes the field if it contains a comma, a quote or a line break,
+/// the quotes being doubled.
+fn escape_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Row of `ExportTable::Tokens`.
+pub(crate) fn token_row(token: &TokenInfo) -> Vec<String> {
+    vec![
+        token.contract_address.clone(),
+        token.token_id.clone(),
+        token.token_id_hex.clone(),
+        token.owner.clone(),
+        token.is_burned.to_string(),
+        optional(token.burned_at_block),
+    ]
+}
+
+/// Row of `ExportTable::Events` or `ExportTable::Transfers` for a transfer.
+pub(crate) fn transfer_row(table: ExportTable, event: &TokenTransferEvent) -> Vec<String> {
+    match table {
+        ExportTable::Transfers => vec![
+            event.event_id.clone(),
+            event.event_type.to_string(),
+            event.contract_address.clone(),
+            event.contract_type.clone(),
+            event.token_id.clone(),
+            event.from_address.clone(),
+            event.to_address.clone(),
+            event.quantity.to_string(),
+            event.transaction_hash.clone(),
+            optional(event.block_number),
+            event.timestamp.to_string(),
+        ],
+        _ => vec![
+            event.event_id.clone(),
+            event.event_type.to_string(),
+            event.contract_address.clone(),
+            event.token_id.clone(),
+            event.from_address.clone(),
+            event.to_address.clone(),
+            event.transaction_hash.clone(),
+            optional(event.block_number),
+            event.timestamp.to_string(),
+        ],
+    }
+}
+
+/// Row of `ExportTable::Events` for a sale.
+pub(crate) fn sale_row(event: &TokenSaleEvent) -> Vec<String> {
+    vec![
+        event.event_id.clone(),
+        event.event_type.to_string(),
+        event.nft_contract_address.clone(),
+        event.token_id.clone(),
+        event.from_address.clone(),
+        event.to_address.clone(),
+        event.transaction_hash.clone(),
+        optional(event.block_number),
+        event.timestamp.to_string(),
+    ]
+}
+
+/// Row of `ExportTable::Blocks`.
+pub(crate) fn block_row(block_timestamp: u64, info: &BlockInfo) -> Vec<String> {
+    vec![
+        info.block_number.to_string(),
+        block_timestamp.to_string(),
+        info.status.to_string(),
+        info.indexer_version.clone(),
+        info.indexer_identifier.clone(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    #[test]
+    fn test_escape_field() {
+        assert_eq!(escape_field("0x1"), "0x1");
+        assert_eq!(escape_field("a,b"), "\"a,b\"");
+        assert_eq!(escape_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape_field("two\r\nlines"), "\"two\r\nlines\"");
+    }
+
+    #[tokio::test]
+    async fn test_export_csv() {
+        let storage = InMemoryStorage::new();
+        for (contract_address, token_id_hex) in [("0x2", "0x1"), ("0x1", "0x2"), ("0x1", "0x1")] {
+            let token = TokenInfo {
+                contract_address: contract_address.to_string(),
+                token_id: "1".to_string(),
+                token_id_hex: token_id_hex.to_string(),
+                owner: "0xa,0xb".to_string(),
+                ..Default::default()
+            };
+            storage.register_token(&token, 0).await.unwrap();
+        }
+
+        let mut csv = vec![];
+        let count = storage
+            .export_csv(ExportTable::Tokens, Compression::None, &mut csv)
+            .await
+            .unwrap();
+
+        assert_eq!(count, 3);
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "contract_address,token_id,token_id_hex,owner,is_burned,burned_at_block\r\n\
+             0x1,1,0x1,\"0xa,0xb\",false,\r\n\
+             0x1,1,0x2,\"0xa,0xb\",false,\r\n\
+             0x2,1,0x1,\"0xa,0xb\",false,\r\n"
+        );
+
+        let mut csv = vec![];
+        assert_eq!(
+            storage
+                .export_csv(ExportTable::Blocks, Compression::None, &mut csv)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "block_number,block_timestamp,status,indexer_version,indexer_identifier\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_csv_compressed_round_trip() {
+        use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+        use tokio::io::AsyncReadExt;
+
+        let storage = InMemoryStorage::new();
+        // More than a page, for the stream to be written in several parts.
+        for i in 0..(EXPORT_PAGE_ROWS as u64 + 10) {
+            let token = TokenInfo {
+                contract_address: "0x1".to_string(),
+                token_id: i.to_string(),
+                token_id_hex: format!("0x{:x}", i),
+                owner: "0xa".to_string(),
+                ..Default::default()
+            };
+            storage.register_token(&token, 0).await.unwrap();
+        }
+
+        let mut plain = vec![];
+        let count = storage
+            .export_csv(ExportTable::Tokens, Compression::None, &mut plain)
+            .await
+            .unwrap();
+        assert_eq!(count, EXPORT_PAGE_ROWS as u64 + 10);
+
+        for compression in [
+            Compression::Gzip,
+            Compression::Zstd(1),
+            Compression::Zstd(19),
+        ] {
+            let mut compressed = vec![];
+            let count = storage
+                .export_csv(ExportTable::Tokens, compression, &mut compressed)
+                .await
+                .unwrap();
+            assert_eq!(count, EXPORT_PAGE_ROWS as u64 + 10);
+            assert!(compressed.len() < plain.len());
+
+            let mut decoded = vec![];
+            match compression {
+                Compression::Gzip => GzipDecoder::new(compressed.as_slice())
+                    .read_to_end(&mut decoded)
+                    .await
+                    .unwrap(),
+                _ => ZstdDecoder::new(compressed.as_slice())
+                    .read_to_end(&mut decoded)
+                    .await
+                    .unwrap(),
+            };
+            assert_eq!(decoded, plain, "{:?}", compression);
+        }
+    }
+}
@@ -0,0 +1,1647 @@
+//! In-memory implementation of the storage.
+//!
+//! This implementation is intended for tests and benchmarks,
+//! where no database is available. The data are lost when
+//! the storage is dropped.
+use ark_starknet::format::to_hex_str;
+use async_trait::async_trait;
+use starknet::core::types::FieldElement;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::trace;
+
+use crate::storage::export::{block_row, sale_row, token_row, transfer_row};
+use crate::storage::types::*;
+use crate::storage::Storage;
+
+/// All the data held by the in-memory storage.
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryData {
+    /// Tokens, by (contract address, token id hex).
+    pub tokens: HashMap<(String, String), TokenInfo>,
+    /// Mints, by (contract address, token id hex).
+    pub mints: HashMap<(String, String), TokenMintInfo>,
+    /// Transfer events, by event id.
+    pub transfer_events: HashMap<String, TokenTransferEvent>,
+    /// Sale events, by event id.
+    pub sale_events: HashMap<String, TokenSaleEvent>,
+    /// Contracts, by (contract address, chain id).
+    pub contracts: HashMap<(String, String), ContractInfo>,
+    /// Blocks, by block number, with their timestamp.
+    pub blocks: HashMap<u64, (u64, BlockInfo)>,
+    /// Heartbeats of the indexers, by identifier.
+    pub indexers: BTreeMap<String, IndexerInfo>,
+    /// Milliseconds since the epoch at which the blocks were marked
+    /// as processing, by block number.
+    pub processing_started_at: HashMap<u64, u64>,
+    /// Milliseconds since the epoch at which the blocks were terminated,
+    /// by block number.
+    pub terminated_at: HashMap<u64, u64>,
+    /// Etags of the blocks, by block number. Only valid for the blocks stored.
+    pub block_etags: HashMap<u64, u64>,
+    /// Collections supply, by contract address.
+    pub supplies: HashMap<String, i64>,
+    /// Collections total supply reported by the contracts, by contract address.
+    pub total_supplies: HashMap<String, u64>,
+    /// Collections counters, by contract address.
+    pub collection_stats: HashMap<String, CollectionStats>,
+    /// Dead-letter queue, by failed event id.
+    pub failed_events: HashMap<String, FailedEvent>,
+    /// Events which couldn't be attributed to a collection, by id.
+    pub quarantined_events: HashMap<String, QuarantinedEvent>,
+    /// Tokens attributes values, by (contract address, token id hex, trait type).
+    pub attributes: HashMap<(String, String, String), String>,
+    /// Patched metadata values (except attributes),
+    /// by (contract address, token id hex, field).
+    pub token_metadata: HashMap<(String, String, String), String>,
+    /// Audit log of the metadata patches, in the order they were applied.
+    pub metadata_patches: Vec<MetadataPatchRecord>,
+    /// Next block to re-index, by contract address.
+    pub reindex_cursors: HashMap<String, u64>,
+    /// Contracts which events must be backfilled.
+    pub contracts_to_backfill: BTreeSet<String>,
+    /// Contract types, by class hash.
+    pub class_hash_types: HashMap<String, ContractType>,
+    /// Contract types overridden by an admin, by contract address.
+    pub contract_overrides: BTreeMap<String, ContractType>,
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryStorage {
+    data: Mutex<InMemoryData>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of all the data currently stored.
+    pub fn dump(&self) -> InMemoryData {
+        self.data().clone()
+    }
+
+    fn data(&self) -> std::sync::MutexGuard<'_, InMemoryData> {
+        self.data.lock().expect("In-memory storage lock poisoned")
+    }
+}
+
+impl InMemoryData {
+    fn block_etag(&self, block_number: u64) -> u64 {
+        if self.blocks.contains_key(&block_number) {
+            self.block_etags
+                .get(&block_number)
+                .copied()
+                .unwrap_or_default()
+        } else {
+            0
+        }
+    }
+
+    fn set_block_info(&mut self, block_number: u64, block_timestamp: u64, info: BlockInfo) {
+        if info.status == BlockIndexingStatus::Processing {
+            self.processing_started_at.insert(block_number, now_ms());
+        } else {
+            self.processing_started_at.remove(&block_number);
+        }
+
+        if info.status == BlockIndexingStatus::Terminated {
+            self.terminated_at.insert(block_number, now_ms());
+        } else {
+            self.terminated_at.remove(&block_number);
+        }
+
+        let etag = self.block_etag(block_number) + 1;
+        self.block_etags.insert(block_number, etag);
+        self.blocks.insert(block_number, (block_timestamp, info));
+    }
+}
+
+#[async_trait]
+impl Storage for InMemoryStorage {
+    async fn register_mint(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+        _token_id: &str,
+        info: &TokenMintInfo,
+    ) -> Result<(), StorageError> {
+        trace!("Registering mint {} {}", contract_address, token_id_hex);
+
+        self.data().mints.insert(
+            (contract_address.to_string(), token_id_hex.to_string()),
+            info.clone(),
+        );
+
+        Ok(())
+    }
+
+    async fn register_token(
+        &self,
+        token: &TokenInfo,
+        _block_timestamp: u64,
+    ) -> Result<(), StorageError> {
+        trace!("Registering token {:?}", token);
+
+        let key = (token.contract_address.clone(), token.token_id_hex.clone());
+        let mut data = self.data();
+
+        if data.tokens.contains_key(&key) {
+            return Err(StorageError::AlreadyExists(format!(
+                "token id = {}",
+                token.token_id_hex
+            )));
+        }
+
+        data.tokens.insert(key, token.clone());
+
+        Ok(())
+    }
+
+    async fn register_sale_event(
+        &self,
+        event: &TokenSaleEvent,
+        _block_timestamp: u64,
+    ) -> Result<(), StorageError> {
+        trace!("Registering sale event {:?}", event);
+
+        self.data()
+            .sale_events
+            .insert(event.event_id.clone(), event.clone());
+
+        Ok(())
+    }
+
+    async fn register_transfer_event(
+        &self,
+        event: &TokenTransferEvent,
+        _block_timestamp: u64,
+    ) -> Result<(), StorageError> {
+        trace!("Registering event {:?}", event);
+
+        let mut data = self.data();
+
+        if data.transfer_events.contains_key(&event.event_id) {
+            return Err(StorageError::AlreadyExists(format!(
+                "event id = {}",
+                event.event_id
+            )));
+        }
+
+        data.transfer_events
+            .insert(event.event_id.clone(), event.clone());
+
+        Ok(())
+    }
+
+    async fn upsert_event(&self, event: &TokenTransferEvent) -> Result<bool, StorageError> {
+        trace!("Upserting event {:?}", event);
+
+        let mut data = self.data();
+        if data.transfer_events.contains_key(&event.event_id) {
+            return Ok(false);
+        }
+
+        data.transfer_events
+            .insert(event.event_id.clone(), event.clone());
+
+        Ok(true)
+    }
+
+    async fn replace_event(&self, event: &TokenTransferEvent) -> Result<(), StorageError> {
+        trace!("Replacing event {:?}", event);
+
+        self.data()
+            .transfer_events
+            .insert(event.event_id.clone(), event.clone());
+
+        Ok(())
+    }
+
+    async fn upsert_decoded_event(
+        &self,
+        event: &DecodedTransfer,
+    ) -> Result<Option<TokenTransferEvent>, StorageError> {
+        let event_id = to_hex_str(&event.event_id);
+        let mut data = self.data();
+        if data.transfer_events.contains_key(&event_id) {
+            return Ok(None);
+        }
+
+        let token_event = event.to_token_event();
+        data.transfer_events.insert(event_id, token_event.clone());
+
+        Ok(Some(token_event))
+    }
+
+    async fn upsert_token_attribute(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+        trait_type: &str,
+        value: &str,
+    ) -> Result<(), StorageError> {
+        self.data().attributes.insert(
+            (
+                contract_address.to_string(),
+                token_id_hex.to_string(),
+                trait_type.to_string(),
+            ),
+            value.to_string(),
+        );
+
+        Ok(())
+    }
+
+    async fn apply_metadata_patch(&self, patch: &MetadataPatchRecord) -> Result<(), StorageError> {
+        let mut data = self.data();
+        let key = |field: String| {
+            (
+                patch.contract_address.clone(),
+                patch.token_id_hex.clone(),
+                field,
+            )
+        };
+
+        match &patch.field {
+            MetadataField::Attribute(trait_type) => data
+                .attributes
+                .insert(key(trait_type.clone()), patch.value.clone()),
+            field => data
+                .token_metadata
+                .insert(key(field.to_string()), patch.value.clone()),
+        };
+        data.metadata_patches.push(patch.clone());
+
+        Ok(())
+    }
+
+    async fn get_metadata_patches(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+    ) -> Result<Vec<MetadataPatchRecord>, StorageError> {
+        Ok(self
+            .data()
+            .metadata_patches
+            .iter()
+            .filter(|p| p.contract_address == contract_address && p.token_id_hex == token_id_hex)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_tokens_by_attribute(
+        &self,
+        contract_address: &str,
+        trait_type: &str,
+        value: &str,
+    ) -> Result<Vec<String>, StorageError> {
+        let mut tokens: Vec<String> = self
+            .data()
+            .attributes
+            .iter()
+            .filter(|((c, _, t), v)| c == contract_address && t == trait_type && *v == value)
+            .map(|((_, token_id_hex, _), _)| token_id_hex.clone())
+            .collect();
+        tokens.sort();
+
+        Ok(tokens)
+    }
+
+    async fn get_token(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+    ) -> Result<Option<TokenInfo>, StorageError> {
+        Ok(self
+            .data()
+            .tokens
+            .get(&(contract_address.to_string(), token_id_hex.to_string()))
+            .cloned())
+    }
+
+    async fn set_token_owner(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+        owner: &str,
+    ) -> Result<(), StorageError> {
+        let mut data = self.data();
+        let token = data
+            .tokens
+            .get_mut(&(contract_address.to_string(), token_id_hex.to_string()))
+            .ok_or_else(|| StorageError::NotFound {
+                entity: "token",
+                key: token_id_hex.to_string(),
+            })?;
+
+        token.owner = owner.to_string();
+
+        Ok(())
+    }
+
+    async fn set_token_burned(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+        is_burned: bool,
+        block_number: Option<u64>,
+    ) -> Result<(), StorageError> {
+        let mut data = self.data();
+        let token = data
+            .tokens
+            .get_mut(&(contract_address.to_string(), token_id_hex.to_string()))
+            .ok_or_else(|| StorageError::NotFound {
+                entity: "token",
+                key: token_id_hex.to_string(),
+            })?;
+
+        token.is_burned = is_burned;
+        token.burned_at_block = if is_burned { block_number } else { None };
+
+        Ok(())
+    }
+
+    async fn get_tokens_paginated(
+        &self,
+        contract_address: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<TokenInfo>, bool), StorageError> {
+        let mut tokens: Vec<TokenInfo> = self
+            .data()
+            .tokens
+            .iter()
+            .filter(|((c, token_id_hex), _)| {
+                c == contract_address && after.map_or(true, |a| token_id_hex.as_str() > a)
+            })
+            .map(|(_, token)| token.clone())
+            .collect();
+        tokens.sort_by(|a, b| a.token_id_hex.cmp(&b.token_id_hex));
+
+        let has_more = tokens.len() > limit;
+        tokens.truncate(limit);
+
+        Ok((tokens, has_more))
+    }
+
+    async fn get_event_by_tx_hash(
+        &self,
+        transaction_hash: &str,
+    ) -> Result<Vec<TokenEvent>, StorageError> {
+        let data = self.data();
+
+        let transfers = data
+            .transfer_events
+            .values()
+            .filter(|e| e.transaction_hash == transaction_hash)
+            .cloned()
+            .map(TokenEvent::Transfer);
+
+        let sales = data
+            .sale_events
+            .values()
+            .filter(|e| e.transaction_hash == transaction_hash)
+            .cloned()
+            .map(TokenEvent::Sale);
+
+        Ok(transfers.chain(sales).collect())
+    }
+
+    async fn get_events_for_token(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+    ) -> Result<Vec<TokenEvent>, StorageError> {
+        let data = self.data();
+
+        let transfers = data
+            .transfer_events
+            .values()
+            .filter(|e| e.contract_address == contract_address && e.token_id_hex == token_id_hex)
+            .cloned()
+            .map(TokenEvent::Transfer);
+
+        let sales = data
+            .sale_events
+            .values()
+            .filter(|e| {
+                e.nft_contract_address == contract_address && e.token_id_hex == token_id_hex
+            })
+            .cloned()
+            .map(TokenEvent::Sale);
+
+        let mut events: Vec<TokenEvent> = transfers.chain(sales).collect();
+        events.sort_by_cached_key(|e| {
+            let (block_number, timestamp, event_id) = match e {
+                TokenEvent::Transfer(t) => (t.block_number, t.timestamp, t.event_id.clone()),
+                TokenEvent::Sale(s) => (s.block_number, s.timestamp, s.event_id.clone()),
+            };
+            (block_number.is_none(), block_number, timestamp, event_id)
+        });
+
+        Ok(events)
+    }
+
+    async fn get_contract_type(
+        &self,
+        contract_address: &str,
+        chain_id: &str,
+    ) -> Result<Option<ContractType>, StorageError> {
+        Ok(self
+            .data()
+            .contracts
+            .get(&(contract_address.to_string(), chain_id.to_string()))
+            .map(|c| c.contract_type.parse().unwrap_or(ContractType::Other)))
+    }
+
+    async fn register_contract_info(
+        &self,
+        info: &ContractInfo,
+        _block_timestamp: u64,
+        chain_id: &str,
+    ) -> Result<(), StorageError> {
+        let key = (info.contract_address.clone(), chain_id.to_string());
+        let mut data = self.data();
+
+        if data.contracts.contains_key(&key) {
+            return Err(StorageError::AlreadyExists(format!(
+                "contract addr = {}",
+                info.contract_address
+            )));
+        }
+
+        data.contracts.insert(key, info.clone());
+
+        Ok(())
+    }
+
+    async fn set_contract_type(
+        &self,
+        contract_address: &str,
+        chain_id: &str,
+        contract_type: &ContractType,
+    ) -> Result<(), StorageError> {
+        self.data()
+            .contracts
+            .get_mut(&(contract_address.to_string(), chain_id.to_string()))
+            .map(|c| c.contract_type = contract_type.to_string())
+            .ok_or_else(|| StorageError::NotFound {
+                entity: "contract",
+                key: contract_address.to_string(),
+            })
+    }
+
+    async fn set_contract_backfill(
+        &self,
+        contract_address: &str,
+        needs_backfill: bool,
+    ) -> Result<(), StorageError> {
+        let mut data = self.data();
+        if needs_backfill {
+            data.contracts_to_backfill
+                .insert(contract_address.to_string());
+        } else {
+            data.contracts_to_backfill.remove(contract_address);
+        }
+
+        Ok(())
+    }
+
+    async fn get_contracts_to_backfill(&self) -> Result<Vec<String>, StorageError> {
+        Ok(self.data().contracts_to_backfill.iter().cloned().collect())
+    }
+
+    async fn set_block_info(
+        &self,
+        block_number: u64,
+        block_timestamp: u64,
+        info: BlockInfo,
+    ) -> Result<(), StorageError> {
+        self.data()
+            .set_block_info(block_number, block_timestamp, info);
+        Ok(())
+    }
+
+    async fn get_block_etag(&self, block_number: u64) -> Result<u64, StorageError> {
+        Ok(self.data().block_etag(block_number))
+    }
+
+    async fn set_block_info_if_etag(
+        &self,
+        block_number: u64,
+        block_timestamp: u64,
+        info: BlockInfo,
+        expected_etag: u64,
+    ) -> Result<u64, StorageError> {
+        let mut data = self.data();
+
+        let etag = data.block_etag(block_number);
+        if etag == expected_etag {
+            data.set_block_info(block_number, block_timestamp, info);
+        }
+
+        Ok(etag)
+    }
+
+    async fn set_block_heartbeat(
+        &self,
+        block_number: u64,
+        heartbeat_at: u64,
+    ) -> Result<(), StorageError> {
+        let mut data = self.data();
+        let (_, info) =
+            data.blocks
+                .get_mut(&block_number)
+                .ok_or_else(|| StorageError::NotFound {
+                    entity: "block",
+                    key: block_number.to_string(),
+                })?;
+        info.last_heartbeat_at = heartbeat_at;
+        Ok(())
+    }
+
+    async fn get_block_info(&self, block_number: u64) -> Result<Option<BlockInfo>, StorageError> {
+        Ok(self
+            .data()
+            .blocks
+            .get(&block_number)
+            .map(|(_, info)| info.clone()))
+    }
+
+    async fn get_block_details(
+        &self,
+        block_number: u64,
+    ) -> Result<Option<BlockDetails>, StorageError> {
+        let data = self.data();
+        Ok(data
+            .blocks
+            .get(&block_number)
+            .map(|(_, info)| BlockDetails {
+                info: info.clone(),
+                processing_started_at: data.processing_started_at.get(&block_number).copied(),
+                terminated_at: data.terminated_at.get(&block_number).copied(),
+                event_count: data
+                    .transfer_events
+                    .values()
+                    .filter(|e| e.block_number == Some(block_number))
+                    .count() as u64,
+            }))
+    }
+
+    async fn get_last_terminated_block(&self) -> Result<Option<(u64, u64)>, StorageError> {
+        Ok(self
+            .data()
+            .blocks
+            .iter()
+            .filter(|(_, (_, info))| info.status == BlockIndexingStatus::Terminated)
+            .map(|(n, (ts, _))| (*n, *ts))
+            .max())
+    }
+
+    async fn get_processing_blocks(
+        &self,
+        indexer_identifier: &str,
+    ) -> Result<Vec<u64>, StorageError> {
+        let mut blocks: Vec<u64> = self
+            .data()
+            .blocks
+            .iter()
+            .filter(|(_, (_, info))| {
+                info.status == BlockIndexingStatus::Processing
+                    && info.indexer_identifier == indexer_identifier
+            })
+            .map(|(n, _)| *n)
+            .collect();
+        blocks.sort_unstable();
+        Ok(blocks)
+    }
+
+    async fn get_indexer_versions(&self) -> Result<Vec<String>, StorageError> {
+        let versions: BTreeSet<String> = self
+            .data()
+            .blocks
+            .values()
+            .map(|(_, info)| info.indexer_version.clone())
+            .collect();
+        Ok(versions.into_iter().collect())
+    }
+
+    async fn register_indexer(&self, info: &IndexerInfo) -> Result<(), StorageError> {
+        self.data()
+            .indexers
+            .insert(info.identifier.clone(), info.clone());
+        Ok(())
+    }
+
+    async fn get_indexers(&self) -> Result<Vec<IndexerInfo>, StorageError> {
+        Ok(self.data().indexers.values().cloned().collect())
+    }
+
+    async fn count_processing_blocks(&self) -> Result<u64, StorageError> {
+        Ok(self
+            .data()
+            .blocks
+            .values()
+            .filter(|(_, info)| info.status == BlockIndexingStatus::Processing)
+            .count() as u64)
+    }
+
+    async fn count_terminated_blocks_in_range(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<u64, StorageError> {
+        Ok(self
+            .data()
+            .blocks
+            .iter()
+            .filter(|(n, (_, info))| {
+                (from_block..=to_block).contains(*n)
+                    && info.status == BlockIndexingStatus::Terminated
+            })
+            .count() as u64)
+    }
+
+    async fn get_terminated_blocks_in_range(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<u64>, StorageError> {
+        let mut blocks: Vec<u64> = self
+            .data()
+            .blocks
+            .iter()
+            .filter(|(n, (_, info))| {
+                (from_block..=to_block).contains(*n)
+                    && info.status == BlockIndexingStatus::Terminated
+            })
+            .map(|(n, _)| *n)
+            .collect();
+        blocks.sort();
+        Ok(blocks)
+    }
+
+    async fn get_processing_blocks_started_before(
+        &self,
+        started_before_ms: u64,
+    ) -> Result<Vec<u64>, StorageError> {
+        let data = self.data();
+        let mut blocks: Vec<u64> = data
+            .blocks
+            .iter()
+            .filter(|(n, (_, info))| {
+                info.status == BlockIndexingStatus::Processing
+                    && data
+                        .processing_started_at
+                        .get(n)
+                        .is_some_and(|started_at| *started_at < started_before_ms)
+            })
+            .map(|(n, _)| *n)
+            .collect();
+        blocks.sort_unstable();
+        Ok(blocks)
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn count_block_events(&self, block_number: u64) -> Result<u64, StorageError> {
+        Ok(self
+            .data()
+            .transfer_events
+            .values()
+            .filter(|e| e.block_number == Some(block_number))
+            .count() as u64)
+    }
+
+    async fn get_block_event_ids(&self, block_number: u64) -> Result<Vec<String>, StorageError> {
+        let mut ids: Vec<String> = self
+            .data()
+            .transfer_events
+            .values()
+            .filter(|e| e.block_number == Some(block_number))
+            .map(|e| e.event_id.clone())
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    async fn count_events_by_block(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<(u64, u64)>, StorageError> {
+        let mut counts: BTreeMap<u64, u64> = BTreeMap::new();
+        for n in self
+            .data()
+            .transfer_events
+            .values()
+            .filter_map(|e| e.block_number)
+            .filter(|n| (from_block..=to_block).contains(n))
+        {
+            *counts.entry(n).or_default() += 1;
+        }
+
+        Ok(counts.into_iter().collect())
+    }
+
+    async fn get_block_transfer_events(
+        &self,
+        block_number: u64,
+    ) -> Result<Vec<TokenTransferEvent>, StorageError> {
+        let mut events: Vec<TokenTransferEvent> = self
+            .data()
+            .transfer_events
+            .values()
+            .filter(|e| e.block_number == Some(block_number))
+            .cloned()
+            .collect();
+        events.sort_by(|a, b| a.event_id.cmp(&b.event_id));
+        Ok(events)
+    }
+
+    async fn get_contract_transfer_events(
+        &self,
+        contract_address: &str,
+        limit: usize,
+    ) -> Result<Vec<TokenTransferEvent>, StorageError> {
+        let mut events: Vec<TokenTransferEvent> = self
+            .data()
+            .transfer_events
+            .values()
+            .filter(|e| e.contract_address == contract_address)
+            .cloned()
+            .collect();
+        events.sort_by(|a, b| a.event_id.cmp(&b.event_id));
+        events.truncate(limit);
+        Ok(events)
+    }
+
+    async fn count_transfers_in_blocks(
+        &self,
+        contract_address: &str,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<u64, StorageError> {
+        Ok(self
+            .data()
+            .transfer_events
+            .values()
+            .filter(|e| e.contract_address == contract_address)
+            .filter(|e| {
+                e.block_number
+                    .is_some_and(|n| n >= from_block && n <= to_block)
+            })
+            .count() as u64)
+    }
+
+    async fn count_transfer_events(&self) -> Result<u64, StorageError> {
+        Ok(self.data().transfer_events.len() as u64)
+    }
+
+    async fn get_transfer_events_after(
+        &self,
+        after_event_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<TokenTransferEvent>, StorageError> {
+        let mut events: Vec<TokenTransferEvent> = self
+            .data()
+            .transfer_events
+            .values()
+            .filter(|e| after_event_id.map_or(true, |after| e.event_id.as_str() > after))
+            .cloned()
+            .collect();
+        events.sort_by(|a, b| a.event_id.cmp(&b.event_id));
+        events.truncate(limit);
+        Ok(events)
+    }
+
+    async fn count_transfers_in_range(
+        &self,
+        contract_address: &str,
+        from_ts: u64,
+        to_ts: u64,
+    ) -> Result<u64, StorageError> {
+        Ok(self
+            .data()
+            .transfer_events
+            .values()
+            .filter(|e| e.contract_address == contract_address)
+            .filter(|e| e.timestamp >= from_ts && e.timestamp < to_ts)
+            .count() as u64)
+    }
+
+    async fn sum_transfer_volume_in_range(
+        &self,
+        contract_address: &str,
+        from_ts: u64,
+        to_ts: u64,
+    ) -> Result<u128, StorageError> {
+        Ok(self
+            .data()
+            .transfer_events
+            .values()
+            .filter(|e| e.contract_address == contract_address)
+            .filter(|e| e.timestamp >= from_ts && e.timestamp < to_ts)
+            .map(|e| e.quantity as u128)
+            .sum())
+    }
+
+    async fn finalize_pending_events(
+        &self,
+        block_timestamp: u64,
+        block_number: u64,
+    ) -> Result<(), StorageError> {
+        self.data()
+            .transfer_events
+            .values_mut()
+            .filter(|e| e.timestamp == block_timestamp && e.is_pending())
+            .for_each(|e| e.block_number = Some(block_number));
+
+        Ok(())
+    }
+
+    async fn adjust_collection_supply(
+        &self,
+        contract_address: &str,
+        delta: i64,
+    ) -> Result<(), StorageError> {
+        *self
+            .data()
+            .supplies
+            .entry(contract_address.to_string())
+            .or_default() += delta;
+
+        Ok(())
+    }
+
+    async fn recount_collection_supply(&self, contract_address: &str) -> Result<i64, StorageError> {
+        let mut data = self.data();
+
+        let supply = data
+            .transfer_events
+            .values()
+            .filter(|e| e.contract_address == contract_address)
+            .map(|e| e.supply_delta())
+            .sum();
+
+        data.supplies.insert(contract_address.to_string(), supply);
+
+        Ok(supply)
+    }
+
+    async fn count_collection_tokens(
+        &self,
+        contract_address: &str,
+    ) -> Result<(u64, u64), StorageError> {
+        let data = self.data();
+        let tokens: Vec<&TokenInfo> = data
+            .tokens
+            .values()
+            .filter(|t| t.contract_address == contract_address && !t.is_burned)
+            .collect();
+        let holders: HashSet<&str> = tokens.iter().map(|t| t.owner.as_str()).collect();
+
+        Ok((tokens.len() as u64, holders.len() as u64))
+    }
+
+    async fn upsert_collection_stats(
+        &self,
+        contract_address: &str,
+        stats: &CollectionStats,
+    ) -> Result<(), StorageError> {
+        self.data()
+            .collection_stats
+            .insert(contract_address.to_string(), *stats);
+
+        Ok(())
+    }
+
+    async fn get_collection_stats(
+        &self,
+        contract_address: &str,
+    ) -> Result<Option<CollectionStats>, StorageError> {
+        Ok(self.data().collection_stats.get(contract_address).copied())
+    }
+
+    async fn get_total_supply(&self, contract_address: &str) -> Result<Option<u64>, StorageError> {
+        Ok(self.data().total_supplies.get(contract_address).copied())
+    }
+
+    async fn set_total_supply(
+        &self,
+        contract_address: &str,
+        total_supply: u64,
+    ) -> Result<(), StorageError> {
+        self.data()
+            .total_supplies
+            .insert(contract_address.to_string(), total_supply);
+
+        Ok(())
+    }
+
+    async fn purge_contract_range(
+        &self,
+        contract_address: &str,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<PurgedItems, StorageError> {
+        let mut data = self.data();
+        let data = &mut *data;
+        let mut purged = PurgedItems::default();
+        let mut minted: Vec<(String, String)> = vec![];
+
+        let supplies = &mut data.supplies;
+        data.transfer_events.retain(|_, e| {
+            if e.contract_address != contract_address
+                || !e
+                    .block_number
+                    .is_some_and(|n| n >= from_block && n <= to_block)
+            {
+                return true;
+            }
+
+            if e.event_type == EventType::Mint {
+                minted.push((e.contract_address.clone(), e.token_id_hex.clone()));
+            }
+            *supplies.entry(e.contract_address.clone()).or_default() -= e.supply_delta();
+            purged.events += 1;
+            false
+        });
+
+        for key in minted {
+            data.mints.remove(&key);
+            if data.tokens.remove(&key).is_some() {
+                purged.tokens += 1;
+            }
+        }
+
+        Ok(purged)
+    }
+
+    async fn get_reindex_cursor(
+        &self,
+        contract_address: &str,
+    ) -> Result<Option<u64>, StorageError> {
+        Ok(self.data().reindex_cursors.get(contract_address).copied())
+    }
+
+    async fn set_reindex_cursor(
+        &self,
+        contract_address: &str,
+        block_number: Option<u64>,
+    ) -> Result<(), StorageError> {
+        let mut data = self.data();
+
+        match block_number {
+            Some(n) => data.reindex_cursors.insert(contract_address.to_string(), n),
+            None => data.reindex_cursors.remove(contract_address),
+        };
+
+        Ok(())
+    }
+
+    async fn get_class_hash_type(
+        &self,
+        class_hash: &str,
+    ) -> Result<Option<ContractType>, StorageError> {
+        Ok(self.data().class_hash_types.get(class_hash).cloned())
+    }
+
+    async fn set_class_hash_type(
+        &self,
+        class_hash: &str,
+        contract_type: Option<ContractType>,
+    ) -> Result<(), StorageError> {
+        let mut data = self.data();
+
+        match contract_type {
+            Some(t) => data.class_hash_types.insert(class_hash.to_string(), t),
+            None => data.class_hash_types.remove(class_hash),
+        };
+
+        Ok(())
+    }
+
+    async fn register_contract_type_override(
+        &self,
+        address: FieldElement,
+        contract_type: ContractType,
+    ) -> Result<(), StorageError> {
+        self.data()
+            .contract_overrides
+            .insert(to_hex_str(&address), contract_type);
+        Ok(())
+    }
+
+    async fn get_contract_type_overrides(
+        &self,
+    ) -> Result<Vec<(String, ContractType)>, StorageError> {
+        Ok(self
+            .data()
+            .contract_overrides
+            .iter()
+            .map(|(a, t)| (a.clone(), t.clone()))
+            .collect())
+    }
+
+    async fn register_failed_event(&self, event: &FailedEvent) -> Result<(), StorageError> {
+        let mut data = self.data();
+
+        if data.failed_events.contains_key(&event.id) {
+            return Err(StorageError::AlreadyExists(format!(
+                "failed event id = {}",
+                event.id
+            )));
+        }
+
+        data.failed_events.insert(event.id.clone(), event.clone());
+
+        Ok(())
+    }
+
+    async fn get_failed_events(&self, limit: usize) -> Result<Vec<FailedEvent>, StorageError> {
+        let mut events: Vec<FailedEvent> = self.data().failed_events.values().cloned().collect();
+        events.sort_by_key(|e| e.block_timestamp);
+        events.truncate(limit);
+
+        Ok(events)
+    }
+
+    async fn remove_failed_event(&self, id: &str) -> Result<(), StorageError> {
+        self.data().failed_events.remove(id);
+        Ok(())
+    }
+
+    async fn register_quarantined_event(
+        &self,
+        event: &QuarantinedEvent,
+    ) -> Result<(), StorageError> {
+        self.data()
+            .quarantined_events
+            .insert(event.id.clone(), event.clone());
+        Ok(())
+    }
+
+    async fn get_quarantined_events(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<QuarantinedEvent>, StorageError> {
+        let mut events: Vec<QuarantinedEvent> =
+            self.data().quarantined_events.values().cloned().collect();
+        events.sort_by_key(|e| e.block_timestamp);
+        events.truncate(limit);
+
+        Ok(events)
+    }
+
+    async fn remove_quarantined_event(&self, id: &str) -> Result<(), StorageError> {
+        self.data().quarantined_events.remove(id);
+        Ok(())
+    }
+
+    async fn clean_block(
+        &self,
+        block_timestamp: u64,
+        _block_number: Option<u64>,
+    ) -> Result<(), StorageError> {
+        let mut data = self.data();
+        let data = &mut *data;
+
+        data.blocks.retain(|_, (ts, _)| *ts != block_timestamp);
+
+        for e in data
+            .transfer_events
+            .values()
+            .filter(|e| e.timestamp == block_timestamp)
+        {
+            *data.supplies.entry(e.contract_address.clone()).or_default() -= e.supply_delta();
+        }
+
+        data.transfer_events
+            .retain(|_, e| e.timestamp != block_timestamp);
+        data.sale_events
+            .retain(|_, e| e.timestamp != block_timestamp);
+
+        Ok(())
+    }
+
+    async fn export_rows(
+        &self,
+        table: ExportTable,
+        after: Option<&ExportKey>,
+        limit: usize,
+    ) -> Result<Vec<Vec<String>>, StorageError> {
+        let data = self.data();
+        let event_key = |event_id: &str| ExportKey::Event(event_id.to_string());
+
+        // Rows with their sort key.
+        let mut rows: Vec<(ExportKey, Vec<String>)> = match table {
+            ExportTable::Tokens => data
+                .tokens
+                .iter()
+                .map(|((address, id), t)| {
+                    let key = ExportKey::Token {
+                        contract_address: address.clone(),
+                        token_id_hex: id.clone(),
+                    };
+                    (key, token_row(t))
+                })
+                .collect(),
+            ExportTable::Events => data
+                .transfer_events
+                .values()
+                .map(|e| (event_key(&e.event_id), transfer_row(table, e)))
+                .chain(
+                    data.sale_events
+                        .values()
+                        .map(|e| (event_key(&e.event_id), sale_row(e))),
+                )
+                .collect(),
+            ExportTable::Transfers => data
+                .transfer_events
+                .values()
+                .map(|e| (event_key(&e.event_id), transfer_row(table, e)))
+                .collect(),
+            ExportTable::Blocks => data
+                .blocks
+                .iter()
+                .map(|(n, (ts, info))| (ExportKey::Block(*n), block_row(*ts, info)))
+                .collect(),
+        };
+        rows.retain(|(key, _)| after.map_or(true, |after| key > after));
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(rows.into_iter().take(limit).map(|(_, row)| row).collect())
+    }
+
+    async fn prune_blocks_before(&self, block_number: u64) -> Result<usize, StorageError> {
+        let mut data = self.data();
+
+        let count = data.blocks.len();
+        data.blocks.retain(|n, _| *n >= block_number);
+        let pruned = count - data.blocks.len();
+
+        let is_retained = |n: Option<u64>| n.map_or(true, |n| n >= block_number);
+        data.transfer_events
+            .retain(|_, e| is_retained(e.block_number));
+        data.sale_events.retain(|_, e| is_retained(e.block_number));
+
+        Ok(pruned)
+    }
+
+    async fn rollback_to_block(
+        &self,
+        last_valid_block: u64,
+    ) -> Result<RollbackStats, StorageError> {
+        let mut data = self.data();
+        let data = &mut *data;
+        let is_rolled_back = |n: Option<u64>| n.is_some_and(|n| n > last_valid_block);
+        let mut stats = RollbackStats::default();
+
+        let count = data.blocks.len();
+        data.blocks.retain(|n, _| *n <= last_valid_block);
+        data.processing_started_at
+            .retain(|n, _| *n <= last_valid_block);
+        data.terminated_at.retain(|n, _| *n <= last_valid_block);
+        stats.blocks_removed = (count - data.blocks.len()) as u64;
+
+        let supplies = &mut data.supplies;
+        let mut affected: BTreeSet<(String, String)> = BTreeSet::new();
+        data.transfer_events.retain(|_, e| {
+            if !is_rolled_back(e.block_number) {
+                return true;
+            }
+
+            *supplies.entry(e.contract_address.clone()).or_default() -= e.supply_delta();
+            affected.insert((e.contract_address.clone(), e.token_id_hex.clone()));
+            stats.events_removed += 1;
+            false
+        });
+
+        let count = data.sale_events.len();
+        data.sale_events
+            .retain(|_, e| !is_rolled_back(e.block_number));
+        stats.events_removed += (count - data.sale_events.len()) as u64;
+
+        for key in affected {
+            // The pending events are the most recent ones.
+            let last_owner = data
+                .transfer_events
+                .values()
+                .filter(|e| e.contract_address == key.0 && e.token_id_hex == key.1)
+                .max_by_key(|e| (e.block_number.is_none(), e.block_number, e.timestamp))
+                .map(|e| e.to_address.clone());
+
+            match last_owner {
+                None => {
+                    data.mints.remove(&key);
+                    if data.tokens.remove(&key).is_some() {
+                        stats.tokens_affected += 1;
+                    }
+                }
+                Some(owner) => {
+                    if let Some(token) = data.tokens.get_mut(&key) {
+                        token.owner = owner;
+                        if is_rolled_back(token.burned_at_block) {
+                            token.is_burned = false;
+                            token.burned_at_block = None;
+                        }
+                        stats.tokens_affected += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(event_id: &str, timestamp: u64, quantity: u64) -> TokenTransferEvent {
+        TokenTransferEvent {
+            event_id: event_id.to_string(),
+            contract_address: "0x1".to_string(),
+            timestamp,
+            quantity,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfers_in_range() {
+        let storage = InMemoryStorage::new();
+
+        for (id, ts, quantity) in [("0xa", 10, 1), ("0xb", 20, 5), ("0xc", 30, 2)] {
+            storage
+                .register_transfer_event(&transfer(id, ts, quantity), ts)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            storage
+                .count_transfers_in_range("0x1", 10, 30)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            storage
+                .sum_transfer_volume_in_range("0x1", 10, 31)
+                .await
+                .unwrap(),
+            8
+        );
+        assert_eq!(
+            storage
+                .count_transfers_in_range("0x2", 0, 100)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prune_blocks_before() {
+        let storage = InMemoryStorage::new();
+
+        for (n, id) in [(1, "0xa"), (2, "0xb"), (3, "0xc")] {
+            storage
+                .set_block_info(
+                    n,
+                    n * 10,
+                    BlockInfo {
+                        indexer_version: "v0".to_string(),
+                        indexer_identifier: "test".to_string(),
+                        status: BlockIndexingStatus::Terminated,
+                        block_number: n,
+                        selector_hash: None,
+                        last_heartbeat_at: 0,
+                    },
+                )
+                .await
+                .unwrap();
+            let event = TokenTransferEvent {
+                block_number: Some(n),
+                ..transfer(id, n * 10, 1)
+            };
+            storage
+                .register_transfer_event(&event, n * 10)
+                .await
+                .unwrap();
+        }
+
+        // A pending event is never pruned.
+        storage
+            .register_transfer_event(&transfer("0xd", 40, 1), 40)
+            .await
+            .unwrap();
+
+        assert_eq!(storage.prune_blocks_before(3).await.unwrap(), 2);
+        assert_eq!(storage.prune_blocks_before(3).await.unwrap(), 0);
+
+        let data = storage.dump();
+        assert_eq!(data.blocks.keys().collect::<Vec<_>>(), vec![&3]);
+        let mut events: Vec<&String> = data.transfer_events.keys().collect();
+        events.sort();
+        assert_eq!(events, vec!["0xc", "0xd"]);
+    }
+
+    #[tokio::test]
+    async fn test_tokens_paginated() {
+        let storage = InMemoryStorage::new();
+
+        for (contract, id) in [("0x1", 3u64), ("0x1", 1), ("0x1", 2), ("0x2", 4)] {
+            let token = TokenInfo {
+                contract_address: contract.to_string(),
+                token_id: id.to_string(),
+                token_id_hex: format!("0x{:064x}", id),
+                ..Default::default()
+            };
+            storage.register_token(&token, 0).await.unwrap();
+        }
+
+        let ids = |tokens: &[TokenInfo]| -> Vec<String> {
+            tokens.iter().map(|t| t.token_id.clone()).collect()
+        };
+
+        let (page, has_more) = storage.get_tokens_paginated("0x1", None, 2).await.unwrap();
+        assert_eq!(ids(&page), vec!["1", "2"]);
+        assert!(has_more);
+
+        let after = page.last().map(|t| t.token_id_hex.clone());
+        let (page, has_more) = storage
+            .get_tokens_paginated("0x1", after.as_deref(), 2)
+            .await
+            .unwrap();
+        assert_eq!(ids(&page), vec!["3"]);
+        assert!(!has_more);
+    }
+
+    #[tokio::test]
+    async fn test_tokens_by_attribute() {
+        let storage = InMemoryStorage::new();
+
+        storage
+            .upsert_token_attribute("0x1", "0xa", "Background", "Blue")
+            .await
+            .unwrap();
+        storage
+            .upsert_token_attribute("0x1", "0xb", "Background", "Red")
+            .await
+            .unwrap();
+        storage
+            .upsert_token_attribute("0x2", "0xa", "Background", "Blue")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage
+                .get_tokens_by_attribute("0x1", "Background", "Blue")
+                .await
+                .unwrap(),
+            vec!["0xa".to_string()]
+        );
+
+        // The value of a trait is replaced.
+        storage
+            .upsert_token_attribute("0x1", "0xb", "Background", "Blue")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .get_tokens_by_attribute("0x1", "Background", "Blue")
+                .await
+                .unwrap(),
+            vec!["0xa".to_string(), "0xb".to_string()]
+        );
+        assert!(storage
+            .get_tokens_by_attribute("0x1", "Background", "Red")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_events_for_token() {
+        let storage = InMemoryStorage::new();
+
+        // (event id, token id, block number, timestamp)
+        let events = [
+            ("0xa", "0x7", Some(2), 20),
+            ("0xb", "0x7", None, 30),
+            ("0xc", "0x7", Some(1), 10),
+            ("0xd", "0x8", Some(1), 10),
+            ("0x9", "0x7", Some(2), 20),
+        ];
+        for (id, token_id_hex, block_number, ts) in events {
+            let event = TokenTransferEvent {
+                token_id_hex: token_id_hex.to_string(),
+                block_number,
+                ..transfer(id, ts, 1)
+            };
+            storage.register_transfer_event(&event, ts).await.unwrap();
+        }
+        storage
+            .register_sale_event(
+                &TokenSaleEvent {
+                    timestamp: 20,
+                    from_address: String::new(),
+                    to_address: String::new(),
+                    nft_contract_address: "0x1".to_string(),
+                    nft_type: None,
+                    marketplace_contract_address: String::new(),
+                    marketplace_name: String::new(),
+                    transaction_hash: String::new(),
+                    token_id: "7".to_string(),
+                    token_id_hex: "0x7".to_string(),
+                    event_type: EventType::Sale,
+                    event_id: "0xe".to_string(),
+                    block_number: Some(2),
+                    updated_at: None,
+                    quantity: 1,
+                    currency_address: None,
+                    price: "1".to_string(),
+                },
+                20,
+            )
+            .await
+            .unwrap();
+
+        let history: Vec<String> = storage
+            .get_events_for_token("0x1", "0x7")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| match e {
+                TokenEvent::Transfer(t) => t.event_id,
+                TokenEvent::Sale(s) => s.event_id,
+            })
+            .collect();
+        assert_eq!(history, vec!["0xc", "0x9", "0xa", "0xe", "0xb"]);
+    }
+
+    #[tokio::test]
+    async fn test_count_events_by_block() {
+        let storage = InMemoryStorage::new();
+
+        for (id, block_number) in [
+            ("0xa", Some(3)),
+            ("0xb", Some(1)),
+            ("0xc", Some(3)),
+            ("0xd", Some(5)),
+            ("0xe", None),
+        ] {
+            let event = TokenTransferEvent {
+                block_number,
+                ..transfer(id, 10, 1)
+            };
+            storage.register_transfer_event(&event, 10).await.unwrap();
+        }
+
+        assert_eq!(
+            storage.count_events_by_block(1, 4).await.unwrap(),
+            vec![(1, 1), (3, 2)]
+        );
+        assert!(storage
+            .count_events_by_block(6, 9)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_block_details() {
+        let storage = InMemoryStorage::new();
+        assert_eq!(storage.get_block_details(1).await.unwrap(), None);
+
+        let info = |status| BlockInfo {
+            indexer_version: "v0".to_string(),
+            indexer_identifier: "test".to_string(),
+            status,
+            block_number: 1,
+            selector_hash: None,
+            last_heartbeat_at: 0,
+        };
+
+        storage
+            .set_block_info(1, 10, info(BlockIndexingStatus::Processing))
+            .await
+            .unwrap();
+        let details = storage.get_block_details(1).await.unwrap().unwrap();
+        assert_eq!(details.info, info(BlockIndexingStatus::Processing));
+        assert!(details.processing_started_at.is_some());
+        assert_eq!(details.terminated_at, None);
+        assert_eq!(details.event_count, 0);
+
+        let event = TokenTransferEvent {
+            block_number: Some(1),
+            ..transfer("0xa", 10, 1)
+        };
+        storage.register_transfer_event(&event, 10).await.unwrap();
+        storage
+            .set_block_info(1, 10, info(BlockIndexingStatus::Terminated))
+            .await
+            .unwrap();
+        let details = storage.get_block_details(1).await.unwrap().unwrap();
+        assert_eq!(details.processing_started_at, None);
+        assert!(details.terminated_at.is_some());
+        assert_eq!(details.event_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_rollback_to_block() {
+        let storage = InMemoryStorage::new();
+
+        let token = |id: &str, owner: &str| TokenInfo {
+            contract_address: "0x1".to_string(),
+            token_id_hex: id.to_string(),
+            owner: owner.to_string(),
+            ..Default::default()
+        };
+        let event =
+            |event_id: &str, token_id: &str, event_type, to: &str, block: u64| TokenTransferEvent {
+                token_id_hex: token_id.to_string(),
+                to_address: to.to_string(),
+                event_type,
+                block_number: Some(block),
+                ..transfer(event_id, block * 10, 1)
+            };
+
+        // The token 0x1 is minted in block 1, transferred in block 2 then burned
+        // in block 3. The token 0x2 is minted in block 3.
+        for (e, ts) in [
+            (event("0xa", "0x1", EventType::Mint, "0xaa", 1), 10),
+            (event("0xb", "0x1", EventType::Transfer, "0xbb", 2), 20),
+            (event("0xc", "0x1", EventType::Burn, "0x0", 3), 30),
+            (event("0xd", "0x2", EventType::Mint, "0xdd", 3), 30),
+        ] {
+            storage.register_transfer_event(&e, ts).await.unwrap();
+        }
+        storage
+            .register_token(
+                &TokenInfo {
+                    is_burned: true,
+                    burned_at_block: Some(3),
+                    ..token("0x1", "0x0")
+                },
+                10,
+            )
+            .await
+            .unwrap();
+        storage
+            .register_token(&token("0x2", "0xdd"), 30)
+            .await
+            .unwrap();
+        for n in 1..=3 {
+            let info = BlockInfo {
+                indexer_version: "v0".to_string(),
+                indexer_identifier: "test".to_string(),
+                status: BlockIndexingStatus::Terminated,
+                block_number: n,
+                selector_hash: None,
+                last_heartbeat_at: 0,
+            };
+            storage.set_block_info(n, n * 10, info).await.unwrap();
+        }
+        storage.data().supplies.insert("0x1".to_string(), 1);
+
+        assert_eq!(
+            storage.rollback_to_block(1).await.unwrap(),
+            RollbackStats {
+                blocks_removed: 2,
+                events_removed: 3,
+                tokens_affected: 2,
+            }
+        );
+
+        let data = storage.dump();
+        assert_eq!(data.blocks.keys().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(data.transfer_events.keys().collect::<Vec<_>>(), vec!["0xa"]);
+        assert_eq!(data.supplies["0x1"], 1);
+        assert_eq!(data.tokens.len(), 1);
+        assert_eq!(
+            data.tokens[&("0x1".to_string(), "0x1".to_string())],
+            token("0x1", "0xaa")
+        );
+
+        assert_eq!(
+            storage.rollback_to_block(1).await.unwrap(),
+            RollbackStats::default()
+        );
+    }
+}
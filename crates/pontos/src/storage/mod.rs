@@ -1,21 +1,34 @@
+pub mod export;
+#[cfg(any(test, feature = "testing"))]
+pub mod memory;
 #[cfg(feature = "sqlxdb")]
 pub mod sqlx;
 pub mod types;
 pub mod utils;
 use self::types::TokenSaleEvent;
 use crate::storage::types::{
-    BlockInfo, ContractInfo, ContractType, StorageError, TokenInfo, TokenMintInfo,
-    TokenTransferEvent,
+    BlockDetails, BlockInfo, CollectionStats, ContractInfo, ContractType, DecodedTransfer,
+    ExportKey, ExportTable, FailedEvent, IndexerInfo, MetadataPatchRecord, PurgedItems,
+    QuarantinedEvent, RollbackStats, StorageError, TokenEvent, TokenInfo, TokenMintInfo,
+    TokenTransferEvent, VacuumStats,
 };
 use async_trait::async_trait;
+pub use export::CsvExport;
+#[cfg(any(test, feature = "testing"))]
+pub use memory::InMemoryStorage;
 #[cfg(test)]
 use mockall::automock;
 #[cfg(feature = "sqlxdb")]
 pub use sqlx::DefaultSqlxStorage;
+use starknet::core::types::FieldElement;
 
+/// Storage of the indexed data.
+///
+/// The storage is shared between the managers of a Pontos instance,
+/// and must be `Sync`.
 #[async_trait]
 #[cfg_attr(test, automock)]
-pub trait Storage {
+pub trait Storage: Sync {
     async fn register_mint(
         &self,
         contract_address: &str,
@@ -42,11 +55,130 @@ pub trait Storage {
         block_timestamp: u64,
     ) -> Result<(), StorageError>;
 
+    /// Registers the transfer event, unless an event with the same id is already
+    /// registered. The id being derived from the transaction hash and the
+    /// content of the event, the same event fetched twice (by overlapping pages,
+    /// or by concurrent indexers) is only registered once.
+    /// The check and the insertion must be atomic.
+    ///
+    /// Returns false if the event was already registered.
+    async fn upsert_event(&self, event: &TokenTransferEvent) -> Result<bool, StorageError>;
+
+    /// Registers the transfer event, replacing the event with the same id
+    /// if it is already registered.
+    async fn replace_event(&self, event: &TokenTransferEvent) -> Result<(), StorageError>;
+
+    /// Registers a decoded transfer event, as `upsert_event` does.
+    /// Returns the event rendered as stored, or `None` if the event
+    /// was already registered.
+    ///
+    /// The default implementation renders the event before calling
+    /// `upsert_event`. Storages able to check the event id first may skip
+    /// the rendering of the events already registered.
+    async fn upsert_decoded_event(
+        &self,
+        event: &DecodedTransfer,
+    ) -> Result<Option<TokenTransferEvent>, StorageError> {
+        let token_event = event.to_token_event();
+        Ok(self
+            .upsert_event(&token_event)
+            .await?
+            .then_some(token_event))
+    }
+
+    /// Sets the value of a trait of the token, replacing any previous value
+    /// for the same trait type.
+    async fn upsert_token_attribute(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+        trait_type: &str,
+        value: &str,
+    ) -> Result<(), StorageError>;
+
+    /// Applies a metadata correction to the token, and records it in the
+    /// audit log of the patches, so patched values can be told apart from
+    /// the indexed ones. A patch of an attribute replaces its value
+    /// as `upsert_token_attribute` does.
+    async fn apply_metadata_patch(&self, patch: &MetadataPatchRecord) -> Result<(), StorageError>;
+
+    /// Returns the metadata patches applied to the token, oldest first.
+    async fn get_metadata_patches(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+    ) -> Result<Vec<MetadataPatchRecord>, StorageError>;
+
+    /// Returns the ids (hex) of the tokens of the contract having
+    /// the given value for the trait type.
+    async fn get_tokens_by_attribute(
+        &self,
+        contract_address: &str,
+        trait_type: &str,
+        value: &str,
+    ) -> Result<Vec<String>, StorageError>;
+
+    /// Returns the token, if registered.
+    async fn get_token(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+    ) -> Result<Option<TokenInfo>, StorageError>;
+
+    /// Marks the token as burned at the given block (`None` for the pending
+    /// block), or as not burned if `is_burned` is false.
+    async fn set_token_burned(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+        is_burned: bool,
+        block_number: Option<u64>,
+    ) -> Result<(), StorageError>;
+
+    /// Sets the owner of the token.
+    /// Returns `NotFound` if the token is not registered.
+    async fn set_token_owner(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+        owner: &str,
+    ) -> Result<(), StorageError>;
+
+    /// Returns a page of at most `limit` tokens of the contract, ordered by
+    /// token id, starting after the token id (hex) `after` if any.
+    /// The boolean is true if more tokens follow this page.
+    ///
+    /// The tokens ids (hex) being zero-padded, the page is located with the key
+    /// and not with an offset, for a constant cost whatever the page.
+    async fn get_tokens_paginated(
+        &self,
+        contract_address: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<TokenInfo>, bool), StorageError>;
+
+    /// Returns all the events registered for the given transaction hash.
+    async fn get_event_by_tx_hash(
+        &self,
+        transaction_hash: &str,
+    ) -> Result<Vec<TokenEvent>, StorageError>;
+
+    /// Returns all the events registered for the token, ordered by block
+    /// number, the events of the pending block last, then by block timestamp.
+    /// The order of the events within a block is not recorded: they are
+    /// ordered by event id.
+    async fn get_events_for_token(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+    ) -> Result<Vec<TokenEvent>, StorageError>;
+
+    /// Returns `None` if the contract is not registered.
     async fn get_contract_type(
         &self,
         contract_address: &str,
         chain_id: &str,
-    ) -> Result<ContractType, StorageError>;
+    ) -> Result<Option<ContractType>, StorageError>;
 
     async fn register_contract_info(
         &self,
@@ -55,7 +187,29 @@ pub trait Storage {
         chain_id: &str,
     ) -> Result<(), StorageError>;
 
+    /// Changes the type of a registered contract.
+    /// Returns `NotFound` if the contract is not registered.
+    async fn set_contract_type(
+        &self,
+        contract_address: &str,
+        chain_id: &str,
+        contract_type: &ContractType,
+    ) -> Result<(), StorageError>;
+
+    /// Marks (or unmarks) the contract as having its events of the blocks
+    /// already indexed to be indexed, as it was identified late.
+    async fn set_contract_backfill(
+        &self,
+        contract_address: &str,
+        needs_backfill: bool,
+    ) -> Result<(), StorageError>;
+
+    /// Returns the contracts marked with `set_contract_backfill`.
+    async fn get_contracts_to_backfill(&self) -> Result<Vec<String>, StorageError>;
+
     /// A block info is only set if the block has a number and a timestamp.
+    /// The time at which a block is marked as processing is recorded,
+    /// for `get_processing_blocks_started_before`.
     async fn set_block_info(
         &self,
         block_number: u64,
@@ -63,13 +217,350 @@ pub trait Storage {
         info: BlockInfo,
     ) -> Result<(), StorageError>;
 
-    async fn get_block_info(&self, block_number: u64) -> Result<BlockInfo, StorageError>;
+    /// Returns `None` if the block is unknown.
+    async fn get_block_info(&self, block_number: u64) -> Result<Option<BlockInfo>, StorageError>;
+
+    /// Returns the etag of the block, a counter incremented by each
+    /// `set_block_info` of the block. 0 if the block is unknown.
+    async fn get_block_etag(&self, block_number: u64) -> Result<u64, StorageError>;
+
+    /// Same as `set_block_info`, only if the etag of the block is still
+    /// `expected_etag`, atomically. Returns the etag found before the write,
+    /// the block being left untouched if it is not `expected_etag`.
+    async fn set_block_info_if_etag(
+        &self,
+        block_number: u64,
+        block_timestamp: u64,
+        info: BlockInfo,
+        expected_etag: u64,
+    ) -> Result<u64, StorageError>;
+
+    /// Returns the stored metadata of the block, with its processing times
+    /// and its number of events. `None` if the block is unknown.
+    async fn get_block_details(
+        &self,
+        block_number: u64,
+    ) -> Result<Option<BlockDetails>, StorageError>;
+
+    /// Records that the indexer processing the block is alive, at `heartbeat_at`
+    /// (milliseconds since the epoch). Returns `NotFound` if the block is unknown.
+    async fn set_block_heartbeat(
+        &self,
+        block_number: u64,
+        heartbeat_at: u64,
+    ) -> Result<(), StorageError>;
+
+    /// Returns the number and the timestamp of the highest block
+    /// marked as terminated, `None` if no block is terminated.
+    async fn get_last_terminated_block(&self) -> Result<Option<(u64, u64)>, StorageError>;
+
+    /// Returns the numbers of the blocks still marked as processing
+    /// by the given indexer, in ascending order.
+    async fn get_processing_blocks(
+        &self,
+        indexer_identifier: &str,
+    ) -> Result<Vec<u64>, StorageError>;
+
+    /// Returns the distinct versions of the indexers which indexed the blocks.
+    async fn get_indexer_versions(&self) -> Result<Vec<String>, StorageError>;
+
+    /// Records the heartbeat of an indexer instance, replacing the
+    /// previous record of the same identifier.
+    async fn register_indexer(&self, info: &IndexerInfo) -> Result<(), StorageError>;
+
+    /// Returns the records of all the indexers, by identifier.
+    async fn get_indexers(&self) -> Result<Vec<IndexerInfo>, StorageError>;
+
+    /// Returns the number of blocks marked as processing, by any indexer.
+    async fn count_processing_blocks(&self) -> Result<u64, StorageError>;
+
+    /// Returns the number of blocks marked as terminated, by any indexer,
+    /// from `from_block` to `to_block` inclusive.
+    async fn count_terminated_blocks_in_range(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<u64, StorageError>;
+
+    /// Returns the numbers of the blocks marked as terminated, by any indexer,
+    /// from `from_block` to `to_block` inclusive, in ascending order.
+    async fn get_terminated_blocks_in_range(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<u64>, StorageError>;
+
+    /// Returns the numbers of the blocks marked as processing before
+    /// `started_before_ms` (milliseconds since the epoch), in ascending order.
+    async fn get_processing_blocks_started_before(
+        &self,
+        started_before_ms: u64,
+    ) -> Result<Vec<u64>, StorageError>;
+
+    /// Checks that the storage is reachable, with a query as light as possible.
+    async fn health_check(&self) -> Result<(), StorageError>;
+
+    /// Returns the number of transfer events stored for the given block number.
+    async fn count_block_events(&self, block_number: u64) -> Result<u64, StorageError>;
+
+    /// Returns the ids of the transfer events stored for the given block number,
+    /// sorted.
+    async fn get_block_event_ids(&self, block_number: u64) -> Result<Vec<String>, StorageError>;
+
+    /// Returns the number of transfer events stored for each block of
+    /// `from_block..=to_block`, as `(block_number, event_count)` sorted by
+    /// block number, with a single aggregate query. The blocks without
+    /// events are omitted.
+    async fn count_events_by_block(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<(u64, u64)>, StorageError>;
+
+    /// Returns the transfer events stored for the given block number,
+    /// ordered by event id, the order within the block not being recorded.
+    async fn get_block_transfer_events(
+        &self,
+        block_number: u64,
+    ) -> Result<Vec<TokenTransferEvent>, StorageError>;
+
+    /// Returns up to `limit` transfer events of the contract, ordered by event id.
+    async fn get_contract_transfer_events(
+        &self,
+        contract_address: &str,
+        limit: usize,
+    ) -> Result<Vec<TokenTransferEvent>, StorageError>;
+
+    /// Returns the number of transfer events (including mints and burns)
+    /// of the contract, with a block number in `[from_block, to_block]`.
+    async fn count_transfers_in_blocks(
+        &self,
+        contract_address: &str,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<u64, StorageError>;
+
+    /// Returns the number of transfer events stored, pending ones included.
+    async fn count_transfer_events(&self) -> Result<u64, StorageError>;
+
+    /// Returns up to `limit` transfer events with an id greater than
+    /// `after_event_id` (all the events if `None`), ordered by event id.
+    /// Used to walk through all the events stored, page by page.
+    async fn get_transfer_events_after(
+        &self,
+        after_event_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<TokenTransferEvent>, StorageError>;
+
+    /// Returns the number of transfer events (including mints and burns)
+    /// of the contract, with a block timestamp in `[from_ts, to_ts[`.
+    async fn count_transfers_in_range(
+        &self,
+        contract_address: &str,
+        from_ts: u64,
+        to_ts: u64,
+    ) -> Result<u64, StorageError>;
+
+    /// Returns the sum of the quantities transferred by the transfer events
+    /// (including mints and burns) of the contract, with a block timestamp
+    /// in `[from_ts, to_ts[`.
+    async fn sum_transfer_volume_in_range(
+        &self,
+        contract_address: &str,
+        from_ts: u64,
+        to_ts: u64,
+    ) -> Result<u128, StorageError>;
+
+    /// Sets the block number of the events registered for the pending block
+    /// with the given timestamp, once this block is promoted to latest.
+    async fn finalize_pending_events(
+        &self,
+        block_timestamp: u64,
+        block_number: u64,
+    ) -> Result<(), StorageError>;
+
+    /// Adds the given delta (which can be negative) to the supply
+    /// of the collection.
+    async fn adjust_collection_supply(
+        &self,
+        contract_address: &str,
+        delta: i64,
+    ) -> Result<(), StorageError>;
+
+    /// Recomputes the supply of the collection from the mints and burns
+    /// stored, overwriting the current value. Returns the new supply.
+    async fn recount_collection_supply(&self, contract_address: &str) -> Result<i64, StorageError>;
+
+    /// Returns the number of tokens of the collection not burned,
+    /// and the number of distinct owners of those tokens.
+    async fn count_collection_tokens(
+        &self,
+        contract_address: &str,
+    ) -> Result<(u64, u64), StorageError>;
+
+    /// Replaces the counters of the collection, at once.
+    async fn upsert_collection_stats(
+        &self,
+        contract_address: &str,
+        stats: &CollectionStats,
+    ) -> Result<(), StorageError>;
+
+    /// Returns the counters of the collection, `None` if never recorded.
+    async fn get_collection_stats(
+        &self,
+        contract_address: &str,
+    ) -> Result<Option<CollectionStats>, StorageError>;
+
+    /// Returns the total supply of the collection reported by the contract,
+    /// if it was cached with `set_total_supply`.
+    async fn get_total_supply(&self, contract_address: &str) -> Result<Option<u64>, StorageError>;
+
+    /// Caches the total supply of the collection reported by the contract.
+    async fn set_total_supply(
+        &self,
+        contract_address: &str,
+        total_supply: u64,
+    ) -> Result<(), StorageError>;
+
+    /// Removes the transfer events of the contract with a block number in
+    /// `[from_block, to_block]`, and the tokens minted by those events.
+    /// The supply of the collection must be adjusted to reverse the removed events.
+    /// The blocks info are left untouched. The purge must be atomic.
+    async fn purge_contract_range(
+        &self,
+        contract_address: &str,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<PurgedItems, StorageError>;
+
+    /// Returns the next block to re-index for the contract,
+    /// if a re-indexation is in progress.
+    ///
+    /// The cursors of the chunked `Pontos::index_block_range` are stored
+    /// along, with a `range:` prefixed key instead of a contract address.
+    async fn get_reindex_cursor(&self, contract_address: &str)
+        -> Result<Option<u64>, StorageError>;
+
+    /// Sets the next block to re-index for the contract,
+    /// or clears the cursor if `None`.
+    async fn set_reindex_cursor(
+        &self,
+        contract_address: &str,
+        block_number: Option<u64>,
+    ) -> Result<(), StorageError>;
+
+    /// Returns the type memoized for the contract class hash, if any.
+    async fn get_class_hash_type(
+        &self,
+        class_hash: &str,
+    ) -> Result<Option<ContractType>, StorageError>;
+
+    /// Memoizes the type of the contracts of the given class hash,
+    /// or invalidates the class hash if `None`.
+    async fn set_class_hash_type(
+        &self,
+        class_hash: &str,
+        contract_type: Option<ContractType>,
+    ) -> Result<(), StorageError>;
+
+    /// Overrides permanently the type of the contract, whatever the
+    /// identification finds. Replaces the previous override, if any.
+    async fn register_contract_type_override(
+        &self,
+        address: FieldElement,
+        contract_type: ContractType,
+    ) -> Result<(), StorageError>;
+
+    /// Returns all the contract type overrides, by contract address.
+    async fn get_contract_type_overrides(
+        &self,
+    ) -> Result<Vec<(String, ContractType)>, StorageError>;
+
+    /// Adds an event to the dead-letter queue.
+    /// Returns `AlreadyExists` if an event with the same id is already queued.
+    async fn register_failed_event(&self, event: &FailedEvent) -> Result<(), StorageError>;
+
+    /// Returns up to `limit` events of the dead-letter queue,
+    /// the oldest blocks first.
+    async fn get_failed_events(&self, limit: usize) -> Result<Vec<FailedEvent>, StorageError>;
+
+    /// Removes an event from the dead-letter queue.
+    async fn remove_failed_event(&self, id: &str) -> Result<(), StorageError>;
+
+    /// Quarantines an event which couldn't be attributed to a collection,
+    /// replacing the event with the same id, if any.
+    async fn register_quarantined_event(
+        &self,
+        event: &QuarantinedEvent,
+    ) -> Result<(), StorageError>;
+
+    /// Returns up to `limit` quarantined events, the oldest blocks first.
+    async fn get_quarantined_events(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<QuarantinedEvent>, StorageError>;
+
+    /// Removes an event from the quarantine, once reviewed.
+    async fn remove_quarantined_event(&self, id: &str) -> Result<(), StorageError>;
 
     /// The block timestamps is always present. But the number can be missing
     /// for the pending block support.
+    ///
+    /// The supply of the collections must be adjusted to reverse
+    /// the mints and burns being removed.
     async fn clean_block(
         &self,
         block_timestamp: u64,
         block_number: Option<u64>,
     ) -> Result<(), StorageError>;
+
+    /// Removes the info of the blocks older than `block_number`, and the events
+    /// registered in those blocks. The tokens and the supply of the collections
+    /// are left untouched, as well as the events of the pending block.
+    /// Returns the number of blocks removed.
+    async fn prune_blocks_before(&self, block_number: u64) -> Result<usize, StorageError>;
+
+    /// Undoes, in a single transaction, the writes of the blocks after
+    /// `last_valid_block`, after a reorg: their info and their events are
+    /// removed, and the supply of the collections is adjusted.
+    ///
+    /// The tokens of the removed events are restored from the last event
+    /// remaining for them: the owner is its recipient, and a burn done after
+    /// `last_valid_block` is reverted. The tokens without any event left, minted
+    /// after `last_valid_block`, are removed. The events of the pending block
+    /// are left untouched.
+    async fn rollback_to_block(&self, last_valid_block: u64)
+        -> Result<RollbackStats, StorageError>;
+
+    /// Returns the rows of the table after the row of the key `after`, or from
+    /// the first row if `None`, up to `limit` rows, in the order and with the
+    /// columns documented in `ExportTable`. Used by `CsvExport::export_csv`,
+    /// with the key of the last row of the previous page.
+    async fn export_rows(
+        &self,
+        table: ExportTable,
+        after: Option<&ExportKey>,
+        limit: usize,
+    ) -> Result<Vec<Vec<String>>, StorageError>;
+
+    /// Hints that the writes of a block are about to be done, and may be
+    /// batched (in a single transaction for instance) until `end_bulk_write`.
+    /// The hint is advisory, the default implementation does nothing.
+    async fn begin_bulk_write(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Hints that the writes started with `begin_bulk_write` are done,
+    /// and must be committed. Always called after `begin_bulk_write`,
+    /// even if some of the writes failed.
+    async fn end_bulk_write(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Permanently removes the records left behind by the deletions, like
+    /// `prune_blocks_before`, and reclaims their space. The default
+    /// implementation, for the storages deleting the records at once, does nothing.
+    async fn vacuum(&self) -> Result<VacuumStats, StorageError> {
+        Ok(VacuumStats::default())
+    }
 }
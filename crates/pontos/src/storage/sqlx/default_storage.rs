@@ -3,13 +3,22 @@
 //! The implementation in this file is very naive, and mostly
 //! used for testing and as an example of implementation.
 //! No optimization was done for indexing or PK/FK managment.
+use ark_starknet::format::to_hex_str;
+use ark_starknet::CairoU256;
 use async_trait::async_trait;
 
 use log::trace;
-use sqlx::{any::AnyPoolOptions, AnyPool, Error as SqlxError, FromRow};
+use sqlx::{
+    any::{AnyPoolOptions, AnyRow},
+    AnyPool, Error as SqlxError, FromRow, Row,
+};
+use starknet::core::types::FieldElement;
+use std::collections::{BTreeSet, HashMap};
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use super::types::*;
+use crate::storage::export::{block_row, token_row, transfer_row};
 use crate::storage::types::*;
 use crate::Storage;
 
@@ -23,6 +32,50 @@ pub struct DefaultSqlxStorage {
     pool: AnyPool,
 }
 
+/// Columns selected to build a `TokenTransferEvent` from a `token_event` row.
+const TRANSFER_EVENT_COLUMNS: &str = "block_timestamp, from_address, to_address, contract_address, transaction_hash, token_id, contract_type, event_type, event_id, block_number, quantity";
+
+fn transfer_event_from_row(row: &AnyRow) -> Result<TokenTransferEvent, StorageError> {
+    let block_timestamp: i64 = row.try_get("block_timestamp")?;
+    let block_number: Option<i64> = row.try_get("block_number")?;
+    let event_type: String = row.try_get("event_type")?;
+    let quantity: i64 = row.try_get("quantity")?;
+
+    Ok(TokenTransferEvent {
+        timestamp: block_timestamp as u64,
+        from_address: row.try_get("from_address")?,
+        to_address: row.try_get("to_address")?,
+        contract_address: row.try_get("contract_address")?,
+        transaction_hash: row.try_get("transaction_hash")?,
+        token_id: row.try_get("token_id")?,
+        contract_type: row.try_get("contract_type")?,
+        event_type: EventType::from_str(&event_type).unwrap_or(EventType::Uninitialized),
+        event_id: row.try_get("event_id")?,
+        block_number: block_number.map(|n| n as u64),
+        quantity: quantity as u64,
+        ..Default::default()
+    })
+}
+
+/// Columns selected to build a `TokenInfo` from a `token` row.
+const TOKEN_COLUMNS: &str =
+    "contract_address, token_id, token_id_hex, owner, is_burned, burned_at_block";
+
+/// The chain id is not stored with the tokens.
+fn token_from_row(row: &AnyRow) -> Result<TokenInfo, StorageError> {
+    let burned_at_block: Option<i64> = row.try_get("burned_at_block")?;
+
+    Ok(TokenInfo {
+        contract_address: row.try_get("contract_address")?,
+        token_id: row.try_get("token_id")?,
+        token_id_hex: row.try_get("token_id_hex")?,
+        owner: row.try_get("owner")?,
+        is_burned: row.try_get("is_burned")?,
+        burned_at_block: burned_at_block.map(|n| n as u64),
+        ..Default::default()
+    })
+}
+
 impl DefaultSqlxStorage {
     pub fn get_pool_ref(&self) -> &AnyPool {
         &self.pool
@@ -74,7 +127,7 @@ impl DefaultSqlxStorage {
     }
 
     async fn get_event_by_id(&self, event_id: &str) -> Result<Option<EventData>, StorageError> {
-        let q = "SELECT * FROM event WHERE event_id = $1";
+        let q = "SELECT * FROM token_event WHERE event_id = $1";
 
         match sqlx::query(q).bind(event_id).fetch_all(&self.pool).await {
             Ok(rows) => {
@@ -130,6 +183,82 @@ impl DefaultSqlxStorage {
             Err(e) => Err(StorageError::DatabaseError(e.to_string())),
         }
     }
+
+    /// Writes the block info, only if its etag is `expected_etag` when set,
+    /// and returns the number of blocks written.
+    async fn write_block_info(
+        &self,
+        block_number: u64,
+        block_timestamp: u64,
+        info: BlockInfo,
+        expected_etag: Option<u64>,
+    ) -> Result<u64, StorageError> {
+        trace!("Setting block info {:?} for block #{}", info, block_number);
+
+        let exists = sqlx::query("SELECT 1 FROM indexer WHERE indexer_identifier = $1")
+            .bind(info.indexer_identifier.clone())
+            .fetch_optional(&self.pool)
+            .await?
+            .is_some();
+
+        if !exists {
+            let q = "INSERT INTO indexer (indexer_identifier, indexer_version) VALUES ($1, $2)";
+            sqlx::query(q)
+                .bind(info.indexer_identifier.clone())
+                .bind(info.indexer_version.clone())
+                .execute(&self.pool)
+                .await?;
+        }
+
+        let processing_started_at = (info.status == BlockIndexingStatus::Processing).then(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or_default()
+        });
+
+        let terminated_at = (info.status == BlockIndexingStatus::Terminated).then(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or_default()
+        });
+
+        let r = if (self.get_block_by_timestamp(block_timestamp).await?).is_some() {
+            let q = "UPDATE block SET block_number = $1, block_status = $2, indexer_identifier = $3, processing_started_at = $4, selector_hash = $5, last_heartbeat_at = $6, terminated_at = $7, etag = etag + 1 WHERE block_timestamp = $8 AND ($9 OR etag = $10)";
+            sqlx::query(q)
+                .bind(block_number.to_string())
+                .bind(info.status.to_string())
+                .bind(info.indexer_identifier.clone())
+                .bind(processing_started_at)
+                .bind(info.selector_hash.clone())
+                .bind(info.last_heartbeat_at as i64)
+                .bind(terminated_at)
+                .bind(block_timestamp.to_string())
+                .bind(expected_etag.is_none())
+                .bind(expected_etag.unwrap_or_default() as i64)
+                .execute(&self.pool)
+                .await?
+        } else if expected_etag.unwrap_or_default() != 0 {
+            return Ok(0);
+        } else {
+            let q = "INSERT INTO block (block_timestamp, block_number, block_status, indexer_identifier, processing_started_at, selector_hash, last_heartbeat_at, terminated_at, etag) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, 1) ON CONFLICT (block_number) DO NOTHING";
+
+            sqlx::query(q)
+                .bind(block_timestamp.to_string())
+                .bind(block_number.to_string())
+                .bind(info.status.to_string())
+                .bind(info.indexer_identifier.clone())
+                .bind(processing_started_at)
+                .bind(info.selector_hash.clone())
+                .bind(info.last_heartbeat_at as i64)
+                .bind(terminated_at)
+                .execute(&self.pool)
+                .await?
+        };
+
+        Ok(r.rows_affected())
+    }
 }
 
 #[async_trait]
@@ -219,7 +348,7 @@ impl Storage for DefaultSqlxStorage {
             )));
         }
 
-        let q = "INSERT INTO token_event (block_timestamp, contract_address, from_address, to_address, transaction_hash, token_id, contract_type, event_type, event_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)";
+        let q = "INSERT INTO token_event (block_timestamp, from_address, to_address, contract_address, transaction_hash, token_id, contract_type, event_type, event_id, block_number, quantity, token_id_hex) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)";
 
         let _r = sqlx::query(q)
             .bind(event.timestamp.to_string())
@@ -231,29 +360,344 @@ impl Storage for DefaultSqlxStorage {
             .bind(event.contract_type.clone())
             .bind(event.event_type.to_string())
             .bind(event.event_id.clone())
+            .bind(event.block_number.map(|n| n as i64))
+            .bind(event.quantity as i64)
+            .bind(event.token_id_hex.clone())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn upsert_event(&self, event: &TokenTransferEvent) -> Result<bool, StorageError> {
+        trace!("Upserting event {:?}", event);
+
+        let q = "INSERT INTO token_event (block_timestamp, from_address, to_address, contract_address, transaction_hash, token_id, contract_type, event_type, event_id, block_number, quantity, token_id_hex) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) ON CONFLICT (event_id) DO NOTHING";
+
+        let inserted = sqlx::query(q)
+            .bind(event.timestamp.to_string())
+            .bind(event.from_address.clone())
+            .bind(event.to_address.clone())
+            .bind(event.contract_address.clone())
+            .bind(event.transaction_hash.clone())
+            .bind(event.token_id.clone())
+            .bind(event.contract_type.clone())
+            .bind(event.event_type.to_string())
+            .bind(event.event_id.clone())
+            .bind(event.block_number.map(|n| n as i64))
+            .bind(event.quantity as i64)
+            .bind(event.token_id_hex.clone())
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(inserted > 0)
+    }
+
+    async fn replace_event(&self, event: &TokenTransferEvent) -> Result<(), StorageError> {
+        trace!("Replacing event {:?}", event);
+
+        let q = "INSERT INTO token_event (block_timestamp, from_address, to_address, contract_address, transaction_hash, token_id, contract_type, event_type, event_id, block_number, quantity, token_id_hex) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) ON CONFLICT (event_id) DO UPDATE SET block_timestamp = excluded.block_timestamp, from_address = excluded.from_address, to_address = excluded.to_address, contract_address = excluded.contract_address, transaction_hash = excluded.transaction_hash, token_id = excluded.token_id, contract_type = excluded.contract_type, event_type = excluded.event_type, block_number = excluded.block_number, quantity = excluded.quantity, token_id_hex = excluded.token_id_hex";
+
+        sqlx::query(q)
+            .bind(event.timestamp.to_string())
+            .bind(event.from_address.clone())
+            .bind(event.to_address.clone())
+            .bind(event.contract_address.clone())
+            .bind(event.transaction_hash.clone())
+            .bind(event.token_id.clone())
+            .bind(event.contract_type.clone())
+            .bind(event.event_type.to_string())
+            .bind(event.event_id.clone())
+            .bind(event.block_number.map(|n| n as i64))
+            .bind(event.quantity as i64)
+            .bind(event.token_id_hex.clone())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn upsert_token_attribute(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+        trait_type: &str,
+        value: &str,
+    ) -> Result<(), StorageError> {
+        trace!(
+            "Setting attribute {}={} of token {} {}",
+            trait_type,
+            value,
+            contract_address,
+            token_id_hex
+        );
+
+        let q = "INSERT INTO token_attribute (contract_address, token_id_hex, trait_type, value) VALUES ($1, $2, $3, $4) ON CONFLICT (contract_address, token_id_hex, trait_type) DO UPDATE SET value = excluded.value";
+        sqlx::query(q)
+            .bind(contract_address)
+            .bind(token_id_hex)
+            .bind(trait_type)
+            .bind(value)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn apply_metadata_patch(&self, patch: &MetadataPatchRecord) -> Result<(), StorageError> {
+        trace!(
+            "Patching {} of token {} {}",
+            patch.field.to_string(),
+            patch.contract_address,
+            patch.token_id_hex
+        );
+
+        match &patch.field {
+            MetadataField::Attribute(trait_type) => {
+                self.upsert_token_attribute(
+                    &patch.contract_address,
+                    &patch.token_id_hex,
+                    trait_type,
+                    &patch.value,
+                )
+                .await?
+            }
+            field => {
+                let q = "INSERT INTO token_metadata (contract_address, token_id_hex, field, value) VALUES ($1, $2, $3, $4) ON CONFLICT (contract_address, token_id_hex, field) DO UPDATE SET value = excluded.value";
+                sqlx::query(q)
+                    .bind(&patch.contract_address)
+                    .bind(&patch.token_id_hex)
+                    .bind(field.to_string())
+                    .bind(&patch.value)
+                    .execute(&self.pool)
+                    .await?;
+            }
+        }
+
+        let q = "INSERT INTO token_metadata_patch (contract_address, token_id_hex, field, value, applied_at) VALUES ($1, $2, $3, $4, $5)";
+        sqlx::query(q)
+            .bind(&patch.contract_address)
+            .bind(&patch.token_id_hex)
+            .bind(patch.field.to_string())
+            .bind(&patch.value)
+            .bind(patch.applied_at as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_metadata_patches(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+    ) -> Result<Vec<MetadataPatchRecord>, StorageError> {
+        let q = "SELECT field, value, applied_at FROM token_metadata_patch WHERE contract_address = $1 AND token_id_hex = $2 ORDER BY applied_at";
+        let rows = sqlx::query(q)
+            .bind(contract_address)
+            .bind(token_id_hex)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter()
+            .map(|r| {
+                let field: String = r.try_get("field")?;
+                let applied_at: i64 = r.try_get("applied_at")?;
+                Ok(MetadataPatchRecord {
+                    contract_address: contract_address.to_string(),
+                    token_id_hex: token_id_hex.to_string(),
+                    field: MetadataField::from_str(&field).map_err(|_| {
+                        StorageError::DatabaseError(format!("Invalid metadata field {}", field))
+                    })?,
+                    value: r.try_get("value")?,
+                    applied_at: applied_at as u64,
+                })
+            })
+            .collect()
+    }
+
+    async fn get_tokens_by_attribute(
+        &self,
+        contract_address: &str,
+        trait_type: &str,
+        value: &str,
+    ) -> Result<Vec<String>, StorageError> {
+        let q = "SELECT token_id_hex FROM token_attribute WHERE contract_address = $1 AND trait_type = $2 AND value = $3 ORDER BY token_id_hex";
+        Ok(sqlx::query_scalar(q)
+            .bind(contract_address)
+            .bind(trait_type)
+            .bind(value)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn get_token(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+    ) -> Result<Option<TokenInfo>, StorageError> {
+        let q = format!(
+            "SELECT {} FROM token WHERE contract_address = $1 AND token_id_hex = $2",
+            TOKEN_COLUMNS
+        );
+
+        sqlx::query(&q)
+            .bind(contract_address)
+            .bind(token_id_hex)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|r| token_from_row(&r))
+            .transpose()
+    }
+
+    async fn set_token_owner(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+        owner: &str,
+    ) -> Result<(), StorageError> {
+        let q = "UPDATE token SET owner = $1 WHERE contract_address = $2 AND token_id_hex = $3";
+        let r = sqlx::query(q)
+            .bind(owner)
+            .bind(contract_address)
+            .bind(token_id_hex)
+            .execute(&self.pool)
+            .await?;
+
+        if r.rows_affected() == 0 {
+            return Err(StorageError::NotFound {
+                entity: "token",
+                key: token_id_hex.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn set_token_burned(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+        is_burned: bool,
+        block_number: Option<u64>,
+    ) -> Result<(), StorageError> {
+        let block_number = block_number.filter(|_| is_burned).map(|n| n as i64);
+
+        let q = "UPDATE token SET is_burned = $1, burned_at_block = $2 WHERE contract_address = $3 AND token_id_hex = $4";
+        let r = sqlx::query(q)
+            .bind(is_burned)
+            .bind(block_number)
+            .bind(contract_address)
+            .bind(token_id_hex)
             .execute(&self.pool)
             .await?;
 
+        if r.rows_affected() == 0 {
+            return Err(StorageError::NotFound {
+                entity: "token",
+                key: token_id_hex.to_string(),
+            });
+        }
+
         Ok(())
     }
 
+    async fn get_tokens_paginated(
+        &self,
+        contract_address: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<TokenInfo>, bool), StorageError> {
+        // One more token is fetched to know if an other page follows.
+        let q = format!(
+            "SELECT {} FROM token WHERE contract_address = $1 AND token_id_hex > $2 ORDER BY token_id_hex LIMIT $3",
+            TOKEN_COLUMNS
+        );
+        let rows = sqlx::query(&q)
+            .bind(contract_address)
+            .bind(after.unwrap_or_default())
+            .bind(limit as i64 + 1)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut tokens = rows
+            .iter()
+            .map(token_from_row)
+            .collect::<Result<Vec<TokenInfo>, StorageError>>()?;
+
+        let has_more = tokens.len() > limit;
+        tokens.truncate(limit);
+
+        Ok((tokens, has_more))
+    }
+
+    async fn get_event_by_tx_hash(
+        &self,
+        transaction_hash: &str,
+    ) -> Result<Vec<TokenEvent>, StorageError> {
+        trace!("Getting events for transaction {}", transaction_hash);
+
+        let q = format!(
+            "SELECT {} FROM token_event WHERE transaction_hash = $1",
+            TRANSFER_EVENT_COLUMNS
+        );
+
+        let rows = sqlx::query(&q)
+            .bind(transaction_hash)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter()
+            .map(|r| transfer_event_from_row(r).map(TokenEvent::Transfer))
+            .collect()
+    }
+
+    async fn get_events_for_token(
+        &self,
+        contract_address: &str,
+        token_id_hex: &str,
+    ) -> Result<Vec<TokenEvent>, StorageError> {
+        trace!(
+            "Getting events for token {} {}",
+            contract_address,
+            token_id_hex
+        );
+
+        // The events are stored with the decimal token id only.
+        let token_id = CairoU256::from_hex_be(token_id_hex)
+            .map_err(|e| {
+                StorageError::DatabaseError(format!("Invalid token id {}: {}", token_id_hex, e))
+            })?
+            .to_decimal(false);
+
+        let q = format!(
+            "SELECT {} FROM token_event WHERE contract_address = $1 AND token_id = $2 ORDER BY block_number IS NULL, block_number, block_timestamp, event_id",
+            TRANSFER_EVENT_COLUMNS
+        );
+
+        let rows = sqlx::query(&q)
+            .bind(contract_address)
+            .bind(token_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter()
+            .map(|r| transfer_event_from_row(r).map(TokenEvent::Transfer))
+            .collect()
+    }
+
     async fn get_contract_type(
         &self,
         contract_address: &str,
         chain_id: &str,
-    ) -> Result<ContractType, StorageError> {
+    ) -> Result<Option<ContractType>, StorageError> {
         trace!("Getting contract info for contract {}", contract_address);
 
-        if let Some(c) = self
+        Ok(self
             .get_contract_by_address(contract_address, chain_id)
             .await?
-        {
-            Ok(ContractType::from_str(&c.contract_type).unwrap())
-        } else {
-            Err(StorageError::NotFound(format!(
-                "contract_address: {contract_address}"
-            )))
-        }
+            .map(|c| ContractType::from_str(&c.contract_type).unwrap()))
     }
 
     async fn register_contract_info(
@@ -291,57 +735,125 @@ impl Storage for DefaultSqlxStorage {
         Ok(())
     }
 
-    async fn set_block_info(
+    async fn set_contract_type(
         &self,
-        block_number: u64,
-        block_timestamp: u64,
-        info: BlockInfo,
+        contract_address: &str,
+        _chain_id: &str,
+        contract_type: &ContractType,
     ) -> Result<(), StorageError> {
-        trace!("Setting block info {:?} for block #{}", info, block_number);
+        trace!(
+            "Setting contract type {} of contract {}",
+            contract_type.to_string(),
+            contract_address
+        );
 
-        let exists = sqlx::query("SELECT 1 FROM indexer WHERE indexer_identifier = $1")
-            .bind(info.indexer_identifier.clone())
-            .fetch_optional(&self.pool)
+        let q = "UPDATE contract SET contract_type = $1 WHERE contract_address = $2";
+        let updated = sqlx::query(q)
+            .bind(contract_type.to_string())
+            .bind(contract_address)
+            .execute(&self.pool)
             .await?
-            .is_some();
+            .rows_affected();
 
-        if !exists {
-            let q = "INSERT INTO indexer (indexer_identifier, indexer_version) VALUES ($1, $2)";
-            sqlx::query(q)
-                .bind(info.indexer_identifier.clone())
-                .bind(info.indexer_version.clone())
-                .execute(&self.pool)
-                .await?;
+        if updated == 0 {
+            return Err(StorageError::NotFound {
+                entity: "contract",
+                key: contract_address.to_string(),
+            });
         }
 
-        let _r = if (self.get_block_by_timestamp(block_timestamp).await?).is_some() {
-            let q = "UPDATE block SET block_number = $1, block_status = $2, indexer_identifier = $3 WHERE block_timestamp = $4";
-            sqlx::query(q)
-                .bind(block_number.to_string())
-                .bind(info.status.to_string())
-                .bind(info.indexer_identifier.clone())
-                .bind(block_timestamp.to_string())
-                .execute(&self.pool)
-                .await?
-        } else {
-            let q = "INSERT INTO block (block_timestamp, block_number, block_status, indexer_identifier) VALUES ($1, $2, $3, $4) ON CONFLICT (block_number) DO NOTHING";
+        Ok(())
+    }
 
-            sqlx::query(q)
-                .bind(block_timestamp.to_string())
-                .bind(block_number.to_string())
-                .bind(info.status.to_string())
-                .bind(info.indexer_identifier.clone())
-                .execute(&self.pool)
-                .await?
+    async fn set_contract_backfill(
+        &self,
+        contract_address: &str,
+        needs_backfill: bool,
+    ) -> Result<(), StorageError> {
+        let q = if needs_backfill {
+            "INSERT INTO contract_backfill (contract_address) VALUES ($1) ON CONFLICT (contract_address) DO NOTHING"
+        } else {
+            "DELETE FROM contract_backfill WHERE contract_address = $1"
         };
 
+        sqlx::query(q)
+            .bind(contract_address)
+            .execute(&self.pool)
+            .await?;
+
         Ok(())
     }
 
-    async fn get_block_info(&self, block_number: u64) -> Result<BlockInfo, StorageError> {
-        trace!("Getting block info for block #{}", block_number);
+    async fn get_contracts_to_backfill(&self) -> Result<Vec<String>, StorageError> {
+        let q = "SELECT contract_address FROM contract_backfill ORDER BY contract_address";
+        Ok(sqlx::query_scalar(q).fetch_all(&self.pool).await?)
+    }
 
-        let q = "SELECT * FROM block WHERE block_number = $1";
+    async fn set_block_info(
+        &self,
+        block_number: u64,
+        block_timestamp: u64,
+        info: BlockInfo,
+    ) -> Result<(), StorageError> {
+        self.write_block_info(block_number, block_timestamp, info, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_block_etag(&self, block_number: u64) -> Result<u64, StorageError> {
+        let q = "SELECT etag FROM block WHERE block_number = $1";
+        let etag: Option<i64> = sqlx::query_scalar(q)
+            .bind(block_number as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(etag.unwrap_or_default() as u64)
+    }
+
+    async fn set_block_info_if_etag(
+        &self,
+        block_number: u64,
+        block_timestamp: u64,
+        info: BlockInfo,
+        expected_etag: u64,
+    ) -> Result<u64, StorageError> {
+        let written = self
+            .write_block_info(block_number, block_timestamp, info, Some(expected_etag))
+            .await?;
+
+        if written > 0 {
+            Ok(expected_etag)
+        } else {
+            self.get_block_etag(block_number).await
+        }
+    }
+
+    async fn set_block_heartbeat(
+        &self,
+        block_number: u64,
+        heartbeat_at: u64,
+    ) -> Result<(), StorageError> {
+        let q = "UPDATE block SET last_heartbeat_at = $1 WHERE block_number = $2";
+        let r = sqlx::query(q)
+            .bind(heartbeat_at as i64)
+            .bind(block_number as i64)
+            .execute(&self.pool)
+            .await?;
+
+        if r.rows_affected() == 0 {
+            return Err(StorageError::NotFound {
+                entity: "block",
+                key: block_number.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn get_block_info(&self, block_number: u64) -> Result<Option<BlockInfo>, StorageError> {
+        trace!("Getting block info for block #{}", block_number);
+
+        let q = "SELECT * FROM block WHERE block_number = $1";
 
         match sqlx::query(q)
             .bind(block_number.to_string())
@@ -350,23 +862,824 @@ impl Storage for DefaultSqlxStorage {
         {
             Ok(rows) => {
                 if rows.is_empty() {
-                    Err(StorageError::NotFound(format!(
-                        "block number {block_number}"
-                    )))
+                    Ok(None)
                 } else {
                     let d = BlockData::from_row(&rows[0])?;
-                    Ok(BlockInfo {
+                    Ok(Some(BlockInfo {
                         indexer_version: d.indexer_version.clone(),
                         indexer_identifier: d.indexer_identifier.clone(),
                         status: BlockIndexingStatus::from_str(&d.status).unwrap(),
                         block_number,
-                    })
+                        selector_hash: d.selector_hash.clone(),
+                        last_heartbeat_at: d.last_heartbeat_at.unwrap_or_default() as u64,
+                    }))
                 }
             }
             Err(e) => Err(StorageError::DatabaseError(e.to_string())),
         }
     }
 
+    async fn get_block_details(
+        &self,
+        block_number: u64,
+    ) -> Result<Option<BlockDetails>, StorageError> {
+        trace!("Getting block details for block #{}", block_number);
+
+        let q = "SELECT *, (SELECT COUNT(*) FROM token_event WHERE token_event.block_number = block.block_number) AS event_count FROM block WHERE block_number = $1";
+
+        let Some(row) = sqlx::query(q)
+            .bind(block_number as i64)
+            .fetch_optional(&self.pool)
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let d = BlockData::from_row(&row)?;
+        let processing_started_at: Option<i64> = row.try_get("processing_started_at")?;
+        let terminated_at: Option<i64> = row.try_get("terminated_at")?;
+        let event_count: i64 = row.try_get("event_count")?;
+
+        Ok(Some(BlockDetails {
+            info: BlockInfo {
+                indexer_version: d.indexer_version,
+                indexer_identifier: d.indexer_identifier,
+                status: BlockIndexingStatus::from_str(&d.status)
+                    .map_err(|_| StorageError::InvalidStatus(d.status.clone()))?,
+                block_number,
+                selector_hash: d.selector_hash,
+                last_heartbeat_at: d.last_heartbeat_at.unwrap_or_default() as u64,
+            },
+            processing_started_at: processing_started_at.map(|t| t as u64),
+            terminated_at: terminated_at.map(|t| t as u64),
+            event_count: event_count as u64,
+        }))
+    }
+
+    async fn get_last_terminated_block(&self) -> Result<Option<(u64, u64)>, StorageError> {
+        trace!("Getting last terminated block");
+
+        let q = "SELECT block_number, block_timestamp FROM block WHERE block_status = $1 ORDER BY block_number DESC LIMIT 1";
+        let block: Option<(i64, i64)> = sqlx::query_as(q)
+            .bind(BlockIndexingStatus::Terminated.to_string())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(block.map(|(n, ts)| (n as u64, ts as u64)))
+    }
+
+    async fn get_processing_blocks(
+        &self,
+        indexer_identifier: &str,
+    ) -> Result<Vec<u64>, StorageError> {
+        trace!(
+            "Getting processing blocks of indexer {}",
+            indexer_identifier
+        );
+
+        let q = "SELECT block_number FROM block WHERE block_status = $1 AND indexer_identifier = $2 ORDER BY block_number";
+        let blocks: Vec<i64> = sqlx::query_scalar(q)
+            .bind(BlockIndexingStatus::Processing.to_string())
+            .bind(indexer_identifier)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(blocks.into_iter().map(|n| n as u64).collect())
+    }
+
+    async fn get_indexer_versions(&self) -> Result<Vec<String>, StorageError> {
+        let q = "SELECT DISTINCT indexer_version FROM block ORDER BY indexer_version";
+        Ok(sqlx::query_scalar(q).fetch_all(&self.pool).await?)
+    }
+
+    async fn register_indexer(&self, info: &IndexerInfo) -> Result<(), StorageError> {
+        let q = "INSERT INTO indexer_heartbeat (indexer_identifier, indexer_version, last_heartbeat, last_block, inactive) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (indexer_identifier) DO UPDATE SET indexer_version = excluded.indexer_version, last_heartbeat = excluded.last_heartbeat, last_block = excluded.last_block, inactive = excluded.inactive";
+        sqlx::query(q)
+            .bind(info.identifier.clone())
+            .bind(info.version.clone())
+            .bind(info.last_heartbeat as i64)
+            .bind(info.last_block.map(|n| n as i64))
+            .bind(info.inactive)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_indexers(&self) -> Result<Vec<IndexerInfo>, StorageError> {
+        let q = "SELECT indexer_identifier, indexer_version, last_heartbeat, last_block, inactive FROM indexer_heartbeat ORDER BY indexer_identifier";
+        let rows = sqlx::query(q).fetch_all(&self.pool).await?;
+
+        rows.iter()
+            .map(|r| {
+                let last_heartbeat: i64 = r.try_get("last_heartbeat")?;
+                let last_block: Option<i64> = r.try_get("last_block")?;
+                Ok(IndexerInfo {
+                    identifier: r.try_get("indexer_identifier")?,
+                    version: r.try_get("indexer_version")?,
+                    last_heartbeat: last_heartbeat as u64,
+                    last_block: last_block.map(|n| n as u64),
+                    inactive: r.try_get("inactive")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn count_processing_blocks(&self) -> Result<u64, StorageError> {
+        let q = "SELECT COUNT(*) FROM block WHERE block_status = $1";
+        let count: i64 = sqlx::query_scalar(q)
+            .bind(BlockIndexingStatus::Processing.to_string())
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count as u64)
+    }
+
+    async fn count_terminated_blocks_in_range(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<u64, StorageError> {
+        let q = "SELECT COUNT(*) FROM block WHERE block_status = $1 AND block_number >= $2 AND block_number <= $3";
+        let count: i64 = sqlx::query_scalar(q)
+            .bind(BlockIndexingStatus::Terminated.to_string())
+            .bind(from_block as i64)
+            .bind(to_block as i64)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count as u64)
+    }
+
+    async fn get_terminated_blocks_in_range(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<u64>, StorageError> {
+        trace!(
+            "Getting terminated blocks from #{} to #{}",
+            from_block,
+            to_block
+        );
+
+        let q = "SELECT block_number FROM block WHERE block_status = $1 AND block_number >= $2 AND block_number <= $3 ORDER BY block_number";
+        let blocks: Vec<i64> = sqlx::query_scalar(q)
+            .bind(BlockIndexingStatus::Terminated.to_string())
+            .bind(from_block as i64)
+            .bind(to_block as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(blocks.into_iter().map(|n| n as u64).collect())
+    }
+
+    async fn get_processing_blocks_started_before(
+        &self,
+        started_before_ms: u64,
+    ) -> Result<Vec<u64>, StorageError> {
+        trace!(
+            "Getting processing blocks started before {}",
+            started_before_ms
+        );
+
+        let q = "SELECT block_number FROM block WHERE block_status = $1 AND processing_started_at < $2 ORDER BY block_number";
+        let blocks: Vec<i64> = sqlx::query_scalar(q)
+            .bind(BlockIndexingStatus::Processing.to_string())
+            .bind(started_before_ms as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(blocks.into_iter().map(|n| n as u64).collect())
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn count_block_events(&self, block_number: u64) -> Result<u64, StorageError> {
+        trace!("Counting events for block #{}", block_number);
+
+        let q = "SELECT COUNT(*) FROM token_event WHERE block_number = $1";
+        let count: i64 = sqlx::query_scalar(q)
+            .bind(block_number as i64)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count as u64)
+    }
+
+    async fn get_block_event_ids(&self, block_number: u64) -> Result<Vec<String>, StorageError> {
+        trace!("Getting event ids for block #{}", block_number);
+
+        let q = "SELECT event_id FROM token_event WHERE block_number = $1 ORDER BY event_id";
+        Ok(sqlx::query_scalar(q)
+            .bind(block_number as i64)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn count_events_by_block(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<(u64, u64)>, StorageError> {
+        let q = "SELECT block_number, COUNT(*) AS event_count FROM token_event WHERE block_number >= $1 AND block_number <= $2 GROUP BY block_number ORDER BY block_number";
+        let rows = sqlx::query(q)
+            .bind(from_block as i64)
+            .bind(to_block as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter()
+            .map(|r| {
+                let block_number: i64 = r.try_get("block_number")?;
+                let event_count: i64 = r.try_get("event_count")?;
+                Ok((block_number as u64, event_count as u64))
+            })
+            .collect()
+    }
+
+    async fn get_block_transfer_events(
+        &self,
+        block_number: u64,
+    ) -> Result<Vec<TokenTransferEvent>, StorageError> {
+        trace!("Getting transfer events for block #{}", block_number);
+
+        let q = format!(
+            "SELECT {} FROM token_event WHERE block_number = $1 ORDER BY event_id",
+            TRANSFER_EVENT_COLUMNS
+        );
+        let rows = sqlx::query(&q)
+            .bind(block_number as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter().map(transfer_event_from_row).collect()
+    }
+
+    async fn get_contract_transfer_events(
+        &self,
+        contract_address: &str,
+        limit: usize,
+    ) -> Result<Vec<TokenTransferEvent>, StorageError> {
+        trace!(
+            "Getting {} transfer events of contract {}",
+            limit,
+            contract_address
+        );
+
+        let q = format!(
+            "SELECT {}, token_id_hex FROM token_event WHERE contract_address = $1 ORDER BY event_id LIMIT $2",
+            TRANSFER_EVENT_COLUMNS
+        );
+        let rows = sqlx::query(&q)
+            .bind(contract_address)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter()
+            .map(|r| {
+                Ok(TokenTransferEvent {
+                    token_id_hex: r.try_get("token_id_hex")?,
+                    ..transfer_event_from_row(r)?
+                })
+            })
+            .collect()
+    }
+
+    async fn count_transfers_in_blocks(
+        &self,
+        contract_address: &str,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<u64, StorageError> {
+        let q = "SELECT COUNT(*) FROM token_event WHERE contract_address = $1 AND block_number BETWEEN $2 AND $3";
+        let count: i64 = sqlx::query_scalar(q)
+            .bind(contract_address)
+            .bind(from_block as i64)
+            .bind(to_block as i64)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count as u64)
+    }
+
+    async fn count_transfer_events(&self) -> Result<u64, StorageError> {
+        let q = "SELECT COUNT(*) FROM token_event";
+        let count: i64 = sqlx::query_scalar(q).fetch_one(&self.pool).await?;
+
+        Ok(count as u64)
+    }
+
+    async fn get_transfer_events_after(
+        &self,
+        after_event_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<TokenTransferEvent>, StorageError> {
+        trace!(
+            "Getting {} transfer events after {:?}",
+            limit,
+            after_event_id
+        );
+
+        let q = format!(
+            "SELECT {}, token_id_hex FROM token_event WHERE $1 IS NULL OR event_id > $1 ORDER BY event_id LIMIT $2",
+            TRANSFER_EVENT_COLUMNS
+        );
+        let rows = sqlx::query(&q)
+            .bind(after_event_id)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter()
+            .map(|r| {
+                Ok(TokenTransferEvent {
+                    token_id_hex: r.try_get("token_id_hex")?,
+                    ..transfer_event_from_row(r)?
+                })
+            })
+            .collect()
+    }
+
+    async fn count_transfers_in_range(
+        &self,
+        contract_address: &str,
+        from_ts: u64,
+        to_ts: u64,
+    ) -> Result<u64, StorageError> {
+        let q = "SELECT COUNT(*) FROM token_event WHERE contract_address = $1 AND block_timestamp >= $2 AND block_timestamp < $3";
+        let count: i64 = sqlx::query_scalar(q)
+            .bind(contract_address)
+            .bind(from_ts as i64)
+            .bind(to_ts as i64)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count as u64)
+    }
+
+    async fn sum_transfer_volume_in_range(
+        &self,
+        contract_address: &str,
+        from_ts: u64,
+        to_ts: u64,
+    ) -> Result<u128, StorageError> {
+        let q = "SELECT CAST(COALESCE(SUM(quantity), 0) AS BIGINT) FROM token_event WHERE contract_address = $1 AND block_timestamp >= $2 AND block_timestamp < $3";
+        let volume: i64 = sqlx::query_scalar(q)
+            .bind(contract_address)
+            .bind(from_ts as i64)
+            .bind(to_ts as i64)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(volume as u128)
+    }
+
+    async fn finalize_pending_events(
+        &self,
+        block_timestamp: u64,
+        block_number: u64,
+    ) -> Result<(), StorageError> {
+        trace!(
+            "Finalizing pending events [ts: {}] as block #{}",
+            block_timestamp,
+            block_number
+        );
+
+        let q = "UPDATE token_event SET block_number = $1 WHERE block_timestamp = $2 AND block_number IS NULL";
+        sqlx::query(q)
+            .bind(block_number as i64)
+            .bind(block_timestamp as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn adjust_collection_supply(
+        &self,
+        contract_address: &str,
+        delta: i64,
+    ) -> Result<(), StorageError> {
+        trace!("Adjusting supply of {} by {}", contract_address, delta);
+
+        let q = "INSERT INTO collection_supply (contract_address, supply) VALUES ($1, $2) ON CONFLICT (contract_address) DO UPDATE SET supply = collection_supply.supply + excluded.supply";
+        sqlx::query(q)
+            .bind(contract_address)
+            .bind(delta)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn recount_collection_supply(&self, contract_address: &str) -> Result<i64, StorageError> {
+        trace!("Recounting supply of {}", contract_address);
+
+        let q = format!(
+            "SELECT {} FROM token_event WHERE contract_address = $1",
+            TRANSFER_EVENT_COLUMNS
+        );
+        let rows = sqlx::query(&q)
+            .bind(contract_address)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut supply: i64 = 0;
+        for r in rows.iter() {
+            supply += transfer_event_from_row(r)?.supply_delta();
+        }
+
+        let q = "INSERT INTO collection_supply (contract_address, supply) VALUES ($1, $2) ON CONFLICT (contract_address) DO UPDATE SET supply = excluded.supply";
+        sqlx::query(q)
+            .bind(contract_address)
+            .bind(supply)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(supply)
+    }
+
+    async fn count_collection_tokens(
+        &self,
+        contract_address: &str,
+    ) -> Result<(u64, u64), StorageError> {
+        let q = "SELECT COUNT(*) AS tokens, COUNT(DISTINCT owner) AS holders FROM token WHERE contract_address = $1 AND is_burned = FALSE";
+        let row = sqlx::query(q)
+            .bind(contract_address)
+            .fetch_one(&self.pool)
+            .await?;
+
+        let tokens: i64 = row.try_get("tokens")?;
+        let holders: i64 = row.try_get("holders")?;
+        Ok((tokens as u64, holders as u64))
+    }
+
+    async fn upsert_collection_stats(
+        &self,
+        contract_address: &str,
+        stats: &CollectionStats,
+    ) -> Result<(), StorageError> {
+        trace!("Updating stats of {}: {:?}", contract_address, stats);
+
+        let q = "INSERT INTO collection_stats (contract_address, total_tokens, unique_holders, total_transfers, last_updated_block) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (contract_address) DO UPDATE SET total_tokens = excluded.total_tokens, unique_holders = excluded.unique_holders, total_transfers = excluded.total_transfers, last_updated_block = excluded.last_updated_block";
+        sqlx::query(q)
+            .bind(contract_address)
+            .bind(stats.total_tokens as i64)
+            .bind(stats.unique_holders as i64)
+            .bind(stats.total_transfers as i64)
+            .bind(stats.last_updated_block as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_collection_stats(
+        &self,
+        contract_address: &str,
+    ) -> Result<Option<CollectionStats>, StorageError> {
+        let q = "SELECT total_tokens, unique_holders, total_transfers, last_updated_block FROM collection_stats WHERE contract_address = $1";
+        let row = sqlx::query(q)
+            .bind(contract_address)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        row.map(|r| {
+            Ok(CollectionStats {
+                total_tokens: r.try_get::<i64, _>("total_tokens")? as u64,
+                unique_holders: r.try_get::<i64, _>("unique_holders")? as u64,
+                total_transfers: r.try_get::<i64, _>("total_transfers")? as u64,
+                last_updated_block: r.try_get::<i64, _>("last_updated_block")? as u64,
+            })
+        })
+        .transpose()
+    }
+
+    async fn get_total_supply(&self, contract_address: &str) -> Result<Option<u64>, StorageError> {
+        let q = "SELECT total_supply FROM collection_supply WHERE contract_address = $1";
+        let total_supply: Option<Option<i64>> = sqlx::query_scalar(q)
+            .bind(contract_address)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(total_supply.flatten().map(|s| s as u64))
+    }
+
+    async fn set_total_supply(
+        &self,
+        contract_address: &str,
+        total_supply: u64,
+    ) -> Result<(), StorageError> {
+        trace!(
+            "Setting total supply of {} to {}",
+            contract_address,
+            total_supply
+        );
+
+        let q = "INSERT INTO collection_supply (contract_address, total_supply) VALUES ($1, $2) ON CONFLICT (contract_address) DO UPDATE SET total_supply = excluded.total_supply";
+        sqlx::query(q)
+            .bind(contract_address)
+            .bind(total_supply as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn purge_contract_range(
+        &self,
+        contract_address: &str,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<PurgedItems, StorageError> {
+        trace!(
+            "Purging contract {} [blocks: {} - {}]",
+            contract_address,
+            from_block,
+            to_block
+        );
+
+        let mut tx = self.pool.begin().await?;
+
+        // Reverse the supply variations of the events being removed.
+        let q = format!(
+            "SELECT {} FROM token_event WHERE contract_address = $1 AND block_number BETWEEN $2 AND $3",
+            TRANSFER_EVENT_COLUMNS
+        );
+        let rows = sqlx::query(&q)
+            .bind(contract_address)
+            .bind(from_block as i64)
+            .bind(to_block as i64)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        let mut delta: i64 = 0;
+        for r in rows.iter() {
+            delta -= transfer_event_from_row(r)?.supply_delta();
+        }
+
+        if delta != 0 {
+            let q = "INSERT INTO collection_supply (contract_address, supply) VALUES ($1, $2) ON CONFLICT (contract_address) DO UPDATE SET supply = collection_supply.supply + excluded.supply";
+            sqlx::query(q)
+                .bind(contract_address)
+                .bind(delta)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        // The tokens minted by the events removed, before removing them.
+        let q = "DELETE FROM token WHERE contract_address = $1 AND token_id_hex IN (SELECT token_id_hex FROM token_event WHERE contract_address = $1 AND event_type = $2 AND block_number BETWEEN $3 AND $4)";
+        let tokens = sqlx::query(q)
+            .bind(contract_address)
+            .bind(EventType::Mint.to_string())
+            .bind(from_block as i64)
+            .bind(to_block as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        let q = "DELETE FROM token_event WHERE contract_address = $1 AND block_number BETWEEN $2 AND $3";
+        let events = sqlx::query(q)
+            .bind(contract_address)
+            .bind(from_block as i64)
+            .bind(to_block as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(PurgedItems { events, tokens })
+    }
+
+    async fn get_reindex_cursor(
+        &self,
+        contract_address: &str,
+    ) -> Result<Option<u64>, StorageError> {
+        let q = "SELECT block_number FROM reindex_cursor WHERE contract_address = $1";
+        let cursor: Option<i64> = sqlx::query_scalar(q)
+            .bind(contract_address)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(cursor.map(|n| n as u64))
+    }
+
+    async fn set_reindex_cursor(
+        &self,
+        contract_address: &str,
+        block_number: Option<u64>,
+    ) -> Result<(), StorageError> {
+        match block_number {
+            Some(n) => {
+                let q = "INSERT INTO reindex_cursor (contract_address, block_number) VALUES ($1, $2) ON CONFLICT (contract_address) DO UPDATE SET block_number = excluded.block_number";
+                sqlx::query(q)
+                    .bind(contract_address)
+                    .bind(n as i64)
+                    .execute(&self.pool)
+                    .await?;
+            }
+            None => {
+                sqlx::query("DELETE FROM reindex_cursor WHERE contract_address = $1")
+                    .bind(contract_address)
+                    .execute(&self.pool)
+                    .await?;
+            }
+        };
+
+        Ok(())
+    }
+
+    async fn get_class_hash_type(
+        &self,
+        class_hash: &str,
+    ) -> Result<Option<ContractType>, StorageError> {
+        let q = "SELECT contract_type FROM class_hash_type WHERE class_hash = $1";
+        let contract_type: Option<String> = sqlx::query_scalar(q)
+            .bind(class_hash)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(contract_type.map(|t| ContractType::from_str(&t).unwrap()))
+    }
+
+    async fn set_class_hash_type(
+        &self,
+        class_hash: &str,
+        contract_type: Option<ContractType>,
+    ) -> Result<(), StorageError> {
+        match contract_type {
+            Some(t) => {
+                let q = "INSERT INTO class_hash_type (class_hash, contract_type) VALUES ($1, $2) ON CONFLICT (class_hash) DO UPDATE SET contract_type = excluded.contract_type";
+                sqlx::query(q)
+                    .bind(class_hash)
+                    .bind(t.to_string())
+                    .execute(&self.pool)
+                    .await?;
+            }
+            None => {
+                sqlx::query("DELETE FROM class_hash_type WHERE class_hash = $1")
+                    .bind(class_hash)
+                    .execute(&self.pool)
+                    .await?;
+            }
+        };
+
+        Ok(())
+    }
+
+    async fn register_contract_type_override(
+        &self,
+        address: FieldElement,
+        contract_type: ContractType,
+    ) -> Result<(), StorageError> {
+        let q = "INSERT INTO contract_overrides (contract_address, contract_type) VALUES ($1, $2) ON CONFLICT (contract_address) DO UPDATE SET contract_type = excluded.contract_type";
+        sqlx::query(q)
+            .bind(to_hex_str(&address))
+            .bind(contract_type.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_contract_type_overrides(
+        &self,
+    ) -> Result<Vec<(String, ContractType)>, StorageError> {
+        let q = "SELECT contract_address, contract_type FROM contract_overrides ORDER BY contract_address";
+        let overrides: Vec<(String, String)> = sqlx::query_as(q).fetch_all(&self.pool).await?;
+
+        Ok(overrides
+            .into_iter()
+            .map(|(a, t)| (a, ContractType::from_str(&t).unwrap()))
+            .collect())
+    }
+
+    async fn register_failed_event(&self, event: &FailedEvent) -> Result<(), StorageError> {
+        trace!("Registering failed event {:?}", event);
+
+        let exists = sqlx::query("SELECT 1 FROM failed_event WHERE id = $1")
+            .bind(event.id.clone())
+            .fetch_optional(&self.pool)
+            .await?
+            .is_some();
+
+        if exists {
+            return Err(StorageError::AlreadyExists(format!(
+                "failed event id = {}",
+                event.id
+            )));
+        }
+
+        let q = "INSERT INTO failed_event (id, contract_address, transaction_hash, chain_id, block_timestamp, payload, error) VALUES ($1, $2, $3, $4, $5, $6, $7)";
+        sqlx::query(q)
+            .bind(event.id.clone())
+            .bind(event.contract_address.clone())
+            .bind(event.transaction_hash.clone())
+            .bind(event.chain_id.clone())
+            .bind(event.block_timestamp as i64)
+            .bind(event.payload.clone())
+            .bind(event.error.clone())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_failed_events(&self, limit: usize) -> Result<Vec<FailedEvent>, StorageError> {
+        let q = "SELECT id, contract_address, transaction_hash, chain_id, block_timestamp, payload, error FROM failed_event ORDER BY block_timestamp LIMIT $1";
+        let rows = sqlx::query(q)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter()
+            .map(|r| {
+                let block_timestamp: i64 = r.try_get("block_timestamp")?;
+                Ok(FailedEvent {
+                    id: r.try_get("id")?,
+                    contract_address: r.try_get("contract_address")?,
+                    transaction_hash: r.try_get("transaction_hash")?,
+                    chain_id: r.try_get("chain_id")?,
+                    block_timestamp: block_timestamp as u64,
+                    payload: r.try_get("payload")?,
+                    error: r.try_get("error")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn remove_failed_event(&self, id: &str) -> Result<(), StorageError> {
+        sqlx::query("DELETE FROM failed_event WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn register_quarantined_event(
+        &self,
+        event: &QuarantinedEvent,
+    ) -> Result<(), StorageError> {
+        trace!("Quarantining event {:?}", event);
+
+        let q = "INSERT INTO quarantined_event (id, emitter_address, transaction_hash, chain_id, block_timestamp, payload, rule, reason) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (id) DO UPDATE SET rule = excluded.rule, reason = excluded.reason";
+        sqlx::query(q)
+            .bind(event.id.clone())
+            .bind(event.emitter_address.clone())
+            .bind(event.transaction_hash.clone())
+            .bind(event.chain_id.clone())
+            .bind(event.block_timestamp as i64)
+            .bind(event.payload.clone())
+            .bind(event.rule.clone())
+            .bind(event.reason.clone())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_quarantined_events(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<QuarantinedEvent>, StorageError> {
+        let q = "SELECT id, emitter_address, transaction_hash, chain_id, block_timestamp, payload, rule, reason FROM quarantined_event ORDER BY block_timestamp LIMIT $1";
+        let rows = sqlx::query(q)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter()
+            .map(|r| {
+                let block_timestamp: i64 = r.try_get("block_timestamp")?;
+                Ok(QuarantinedEvent {
+                    id: r.try_get("id")?,
+                    emitter_address: r.try_get("emitter_address")?,
+                    transaction_hash: r.try_get("transaction_hash")?,
+                    chain_id: r.try_get("chain_id")?,
+                    block_timestamp: block_timestamp as u64,
+                    payload: r.try_get("payload")?,
+                    rule: r.try_get("rule")?,
+                    reason: r.try_get("reason")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn remove_quarantined_event(&self, id: &str) -> Result<(), StorageError> {
+        sqlx::query("DELETE FROM quarantined_event WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
     async fn clean_block(
         &self,
         block_timestamp: u64,
@@ -383,6 +1696,29 @@ impl Storage for DefaultSqlxStorage {
             .fetch_all(&self.pool)
             .await?;
 
+        // Reverse the supply variations of the events being removed.
+        let q = format!(
+            "SELECT {} FROM token_event WHERE block_timestamp = $1::bigint",
+            TRANSFER_EVENT_COLUMNS
+        );
+        let rows = sqlx::query(&q)
+            .bind(block_timestamp.to_string())
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut deltas: HashMap<String, i64> = HashMap::new();
+        for r in rows.iter() {
+            let event = transfer_event_from_row(r)?;
+            *deltas.entry(event.contract_address.clone()).or_default() -= event.supply_delta();
+        }
+
+        for (contract_address, delta) in deltas {
+            if delta != 0 {
+                self.adjust_collection_supply(&contract_address, delta)
+                    .await?;
+            }
+        }
+
         let q = "DELETE FROM token_event WHERE block_timestamp = $1::bigint";
         sqlx::query(q)
             .bind(block_timestamp.to_string())
@@ -391,4 +1727,321 @@ impl Storage for DefaultSqlxStorage {
 
         Ok(())
     }
+
+    async fn prune_blocks_before(&self, block_number: u64) -> Result<usize, StorageError> {
+        trace!("Pruning blocks before #{}", block_number);
+
+        let q = "DELETE FROM block WHERE block_number < $1";
+        let blocks = sqlx::query(q)
+            .bind(block_number as i64)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        let q = "DELETE FROM token_event WHERE block_number < $1";
+        sqlx::query(q)
+            .bind(block_number as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(blocks as usize)
+    }
+
+    async fn rollback_to_block(
+        &self,
+        last_valid_block: u64,
+    ) -> Result<RollbackStats, StorageError> {
+        trace!("Rolling back to block #{}", last_valid_block);
+
+        let mut tx = self.pool.begin().await?;
+
+        let q = format!(
+            "SELECT {}, token_id_hex FROM token_event WHERE block_number > $1",
+            TRANSFER_EVENT_COLUMNS
+        );
+        let rows = sqlx::query(&q)
+            .bind(last_valid_block as i64)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        let mut deltas: HashMap<String, i64> = HashMap::new();
+        let mut affected: BTreeSet<(String, String)> = BTreeSet::new();
+        for r in rows.iter() {
+            let event = transfer_event_from_row(r)?;
+            *deltas.entry(event.contract_address.clone()).or_default() -= event.supply_delta();
+            affected.insert((event.contract_address, r.try_get("token_id_hex")?));
+        }
+
+        let mut stats = RollbackStats::default();
+
+        let q = "DELETE FROM token_event WHERE block_number > $1";
+        stats.events_removed = sqlx::query(q)
+            .bind(last_valid_block as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        for (contract_address, token_id_hex) in affected {
+            // The pending events are the most recent ones.
+            let q = "SELECT to_address FROM token_event WHERE contract_address = $1 AND token_id_hex = $2 ORDER BY (block_number IS NULL) DESC, block_number DESC, block_timestamp DESC LIMIT 1";
+            let last_owner: Option<String> = sqlx::query_scalar(q)
+                .bind(&contract_address)
+                .bind(&token_id_hex)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+            let result = match last_owner {
+                None => {
+                    let q = "DELETE FROM token WHERE contract_address = $1 AND token_id_hex = $2";
+                    sqlx::query(q)
+                        .bind(&contract_address)
+                        .bind(&token_id_hex)
+                        .execute(&mut *tx)
+                        .await?
+                }
+                Some(owner) => {
+                    let q = "UPDATE token SET owner = $1, is_burned = CASE WHEN burned_at_block > $2 THEN FALSE ELSE is_burned END, burned_at_block = CASE WHEN burned_at_block > $2 THEN NULL ELSE burned_at_block END WHERE contract_address = $3 AND token_id_hex = $4";
+                    sqlx::query(q)
+                        .bind(owner)
+                        .bind(last_valid_block as i64)
+                        .bind(&contract_address)
+                        .bind(&token_id_hex)
+                        .execute(&mut *tx)
+                        .await?
+                }
+            };
+            stats.tokens_affected += result.rows_affected();
+        }
+
+        for (contract_address, delta) in deltas {
+            if delta != 0 {
+                let q = "INSERT INTO collection_supply (contract_address, supply) VALUES ($1, $2) ON CONFLICT (contract_address) DO UPDATE SET supply = collection_supply.supply + excluded.supply";
+                sqlx::query(q)
+                    .bind(contract_address)
+                    .bind(delta)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+
+        let q = "DELETE FROM block WHERE block_number > $1";
+        stats.blocks_removed = sqlx::query(q)
+            .bind(last_valid_block as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(stats)
+    }
+
+    async fn export_rows(
+        &self,
+        table: ExportTable,
+        after: Option<&ExportKey>,
+        limit: usize,
+    ) -> Result<Vec<Vec<String>>, StorageError> {
+        // The pages start after the sort key of the previous page, for each
+        // page to be read from the index instead of skipping the previous rows.
+        // The sales are not stored: the events are the transfers.
+        let (select, filter, order) = match table {
+            ExportTable::Tokens => (
+                format!("SELECT {} FROM token", TOKEN_COLUMNS),
+                "(contract_address, token_id_hex) > ($2, $3)",
+                "contract_address, token_id_hex",
+            ),
+            ExportTable::Events | ExportTable::Transfers => (
+                format!("SELECT {} FROM token_event", TRANSFER_EVENT_COLUMNS),
+                "event_id > $2",
+                "event_id",
+            ),
+            ExportTable::Blocks => (
+                "SELECT * FROM block".to_string(),
+                "block_number > $2",
+                "block_number",
+            ),
+        };
+        let q = match after {
+            Some(_) => format!("{} WHERE {} ORDER BY {} LIMIT $1", select, filter, order),
+            None => format!("{} ORDER BY {} LIMIT $1", select, order),
+        };
+
+        let query = sqlx::query(&q).bind(limit as i64);
+        let query = match (table, after) {
+            (_, None) => query,
+            (
+                ExportTable::Tokens,
+                Some(ExportKey::Token {
+                    contract_address,
+                    token_id_hex,
+                }),
+            ) => query.bind(contract_address).bind(token_id_hex),
+            (ExportTable::Events | ExportTable::Transfers, Some(ExportKey::Event(event_id))) => {
+                query.bind(event_id)
+            }
+            (ExportTable::Blocks, Some(ExportKey::Block(block_number))) => {
+                query.bind(*block_number as i64)
+            }
+            (_, Some(key)) => {
+                return Err(StorageError::ExportError(format!(
+                    "Key {:?} is not a key of the table {:?}",
+                    key, table
+                )))
+            }
+        };
+        let rows = query.fetch_all(&self.pool).await?;
+
+        rows.iter()
+            .map(|r| match table {
+                ExportTable::Tokens => token_from_row(r).map(|t| token_row(&t)),
+                ExportTable::Events | ExportTable::Transfers => {
+                    transfer_event_from_row(r).map(|e| transfer_row(table, &e))
+                }
+                ExportTable::Blocks => {
+                    let d = BlockData::from_row(r)?;
+                    let info = BlockInfo {
+                        indexer_version: d.indexer_version,
+                        indexer_identifier: d.indexer_identifier,
+                        status: BlockIndexingStatus::from_str(&d.status)
+                            .unwrap_or(BlockIndexingStatus::None),
+                        block_number: d.number as u64,
+                        selector_hash: d.selector_hash,
+                        last_heartbeat_at: d.last_heartbeat_at.unwrap_or_default() as u64,
+                    };
+                    Ok(block_row(d.timestamp as u64, &info))
+                }
+            })
+            .collect()
+    }
+
+    async fn vacuum(&self) -> Result<VacuumStats, StorageError> {
+        trace!("Vacuuming the database");
+
+        // The records are deleted at once: only the space of the deleted rows
+        // is reclaimed, which size is not reported by the databases.
+        sqlx::query("VACUUM").execute(&self.pool).await?;
+
+        Ok(VacuumStats::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn migrated_storage() -> DefaultSqlxStorage {
+        sqlx::any::install_default_drivers();
+
+        let storage = DefaultSqlxStorage::new_any("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!("src/storage/sqlx/migrations")
+            .run(storage.get_pool_ref())
+            .await
+            .unwrap();
+
+        storage
+    }
+
+    fn event(event_id: &str, transaction_hash: &str) -> TokenTransferEvent {
+        TokenTransferEvent {
+            timestamp: 1_700_000_000,
+            from_address: "0x0".to_string(),
+            to_address: "0x1234".to_string(),
+            contract_address: "0x5678".to_string(),
+            contract_type: "ERC721".to_string(),
+            transaction_hash: transaction_hash.to_string(),
+            token_id: "1".to_string(),
+            token_id_hex: "0x1".to_string(),
+            event_type: EventType::Mint,
+            event_id: event_id.to_string(),
+            block_number: Some(10),
+            quantity: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_event_by_tx_hash_after_migrations() {
+        let storage = migrated_storage().await;
+
+        for (event_id, transaction_hash) in
+            [("0xe1", "0xaaaa"), ("0xe2", "0xaaaa"), ("0xe3", "0xbbbb")]
+        {
+            storage
+                .register_transfer_event(&event(event_id, transaction_hash), 0)
+                .await
+                .unwrap();
+        }
+
+        let mut events: Vec<TokenTransferEvent> = storage
+            .get_event_by_tx_hash("0xaaaa")
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|e| match e {
+                TokenEvent::Transfer(t) => Some(t),
+                _ => None,
+            })
+            .collect();
+        events.sort_by(|a, b| a.event_id.cmp(&b.event_id));
+
+        assert_eq!(events.len(), 2);
+        for (stored, event_id) in events.iter().zip(["0xe1", "0xe2"]) {
+            let expected = event(event_id, "0xaaaa");
+            assert_eq!(stored.event_id, expected.event_id);
+            assert_eq!(stored.timestamp, expected.timestamp);
+            assert_eq!(stored.to_address, expected.to_address);
+            assert_eq!(stored.event_type, expected.event_type);
+            assert_eq!(stored.block_number, expected.block_number);
+            assert_eq!(stored.quantity, expected.quantity);
+        }
+
+        // The event id is the primary key of the renamed table.
+        assert!(matches!(
+            storage
+                .register_transfer_event(&event("0xe1", "0xaaaa"), 0)
+                .await,
+            Err(StorageError::AlreadyExists(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_export_rows_pages_after_key() {
+        let storage = migrated_storage().await;
+
+        for event_id in ["0xe3", "0xe1", "0xe2"] {
+            storage
+                .register_transfer_event(&event(event_id, "0xaaaa"), 0)
+                .await
+                .unwrap();
+        }
+
+        let mut pages = vec![];
+        let mut after = None;
+        loop {
+            let rows = storage
+                .export_rows(ExportTable::Transfers, after.as_ref(), 2)
+                .await
+                .unwrap();
+            let Some(last) = rows.last() else {
+                break;
+            };
+            after = Some(ExportKey::Event(last[0].clone()));
+            pages.push(rows.iter().map(|r| r[0].clone()).collect::<Vec<_>>());
+        }
+        assert_eq!(pages, vec![vec!["0xe1", "0xe2"], vec!["0xe3"]]);
+
+        assert!(matches!(
+            storage
+                .export_rows(
+                    ExportTable::Blocks,
+                    Some(&ExportKey::Event("0xe1".to_string())),
+                    2
+                )
+                .await,
+            Err(StorageError::ExportError(_))
+        ));
+    }
 }
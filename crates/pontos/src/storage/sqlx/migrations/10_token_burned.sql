@@ -0,0 +1,2 @@
+ALTER TABLE token ADD COLUMN is_burned BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE token ADD COLUMN burned_at_block BIGINT;
@@ -0,0 +1 @@
+ALTER TABLE block ADD COLUMN processing_started_at BIGINT;
@@ -0,0 +1,5 @@
+CREATE TABLE contract_backfill (
+       contract_address TEXT NOT NULL,
+
+       PRIMARY KEY (contract_address)
+);
@@ -0,0 +1,18 @@
+CREATE TABLE token_metadata (
+       contract_address TEXT NOT NULL,
+       token_id_hex TEXT NOT NULL,
+       field TEXT NOT NULL,
+       value TEXT NOT NULL,
+
+       PRIMARY KEY (contract_address, token_id_hex, field)
+);
+
+CREATE TABLE token_metadata_patch (
+       contract_address TEXT NOT NULL,
+       token_id_hex TEXT NOT NULL,
+       field TEXT NOT NULL,
+       value TEXT NOT NULL,
+       applied_at BIGINT NOT NULL
+);
+
+CREATE INDEX token_metadata_patch_token_idx ON token_metadata_patch (contract_address, token_id_hex);
@@ -0,0 +1 @@
+ALTER TABLE block ADD COLUMN selector_hash TEXT;
@@ -0,0 +1,6 @@
+CREATE TABLE contract_overrides (
+       contract_address TEXT NOT NULL,
+       contract_type TEXT NOT NULL,
+
+       PRIMARY KEY (contract_address)
+);
@@ -0,0 +1,12 @@
+CREATE TABLE quarantined_event (
+       id TEXT NOT NULL,
+       emitter_address TEXT NOT NULL,
+       transaction_hash TEXT NOT NULL,
+       chain_id TEXT NOT NULL,
+       block_timestamp BIGINT NOT NULL,
+       payload TEXT NOT NULL,
+       rule TEXT NOT NULL,
+       reason TEXT NOT NULL,
+
+       PRIMARY KEY (id)
+);
@@ -0,0 +1 @@
+ALTER TABLE block ADD COLUMN last_heartbeat_at BIGINT;
@@ -0,0 +1,8 @@
+CREATE TABLE indexer_heartbeat (
+       indexer_identifier TEXT NOT NULL,
+       indexer_version TEXT NOT NULL,
+       last_heartbeat BIGINT NOT NULL,
+       last_block BIGINT,
+
+       PRIMARY KEY (indexer_identifier)
+);
@@ -0,0 +1 @@
+ALTER TABLE indexer_heartbeat ADD COLUMN inactive BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+ALTER TABLE event ADD COLUMN block_number BIGINT;
@@ -0,0 +1 @@
+CREATE INDEX event_token_idx ON event (contract_address, token_id, block_number, block_timestamp);
@@ -0,0 +1,9 @@
+CREATE TABLE collection_stats (
+       contract_address TEXT NOT NULL,
+       total_tokens BIGINT NOT NULL DEFAULT 0,
+       unique_holders BIGINT NOT NULL DEFAULT 0,
+       total_transfers BIGINT NOT NULL DEFAULT 0,
+       last_updated_block BIGINT NOT NULL DEFAULT 0,
+
+       PRIMARY KEY (contract_address)
+);
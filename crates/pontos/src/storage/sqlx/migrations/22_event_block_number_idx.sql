@@ -0,0 +1 @@
+CREATE INDEX event_block_number_idx ON event (block_number);
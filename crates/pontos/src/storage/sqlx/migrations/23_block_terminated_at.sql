@@ -0,0 +1 @@
+ALTER TABLE block ADD COLUMN terminated_at BIGINT;
@@ -0,0 +1 @@
+ALTER TABLE block ADD COLUMN etag BIGINT NOT NULL DEFAULT 0;
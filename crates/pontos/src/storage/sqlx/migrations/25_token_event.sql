@@ -0,0 +1 @@
+ALTER TABLE event RENAME TO token_event;
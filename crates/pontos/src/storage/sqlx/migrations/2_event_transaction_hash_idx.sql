@@ -0,0 +1 @@
+CREATE INDEX event_transaction_hash_idx ON event (transaction_hash);
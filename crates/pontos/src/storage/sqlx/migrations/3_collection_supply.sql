@@ -0,0 +1,8 @@
+ALTER TABLE event ADD COLUMN quantity BIGINT NOT NULL DEFAULT 1;
+
+CREATE TABLE collection_supply (
+       contract_address TEXT NOT NULL,
+       supply BIGINT NOT NULL DEFAULT 0,
+
+       PRIMARY KEY (contract_address)
+);
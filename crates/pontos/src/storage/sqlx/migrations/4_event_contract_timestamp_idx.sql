@@ -0,0 +1 @@
+CREATE INDEX event_contract_timestamp_idx ON event (contract_address, block_timestamp);
@@ -0,0 +1,11 @@
+CREATE TABLE failed_event (
+       id TEXT NOT NULL,
+       contract_address TEXT NOT NULL,
+       transaction_hash TEXT NOT NULL,
+       chain_id TEXT NOT NULL,
+       block_timestamp BIGINT NOT NULL,
+       payload TEXT NOT NULL,
+       error TEXT NOT NULL,
+
+       PRIMARY KEY (id)
+);
@@ -0,0 +1,10 @@
+CREATE TABLE token_attribute (
+       contract_address TEXT NOT NULL,
+       token_id_hex TEXT NOT NULL,
+       trait_type TEXT NOT NULL,
+       value TEXT NOT NULL,
+
+       PRIMARY KEY (contract_address, token_id_hex, trait_type)
+);
+
+CREATE INDEX token_attribute_value_idx ON token_attribute (contract_address, trait_type, value);
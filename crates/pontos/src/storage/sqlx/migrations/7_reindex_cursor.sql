@@ -0,0 +1,6 @@
+CREATE TABLE reindex_cursor (
+       contract_address TEXT NOT NULL,
+       block_number BIGINT NOT NULL,
+
+       PRIMARY KEY (contract_address)
+);
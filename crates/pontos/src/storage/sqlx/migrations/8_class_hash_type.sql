@@ -0,0 +1,6 @@
+CREATE TABLE class_hash_type (
+       class_hash TEXT NOT NULL,
+       contract_type TEXT NOT NULL,
+
+       PRIMARY KEY (class_hash)
+);
@@ -0,0 +1 @@
+ALTER TABLE collection_supply ADD COLUMN total_supply BIGINT;
@@ -49,6 +49,10 @@ pub struct BlockData {
     pub status: String,
     pub indexer_version: String,
     pub indexer_identifier: String,
+    #[sqlx(default)]
+    pub selector_hash: Option<String>,
+    #[sqlx(default)]
+    pub last_heartbeat_at: Option<i64>,
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
@@ -1,4 +1,6 @@
+use ark_starknet::{format::to_hex_str, CairoU256};
 use serde::{Deserialize, Serialize, Serializer};
+use starknet::core::types::FieldElement;
 use std::collections::HashMap;
 use std::fmt;
 use std::str::FromStr;
@@ -6,11 +8,18 @@ use std::str::FromStr;
 #[derive(Debug, Clone)]
 pub enum StorageError {
     DatabaseError(String),
-    NotFound(String),
+    /// A record expected to be stored is missing, like the token of a transfer
+    /// to update. The read methods return `None` for a missing record instead.
+    NotFound {
+        entity: &'static str,
+        key: String,
+    },
     InvalidStatus(String),
     DuplicateToken(String),
     InvalidMintData(String),
     AlreadyExists(String),
+    /// The writer of an export failed.
+    ExportError(String),
 }
 
 impl fmt::Display for StorageError {
@@ -18,11 +27,14 @@ impl fmt::Display for StorageError {
         // Note the lifetime parameter <'_>
         match self {
             StorageError::DatabaseError(s) => write!(f, "Database error occurred: {s}"),
-            StorageError::NotFound(s) => write!(f, "Item not found in storage: {s}"),
+            StorageError::NotFound { entity, key } => {
+                write!(f, "{entity} not found in storage: {key}")
+            }
             StorageError::InvalidStatus(s) => write!(f, "Invalid status: {s}"),
             StorageError::DuplicateToken(s) => write!(f, "Token already exists in storage: {s}"),
             StorageError::InvalidMintData(s) => write!(f, "Provided mint data is invalid: {s}"),
             StorageError::AlreadyExists(s) => write!(f, "Item already exists in storage: {s}"),
+            StorageError::ExportError(s) => write!(f, "Export failed: {s}"),
         }
     }
 }
@@ -150,8 +162,124 @@ pub struct TokenTransferEvent {
     pub token_id_hex: String,
     pub event_type: EventType,
     pub event_id: String,
+    /// `None` while the event belongs to the pending block, set
+    /// when the pending block is promoted to latest.
     pub block_number: Option<u64>,
     pub updated_at: Option<u64>,
+    /// Number of tokens transferred, always 1 for ERC721.
+    pub quantity: u64,
+    /// Layout the transfer info were decoded from,
+    /// `None` if the event was not decoded from the chain.
+    pub layout: Option<TransferLayout>,
+}
+
+/// Where the from, to and token id of a transfer are located in the event,
+/// which depends on the Cairo version the contract was compiled with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferLayout {
+    /// In the keys, after the selector (Cairo 1).
+    Keys,
+    /// At the beginning of the data (Cairo 0).
+    Data,
+}
+
+/// A transfer event decoded from the chain, holding typed values.
+///
+/// Formatting the felts into strings accounts for most of the allocations
+/// of the indexing: the event is only rendered into a `TokenTransferEvent`
+/// at the storage boundary, once known to be registered.
+#[derive(Debug, Clone)]
+pub struct DecodedTransfer {
+    pub timestamp: u64,
+    pub from_address: FieldElement,
+    pub to_address: FieldElement,
+    pub contract_address: FieldElement,
+    pub contract_type: ContractType,
+    pub transaction_hash: FieldElement,
+    pub token_id: CairoU256,
+    pub event_type: EventType,
+    pub event_id: FieldElement,
+    pub block_number: Option<u64>,
+    pub updated_at: u64,
+    pub quantity: u64,
+    pub layout: TransferLayout,
+}
+
+impl DecodedTransfer {
+    /// Returns the padded hex of the token id, as `CairoU256::to_hex`
+    /// without the intermediate big integer.
+    pub fn token_id_hex(&self) -> String {
+        format!("0x{:032x}{:032x}", self.token_id.high, self.token_id.low)
+    }
+
+    /// Returns the decimal representation of the token id,
+    /// as `CairoU256::to_decimal` (not padded).
+    pub fn token_id_decimal(&self) -> String {
+        if self.token_id.high == 0 {
+            self.token_id.low.to_string()
+        } else {
+            self.token_id.to_decimal(false)
+        }
+    }
+
+    /// Renders the event with the canonical strings stored.
+    pub fn to_token_event(&self) -> TokenTransferEvent {
+        TokenTransferEvent {
+            timestamp: self.timestamp,
+            from_address: to_hex_str(&self.from_address),
+            to_address: to_hex_str(&self.to_address),
+            contract_address: to_hex_str(&self.contract_address),
+            chain_id: String::new(),
+            contract_type: self.contract_type.to_string(),
+            transaction_hash: to_hex_str(&self.transaction_hash),
+            token_id: self.token_id_decimal(),
+            token_id_hex: self.token_id_hex(),
+            event_type: self.event_type.clone(),
+            event_id: to_hex_str(&self.event_id),
+            block_number: self.block_number,
+            updated_at: Some(self.updated_at),
+            quantity: self.quantity,
+            layout: Some(self.layout),
+        }
+    }
+}
+
+impl TokenTransferEvent {
+    /// Returns true if the event belongs to the pending block.
+    pub fn is_pending(&self) -> bool {
+        self.block_number.is_none()
+    }
+
+    /// Returns true if the token is transferred to its own sender,
+    /// which doesn't change the token ownership.
+    pub fn is_self_transfer(&self) -> bool {
+        self.from_address == self.to_address
+    }
+
+    /// Returns true if the token is transferred from and to the zero address.
+    /// Such events are recorded, but don't create any token.
+    pub fn is_anomalous(&self) -> bool {
+        self.is_self_transfer() && is_zero_address(&self.from_address)
+    }
+
+    /// Returns the variation of the collection supply caused by this event:
+    /// positive for a mint, negative for a burn and 0 otherwise.
+    pub fn supply_delta(&self) -> i64 {
+        let quantity = i64::try_from(self.quantity).unwrap_or(i64::MAX);
+
+        match self.event_type {
+            EventType::Mint => quantity,
+            EventType::Burn => -quantity,
+            _ => 0,
+        }
+    }
+}
+
+fn is_zero_address(address: &str) -> bool {
+    address
+        .trim_start_matches("0x")
+        .trim_start_matches('0')
+        .is_empty()
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -191,6 +319,8 @@ impl Default for TokenTransferEvent {
             block_number: None,
             updated_at: None,
             chain_id: "0x534e5f4d41494e".to_string(),
+            quantity: 1,
+            layout: None,
         }
     }
 }
@@ -202,6 +332,10 @@ pub struct TokenInfo {
     pub chain_id: String,
     pub token_id_hex: String,
     pub owner: String,
+    /// True once an ERC721 token is transferred to the zero address.
+    pub is_burned: bool,
+    /// Block of the burn, `None` if not burned or burned in the pending block.
+    pub burned_at_block: Option<u64>,
 }
 
 #[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
@@ -212,12 +346,15 @@ pub struct TokenMintInfo {
     pub block_number: Option<u64>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum BlockIndexingStatus {
     None,
     Processing,
     Terminated,
+    /// An event of the block failed to be processed in strict mode.
+    /// The block must be indexed again.
+    Failed,
 }
 
 #[allow(clippy::to_string_trait_impl)]
@@ -227,6 +364,7 @@ impl ToString for BlockIndexingStatus {
             BlockIndexingStatus::None => "None".to_string(),
             BlockIndexingStatus::Processing => "Processing".to_string(),
             BlockIndexingStatus::Terminated => "Terminated".to_string(),
+            BlockIndexingStatus::Failed => "Failed".to_string(),
         }
     }
 }
@@ -239,6 +377,7 @@ impl FromStr for BlockIndexingStatus {
             "None" => Ok(BlockIndexingStatus::None),
             "Processing" => Ok(BlockIndexingStatus::Processing),
             "Terminated" => Ok(BlockIndexingStatus::Terminated),
+            "Failed" => Ok(BlockIndexingStatus::Failed),
             _ => Err(()),
         }
     }
@@ -274,12 +413,35 @@ pub struct BlockIndexing {
     pub indexer_version: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BlockInfo {
     pub indexer_version: String,
     pub indexer_identifier: String,
     pub status: BlockIndexingStatus,
     pub block_number: u64,
+    /// Hash of the event selectors the block was terminated with,
+    /// `None` for the blocks terminated before it was recorded.
+    #[serde(default)]
+    pub selector_hash: Option<String>,
+    /// Last time the indexer processing the block reported to be alive,
+    /// in milliseconds since the epoch. 0 if it never did.
+    #[serde(default)]
+    pub last_heartbeat_at: u64,
+}
+
+/// Stored metadata of a block, as returned by `Storage::get_block_details`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockDetails {
+    #[serde(flatten)]
+    pub info: BlockInfo,
+    /// Time at which the block was marked as processing, in milliseconds
+    /// since the epoch. `None` if the block is not in processing.
+    pub processing_started_at: Option<u64>,
+    /// Time at which the block was terminated, in milliseconds since the epoch.
+    /// `None` if the block is not terminated.
+    pub terminated_at: Option<u64>,
+    /// Number of transfer events stored for the block.
+    pub event_count: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -313,6 +475,177 @@ impl FromStr for ContractType {
     }
 }
 
+/// Numbers of items removed from the storage by a purge.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct PurgedItems {
+    pub events: u64,
+    pub tokens: u64,
+}
+
+/// Counters of a collection maintained while indexing, to be read
+/// without aggregating the tokens and the events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct CollectionStats {
+    /// Tokens registered and not burned.
+    pub total_tokens: u64,
+    /// Distinct owners of the tokens not burned.
+    pub unique_holders: u64,
+    /// Transfer events indexed, mints and burns included.
+    pub total_transfers: u64,
+    /// Last accepted block in which the collection was updated.
+    pub last_updated_block: u64,
+}
+
+/// Space reclaimed from the storage by `Storage::vacuum`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct VacuumStats {
+    /// Records left behind by the deletions and removed permanently.
+    pub rows_removed: u64,
+    /// Bytes given back, 0 if not reported by the storage.
+    pub bytes_freed: u64,
+}
+
+/// Records undone by `Storage::rollback_to_block`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RollbackStats {
+    pub blocks_removed: u64,
+    /// Transfer and sale events removed.
+    pub events_removed: u64,
+    /// Tokens removed or restored to their state at the last valid block.
+    pub tokens_affected: u64,
+}
+
+/// Table exported by `CsvExport::export_csv`, with its columns in order.
+/// The values are written as stored, an absent value as an empty field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ExportTable {
+    /// `contract_address, token_id, token_id_hex, owner, is_burned, burned_at_block`,
+    /// ordered by contract address and token id.
+    Tokens,
+    /// All the token events, transfers and sales:
+    /// `event_id, event_type, contract_address, token_id, from_address, to_address,
+    /// transaction_hash, block_number, block_timestamp`, ordered by event id.
+    Events,
+    /// `block_number, block_timestamp, status, indexer_version, indexer_identifier`,
+    /// ordered by block number.
+    Blocks,
+    /// The transfer events only: `event_id, event_type, contract_address,
+    /// contract_type, token_id, from_address, to_address, quantity,
+    /// transaction_hash, block_number, block_timestamp`, ordered by event id.
+    Transfers,
+}
+
+/// Sort key of a row of an `ExportTable`, in the order of the table:
+/// `Storage::export_rows` returns the rows after the given key.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ExportKey {
+    /// Row of `ExportTable::Tokens`.
+    Token {
+        contract_address: String,
+        token_id_hex: String,
+    },
+    /// Row of `ExportTable::Events` or `ExportTable::Transfers`.
+    Event(String),
+    /// Row of `ExportTable::Blocks`.
+    Block(u64),
+}
+
+impl ExportTable {
+    /// Returns the columns of the table, written as header of the export.
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            ExportTable::Tokens => &[
+                "contract_address",
+                "token_id",
+                "token_id_hex",
+                "owner",
+                "is_burned",
+                "burned_at_block",
+            ],
+            ExportTable::Events => &[
+                "event_id",
+                "event_type",
+                "contract_address",
+                "token_id",
+                "from_address",
+                "to_address",
+                "transaction_hash",
+                "block_number",
+                "block_timestamp",
+            ],
+            ExportTable::Blocks => &[
+                "block_number",
+                "block_timestamp",
+                "status",
+                "indexer_version",
+                "indexer_identifier",
+            ],
+            ExportTable::Transfers => &[
+                "event_id",
+                "event_type",
+                "contract_address",
+                "contract_type",
+                "token_id",
+                "from_address",
+                "to_address",
+                "quantity",
+                "transaction_hash",
+                "block_number",
+                "block_timestamp",
+            ],
+        }
+    }
+}
+
+/// An event which failed to be processed, kept in the dead-letter queue
+/// to be reprocessed later.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct FailedEvent {
+    /// Unique identifier of the failed event.
+    pub id: String,
+    pub contract_address: String,
+    pub transaction_hash: String,
+    pub chain_id: String,
+    pub block_timestamp: u64,
+    /// The emitted event, serialized in JSON.
+    pub payload: String,
+    /// The error of the last processing attempt.
+    pub error: String,
+}
+
+/// An indexer instance sharing the storage, as registered by `Storage::register_indexer`.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct IndexerInfo {
+    pub identifier: String,
+    pub version: String,
+    /// Last time the instance reported to be alive, in milliseconds since the epoch.
+    pub last_heartbeat: u64,
+    /// Last block indexed by the instance, if any.
+    pub last_block: Option<u64>,
+    /// Set when the instance was stopped by `Pontos::shutdown`.
+    #[serde(default)]
+    pub inactive: bool,
+}
+
+/// A token event matched by an attribution rule, but which couldn't be
+/// attributed to a collection. Kept for a manual review.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct QuarantinedEvent {
+    /// Unique identifier of the quarantined event.
+    pub id: String,
+    /// Address of the contract which emitted the event.
+    pub emitter_address: String,
+    pub transaction_hash: String,
+    pub chain_id: String,
+    pub block_timestamp: u64,
+    /// The emitted event, serialized in JSON.
+    pub payload: String,
+    /// Name of the attribution rule which matched the event.
+    pub rule: String,
+    /// Why the event couldn't be attributed.
+    pub reason: String,
+}
+
 #[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct ContractInfo {
     pub contract_address: String,
@@ -323,11 +656,105 @@ pub struct ContractInfo {
     pub image: Option<String>,
 }
 
+/// Token metadata field corrected by a patch.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataField {
+    Name,
+    Description,
+    Image,
+    AnimationUrl,
+    ExternalUrl,
+    /// A trait of the token, by trait type. Patched in the token attributes,
+    /// as set by `Storage::upsert_token_attribute`.
+    Attribute(String),
+}
+
+#[allow(clippy::to_string_trait_impl)]
+impl ToString for MetadataField {
+    fn to_string(&self) -> String {
+        match self {
+            MetadataField::Name => "name".to_string(),
+            MetadataField::Description => "description".to_string(),
+            MetadataField::Image => "image".to_string(),
+            MetadataField::AnimationUrl => "animation_url".to_string(),
+            MetadataField::ExternalUrl => "external_url".to_string(),
+            MetadataField::Attribute(trait_type) => format!("attribute:{}", trait_type),
+        }
+    }
+}
+
+impl FromStr for MetadataField {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(MetadataField::Name),
+            "description" => Ok(MetadataField::Description),
+            "image" => Ok(MetadataField::Image),
+            "animation_url" => Ok(MetadataField::AnimationUrl),
+            "external_url" => Ok(MetadataField::ExternalUrl),
+            _ => s
+                .strip_prefix("attribute:")
+                .map(|t| MetadataField::Attribute(t.to_string()))
+                .ok_or(()),
+        }
+    }
+}
+
+/// A metadata correction applied to a token, kept in the audit log
+/// of the storage, apart from the indexed data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetadataPatchRecord {
+    pub contract_address: String,
+    pub token_id_hex: String,
+    pub field: MetadataField,
+    /// The new value, the JSON strings being stored unquoted.
+    pub value: String,
+    /// Seconds since the epoch at which the patch was applied.
+    pub applied_at: u64,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use serde_json::{json, Value};
 
+    #[test]
+    fn test_decoded_transfer_rendering() {
+        for (low, high) in [
+            (0, 0),
+            (42, 0),
+            (u128::MAX, 0),
+            (7, 1),
+            (u128::MAX, u128::MAX),
+        ] {
+            let token_id = CairoU256 { low, high };
+            let transfer = DecodedTransfer {
+                timestamp: 1,
+                from_address: FieldElement::ZERO,
+                to_address: FieldElement::ONE,
+                contract_address: FieldElement::TWO,
+                contract_type: ContractType::ERC721,
+                transaction_hash: FieldElement::THREE,
+                token_id: token_id.clone(),
+                event_type: EventType::Mint,
+                event_id: FieldElement::ONE,
+                block_number: Some(1),
+                updated_at: 2,
+                quantity: 1,
+                layout: TransferLayout::Keys,
+            };
+
+            let event = transfer.to_token_event();
+            assert_eq!(event.token_id_hex, token_id.to_hex());
+            assert_eq!(event.token_id, token_id.to_decimal(false));
+            assert_eq!(event.contract_address, to_hex_str(&FieldElement::TWO));
+            assert_eq!(event.contract_type, "ERC721");
+            assert_eq!(event.layout, Some(TransferLayout::Keys));
+        }
+    }
+
     #[test]
     fn test_token_event_transfer_serialization() {
         let event = TokenEvent::Transfer(TokenTransferEvent {
@@ -344,6 +771,8 @@ mod tests {
             block_number: Some(123),
             updated_at: Some(1625101200),
             chain_id: "0x534e5f4d41494e".to_string(),
+            quantity: 1,
+            layout: Some(TransferLayout::Keys),
         });
 
         let serialized = serde_json::to_string(&event).expect("Failed to serialize TokenEvent");
@@ -0,0 +1,163 @@
+//! A clock advanced manually, to exercise the time-dependent logic
+//! deterministically without waiting.
+use crate::clock::Clock;
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+/// Clock only advanced by `ManualClock::advance`. The sleeps complete once
+/// the clock is advanced past their end.
+#[derive(Debug)]
+pub struct ManualClock {
+    origin: Instant,
+    /// Wall clock time at the creation of the clock.
+    unix_origin: Duration,
+    elapsed: watch::Sender<Duration>,
+    sleeps: AtomicUsize,
+    /// Elapsed time at the end of the last sleep started.
+    last_sleep_end: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            unix_origin: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            elapsed: watch::channel(Duration::ZERO).0,
+            sleeps: AtomicUsize::new(0),
+            last_sleep_end: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Sets the wall clock time at the creation of the clock,
+    /// as the time elapsed since the epoch.
+    pub fn with_unix_time(mut self, unix_time: Duration) -> Self {
+        self.unix_origin = unix_time;
+        self
+    }
+
+    /// Advances the clock, waking up the sleeps ending before the new instant.
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+
+    /// Returns the number of sleeps started since the creation of the clock.
+    pub fn sleeps(&self) -> usize {
+        self.sleeps.load(Ordering::SeqCst)
+    }
+
+    /// Yields until at least `count` sleeps were started, for the code
+    /// under test to reach its next wait before the clock is advanced.
+    pub async fn wait_for_sleeps(&self, count: usize) {
+        while self.sleeps() < count {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Runs the future, advancing the clock to the end of each sleep it
+    /// starts, as if the sleeps completed immediately.
+    pub async fn drive<F: Future>(&self, future: F) -> F::Output {
+        tokio::pin!(future);
+        let mut waits = self.sleeps();
+
+        loop {
+            tokio::select! {
+                biased;
+                output = &mut future => return output,
+                _ = tokio::task::yield_now() => {
+                    if self.sleeps() > waits {
+                        waits = self.sleeps();
+                        let end = *self.last_sleep_end.lock().expect("Clock lock poisoned");
+                        self.elapsed.send_if_modified(|elapsed| {
+                            let advanced = end > *elapsed;
+                            *elapsed = (*elapsed).max(end);
+                            advanced
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.origin + *self.elapsed.borrow()
+    }
+
+    fn unix_time(&self) -> Duration {
+        self.unix_origin + *self.elapsed.borrow()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        let mut elapsed = self.elapsed.subscribe();
+        let end = *elapsed.borrow_and_update() + duration;
+        *self.last_sleep_end.lock().expect("Clock lock poisoned") = end;
+        self.sleeps.fetch_add(1, Ordering::SeqCst);
+
+        while *elapsed.borrow_and_update() < end {
+            // The sender lives as long as the clock.
+            if elapsed.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_manual_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let start = clock.now();
+
+        let sleep = tokio::spawn({
+            let clock = Arc::clone(&clock);
+            async move { clock.sleep(Duration::from_secs(10)).await }
+        });
+        clock.wait_for_sleeps(1).await;
+
+        clock.advance(Duration::from_secs(4));
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+
+        clock.advance(Duration::from_secs(6));
+        sleep.await.unwrap();
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+        assert_eq!(clock.sleeps(), 1);
+
+        let slept = clock
+            .drive(async {
+                clock.sleep(Duration::from_secs(60)).await;
+                clock.sleep(Duration::from_secs(30)).await;
+                "slept"
+            })
+            .await;
+        assert_eq!(slept, "slept");
+        assert_eq!(clock.now() - start, Duration::from_secs(100));
+    }
+
+    #[test]
+    fn test_manual_clock_unix_time() {
+        let clock = ManualClock::new().with_unix_time(Duration::from_secs(1_700_000_000));
+        assert_eq!(clock.unix_time(), Duration::from_secs(1_700_000_000));
+
+        clock.advance(Duration::from_secs(42));
+        assert_eq!(clock.unix_time(), Duration::from_secs(1_700_000_042));
+    }
+}
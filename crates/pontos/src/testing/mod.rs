@@ -0,0 +1,622 @@
+//! Utilities to test and benchmark Pontos without network nor database.
+//!
+//! Enabled with the `testing` feature.
+use crate::event_handler::EventHandler;
+use crate::storage::types::ContractType;
+use ark_starknet::client::{MockStarknetClient, StarknetClientError};
+use ark_starknet::EventResult;
+use starknet::core::types::{BlockId, EmittedEvent, FieldElement};
+use starknet::core::utils::get_selector_from_name;
+use starknet::macros::selector;
+use std::collections::HashMap;
+
+mod clock;
+mod pending_scenario;
+
+pub use crate::storage::InMemoryStorage;
+pub use clock::ManualClock;
+pub use pending_scenario::{HandlerCall, PendingScenario, RecordingEventHandler, ScenarioTx};
+
+/// Timestamp of the first synthetic block.
+const SYNTHETIC_GENESIS_TIMESTAMP: u64 = 1_700_000_000;
+
+/// An event handler doing nothing.
+#[derive(Debug, Default)]
+pub struct NoopEventHandler;
+
+impl EventHandler for NoopEventHandler {}
+
+/// A contract emitting synthetic events.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticContract {
+    pub address: FieldElement,
+    pub contract_type: ContractType,
+}
+
+/// Returns the given number of ERC721 and ERC1155 contracts,
+/// with deterministic addresses.
+pub fn synthetic_contracts(erc721: usize, erc1155: usize) -> Vec<SyntheticContract> {
+    let erc721 = (0..erc721).map(|i| SyntheticContract {
+        address: FieldElement::from(0x721_0000_u64 + i as u64),
+        contract_type: ContractType::ERC721,
+    });
+
+    let erc1155 = (0..erc1155).map(|i| SyntheticContract {
+        address: FieldElement::from(0x1155_0000_u64 + i as u64),
+        contract_type: ContractType::ERC1155,
+    });
+
+    erc721.chain(erc1155).collect()
+}
+
+/// Returns the timestamp of a synthetic block.
+pub fn synthetic_block_timestamp(block_number: u64) -> u64 {
+    SYNTHETIC_GENESIS_TIMESTAMP + block_number
+}
+
+/// Generates `n_events` mint events for the given block, emitted
+/// by the given contracts in a round-robin manner.
+/// Each event has its own transaction and token id.
+pub fn synthetic_block(
+    block_number: u64,
+    n_events: usize,
+    contracts: &[SyntheticContract],
+) -> Vec<EmittedEvent> {
+    if contracts.is_empty() {
+        return vec![];
+    }
+
+    (0..n_events)
+        .map(|i| {
+            let contract = &contracts[i % contracts.len()];
+            let token_id = FieldElement::from(block_number << 32 | i as u64);
+
+            EmittedEvent {
+                from_address: contract.address,
+                block_hash: Some(FieldElement::from(block_number)),
+                transaction_hash: FieldElement::from(block_number << 32 | i as u64),
+                block_number: Some(block_number),
+                keys: vec![selector!("Transfer")],
+                data: vec![
+                    FieldElement::ZERO,
+                    FieldElement::from(0xacc0_u64 + i as u64),
+                    token_id,
+                    FieldElement::ZERO,
+                ],
+            }
+        })
+        .collect()
+}
+
+/// Returns a mint of the given collection emitted by an account contract on
+/// its behalf, following the `EmbeddedCollectionKey` wrapper pattern: the
+/// collection address is inserted in the keys of an ERC721 `Transfer`.
+pub fn wrapped_transfer(
+    block_number: u64,
+    account: FieldElement,
+    collection: FieldElement,
+    token_id: FieldElement,
+) -> EmittedEvent {
+    EmittedEvent {
+        from_address: account,
+        block_hash: Some(FieldElement::from(block_number)),
+        transaction_hash: FieldElement::from(block_number << 32 | 0xffff),
+        block_number: Some(block_number),
+        keys: vec![
+            selector!("Transfer"),
+            collection,
+            FieldElement::ZERO,
+            account,
+            token_id,
+            FieldElement::ZERO,
+        ],
+        data: vec![],
+    }
+}
+
+/// Returns a mocked client serving the given blocks (by block number),
+/// and answering the contract calls of the given contracts like a real
+/// contract of their type would do. Any other contract is identified as other.
+pub fn mock_client(
+    blocks: HashMap<u64, Vec<EmittedEvent>>,
+    contracts: &[SyntheticContract],
+) -> MockStarknetClient {
+    let mut client = MockStarknetClient::default();
+
+    let latest = blocks.keys().max().copied().unwrap_or_default();
+    client
+        .expect_block_id_to_u64()
+        .returning(move |id| match id {
+            BlockId::Number(n) => Ok(*n),
+            _ => Ok(latest),
+        });
+
+    client.expect_block_number().returning(move || Ok(latest));
+
+    client.expect_block_time().returning(|id| match id {
+        BlockId::Number(n) => Ok(synthetic_block_timestamp(n)),
+        _ => Err(StarknetClientError::Other(
+            "Unsupported block id".to_string(),
+        )),
+    });
+
+    let all_blocks = blocks.clone();
+    client
+        .expect_fetch_events()
+        .returning(move |from, to, keys, address, _| {
+            let number = |id: Option<BlockId>, default: u64| match id {
+                Some(BlockId::Number(n)) => n,
+                _ => default,
+            };
+            let (from, to) = (number(from, 0), number(to, u64::MAX));
+
+            let events = all_blocks
+                .iter()
+                .filter(|(n, _)| **n >= from && **n <= to)
+                .map(|(n, events)| {
+                    let events = events
+                        .iter()
+                        .filter(|e| address.map_or(true, |a| e.from_address == a))
+                        .filter(|e| keys.as_ref().map_or(true, |k| keys_match(k, &e.keys)))
+                        .cloned()
+                        .collect();
+                    (*n, events)
+                })
+                .collect();
+
+            Ok(EventResult {
+                events,
+                continuation_token: None,
+            })
+        });
+
+    client
+        .expect_fetch_all_block_events()
+        .returning(move |id, _| match id {
+            BlockId::Number(n) => Ok(HashMap::from([(
+                n,
+                blocks.get(&n).cloned().unwrap_or_default(),
+            )])),
+            _ => Ok(HashMap::new()),
+        });
+
+    expect_contract_calls(&mut client, contracts);
+
+    client
+}
+
+/// Returns true if the keys of the event match the key filter of
+/// `fetch_events`: each key must be one of the keys at its position,
+/// an empty position matching any key.
+fn keys_match(filter: &[Vec<FieldElement>], keys: &[FieldElement]) -> bool {
+    filter
+        .iter()
+        .enumerate()
+        .all(|(i, allowed)| allowed.is_empty() || keys.get(i).is_some_and(|k| allowed.contains(k)))
+}
+
+/// Sets the expectations of the contract calls done to identify
+/// the given contracts and their tokens.
+fn expect_contract_calls(client: &mut MockStarknetClient, contracts: &[SyntheticContract]) {
+    let types: HashMap<FieldElement, ContractType> = contracts
+        .iter()
+        .map(|c| (c.address, c.contract_type.clone()))
+        .collect();
+
+    client
+        .expect_class_hash_at()
+        .returning(|address, _| Ok(address));
+
+    let owner_of = [
+        get_selector_from_name("ownerOf").unwrap(),
+        get_selector_from_name("owner_of").unwrap(),
+    ];
+    let balance_of = [
+        get_selector_from_name("balanceOf").unwrap(),
+        get_selector_from_name("balance_of").unwrap(),
+    ];
+
+    client
+        .expect_call_contract()
+        .returning(move |address, selector, _, _| {
+            let supported = match types.get(&address) {
+                Some(ContractType::ERC721) => owner_of.contains(&selector),
+                Some(ContractType::ERC1155) => balance_of.contains(&selector),
+                _ => false,
+            };
+
+            if supported {
+                Ok(vec![FieldElement::ONE])
+            } else {
+                Err(StarknetClientError::EntrypointNotFound(format!(
+                    "Entry point 0x{:x} not found in contract",
+                    selector
+                )))
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IndexerError, PendingPolling, Pontos, PontosConfig};
+    use ark_starknet::format::to_hex_str;
+    use starknet::core::types::BlockTag;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_index_synthetic_blocks() {
+        let contracts = synthetic_contracts(2, 1);
+        let blocks = HashMap::from([
+            (1, synthetic_block(1, 10, &contracts)),
+            (2, synthetic_block(2, 5, &contracts)),
+        ]);
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                indexer_version: "v0.0.1".to_string(),
+                indexer_identifier: "TASK#123".to_string(),
+                ..Default::default()
+            },
+        );
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(2), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        let data = storage.dump();
+        assert_eq!(data.transfer_events.len(), 15);
+        assert_eq!(data.tokens.len(), 15);
+        assert_eq!(data.contracts.len(), 3);
+        assert_eq!(data.blocks.len(), 2);
+    }
+
+    /// Returns a client serving the blocks 1 to 4, the block 4 being the
+    /// pending block. It is promoted to latest `promote_after` ticks of the
+    /// pending loop after its timestamp was requested by the range path,
+    /// or never if `None`.
+    fn pending_and_range_client(
+        blocks: &HashMap<u64, Vec<EmittedEvent>>,
+        contracts: &[SyntheticContract],
+        promote_after: Option<usize>,
+    ) -> MockStarknetClient {
+        let reached_block_4 = Arc::new(AtomicBool::new(false));
+        let promoted = Arc::new(AtomicBool::new(false));
+        let ticks_after_reached = Arc::new(AtomicUsize::new(0));
+
+        let mut client = MockStarknetClient::default();
+        expect_contract_calls(&mut client, contracts);
+
+        client.expect_block_id_to_u64().returning(|id| match id {
+            BlockId::Number(n) => Ok(*n),
+            _ => Ok(4),
+        });
+
+        let reached = Arc::clone(&reached_block_4);
+        client.expect_block_time().returning(move |id| match id {
+            BlockId::Number(n) => {
+                if n == 4 {
+                    reached.store(true, Ordering::SeqCst);
+                }
+                Ok(synthetic_block_timestamp(n))
+            }
+            _ => Err(StarknetClientError::Other(
+                "Unsupported block id".to_string(),
+            )),
+        });
+
+        let all_blocks = blocks.clone();
+        client
+            .expect_fetch_all_block_events()
+            .returning(move |id, _| match id {
+                BlockId::Number(n) => Ok(HashMap::from([(
+                    n,
+                    all_blocks.get(&n).cloned().unwrap_or_default(),
+                )])),
+                _ => Ok(HashMap::new()),
+            });
+
+        let p = Arc::clone(&promoted);
+        client
+            .expect_block_number()
+            .returning(move || Ok(if p.load(Ordering::SeqCst) { 4 } else { 3 }));
+
+        let block_4_txs: Vec<FieldElement> =
+            blocks[&4].iter().map(|e| e.transaction_hash).collect();
+        let (reached, p) = (Arc::clone(&reached_block_4), Arc::clone(&promoted));
+        client
+            .expect_block_txs_hashes()
+            .returning(move |id| match id {
+                BlockId::Tag(BlockTag::Pending) => {
+                    if reached.load(Ordering::SeqCst)
+                        && promote_after.is_some_and(|after| {
+                            ticks_after_reached.fetch_add(1, Ordering::SeqCst) >= after
+                        })
+                    {
+                        p.store(true, Ordering::SeqCst);
+                    }
+
+                    if p.load(Ordering::SeqCst) {
+                        Ok((synthetic_block_timestamp(5), vec![]))
+                    } else {
+                        Ok((synthetic_block_timestamp(4), block_4_txs.clone()))
+                    }
+                }
+                BlockId::Number(4) => Ok((synthetic_block_timestamp(4), block_4_txs.clone())),
+                _ => Err(StarknetClientError::Other("Unknown block".to_string())),
+            });
+
+        let pending_events = blocks[&4].clone();
+        client
+            .expect_events_from_tx_receipt()
+            .returning(move |tx_hash, _| {
+                Ok(pending_events
+                    .iter()
+                    .filter(|e| e.transaction_hash == tx_hash)
+                    .map(|e| EmittedEvent {
+                        block_hash: None,
+                        block_number: None,
+                        ..e.clone()
+                    })
+                    .collect())
+            });
+
+        client
+    }
+
+    /// Asserts that each event of the blocks was stored once, with its block number.
+    fn assert_events_indexed_once(
+        storage: &InMemoryStorage,
+        blocks: &HashMap<u64, Vec<EmittedEvent>>,
+        contracts: &[SyntheticContract],
+    ) {
+        let data = storage.dump();
+        assert_eq!(
+            data.transfer_events.len(),
+            blocks.values().map(Vec::len).sum::<usize>()
+        );
+        for (n, events) in blocks {
+            for e in events {
+                let stored: Vec<_> = data
+                    .transfer_events
+                    .values()
+                    .filter(|s| s.transaction_hash == to_hex_str(&e.transaction_hash))
+                    .collect();
+                assert_eq!(stored.len(), 1);
+                assert_eq!(stored[0].block_number, Some(*n));
+            }
+        }
+
+        assert_eq!(data.tokens.len(), data.transfer_events.len());
+        assert_eq!(data.blocks.len(), blocks.len());
+        for c in contracts {
+            let minted = blocks
+                .values()
+                .flatten()
+                .filter(|e| e.from_address == c.address)
+                .count();
+            assert_eq!(data.supplies[&to_hex_str(&c.address)], minted as i64);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_pending_and_range() {
+        let contracts = synthetic_contracts(2, 0);
+        let blocks: HashMap<u64, Vec<EmittedEvent>> = (1..=4)
+            .map(|n| (n, synthetic_block(n, 3, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let handler = Arc::new(RecordingEventHandler::new());
+        let pontos = Arc::new(Pontos::new(
+            Arc::new(pending_and_range_client(&blocks, &contracts, Some(3))),
+            Arc::clone(&storage),
+            Arc::clone(&handler),
+            PontosConfig {
+                indexer_version: "v0.0.1".to_string(),
+                indexer_identifier: "TASK#123".to_string(),
+                pending_polling: PendingPolling::FixedInterval(Duration::from_millis(1)),
+                ..Default::default()
+            },
+        ));
+
+        let pending = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move { pontos.index_pending().await }
+        });
+
+        while pontos.pending_snapshot().await.processed_tx_count == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        assert!(matches!(
+            pontos.index_pending().await,
+            Err(IndexerError::PendingLoopAlreadyRunning)
+        ));
+
+        pontos
+            .index_block_range(BlockId::Number(1), BlockId::Number(4), false, "SN_MAIN")
+            .await
+            .unwrap();
+
+        pending.abort();
+
+        // Block 4 was terminated by the pending path once promoted,
+        // and skipped by the range path.
+        let calls = handler.calls();
+        assert!(calls.contains(&HandlerCall::NewLatestBlock { block_number: 4 }));
+        assert!(!calls.contains(&HandlerCall::BlockProcessing {
+            block_timestamp: synthetic_block_timestamp(4),
+            block_number: Some(4),
+        }));
+
+        // No event is duplicated nor lost.
+        assert_events_indexed_once(&storage, &blocks, &contracts);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_pending_and_range_virtual_time() {
+        let contracts = synthetic_contracts(2, 1);
+        let blocks: HashMap<u64, Vec<EmittedEvent>> = (1..=4)
+            .map(|n| (n, synthetic_block(n, 4, &contracts)))
+            .collect();
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let clock = Arc::new(ManualClock::new());
+        let pontos = Arc::new(Pontos::new(
+            Arc::new(pending_and_range_client(&blocks, &contracts, Some(3))),
+            Arc::clone(&storage),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                indexer_version: "v0.0.1".to_string(),
+                indexer_identifier: "TASK#123".to_string(),
+                pending_polling: PendingPolling::FixedInterval(Duration::from_secs(1)),
+                clock: Some(Arc::clone(&clock) as Arc<dyn crate::Clock>),
+                ..Default::default()
+            },
+        ));
+
+        // Both paths start together, racing for the block 4.
+        let pending = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move { pontos.index_pending().await }
+        });
+        let range = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move {
+                pontos
+                    .index_block_range(BlockId::Number(1), BlockId::Number(4), false, "SN_MAIN")
+                    .await
+            }
+        });
+
+        for _ in 0..100 {
+            if range.is_finished() {
+                break;
+            }
+            clock.advance(Duration::from_secs(1));
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+        }
+        range.await.unwrap().unwrap();
+        pending.abort();
+
+        assert_events_indexed_once(&storage, &blocks, &contracts);
+    }
+
+    #[tokio::test]
+    async fn test_range_waits_for_pending_block_bounded() {
+        let contracts = synthetic_contracts(1, 0);
+        let blocks: HashMap<u64, Vec<EmittedEvent>> = (1..=4)
+            .map(|n| (n, synthetic_block(n, 2, &contracts)))
+            .collect();
+
+        let clock = Arc::new(ManualClock::new());
+        let pontos = Arc::new(Pontos::new(
+            Arc::new(pending_and_range_client(&blocks, &contracts, None)),
+            Arc::new(InMemoryStorage::new()),
+            Arc::new(NoopEventHandler),
+            PontosConfig {
+                indexer_version: "v0.0.1".to_string(),
+                indexer_identifier: "TASK#123".to_string(),
+                pending_polling: PendingPolling::FixedInterval(Duration::from_secs(1)),
+                clock: Some(Arc::clone(&clock) as Arc<dyn crate::Clock>),
+                ..Default::default()
+            },
+        ));
+
+        let pending = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move { pontos.index_pending().await }
+        });
+        while pontos.pending_snapshot().await.processed_tx_count == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // The block 4 is never promoted.
+        let result = clock
+            .drive(pontos.index_block_range(
+                BlockId::Number(1),
+                BlockId::Number(4),
+                false,
+                "SN_MAIN",
+            ))
+            .await;
+        pending.abort();
+
+        match result {
+            Err(IndexerError::PendingBlockHeld { block, waited }) => {
+                assert_eq!(block, 4);
+                assert!(waited >= Duration::from_secs(600));
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    /// Queries the chain for each token registered, from the callback.
+    #[derive(Default)]
+    struct QueryingEventHandler {
+        query: std::sync::OnceLock<crate::TokenQuery<InMemoryStorage, MockStarknetClient>>,
+        owners: AtomicUsize,
+        erc721: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl EventHandler for QueryingEventHandler {
+        async fn on_token_event(
+            &self,
+            _event: &crate::storage::types::TokenEvent,
+            token: &crate::storage::types::TokenInfo,
+        ) {
+            let query = self.query.get().unwrap();
+            let address = FieldElement::from_hex_be(&token.contract_address).unwrap();
+            let token_id = ark_starknet::CairoU256::from_hex_be(&token.token_id_hex).unwrap();
+
+            let owner = query
+                .owner_of(address, &token_id, BlockId::Tag(BlockTag::Latest))
+                .await;
+            if owner.ok() == Some(FieldElement::ONE) {
+                self.owners.fetch_add(1, Ordering::SeqCst);
+            }
+            if query.contract_type(address).await.ok() == Some(ContractType::ERC721) {
+                self.erc721.fetch_add(1, Ordering::SeqCst);
+            }
+            // Not exposed by the synthetic contracts.
+            assert!(query.token_uri(address, &token_id).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_query_from_handler() {
+        let contracts = synthetic_contracts(1, 0);
+        let blocks = HashMap::from([(1, synthetic_block(1, 3, &contracts))]);
+
+        let handler = Arc::new(QueryingEventHandler::default());
+        let pontos = Pontos::new(
+            Arc::new(mock_client(blocks, &contracts)),
+            Arc::new(InMemoryStorage::new()),
+            Arc::clone(&handler),
+            PontosConfig::default(),
+        );
+        let _ = handler.query.set(pontos.token_query());
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            pontos.index_block_range(BlockId::Number(1), BlockId::Number(1), false, "SN_MAIN"),
+        )
+        .await
+        .expect("The queries from the handler must not block the indexer")
+        .unwrap();
+
+        assert_eq!(handler.owners.load(Ordering::SeqCst), 3);
+        assert_eq!(handler.erc721.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,509 @@
+//! Scripted pending to latest transitions, to test event handlers
+//! against the sequences of callbacks of `Pontos::index_pending`.
+//!
+//! A scenario is a list of ticks of the pending loop. At each tick, the mocked
+//! node returns the pending block of the tick, and the latest block declared
+//! so far. Each transaction emits one mint from a synthetic ERC721 contract,
+//! the token id being the transaction id.
+//!
+//! The documented sequences of callbacks are:
+//! * While the pending block timestamp is unchanged, only the new transactions
+//!   are processed, each event firing `on_token_event` once.
+//! * When the pending block timestamp changes, the transactions of the latest
+//!   block not processed yet are processed with the previous pending timestamp,
+//!   the events of the previous pending block (registered without block number)
+//!   get the latest block number, then `on_pending_transition` and
+//!   `on_new_latest_block` fire, and the new pending block is processed.
+//! * Sequencer skip: the latest block number can jump by more than one between
+//!   two ticks. Only the latest block is considered for the promotion, and
+//!   `on_pending_transition` and `on_new_latest_block` fire once with the
+//!   latest number.
+//! * Receipt not found: the transaction is not marked as processed, and
+//!   is retried at the next tick, or during the promotion.
+//!
+//! ```ignore
+//! let handler = Arc::new(RecordingEventHandler::new());
+//!
+//! PendingScenario::new()
+//!     .pending(1000, [1, 2])
+//!     .receipt_not_found(3)
+//!     .pending(1000, [1, 2, 3])
+//!     .latest(10, [1, 2, 3, 4])
+//!     .pending(1012, [5])
+//!     .run(Arc::clone(&handler))
+//!     .await?;
+//!
+//! assert_eq!(handler.calls()[..2], [
+//!     HandlerCall::token_event(1, 1000),
+//!     HandlerCall::token_event(2, 1000),
+//! ]);
+//! ```
+use crate::clock::Clock;
+use crate::event_handler::EventHandler;
+use crate::storage::types::{TokenEvent, TokenInfo};
+use crate::{IndexerError, IndexerResult, PendingPolling, Pontos, PontosConfig};
+use ark_starknet::client::{MockStarknetClient, StarknetClientError};
+use ark_starknet::format::to_hex_str;
+use async_trait::async_trait;
+use starknet::core::types::{BlockId, BlockTag, EmittedEvent, FieldElement};
+use starknet::macros::selector;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{expect_contract_calls, synthetic_contracts, InMemoryStorage, ManualClock};
+
+const SCENARIO_CHAIN_ID: &str = "SN_MAIN";
+
+/// Interval between two ticks, on the manual clock of the scenario.
+/// The waits before a retry of the loop are shorter.
+const SCENARIO_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Identifier of a scripted transaction, used as its hash and as the
+/// token id minted by the transaction.
+pub type ScenarioTx = u64;
+
+/// A tick of the pending loop, with the blocks returned by the node.
+#[derive(Debug, Clone)]
+struct Tick {
+    pending_timestamp: u64,
+    pending_txs: Vec<ScenarioTx>,
+    latest: (u64, Vec<ScenarioTx>),
+}
+
+/// State of the mocked node, shared with the mock expectations.
+#[derive(Debug)]
+struct NodeState {
+    ticks: Vec<Tick>,
+    /// Number of ticks started by the pending loop.
+    cursor: usize,
+    /// Remaining receipt failures, by transaction.
+    receipt_failures: HashMap<ScenarioTx, usize>,
+}
+
+impl NodeState {
+    fn current_tick(&self) -> Option<&Tick> {
+        self.cursor.checked_sub(1).and_then(|i| self.ticks.get(i))
+    }
+}
+
+/// Builder of a scripted sequence of pending blocks.
+#[derive(Debug, Clone, Default)]
+pub struct PendingScenario {
+    ticks: Vec<Tick>,
+    latest: (u64, Vec<ScenarioTx>),
+    receipt_failures: HashMap<ScenarioTx, usize>,
+}
+
+impl PendingScenario {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a tick where the pending block has the given timestamp
+    /// and transactions.
+    pub fn pending(mut self, timestamp: u64, txs: impl IntoIterator<Item = ScenarioTx>) -> Self {
+        self.ticks.push(Tick {
+            pending_timestamp: timestamp,
+            pending_txs: txs.into_iter().collect(),
+            latest: self.latest.clone(),
+        });
+        self
+    }
+
+    /// Sets the latest block returned by the node for the next ticks.
+    pub fn latest(mut self, block_number: u64, txs: impl IntoIterator<Item = ScenarioTx>) -> Self {
+        self.latest = (block_number, txs.into_iter().collect());
+        self
+    }
+
+    /// The next fetch of the receipt of the transaction fails.
+    /// Can be called several times for the same transaction.
+    pub fn receipt_not_found(mut self, tx: ScenarioTx) -> Self {
+        *self.receipt_failures.entry(tx).or_default() += 1;
+        self
+    }
+
+    /// Runs `index_pending` until all the ticks are processed,
+    /// and returns the storage filled during the scenario.
+    pub async fn run<E: EventHandler + Send + Sync + 'static>(
+        self,
+        handler: Arc<E>,
+    ) -> IndexerResult<Arc<InMemoryStorage>> {
+        let n_ticks = self.ticks.len();
+        let state = Arc::new(Mutex::new(NodeState {
+            ticks: self.ticks,
+            cursor: 0,
+            receipt_failures: self.receipt_failures,
+        }));
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let clock = Arc::new(ManualClock::new());
+        let pontos = Arc::new(Pontos::new(
+            Arc::new(mock_client(Arc::clone(&state))),
+            Arc::clone(&storage),
+            handler,
+            PontosConfig {
+                indexer_version: "v0.0.1".to_string(),
+                indexer_identifier: "scenario".to_string(),
+                chain_id: Some(SCENARIO_CHAIN_ID.to_string()),
+                pending_polling: PendingPolling::FixedInterval(SCENARIO_TICK_INTERVAL),
+                clock: Some(Arc::clone(&clock) as Arc<dyn Clock>),
+                ..Default::default()
+            },
+        ));
+
+        let task = tokio::spawn({
+            let pontos = Arc::clone(&pontos);
+            async move { pontos.index_pending().await }
+        });
+
+        // The ticks are processed sequentially: once the loop asks
+        // for a tick after the last one, all the ticks are done.
+        // The clock is advanced each time the loop waits.
+        let mut waits = 0;
+        loop {
+            if state.lock().expect("Scenario lock poisoned").cursor > n_ticks {
+                task.abort();
+                return Ok(storage);
+            }
+
+            if task.is_finished() {
+                task.await
+                    .map_err(|e| IndexerError::Anyhow(e.to_string()))??;
+                return Err(IndexerError::Anyhow(
+                    "Pending loop exited before the end of the scenario".to_string(),
+                ));
+            }
+
+            if clock.sleeps() > waits {
+                waits = clock.sleeps();
+                clock.advance(SCENARIO_TICK_INTERVAL);
+            }
+
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+/// The mint emitted by a scripted transaction.
+fn tx_event(tx: ScenarioTx, contract_address: FieldElement) -> EmittedEvent {
+    EmittedEvent {
+        from_address: contract_address,
+        block_hash: None,
+        transaction_hash: FieldElement::from(tx),
+        block_number: None,
+        keys: vec![selector!("Transfer")],
+        data: vec![
+            FieldElement::ZERO,
+            FieldElement::from(0xacc0_u64),
+            FieldElement::from(tx),
+            FieldElement::ZERO,
+        ],
+    }
+}
+
+fn mock_client(state: Arc<Mutex<NodeState>>) -> MockStarknetClient {
+    let mut client = MockStarknetClient::default();
+    let contracts = synthetic_contracts(1, 0);
+    let contract_address = contracts[0].address;
+
+    expect_contract_calls(&mut client, &contracts);
+
+    let to_felts = |txs: &[ScenarioTx]| txs.iter().map(|tx| FieldElement::from(*tx)).collect();
+
+    let s = Arc::clone(&state);
+    client.expect_block_txs_hashes().returning(move |id| {
+        let mut state = s.lock().expect("Scenario lock poisoned");
+
+        match id {
+            BlockId::Tag(BlockTag::Pending) => {
+                state.cursor += 1;
+                state
+                    .current_tick()
+                    .map(|t| (t.pending_timestamp, to_felts(&t.pending_txs)))
+                    .ok_or_else(|| StarknetClientError::Other("End of scenario".to_string()))
+            }
+            BlockId::Number(n) => state
+                .current_tick()
+                .filter(|t| t.latest.0 == n)
+                .map(|t| (0, to_felts(&t.latest.1)))
+                .ok_or_else(|| StarknetClientError::Other(format!("Block {} not found", n))),
+            _ => Err(StarknetClientError::Other(
+                "Unsupported block id".to_string(),
+            )),
+        }
+    });
+
+    let s = Arc::clone(&state);
+    client.expect_block_number().returning(move || {
+        Ok(s.lock()
+            .expect("Scenario lock poisoned")
+            .current_tick()
+            .map(|t| t.latest.0)
+            .unwrap_or_default())
+    });
+
+    client
+        .expect_events_from_tx_receipt()
+        .returning(move |tx_hash, _| {
+            let tx: ScenarioTx = tx_hash
+                .try_into()
+                .map_err(|_| StarknetClientError::Other("Unknown transaction".to_string()))?;
+
+            let mut state = state.lock().expect("Scenario lock poisoned");
+            if let Some(failures) = state.receipt_failures.get_mut(&tx).filter(|f| **f > 0) {
+                *failures -= 1;
+                return Err(StarknetClientError::Other(format!(
+                    "Transaction hash not found: {}",
+                    tx
+                )));
+            }
+
+            Ok(vec![tx_event(tx, contract_address)])
+        });
+
+    client
+}
+
+/// A callback received by the [`RecordingEventHandler`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandlerCall {
+    BlockProcessing {
+        block_timestamp: u64,
+        block_number: Option<u64>,
+    },
+    BlockProcessed {
+        block_number: u64,
+    },
+    TokenEvent {
+        transaction_hash: String,
+        timestamp: u64,
+        block_number: Option<u64>,
+    },
+    PendingTransition {
+        pending_ts: u64,
+        block_number: u64,
+    },
+    NewLatestBlock {
+        block_number: u64,
+    },
+}
+
+impl HandlerCall {
+    /// The `on_token_event` expected for the pending event of the transaction.
+    pub fn token_event(tx: ScenarioTx, timestamp: u64) -> Self {
+        HandlerCall::TokenEvent {
+            transaction_hash: to_hex_str(&FieldElement::from(tx)),
+            timestamp,
+            block_number: None,
+        }
+    }
+}
+
+/// An event handler recording the callbacks, in order.
+#[derive(Debug, Default)]
+pub struct RecordingEventHandler {
+    calls: Mutex<Vec<HandlerCall>>,
+}
+
+impl RecordingEventHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the callbacks received so far.
+    pub fn calls(&self) -> Vec<HandlerCall> {
+        self.calls.lock().expect("Recorder lock poisoned").clone()
+    }
+
+    fn record(&self, call: HandlerCall) {
+        self.calls
+            .lock()
+            .expect("Recorder lock poisoned")
+            .push(call);
+    }
+}
+
+#[async_trait]
+impl EventHandler for RecordingEventHandler {
+    async fn on_block_processing(&self, block_timestamp: u64, block_number: Option<u64>) {
+        self.record(HandlerCall::BlockProcessing {
+            block_timestamp,
+            block_number,
+        });
+    }
+
+    async fn on_block_processed(&self, block_number: u64, _indexation_progress: f64) {
+        self.record(HandlerCall::BlockProcessed { block_number });
+    }
+
+    async fn on_token_event(&self, event: &TokenEvent, _token: &TokenInfo) {
+        if let TokenEvent::Transfer(e) = event {
+            self.record(HandlerCall::TokenEvent {
+                transaction_hash: e.transaction_hash.clone(),
+                timestamp: e.timestamp,
+                block_number: e.block_number,
+            });
+        }
+    }
+
+    async fn on_pending_transition(&self, pending_ts: u64, latest_block_number: u64) {
+        self.record(HandlerCall::PendingTransition {
+            pending_ts,
+            block_number: latest_block_number,
+        });
+    }
+
+    async fn on_new_latest_block(&self, block_number: u64) {
+        self.record(HandlerCall::NewLatestBlock { block_number });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pending_promotion_sequences() {
+        let handler = Arc::new(RecordingEventHandler::new());
+
+        let storage = PendingScenario::new()
+            .pending(1000, [1, 2])
+            // Retried during the promotion.
+            .receipt_not_found(3)
+            .pending(1000, [1, 2, 3])
+            // Tx 4 is only seen in the latest block.
+            .latest(10, [1, 2, 3, 4])
+            .pending(1012, [5])
+            // Sequencer skip, blocks 11 and 12 are never seen.
+            .latest(13, [5, 6])
+            .pending(1020, [7])
+            // Retried at the next tick.
+            .receipt_not_found(8)
+            .pending(1020, [7, 8])
+            .pending(1020, [7, 8])
+            .run(Arc::clone(&handler))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            handler.calls(),
+            vec![
+                HandlerCall::token_event(1, 1000),
+                HandlerCall::token_event(2, 1000),
+                HandlerCall::token_event(3, 1000),
+                HandlerCall::token_event(4, 1000),
+                HandlerCall::PendingTransition {
+                    pending_ts: 1000,
+                    block_number: 10
+                },
+                HandlerCall::NewLatestBlock { block_number: 10 },
+                HandlerCall::token_event(5, 1012),
+                HandlerCall::token_event(6, 1012),
+                HandlerCall::PendingTransition {
+                    pending_ts: 1012,
+                    block_number: 13
+                },
+                HandlerCall::NewLatestBlock { block_number: 13 },
+                HandlerCall::token_event(7, 1020),
+                HandlerCall::token_event(8, 1020),
+            ]
+        );
+
+        assert_eq!(storage.dump().transfer_events.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_pending_events_finalized_on_promotion() {
+        let storage = PendingScenario::new()
+            .pending(1000, [1, 2])
+            .latest(10, [1, 2, 3])
+            .pending(1012, [4])
+            .run(Arc::new(RecordingEventHandler::new()))
+            .await
+            .unwrap();
+
+        let events: HashMap<String, Option<u64>> = storage
+            .dump()
+            .transfer_events
+            .into_values()
+            .map(|e| (e.transaction_hash, e.block_number))
+            .collect();
+
+        let block_of = |tx: ScenarioTx| events[&to_hex_str(&FieldElement::from(tx))];
+
+        assert_eq!(block_of(1), Some(10));
+        assert_eq!(block_of(2), Some(10));
+        assert_eq!(block_of(3), Some(10));
+        // Still pending.
+        assert_eq!(block_of(4), None);
+    }
+
+    #[tokio::test]
+    async fn test_promoted_block_skipped_by_range() {
+        let storage = PendingScenario::new()
+            .pending(1000, [1, 2])
+            .latest(10, [1, 2, 3])
+            .pending(1012, [4])
+            .run(Arc::new(RecordingEventHandler::new()))
+            .await
+            .unwrap();
+
+        let before = storage.dump();
+        assert_eq!(
+            before.blocks[&10].1.status,
+            crate::storage::types::BlockIndexingStatus::Terminated
+        );
+
+        // The node returns the events of the block, as mined.
+        let contracts = synthetic_contracts(1, 0);
+        let mut client = MockStarknetClient::default();
+        expect_contract_calls(&mut client, &contracts);
+        client.expect_block_id_to_u64().returning(|id| match id {
+            BlockId::Number(n) => Ok(*n),
+            _ => Ok(10),
+        });
+        client.expect_block_time().returning(|_| Ok(1000));
+        let contract_address = contracts[0].address;
+        client
+            .expect_fetch_all_block_events()
+            .returning(move |_, _| {
+                let events = [1, 2, 3]
+                    .into_iter()
+                    .map(|tx| EmittedEvent {
+                        block_number: Some(10),
+                        ..tx_event(tx, contract_address)
+                    })
+                    .collect();
+                Ok(HashMap::from([(10, events)]))
+            });
+
+        let handler = Arc::new(RecordingEventHandler::new());
+        let pontos = Pontos::new(
+            Arc::new(client),
+            Arc::clone(&storage),
+            Arc::clone(&handler),
+            PontosConfig {
+                indexer_version: "v0.0.1".to_string(),
+                indexer_identifier: "range".to_string(),
+                ..Default::default()
+            },
+        );
+
+        pontos
+            .index_block_range(
+                BlockId::Number(10),
+                BlockId::Number(10),
+                false,
+                SCENARIO_CHAIN_ID,
+            )
+            .await
+            .unwrap();
+
+        let after = storage.dump();
+        assert!(handler.calls().is_empty());
+        assert_eq!(after.transfer_events.len(), before.transfer_events.len());
+        assert_eq!(after.blocks[&10].1.indexer_identifier, "scenario");
+    }
+}
@@ -10,6 +10,7 @@ use arkproject::pontos::{
 use async_trait::async_trait;
 use starknet::core::types::BlockId;
 use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -33,6 +34,17 @@ async fn main() -> Result<()> {
         config,
     ));
 
+    // Stop the indexing loops after their current block on SIGTERM.
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let shutdown_pontos = Arc::clone(&pontos);
+    tokio::spawn(async move {
+        sigterm.recv().await;
+        println!("SIGTERM received, shutting down...");
+        if let Err(e) = shutdown_pontos.shutdown().await {
+            println!("Shutdown failed! [{:?}]", e);
+        }
+    });
+
     let mut handles = vec![];
     let do_force = false;
 